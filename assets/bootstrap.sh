@@ -1,15 +1,44 @@
 #!/bin/bash
 # === bootstrap.sh
 set -eo pipefail
-count=0
+# number of packages already unpacked, so that an interrupted run can be resumed
+PROGRESS=/var/lib/aoscbootstrap/stage2-progress
+mkdir -p "${PROGRESS%/*}"
+unpacked=$(cat "$PROGRESS" 2>/dev/null || echo 0)
 PACKAGES=(
 {}
 )
 length=${#PACKAGES[@]}
-for p in "${PACKAGES[@]}"; do
-count=$((count+1))
-echo -e "\e[1m[$count/$length] Installing ${p}...\e[0m"
-dpkg --force-depends --force-unsafe-io --unpack "/var/cache/apt/archives/${p}"
+echo "$length" > "${PROGRESS%/*}/stage2-total"
+# written by aoscbootstrap unless --no-verify-archives is given
+CHECKSUMS=/var/lib/aoscbootstrap/archives.sha256sums
+if [ -f "$CHECKSUMS" ]; then
+echo -e '\e[1mVerifying package archives ...\e[0m'
+( cd /var/cache/apt/archives && sha256sum --quiet -c "$CHECKSUMS" ) \
+|| { echo 'Some package archives are corrupted or have been replaced, see above.' >&2; exit 1; }
+fi
+# the packages extracted by aoscbootstrap in stage 1 could not run their preinst then
+STUB_PREINST=/var/lib/aoscbootstrap/stub-preinst
+if [ -f "$STUB_PREINST" ]; then
+while read -r package; do
+DPKG_MAINTSCRIPT_PACKAGE="${package%%:*}" DPKG_MAINTSCRIPT_NAME=preinst \
+"/var/lib/dpkg/info/$package.preinst" install
+done < "$STUB_PREINST"
+rm -f "$STUB_PREINST"
+fi
+# unpack in batches, in transaction order; the host parses the progress markers
+BATCH_SIZE=100
+for ((start = unpacked; start < length; start += BATCH_SIZE)); do
+batch=("${PACKAGES[@]:start:BATCH_SIZE}")
+end=$((start + ${#batch[@]}))
+echo -e "\e[1m[$((start + 1))-$end/$length] Installing ${#batch[@]} packages...\e[0m"
+if ! dpkg --force-depends --force-unsafe-io --unpack "${batch[@]/#//var/cache/apt/archives/}"; then
+echo "Batch $((start / BATCH_SIZE + 1)) (packages $((start + 1))-$end) failed: ${batch[*]}" >&2
+dpkg --configure -a || true
+exit 1
+fi
+echo "$end" > "$PROGRESS"
+echo "AOSCBOOTSTRAP-PROGRESS $end/$length"
 done
 sync
 count_c=1;length_c=$(dpkg -l | grep -c 'iU')
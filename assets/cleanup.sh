@@ -1,12 +1,58 @@
 #!/bin/bash -e
 # === cleanup.sh
-remove_files () {
+# CLEAN_STEPS (the steps to run, in order) is set by aoscbootstrap before this script, and
+# AOSCBOOTSTRAP_LOG (the log of stage 2 being written, if in the target) in the environment
+selected () {
+    [[ " ${CLEAN_STEPS[*]} " == *" $1 "* ]]
+}
+
+clean_apt_cache () {
+    echo -e '\e[1mRemoving apt caches ...\e[0m'
+    apt-get clean
+    rm -fv /var/cache/apt/*.bin
+}
+
+clean_apt_lists () {
+    echo -e '\e[1mRemoving apt lists ...\e[0m'
+    find /var/lib/apt/lists -mindepth 1 -delete
+}
+
+clean_logs () {
+    echo -e '\e[1mRemoving logs ...\e[0m'
+    find /var/log -mindepth 1 -type f ! -path "${AOSCBOOTSTRAP_LOG:-}" -delete
+}
+
+clean_machine_id () {
+    echo -e '\e[1mRemoving the machine ID ...\e[0m'
+    rm -fv /etc/machine-id /var/lib/dbus/machine-id
+}
+
+clean_tmp () {
+    echo -e '\e[1mRemoving temporary files ...\e[0m'
+    find /tmp /var/tmp -mindepth 1 -delete
+}
+
+clean_doc_trim () {
+    echo -e '\e[1mRemoving documentation ...\e[0m'
+    # copyright files are kept for license compliance
+    find /usr/share/doc -mindepth 1 -not -type d -not -name copyright -delete
+    find /usr/share/man /usr/share/info -mindepth 1 -delete
+}
+
+clean_ssh_host_keys () {
+    # these absolutely should not be in the release files
+    echo -e '\e[1mRemoving SSH host keys ...\e[0m'
+    rm -fv /etc/ssh/ssh_host_*_key*
+}
+
+clean_unowned_files () {
 WHITELIST="^/efi
 ^/etc
 ^/run
 ^/usr
 ^/var/lib/apt/gen
 ^/var/lib/apt/extended_states
+^/var/lib/aoscbootstrap
 ^/var/lib/atm/state
 ^/var/lib/dkms
 ^/var/lib/dpkg
@@ -17,6 +63,15 @@ WHITELIST="^/efi
 ^/proc
 ^/sys
 /\.updated$"
+    # keep what the steps which are not selected would remove
+    selected apt-cache || WHITELIST+=$'\n^/var/cache/apt'
+    selected apt-lists || WHITELIST+=$'\n^/var/lib/apt/lists'
+    selected logs || WHITELIST+=$'\n^/var/log'
+    selected tmp || WHITELIST+=$'\n^/var/tmp'
+    selected machine-id || WHITELIST+=$'\n^/var/lib/dbus/machine-id$'
+    if [ -n "${AOSCBOOTSTRAP_LOG:-}" ]; then
+        WHITELIST+=$'\n^'"${AOSCBOOTSTRAP_LOG}"'$'
+    fi
     local DPKG_FILES ALL_FILES RM_FILES PATTERN_FILES
     local FIND_PID
     DPKG_FILES="$(mktemp)"
@@ -38,12 +93,10 @@ WHITELIST="^/efi
     echo -e '\e[1mRemoving files ...\e[0m'
     xargs -a "$RM_FILES" rm -rfv
     rm -fv "$ALL_FILES" "$DPKG_FILES" "$RM_FILES"
-    # Remove some extra files that absolutely should not be in the release files.
-    echo -e '\e[1mRemoving sensitive files ...\e[0m'
-    rm -fv /etc/machine-id
-    rm -fv /etc/ssh/ssh_host_*_key*
 }
 
 set -eo pipefail
 echo -e '\e[1mCleaning up the installation ...\e[0m'
-remove_files
+for step in "${CLEAN_STEPS[@]}"; do
+    "clean_${step//-/_}"
+done
@@ -0,0 +1,21 @@
+#!/bin/bash
+# === verify.sh
+# Check the installation after stage 2, each problem is reported in a `FAIL:` line
+LOG=/var/log/aoscbootstrap-verify.log
+mkdir -p "${LOG%/*}"
+: > "$LOG"
+fail () {
+    echo "FAIL: $*" | tee -a "$LOG"
+}
+echo -e '\e[1mVerifying the installation ...\e[0m'
+echo '=== dpkg --audit' >> "$LOG"
+audit="$(dpkg --audit 2>&1 || true)"
+echo "$audit" >> "$LOG"
+[ -z "$audit" ] || fail 'dpkg --audit (dpkg -C) reported broken packages'
+# written by bootstrap.sh
+expected="$(cat /var/lib/aoscbootstrap/stage2-total 2>/dev/null || echo 0)"
+installed="$(grep -c '^Package: ' /var/lib/dpkg/status || true)"
+echo "=== /var/lib/dpkg/status: $installed packages, $expected installed by aoscbootstrap" >> "$LOG"
+[ "$installed" -ge "$expected" ] || fail "only $installed of $expected packages are in /var/lib/dpkg/status"
+echo '=== apt-get check' >> "$LOG"
+apt-get check >> "$LOG" 2>&1 || fail 'apt-get check reported unmet dependencies'
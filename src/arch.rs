@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use libaosc::arch::get_arch_name;
+use log::warn;
+
+/// An architecture of AOSC OS, with its names elsewhere
+pub struct Arch {
+    /// The dpkg name
+    pub name: &'static str,
+    /// The name in OCI images (GOARCH) and the variant, if any
+    pub oci: Option<(&'static str, Option<&'static str>)>,
+    /// The name used by qemu-user
+    pub qemu: Option<&'static str>,
+}
+
+/// The architectures of AOSC OS, the only list of them the other modules consult
+pub const ARCHES: &[Arch] = &[
+    Arch {
+        name: "all",
+        oci: None,
+        qemu: None,
+    },
+    Arch {
+        name: "amd64",
+        oci: Some(("amd64", None)),
+        qemu: Some("x86_64"),
+    },
+    Arch {
+        name: "arm64",
+        oci: Some(("arm64", Some("v8"))),
+        qemu: Some("aarch64"),
+    },
+    Arch {
+        name: "armv4",
+        oci: None,
+        qemu: None,
+    },
+    Arch {
+        name: "armv6hf",
+        oci: Some(("arm", Some("v6"))),
+        qemu: None,
+    },
+    Arch {
+        name: "armv7hf",
+        oci: Some(("arm", Some("v7"))),
+        qemu: Some("arm"),
+    },
+    Arch {
+        name: "i486",
+        oci: Some(("386", None)),
+        qemu: Some("i386"),
+    },
+    Arch {
+        name: "loongarch64",
+        oci: Some(("loong64", None)),
+        qemu: Some("loongarch64"),
+    },
+    Arch {
+        name: "loongson2f",
+        oci: None,
+        qemu: None,
+    },
+    Arch {
+        name: "loongson3",
+        oci: Some(("mips64le", None)),
+        qemu: Some("mips64el"),
+    },
+    Arch {
+        name: "m68k",
+        oci: None,
+        qemu: None,
+    },
+    Arch {
+        name: "mips32r6el",
+        oci: Some(("mipsle", None)),
+        qemu: None,
+    },
+    Arch {
+        name: "mips64r6el",
+        oci: Some(("mips64le", None)),
+        qemu: Some("mips64el"),
+    },
+    Arch {
+        name: "powerpc",
+        oci: None,
+        qemu: None,
+    },
+    Arch {
+        name: "ppc64",
+        oci: Some(("ppc64", None)),
+        qemu: Some("ppc64"),
+    },
+    Arch {
+        name: "ppc64el",
+        oci: Some(("ppc64le", None)),
+        qemu: Some("ppc64le"),
+    },
+    Arch {
+        name: "riscv64",
+        oci: Some(("riscv64", None)),
+        qemu: Some("riscv64"),
+    },
+];
+
+/// Look up an architecture by its dpkg name
+pub fn find(name: &str) -> Option<&'static Arch> {
+    ARCHES.iter().find(|a| a.name == name)
+}
+
+/// Names used by uname and other distributions, with their AOSC OS counterparts
+const ALIASES: &[(&str, &str)] = &[
+    ("x86_64", "amd64"),
+    ("aarch64", "arm64"),
+    ("armhf", "armv7hf"),
+    ("loong64", "loongarch64"),
+    ("ppc64le", "ppc64el"),
+    ("riscv64gc", "riscv64"),
+];
+
+/// Map the aliases of the given architectures, rejecting the unknown ones
+pub fn validate(arches: &[String]) -> Result<Vec<String>> {
+    let mut validated = Vec::new();
+    for arch in arches {
+        let name = if let Some(known) = find(arch) {
+            known.name
+        } else if let Some((_, name)) = ALIASES.iter().find(|(alias, _)| alias == arch) {
+            warn!("Using {} for {}, its name in AOSC OS.", name, arch);
+            name
+        } else {
+            return Err(anyhow!(
+                "Unknown architecture {}. Valid architectures are: {}",
+                arch,
+                ARCHES.iter().map(|a| a.name).collect::<Vec<_>>().join(", ")
+            ));
+        };
+        if !validated.iter().any(|a| a == name) {
+            validated.push(name.to_string());
+        }
+    }
+
+    Ok(validated)
+}
+
+/// Validate the given architectures, defaulting to the one of this host, and append
+/// `all` to consider the architecture-independent packages as well
+pub fn resolve(arches: &[String]) -> Result<Vec<String>> {
+    let mut resolved = validate(arches)?;
+    if resolved.iter().all(|a| a == "all") {
+        if !arches.is_empty() {
+            return Err(anyhow!(
+                "Did not find the main architecture, set it with --arch along with all."
+            ));
+        }
+        let host = get_arch_name().ok_or_else(|| {
+            anyhow!("Cannot tell the architecture of this host, set it with --arch.")
+        })?;
+        resolved.push(host.to_string());
+    }
+    if !resolved.iter().any(|a| a == "all") {
+        resolved.push("all".to_string());
+    }
+
+    Ok(resolved)
+}
+
+/// The first architecture which is not `all`, always present after [resolve]
+pub fn main_arch(arches: &[String]) -> &str {
+    arches
+        .iter()
+        .find(|a| *a != "all")
+        .map_or("all", |a| a.as_str())
+}
+
+#[test]
+fn test_validate() -> Result<()> {
+    let arches = |a: &[&str]| a.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        validate(&arches(&["aarch64", "x86_64", "riscv64gc"]))?,
+        arches(&["arm64", "amd64", "riscv64"])
+    );
+    assert_eq!(
+        validate(&arches(&["arm64", "aarch64"]))?,
+        arches(&["arm64"])
+    );
+    let e = validate(&arches(&["arm65"])).unwrap_err().to_string();
+    assert!(e.contains("Unknown architecture arm65"));
+    assert!(e.contains("loongarch64"));
+
+    assert_eq!(resolve(&arches(&["aarch64"]))?, arches(&["arm64", "all"]));
+    assert_eq!(
+        resolve(&arches(&["all", "ppc64el"]))?,
+        arches(&["all", "ppc64el"])
+    );
+    assert!(resolve(&arches(&["all"])).is_err());
+    assert_eq!(
+        main_arch(&resolve(&arches(&["all", "ppc64el"]))?),
+        "ppc64el"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,247 @@
+use std::{
+    path::PathBuf,
+    sync::{Mutex, TryLockError},
+};
+
+use anyhow::anyhow;
+use clap::Parser;
+
+use crate::{
+    cancel::{self, CancellationToken},
+    cli::{self, Args, Cli},
+    error::BootstrapError,
+    events::{self, Artifact, ProgressReporter},
+    timing::{self, PhaseTiming},
+};
+
+/// Held by the running bootstrap, as its state is kept per process
+static RUNNING: Mutex<()> = Mutex::new(());
+
+/// Options of a bootstrap, the same as those of the command line
+///
+/// The methods set the options with the same names. Those without a method here are set
+/// with [option](Self::option) and [flag](Self::flag), e.g. `.flag("--clean")`.
+#[derive(Debug, Clone, Default)]
+pub struct BootstrapBuilder {
+    config: Option<String>,
+    branch: Option<String>,
+    target: Option<PathBuf>,
+    mirror: Option<String>,
+    arches: Vec<String>,
+    include: Vec<String>,
+    topics: Vec<String>,
+    hooks: Vec<(String, PathBuf)>,
+    tar_xz: Option<PathBuf>,
+    tar_gz: Option<PathBuf>,
+    tar_zst: Option<PathBuf>,
+    squashfs: Option<PathBuf>,
+    /// The options set with [option](Self::option) and [flag](Self::flag)
+    args: Vec<String>,
+    cancel: CancellationToken,
+}
+
+/// The result of a successful run
+#[derive(Debug, Clone)]
+pub struct BuildReport {
+    /// The exported archives and the files written next to them, with their checksums
+    pub artifacts: Vec<Artifact>,
+    /// How long each phase took
+    pub timings: Vec<PhaseTiming>,
+    pub total_seconds: f64,
+}
+
+impl BootstrapBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set an option taking a value, e.g. `.option("--hostname", "aosc")`
+    pub fn option(mut self, name: &str, value: impl AsRef<str>) -> Self {
+        self.args.push(format!("{}={}", name, value.as_ref()));
+        self
+    }
+
+    /// Set an option without a value, e.g. `.flag("--deb822")`
+    pub fn flag(mut self, name: &str) -> Self {
+        self.args.push(name.to_string());
+        self
+    }
+
+    /// The config file, a path or an http(s) URL
+    pub fn config(mut self, config: impl Into<String>) -> Self {
+        self.config = Some(config.into());
+        self
+    }
+
+    pub fn branch(mut self, branch: impl Into<String>) -> Self {
+        self.branch = Some(branch.into());
+        self
+    }
+
+    pub fn target(mut self, target: impl Into<PathBuf>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    pub fn mirror(mut self, mirror: impl Into<String>) -> Self {
+        self.mirror = Some(mirror.into());
+        self
+    }
+
+    pub fn arches<I: IntoIterator<Item = S>, S: Into<String>>(mut self, arches: I) -> Self {
+        self.arches.extend(arches.into_iter().map(Into::into));
+        self
+    }
+
+    /// Extra packages to install
+    pub fn include<I: IntoIterator<Item = S>, S: Into<String>>(mut self, packages: I) -> Self {
+        self.include.extend(packages.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn topics<I: IntoIterator<Item = S>, S: Into<String>>(mut self, topics: I) -> Self {
+        self.topics.extend(topics.into_iter().map(Into::into));
+        self
+    }
+
+    /// Run a script on the host at the given phase, see `--hook`
+    pub fn hook(mut self, phase: impl Into<String>, script: impl Into<PathBuf>) -> Self {
+        self.hooks.push((phase.into(), script.into()));
+        self
+    }
+
+    pub fn export_tar_xz(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tar_xz = Some(path.into());
+        self
+    }
+
+    pub fn export_tar_gz(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tar_gz = Some(path.into());
+        self
+    }
+
+    pub fn export_tar_zst(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tar_zst = Some(path.into());
+        self
+    }
+
+    pub fn export_squashfs(mut self, path: impl Into<PathBuf>) -> Self {
+        self.squashfs = Some(path.into());
+        self
+    }
+
+    /// Stop the run when the token is cancelled, e.g. from another thread, failing with
+    /// [Error::Cancelled](crate::Error::Cancelled)
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.cancel = token;
+        self
+    }
+
+    /// Parse the options set with [option](Self::option) and [flag](Self::flag), then
+    /// apply the typed ones over them
+    fn args(&self) -> Result<Args, BootstrapError> {
+        let argv = std::iter::once("aoscbootstrap").chain(self.args.iter().map(String::as_str));
+        let mut cli = Cli::try_parse_from(argv)
+            .map_err(|e| BootstrapError::Config(anyhow!("{}", e.to_string().trim_end())))?;
+        let args = cli.args_mut();
+        let path = |path: &PathBuf| path.to_string_lossy().to_string();
+        args.config = self.config.clone().or(args.config.take());
+        args.branch = self.branch.clone().or(args.branch.take());
+        args.target = self.target.as_ref().map(path).or(args.target.take());
+        args.mirror = self.mirror.clone().or(args.mirror.take());
+        args.arch.extend(self.arches.iter().cloned());
+        args.include.extend(self.include.iter().cloned());
+        if !self.topics.is_empty() {
+            args.topics
+                .get_or_insert_with(Vec::new)
+                .extend(self.topics.iter().cloned());
+        }
+        args.hook.extend(
+            self.hooks
+                .iter()
+                .map(|(phase, script)| format!("{}={}", phase, path(script))),
+        );
+        args.tar_xz = self.tar_xz.as_ref().map(path).or(args.tar_xz.take());
+        args.tar_gz = self.tar_gz.as_ref().map(path).or(args.tar_gz.take());
+        args.tar_zst = self.tar_zst.as_ref().map(path).or(args.tar_zst.take());
+        args.squashfs = self.squashfs.as_ref().map(path).or(args.squashfs.take());
+        args.no_progressbar = true;
+
+        cli.into_args().map_err(BootstrapError::Config)
+    }
+
+    /// Bootstrap the target, sending the progress to the reporter
+    ///
+    /// The messages are logged with the [log] crate, to the logger of the program if any.
+    ///
+    /// The reporter, the cancel token, the timings and what to clean up when interrupted
+    /// are kept per process, so only one bootstrap runs at a time: while one is running,
+    /// the others fail right away with [Error::Busy](crate::Error::Busy).
+    pub fn run(&self, reporter: &dyn ProgressReporter) -> Result<BuildReport, BootstrapError> {
+        let _running = match RUNNING.try_lock() {
+            Ok(guard) => guard,
+            // the run which panicked is over all the same
+            Err(TryLockError::Poisoned(e)) => e.into_inner(),
+            Err(TryLockError::WouldBlock) => {
+                return Err(BootstrapError::Busy(anyhow!(
+                    "Another bootstrap is running in this process."
+                )))
+            }
+        };
+        let args = self.args()?;
+        events::with_reporter(reporter, || {
+            cancel::set_token(self.cancel.clone());
+            let result = cli::run(args);
+            events::end_phase();
+            let (timings, total_seconds) = timing::report();
+            let artifacts = match result {
+                Ok(()) => events::finish(),
+                Err(_) => Vec::new(),
+            };
+            cancel::set_token(CancellationToken::default());
+            result?;
+
+            Ok(BuildReport {
+                artifacts,
+                timings,
+                total_seconds,
+            })
+        })
+    }
+}
+
+#[test]
+fn test_builder() {
+    let args = BootstrapBuilder::new()
+        .config("base.toml")
+        .branch("stable")
+        .target("/var/lib/images/base")
+        .arches(["amd64", "all"])
+        .include(["vim", "git"])
+        .hook("post-stage1", "/usr/lib/hooks/brand.sh")
+        .export_tar_zst("base.tar.zst")
+        .option("--include", "curl")
+        .flag("--deb822")
+        .args()
+        .unwrap();
+    assert_eq!(args.config.as_deref(), Some("base.toml"));
+    assert_eq!(args.branch.as_deref(), Some("stable"));
+    assert_eq!(args.target.as_deref(), Some("/var/lib/images/base"));
+    assert_eq!(args.arch, ["amd64", "all"]);
+    assert_eq!(args.include, ["curl", "vim", "git"]);
+    assert_eq!(args.hook, ["post-stage1=/usr/lib/hooks/brand.sh"]);
+    assert_eq!(args.tar_zst.as_deref(), Some("base.tar.zst"));
+    assert!(args.no_progressbar);
+    assert!(format!("{:?}", args).contains("deb822: true"));
+}
+
+#[test]
+fn test_busy() {
+    struct Quiet;
+
+    impl ProgressReporter for Quiet {}
+
+    let _running = RUNNING.lock().unwrap();
+    let e = BootstrapBuilder::new().run(&Quiet).unwrap_err();
+    assert!(matches!(e, BootstrapError::Busy(_)));
+}
@@ -0,0 +1,106 @@
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Result;
+use thiserror::Error;
+
+/// Stops a running bootstrap at the next check: between the downloads, between the
+/// extracted packages, while exporting and before stage 2. The target is then dealt with
+/// as with Ctrl-C, according to `--on-interrupt`.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask the bootstrap using this token to stop
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Fail with [Cancelled] once cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// Marks the errors raised because the bootstrap was cancelled
+#[derive(Debug, Error)]
+#[error("The bootstrap was cancelled.")]
+pub struct Cancelled;
+
+/// The token of the running bootstrap
+static TOKEN: Mutex<Option<CancellationToken>> = Mutex::new(None);
+
+/// Use the given token for the bootstrap about to run
+pub fn set_token(token: CancellationToken) {
+    *TOKEN.lock().unwrap() = Some(token);
+}
+
+/// The token of the running bootstrap, cancelled by Ctrl-C on the command line
+pub fn token() -> CancellationToken {
+    TOKEN
+        .lock()
+        .unwrap()
+        .get_or_insert_with(Default::default)
+        .clone()
+}
+
+pub fn cancelled() -> bool {
+    token().is_cancelled()
+}
+
+/// Fail with [Cancelled] once the running bootstrap is cancelled
+pub fn check() -> Result<()> {
+    token().check()
+}
+
+/// Fail the writes once cancelled, to stop the downloads in flight and the exports
+pub struct StopOnCancel<W: Write>(pub W, pub CancellationToken);
+
+impl<W: Write> StopOnCancel<W> {
+    /// Stop when the running bootstrap is cancelled
+    pub fn new(inner: W) -> Self {
+        Self(inner, token())
+    }
+}
+
+impl<W: Write> Write for StopOnCancel<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.1.is_cancelled() {
+            return Err(std::io::Error::other(Cancelled));
+        }
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[test]
+fn test_cancel() {
+    let token = CancellationToken::new();
+    assert!(token.check().is_ok());
+    let mut sink = StopOnCancel(Vec::new(), token.clone());
+    sink.write_all(b"usr/").unwrap();
+    token.cancel();
+    assert!(token.check().unwrap_err().is::<Cancelled>());
+    assert!(sink.write_all(b"bin/").is_err());
+    assert_eq!(sink.0, b"usr/");
+}
@@ -0,0 +1,468 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::{File, Metadata},
+    io::{Read, Write},
+    os::unix::{
+        ffi::{OsStrExt, OsStringExt},
+        fs::MetadataExt,
+    },
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytesize::ByteSize;
+use clap::ValueEnum;
+use log::debug;
+use nix::sys::stat::{major, minor};
+use xz2::write::XzEncoder;
+
+use crate::{
+    cancel::{self, StopOnCancel},
+    fs::{kernel_xz_encoder, HashingWriter, XzPlan, TARGET_LOCK},
+};
+
+const MAGIC: &[u8] = b"070701";
+const TRAILER: &str = "TRAILER!!!";
+/// Size of a newc header, before the name
+const HEADER_SIZE: u64 = 110;
+const S_IFDIR: u32 = 0o040000;
+const S_IFREG: u32 = 0o100000;
+/// Where the kernel looks for the early microcode, by vendor
+const MICROCODE_DIR: &str = "kernel/x86/microcode";
+
+/// Compressor of the cpio archive, among those the kernel can unpack an initramfs with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    None,
+    Zstd,
+    Xz,
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// How to compress the archive: the compressor with its settings
+pub enum Encoder<'a> {
+    None,
+    Zstd { threads: u32 },
+    Xz(&'a XzPlan),
+}
+
+#[derive(Default)]
+struct Header {
+    ino: u32,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u32,
+    mtime: u32,
+    size: u32,
+    rdev_major: u32,
+    rdev_minor: u32,
+}
+
+/// Bytes needed to align `len` on 4 bytes, as newc does for the names and the data
+fn padding(len: u64) -> usize {
+    ((4 - len % 4) % 4) as usize
+}
+
+/// Size of an entry in the archive
+fn entry_size(name: &[u8], size: u64) -> u64 {
+    let header = HEADER_SIZE + name.len() as u64 + 1;
+    header + padding(header) as u64 + size + padding(size) as u64
+}
+
+/// Writer of a cpio archive in the newc format, the one of initramfs
+struct CpioWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> CpioWriter<W> {
+    fn new(inner: W) -> Self {
+        CpioWriter { inner }
+    }
+
+    fn append(&mut self, header: &Header, name: &[u8], data: impl Read) -> Result<()> {
+        let fields = [
+            header.ino,
+            header.mode,
+            header.uid,
+            header.gid,
+            header.nlink,
+            header.mtime,
+            header.size,
+            // the device of the file, meaningless once archived
+            0,
+            0,
+            header.rdev_major,
+            header.rdev_minor,
+            name.len() as u32 + 1,
+            // checksum, only used by the crc format
+            0,
+        ];
+        let mut buf = Vec::with_capacity(HEADER_SIZE as usize + name.len() + 4);
+        buf.extend_from_slice(MAGIC);
+        for field in fields {
+            write!(buf, "{:08x}", field)?;
+        }
+        buf.extend_from_slice(name);
+        buf.push(0);
+        buf.resize(buf.len() + padding(buf.len() as u64), 0);
+        self.inner.write_all(&buf)?;
+        let size = header.size as u64;
+        let copied = std::io::copy(&mut data.take(size), &mut self.inner)?;
+        if copied != size {
+            return Err(anyhow!(
+                "{} changed while being archived",
+                String::from_utf8_lossy(name)
+            ));
+        }
+        self.inner.write_all(&[0; 3][..padding(size)])?;
+
+        Ok(())
+    }
+
+    fn append_dir(&mut self, name: &str, ino: u32) -> Result<()> {
+        let header = Header {
+            ino,
+            mode: S_IFDIR | 0o755,
+            nlink: 2,
+            ..Default::default()
+        };
+
+        self.append(&header, name.as_bytes(), std::io::empty())
+    }
+
+    /// Write the trailer, ending the archive
+    fn finish(mut self) -> Result<W> {
+        let header = Header {
+            nlink: 1,
+            ..Default::default()
+        };
+        self.append(&header, TRAILER.as_bytes(), std::io::empty())?;
+
+        Ok(self.inner)
+    }
+}
+
+struct Entry {
+    /// Path in the archive, `.` for the root
+    name: Vec<u8>,
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+/// List the files under the root, each directory before its content, in a stable order
+fn collect_entries(root: &Path) -> Result<Vec<Entry>> {
+    fn walk(dir: &Path, prefix: &[u8], entries: &mut Vec<Entry>) -> Result<()> {
+        let mut children = std::fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        children.sort_by_key(|e| e.file_name());
+        for child in children {
+            cancel::check()?;
+            if prefix.is_empty() && child.file_name() == TARGET_LOCK {
+                continue;
+            }
+            let mut name = prefix.to_vec();
+            if !name.is_empty() {
+                name.push(b'/');
+            }
+            name.extend_from_slice(child.file_name().as_bytes());
+            let path = child.path();
+            let metadata = std::fs::symlink_metadata(&path)?;
+            let is_dir = metadata.is_dir();
+            entries.push(Entry {
+                name: name.clone(),
+                path: path.clone(),
+                metadata,
+            });
+            if is_dir {
+                walk(&path, &name, entries)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    let mut entries = vec![Entry {
+        name: b".".to_vec(),
+        path: root.to_path_buf(),
+        metadata: std::fs::symlink_metadata(root)?,
+    }];
+    walk(root, b"", &mut entries)?;
+
+    Ok(entries)
+}
+
+/// Write the entries, with the data of hard links on the last of them as the kernel and
+/// GNU cpio expect
+fn write_entries<W: Write>(writer: &mut CpioWriter<W>, entries: &[Entry]) -> Result<()> {
+    let mut links: HashMap<(u64, u64), (u32, u32)> = HashMap::new();
+    for entry in entries {
+        let m = &entry.metadata;
+        if !m.is_dir() && m.nlink() > 1 {
+            links.entry((m.dev(), m.ino())).or_default().0 += 1;
+        }
+    }
+    let mut next_ino = 1;
+    let mut inos: HashMap<(u64, u64), u32> = HashMap::new();
+    for entry in entries {
+        cancel::check()?;
+        let m = &entry.metadata;
+        let key = (m.dev(), m.ino());
+        let ino = *inos.entry(key).or_insert_with(|| {
+            next_ino += 1;
+            next_ino - 1
+        });
+        let (nlink, last_link) = match links.get_mut(&key) {
+            Some((count, seen)) => {
+                *seen += 1;
+                (*count, *seen == *count)
+            }
+            None if m.is_dir() => (m.nlink() as u32, true),
+            None => (1, true),
+        };
+        let mut data: Box<dyn Read> = Box::new(std::io::empty());
+        let mut size = 0;
+        if m.file_type().is_symlink() {
+            let target = std::fs::read_link(&entry.path)?;
+            size = target.as_os_str().len() as u64;
+            data = Box::new(std::io::Cursor::new(target.into_os_string().into_vec()));
+        } else if m.is_file() && last_link {
+            size = m.len();
+            data = Box::new(File::open(&entry.path)?);
+        }
+        let name = String::from_utf8_lossy(&entry.name);
+        let header = Header {
+            ino,
+            mode: m.mode(),
+            uid: m.uid(),
+            gid: m.gid(),
+            nlink,
+            mtime: m.mtime().clamp(0, u32::MAX as i64) as u32,
+            size: u32::try_from(size)
+                .map_err(|_| anyhow!("{} is too large for a cpio archive (4 GiB at most)", name))?,
+            rdev_major: major(m.rdev()) as u32,
+            rdev_minor: minor(m.rdev()) as u32,
+        };
+        writer
+            .append(&header, &entry.name, data)
+            .context(format!("when archiving {}", name))?;
+    }
+
+    Ok(())
+}
+
+/// The early microcode segment for the file: an uncompressed archive with the microcode
+/// where the kernel looks for it, or the file itself if it is already one
+fn microcode_segment(microcode: &Path) -> Result<Vec<u8>> {
+    let blob = std::fs::read(microcode).context(format!(
+        "when reading the microcode {}",
+        microcode.display()
+    ))?;
+    if blob.starts_with(MAGIC) {
+        return Ok(blob);
+    }
+    let vendor = if blob.starts_with(b"DMA\0") {
+        "AuthenticAMD"
+    } else if blob.starts_with(&1u32.to_le_bytes()) {
+        // the header version of the Intel microcode updates
+        "GenuineIntel"
+    } else {
+        return Err(anyhow!(
+            "{} is neither an early microcode archive nor an Intel or AMD microcode update.",
+            microcode.display()
+        ));
+    };
+    let mut writer = CpioWriter::new(Vec::new());
+    let mut dir = String::new();
+    for (ino, part) in MICROCODE_DIR.split('/').enumerate() {
+        if !dir.is_empty() {
+            dir.push('/');
+        }
+        dir.push_str(part);
+        writer.append_dir(&dir, ino as u32 + 1)?;
+    }
+    let header = Header {
+        ino: 4,
+        mode: S_IFREG | 0o644,
+        nlink: 1,
+        size: u32::try_from(blob.len())?,
+        ..Default::default()
+    };
+    let name = format!("{}/{}.bin", MICROCODE_DIR, vendor);
+    writer.append(&header, name.as_bytes(), blob.as_slice())?;
+
+    writer.finish()
+}
+
+/// Check that the file can be made into an early microcode segment, before anything is done
+pub fn check_microcode(microcode: &Path) -> Result<()> {
+    microcode_segment(microcode)?;
+
+    Ok(())
+}
+
+/// Make a cpio archive (newc) of the root to be loaded as an initramfs, returning its
+/// SHA256 checksum. The early microcode, if any, goes uncompressed before it.
+pub fn archive_cpio(
+    root: &Path,
+    target: &Path,
+    encoder: Encoder,
+    microcode: Option<&Path>,
+) -> Result<String> {
+    let early = microcode.map(microcode_segment).transpose()?;
+    let entries = collect_entries(root)?;
+    let size = entries
+        .iter()
+        .map(|e| {
+            let m = &e.metadata;
+            let data = if m.is_file() || m.file_type().is_symlink() {
+                m.len()
+            } else {
+                0
+            };
+            entry_size(&e.name, data)
+        })
+        .sum::<u64>();
+    debug!(
+        "Archiving {} files, about {} before compression",
+        entries.len(),
+        ByteSize::b(size)
+    );
+    let mut f = HashingWriter::new(File::create(target)?);
+    if let Some(early) = early {
+        f.write_all(&early)?;
+    }
+    let f = match encoder {
+        Encoder::None => {
+            let mut writer = CpioWriter::new(StopOnCancel::new(f));
+            write_entries(&mut writer, &entries)?;
+            writer.finish()?.0
+        }
+        Encoder::Zstd { threads } => {
+            let mut zstd = zstd::Encoder::new(f, 19)?;
+            zstd.multithread(threads)?;
+            let mut writer = CpioWriter::new(StopOnCancel::new(zstd));
+            write_entries(&mut writer, &entries)?;
+            writer.finish()?.0.finish()?
+        }
+        Encoder::Xz(plan) => {
+            let xz = XzEncoder::new_stream(f, kernel_xz_encoder(plan)?);
+            let mut writer = CpioWriter::new(StopOnCancel::new(xz));
+            write_entries(&mut writer, &entries)?;
+            writer.finish()?.0.finish()?
+        }
+    };
+    let (f, sha256) = f.finish();
+    f.sync_all()?;
+
+    Ok(sha256)
+}
+
+#[test]
+fn test_cpio_archive() -> Result<()> {
+    // read the entries of an uncompressed archive, as (name, mode, nlink, data)
+    fn read_entries(mut archive: &[u8]) -> Vec<(String, u32, u32, Vec<u8>)> {
+        let field = |header: &[u8], i: usize| {
+            let hex = std::str::from_utf8(&header[6 + i * 8..14 + i * 8]).unwrap();
+            u32::from_str_radix(hex, 16).unwrap()
+        };
+        let mut entries = Vec::new();
+        loop {
+            assert!(archive.starts_with(MAGIC));
+            let (mode, nlink, size, name_size) = (
+                field(archive, 1),
+                field(archive, 4),
+                field(archive, 6) as usize,
+                field(archive, 11) as usize,
+            );
+            let name = String::from_utf8(archive[110..109 + name_size].to_vec()).unwrap();
+            let start = 110 + name_size + padding(110 + name_size as u64);
+            let data = archive[start..start + size].to_vec();
+            archive = &archive[start + size + padding(size as u64)..];
+            if name == TRAILER {
+                assert!(archive.is_empty());
+                return entries;
+            }
+            entries.push((name, mode, nlink, data));
+        }
+    }
+
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir_all(root.path().join("usr/bin"))?;
+    std::fs::write(root.path().join("usr/bin/busybox"), "busybox")?;
+    std::fs::hard_link(
+        root.path().join("usr/bin/busybox"),
+        root.path().join("usr/bin/sh"),
+    )?;
+    std::os::unix::fs::symlink("usr/bin", root.path().join("bin"))?;
+    std::fs::write(root.path().join("init"), "#!/bin/sh\n")?;
+    std::fs::write(root.path().join(TARGET_LOCK), "")?;
+    let out = tempfile::tempdir()?;
+    let target = out.path().join("initrd.cpio");
+    let sha256 = archive_cpio(root.path(), &target, Encoder::None, None)?;
+    let archive = std::fs::read(&target)?;
+    assert_eq!(sha256, crate::fs::sha256sum(archive.as_slice())?);
+
+    let entries = read_entries(&archive);
+    let names = entries.iter().map(|e| e.0.as_str()).collect::<Vec<_>>();
+    assert_eq!(
+        names,
+        [
+            ".",
+            "bin",
+            "init",
+            "usr",
+            "usr/bin",
+            "usr/bin/busybox",
+            "usr/bin/sh"
+        ]
+    );
+    assert_eq!(entries[1].1 & 0o170000, 0o120000);
+    assert_eq!(entries[1].3, b"usr/bin");
+    assert_eq!(entries[2].1 & 0o170000, S_IFREG);
+    assert_eq!(entries[2].3, b"#!/bin/sh\n");
+    // the data of the hard links goes with the last one
+    assert_eq!((entries[5].2, entries[5].3.len()), (2, 0));
+    assert_eq!(
+        (entries[6].2, entries[6].3.as_slice()),
+        (2, &b"busybox"[..])
+    );
+
+    let zstd = out.path().join("initrd.cpio.zst");
+    archive_cpio(root.path(), &zstd, Encoder::Zstd { threads: 1 }, None)?;
+    let decoded = zstd::decode_all(File::open(&zstd)?)?;
+    assert_eq!(read_entries(&decoded).len(), entries.len());
+
+    // the early microcode goes first, uncompressed
+    let microcode = out.path().join("GenuineIntel.bin");
+    let mut blob = 1u32.to_le_bytes().to_vec();
+    blob.extend_from_slice(&[0xaa; 60]);
+    std::fs::write(&microcode, &blob)?;
+    archive_cpio(
+        root.path(),
+        &zstd,
+        Encoder::Zstd { threads: 1 },
+        Some(&microcode),
+    )?;
+    let archive = std::fs::read(&zstd)?;
+    let early = microcode_segment(&microcode)?;
+    assert!(archive.starts_with(&early));
+    assert_eq!(zstd::decode_all(&archive[early.len()..])?, decoded);
+    let early = read_entries(&early);
+    assert_eq!(early[3].0, "kernel/x86/microcode/GenuineIntel.bin");
+    assert_eq!(early[3].3, blob);
+    std::fs::write(&microcode, "not microcode")?;
+    assert!(microcode_segment(&microcode).is_err());
+
+    Ok(())
+}
@@ -0,0 +1,314 @@
+use std::{collections::BTreeMap, fmt::Display, fs::File, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde_json::json;
+use tar::{Builder, EntryType, Header};
+
+use crate::fs::{archive_tarball, sha256sum, HashingWriter};
+
+/// A repository and a tag, as given to `--export-docker`
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageName {
+    pub name: String,
+    pub tag: String,
+}
+
+impl Display for ImageName {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.name, self.tag)
+    }
+}
+
+/// Parse `NAME[:TAG]`, the tag defaulting to `latest` as with Docker. The colon of a
+/// registry port (`localhost:5000/aosc`) does not start a tag.
+pub fn parse_image_name(value: &str) -> Result<ImageName, String> {
+    let (name, tag) = match value.rsplit_once(':') {
+        Some((name, tag)) if !tag.contains('/') => (name, tag),
+        _ => (value, "latest"),
+    };
+    let parts = name.split('/').collect::<Vec<_>>();
+    // the first of several parts may be a registry, with a port
+    let valid_name = parts.iter().enumerate().all(|(i, part)| {
+        let registry = i == 0 && parts.len() > 1;
+        !part.is_empty()
+            && part
+                .bytes()
+                .all(|c| is_name_char(c) || (registry && c == b':'))
+    });
+    if !valid_name {
+        return Err(format!(
+            "'{}' is not a valid image name: use lowercase letters, digits, '.', '_', '-' and '/'",
+            name
+        ));
+    }
+    let valid_tag = !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || b"._-".contains(&c));
+    if !valid_tag {
+        return Err(format!(
+            "'{}' is not a valid tag: use at most 128 letters, digits, '.', '_' and '-'",
+            tag
+        ));
+    }
+
+    Ok(ImageName {
+        name: name.to_string(),
+        tag: tag.to_string(),
+    })
+}
+
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_lowercase() || c.is_ascii_digit() || b"._-".contains(&c)
+}
+
+/// Where `--export-docker` writes the image without `--docker-archive`
+pub fn default_archive(image: &ImageName) -> String {
+    format!("{}_{}.tar", image.name.replace(['/', ':'], "_"), image.tag)
+}
+
+/// Map the architecture to its name in OCI images (GOARCH) and its variant, if any
+pub fn oci_arch(arch: &str) -> Option<(&'static str, Option<&'static str>)> {
+    crate::arch::find(arch)?.oci
+}
+
+/// What the image runs, and with which environment
+pub struct ImageConfig {
+    /// Architecture of the target, by its AOSC OS name
+    pub arch: String,
+    pub env: Vec<String>,
+    pub cmd: Vec<String>,
+    /// Creation date of the image, in RFC 3339
+    pub created: String,
+}
+
+impl ImageConfig {
+    /// The image config, referencing the layer by its checksum
+    fn to_json(&self, diff_id: &str) -> Result<serde_json::Value> {
+        let (architecture, variant) = platform(&self.arch)?;
+        let mut config = json!({
+            "architecture": architecture,
+            "os": "linux",
+            "created": self.created,
+            "config": {
+                "Env": self.env,
+                "Cmd": self.cmd,
+            },
+            "rootfs": {
+                "type": "layers",
+                "diff_ids": [format!("sha256:{}", diff_id)],
+            },
+            "history": [{
+                "created": self.created,
+                "created_by": format!("aoscbootstrap {}", env!("CARGO_PKG_VERSION")),
+            }],
+        });
+        if let Some(variant) = variant {
+            config["variant"] = json!(variant);
+        }
+
+        Ok(config)
+    }
+}
+
+fn platform(arch: &str) -> Result<(&'static str, Option<&'static str>)> {
+    oci_arch(arch).ok_or_else(|| {
+        anyhow!(
+            "There is no OCI name for the {} architecture, cannot make an image of it.",
+            arch
+        )
+    })
+}
+
+/// Check that an image can be made for the architecture, before anything is done
+pub fn check_arch(arch: &str) -> Result<()> {
+    platform(arch)?;
+
+    Ok(())
+}
+
+fn append_file(
+    builder: &mut Builder<HashingWriter<File>>,
+    path: &str,
+    data: impl std::io::Read,
+    size: u64,
+    mtime: u64,
+) -> Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(0o644);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, path, data)?;
+
+    Ok(())
+}
+
+/// Make an archive in the format of `docker save`, with the root as its only layer,
+/// returning its SHA256 checksum. The layer is written next to the archive first, as
+/// the archive records its size and checksum.
+pub fn archive_docker_image(
+    root: &Path,
+    target: &Path,
+    image: &ImageName,
+    config: &ImageConfig,
+) -> Result<String> {
+    check_arch(&config.arch)?;
+    let dir = target
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let layer = tempfile::NamedTempFile::new_in(dir)?;
+    let diff_id = archive_tarball(root, layer.path())?;
+    let config = serde_json::to_vec(&config.to_json(&diff_id)?)?;
+    let config_id = sha256sum(config.as_slice())?;
+    let manifest = serde_json::to_vec(&json!([{
+        "Config": format!("{}.json", config_id),
+        "RepoTags": [image.to_string()],
+        "Layers": [format!("{}/layer.tar", diff_id)],
+    }]))?;
+    let repositories = serde_json::to_vec(&BTreeMap::from([(
+        &image.name,
+        BTreeMap::from([(&image.tag, &diff_id)]),
+    )]))?;
+    let mtime = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    let mut builder = Builder::new(HashingWriter::new(File::create(target)?));
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Directory);
+    header.set_size(0);
+    header.set_mode(0o755);
+    header.set_mtime(mtime);
+    builder.append_data(&mut header, format!("{}/", diff_id), std::io::empty())?;
+    let f = File::open(layer.path())?;
+    let size = f.metadata()?.len();
+    append_file(
+        &mut builder,
+        &format!("{}/layer.tar", diff_id),
+        f,
+        size,
+        mtime,
+    )?;
+    for (path, data) in [
+        (format!("{}.json", config_id), &config),
+        ("manifest.json".to_string(), &manifest),
+        ("repositories".to_string(), &repositories),
+    ] {
+        append_file(
+            &mut builder,
+            &path,
+            data.as_slice(),
+            data.len() as u64,
+            mtime,
+        )?;
+    }
+    let (f, sha256) = builder.into_inner()?.finish();
+    f.sync_all()?;
+
+    Ok(sha256)
+}
+
+#[test]
+fn test_parse_image_name() {
+    let image = |name: &str, tag: &str| ImageName {
+        name: name.to_string(),
+        tag: tag.to_string(),
+    };
+    assert_eq!(
+        parse_image_name("aosc/base:20250101"),
+        Ok(image("aosc/base", "20250101"))
+    );
+    assert_eq!(parse_image_name("aosc"), Ok(image("aosc", "latest")));
+    assert_eq!(
+        parse_image_name("localhost:5000/aosc/base"),
+        Ok(image("localhost:5000/aosc/base", "latest"))
+    );
+    assert_eq!(
+        parse_image_name("localhost:5000/aosc:v1"),
+        Ok(image("localhost:5000/aosc", "v1"))
+    );
+    for invalid in [
+        "",
+        ":tag",
+        "AOSC:latest",
+        "aosc:",
+        "aosc:-x",
+        "aosc//base:1",
+    ] {
+        assert!(parse_image_name(invalid).is_err(), "{}", invalid);
+    }
+    assert_eq!(
+        default_archive(&image("localhost:5000/aosc", "v1")),
+        "localhost_5000_aosc_v1.tar"
+    );
+    assert_eq!(oci_arch("loongarch64"), Some(("loong64", None)));
+    assert_eq!(oci_arch("arm64"), Some(("arm64", Some("v8"))));
+    assert!(check_arch("m68k").is_err());
+}
+
+#[test]
+fn test_docker_image() -> Result<()> {
+    use std::io::Read;
+
+    let root = tempfile::tempdir()?;
+    std::fs::create_dir_all(root.path().join("usr/bin"))?;
+    std::fs::write(root.path().join("usr/bin/hello"), "#!/bin/sh\necho hello\n")?;
+    let out = tempfile::tempdir()?;
+    let target = out.path().join("image.tar");
+    let image = parse_image_name("aosc/base:test").unwrap();
+    let config = ImageConfig {
+        arch: "arm64".to_string(),
+        env: vec!["PATH=/usr/bin".to_string()],
+        cmd: vec!["/bin/bash".to_string()],
+        created: "2025-01-01T00:00:00Z".to_string(),
+    };
+    let sha256 = archive_docker_image(root.path(), &target, &image, &config)?;
+    assert_eq!(sha256, sha256sum(File::open(&target)?)?);
+    // no layer is left behind
+    assert_eq!(std::fs::read_dir(out.path())?.count(), 1);
+
+    let mut files = BTreeMap::new();
+    for entry in tar::Archive::new(File::open(&target)?).entries()? {
+        let mut entry = entry?;
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data)?;
+        files.insert(entry.path()?.to_string_lossy().to_string(), data);
+    }
+    let manifest: serde_json::Value = serde_json::from_slice(&files["manifest.json"])?;
+    assert_eq!(manifest[0]["RepoTags"], json!(["aosc/base:test"]));
+    let layer_path = manifest[0]["Layers"][0].as_str().unwrap();
+    let diff_id = sha256sum(files[layer_path].as_slice())?;
+    assert_eq!(layer_path, format!("{}/layer.tar", diff_id));
+    let config_path = manifest[0]["Config"].as_str().unwrap();
+    assert_eq!(
+        config_path,
+        format!("{}.json", sha256sum(files[config_path].as_slice())?)
+    );
+    let config: serde_json::Value = serde_json::from_slice(&files[config_path])?;
+    assert_eq!(config["architecture"], "arm64");
+    assert_eq!(config["variant"], "v8");
+    assert_eq!(config["os"], "linux");
+    assert_eq!(config["config"]["Cmd"], json!(["/bin/bash"]));
+    assert_eq!(config["config"]["Env"], json!(["PATH=/usr/bin"]));
+    assert_eq!(
+        config["rootfs"]["diff_ids"],
+        json!([format!("sha256:{}", diff_id)])
+    );
+    let repositories: serde_json::Value = serde_json::from_slice(&files["repositories"])?;
+    assert_eq!(repositories["aosc/base"]["test"], diff_id.as_str());
+    let layer = tar::Archive::new(files[layer_path].as_slice())
+        .entries()?
+        .map(|e| e.unwrap().path().unwrap().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    assert!(
+        layer.iter().any(|p| p.ends_with("usr/bin/hello")),
+        "{:?}",
+        layer
+    );
+
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use anyhow::Result;
+use bytesize::ByteSize;
+use nix::{
+    sys::statvfs::{statvfs, FsFlags},
+    unistd::Uid,
+};
+use owo_colors::OwoColorize;
+
+use crate::{
+    fs::SquashfsBackend,
+    events,
+    guest::{self, Backend},
+    network,
+};
+
+/// Free space below which a destination is reported, about what a desktop system takes
+const LOW_SPACE: u64 = 8 << 30;
+/// Free inodes below which a destination is reported
+const LOW_INODES: u64 = 500_000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    /// A hard requirement is not met
+    Fail,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        };
+        // pad, so that the report lines up
+        f.pad(name)
+    }
+}
+
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, status: Status, detail: impl Display) -> Self {
+        Check {
+            name: name.into(),
+            status,
+            detail: detail.to_string(),
+        }
+    }
+}
+
+/// What the requested bootstrap needs from the host
+#[derive(Default)]
+pub struct Requirements {
+    /// Whether it has to run as root, i.e. without --unprivileged
+    pub root: bool,
+    /// Backend running stage 2, none if stage 2 does not run here
+    pub backend: Option<Backend>,
+    /// Whether the container is booted, and waited for with libsystemd
+    pub boot: bool,
+    /// Compressor of the squashfs export, if any
+    pub squashfs: Option<String>,
+    /// Architecture of the target, whose binaries run in stage 2
+    pub arch: Option<String>,
+    /// Other tools the exports run
+    pub tools: Vec<&'static str>,
+    /// The target and the export destinations
+    pub paths: Vec<PathBuf>,
+    /// A URL of the mirror to reach
+    pub mirror: Option<String>,
+}
+
+/// The checks quick enough to run before every bootstrap, without touching the
+/// network or the destinations
+pub fn quick_checks(req: &Requirements) -> Vec<Check> {
+    let mut checks = Vec::new();
+    if let Some(backend) = req.backend {
+        checks.extend(check_backend(backend, req.boot));
+    }
+    if let Some(ref arch) = req.arch {
+        checks.push(check_binfmt(arch, req.backend.is_some()));
+    }
+    if let Some(ref compressor) = req.squashfs {
+        checks.push(check_squashfs(compressor));
+    }
+    checks.extend(req.tools.iter().copied().map(check_tool));
+
+    checks
+}
+
+/// Every check for the requested bootstrap
+pub fn all_checks(req: &Requirements) -> Vec<Check> {
+    let mut checks = vec![check_uid(req.root)];
+    checks.extend(quick_checks(req));
+    checks.extend(req.paths.iter().map(|p| check_space(p)));
+    if let Some(ref url) = req.mirror {
+        checks.push(check_mirror(url));
+    }
+
+    checks
+}
+
+/// Print the checks as a table, to stderr with `--json-progress` as stdout carries the events
+pub fn print_report(checks: &[Check]) {
+    let width = checks.iter().map(|c| c.name.len()).max().unwrap_or(0) + 2;
+    for check in checks {
+        let status = format!("{:<6}", check.status);
+        let status = match check.status {
+            Status::Pass => status.green().to_string(),
+            Status::Warn => status.yellow().to_string(),
+            Status::Fail => status.red().bold().to_string(),
+        };
+        let line = format!("{}{:<width$}{}", status, check.name, check.detail, width = width);
+        if events::enabled() {
+            eprintln!("{}", line);
+        } else {
+            println!("{}", line);
+        }
+    }
+}
+
+fn check_uid(required: bool) -> Check {
+    let uid = Uid::effective();
+    if uid.is_root() {
+        Check::new("effective uid", Status::Pass, "running as root")
+    } else if required {
+        Check::new(
+            "effective uid",
+            Status::Fail,
+            format_args!("running as uid {}, root is required", uid),
+        )
+    } else {
+        Check::new(
+            "effective uid",
+            Status::Pass,
+            format_args!("running as uid {}, unprivileged", uid),
+        )
+    }
+}
+
+/// Check that a tool is in PATH, reporting the first line of its version
+fn check_tool(tool: &str) -> Check {
+    let Ok(path) = which::which(tool) else {
+        return Check::new(tool, Status::Fail, "not found in PATH");
+    };
+    let version = Command::new(&path)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|o| {
+            String::from_utf8_lossy(&o.stdout)
+                .lines()
+                .find(|l| !l.trim().is_empty())
+                .map(|l| l.trim().to_string())
+        });
+
+    match version {
+        Some(version) => Check::new(tool, Status::Pass, version),
+        None => Check::new(tool, Status::Pass, path.display()),
+    }
+}
+
+fn check_backend(backend: Backend, boot: bool) -> Vec<Check> {
+    let probed = match guest::probe_backend(backend) {
+        Ok(probed) => probed,
+        Err(e) => return vec![Check::new("stage 2 backend", Status::Fail, e)],
+    };
+    let mut checks = Vec::new();
+    if backend == Backend::Auto {
+        checks.push(Check::new(
+            "stage 2 backend",
+            Status::Pass,
+            format_args!("auto, using {}", probed),
+        ));
+    }
+    let tools: &[&str] = match probed {
+        Backend::Nspawn => &["systemd-nspawn", "machinectl"],
+        Backend::Chroot => &["chroot"],
+        Backend::Bwrap => &["bwrap"],
+        Backend::Auto => &[],
+    };
+    checks.extend(tools.iter().copied().map(check_tool));
+    if probed == Backend::Nspawn && boot {
+        checks.push(match guest::check_libsystemd() {
+            Ok(()) => Check::new("libsystemd", Status::Pass, "loadable"),
+            Err(e) => Check::new(
+                "libsystemd",
+                Status::Warn,
+                format_args!("{}, machinectl is polled instead", e),
+            ),
+        });
+    }
+
+    checks
+}
+
+/// Check that the binaries of the architecture run here, which only stage 2 requires
+fn check_binfmt(arch: &str, stage2: bool) -> Check {
+    let name = format!("{} binaries", arch);
+    match guest::foreign_interpreter(arch) {
+        Ok(None) => Check::new(name, Status::Pass, "native"),
+        Ok(Some((interpreter, _))) => Check::new(
+            name,
+            Status::Pass,
+            format_args!("{} through binfmt_misc", interpreter),
+        ),
+        // only the first line, the rest are hints
+        Err(e) => Check::new(
+            name,
+            if stage2 { Status::Fail } else { Status::Warn },
+            e.to_string().lines().next().unwrap_or_default(),
+        ),
+    }
+}
+
+fn check_squashfs(compressor: &str) -> Check {
+    let backend = SquashfsBackend::probe().and_then(|b| {
+        b.check(compressor)?;
+        Ok(b)
+    });
+
+    match backend {
+        Ok(b) if !b.knows_compressors() => Check::new(
+            "squashfs",
+            Status::Warn,
+            format_args!(
+                "{}, whose compressors are unknown, {} may not be supported",
+                b.version, compressor
+            ),
+        ),
+        Ok(b) => Check::new(
+            "squashfs",
+            Status::Pass,
+            format_args!("{}, with {}", b.version, compressor),
+        ),
+        Err(e) => Check::new("squashfs", Status::Fail, e),
+    }
+}
+
+/// The path itself or its closest existing parent, for destinations yet to be created
+pub fn existing_ancestor(path: &Path) -> &Path {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .unwrap_or(Path::new("."))
+}
+
+fn check_space(path: &Path) -> Check {
+    let name = format!("space at {}", path.display());
+    let stat = match statvfs(existing_ancestor(path)) {
+        Ok(stat) => stat,
+        Err(e) => return Check::new(name, Status::Fail, e),
+    };
+    if stat.flags().contains(FsFlags::ST_RDONLY) {
+        return Check::new(name, Status::Fail, "the filesystem is read-only");
+    }
+    // fsblkcnt_t and fsfilcnt_t are only 32 bits wide on some targets
+    #[allow(clippy::unnecessary_cast)]
+    let (space, inodes, total_inodes) = (
+        stat.blocks_available() as u64 * stat.fragment_size() as u64,
+        stat.files_available() as u64,
+        stat.files() as u64,
+    );
+    // filesystems allocating inodes dynamically (e.g. btrfs) report no inodes at all
+    let detail = if total_inodes > 0 {
+        format!("{} free, {} inodes free", ByteSize::b(space), inodes)
+    } else {
+        format!("{} free", ByteSize::b(space))
+    };
+    let low = space < LOW_SPACE || (total_inodes > 0 && inodes < LOW_INODES);
+
+    Check::new(name, if low { Status::Warn } else { Status::Pass }, detail)
+}
+
+fn check_mirror(url: &str) -> Check {
+    let reached = network::make_new_client()
+        .and_then(|client| Ok(client.head(url).send()?.error_for_status()?));
+
+    match reached {
+        Ok(_) => Check::new("mirror", Status::Pass, url),
+        Err(e) => Check::new("mirror", Status::Fail, format_args!("{}: {:#}", url, e)),
+    }
+}
+
+#[test]
+fn test_doctor_checks() -> Result<()> {
+    assert_eq!(check_tool("sh").status, Status::Pass);
+    let missing = check_tool("aoscbootstrap-no-such-tool");
+    assert_eq!(missing.status, Status::Fail);
+    assert_eq!(missing.detail, "not found in PATH");
+
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().join("a/b");
+    assert_eq!(existing_ancestor(&target), dir.path());
+    assert_eq!(
+        existing_ancestor(Path::new("relative/path")),
+        Path::new(".")
+    );
+    assert_ne!(check_space(&target).status, Status::Fail);
+
+    assert_eq!(check_uid(false).status, Status::Pass);
+    let host = libaosc::arch::get_arch_name().unwrap_or_default();
+    assert_eq!(check_binfmt(host).detail, "native");
+
+    Ok(())
+}
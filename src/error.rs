@@ -0,0 +1,106 @@
+use thiserror::Error;
+
+use crate::cancel::Cancelled;
+
+/// The error ending a run, classified for the exit code
+#[derive(Debug, Error)]
+pub enum BootstrapError {
+    /// The config or the options are invalid (exit code 2, as with usage errors)
+    #[error(transparent)]
+    Config(anyhow::Error),
+    /// A mirror or the topics manifest could not be reached (3)
+    #[error(transparent)]
+    Network(anyhow::Error),
+    /// The dependencies could not be resolved, or the result is unusable (4)
+    #[error(transparent)]
+    Resolve(anyhow::Error),
+    /// Not enough disk space for the bootstrap (5)
+    #[error(transparent)]
+    DiskSpace(anyhow::Error),
+    /// Stage 2 failed in the container or chroot (6)
+    #[error(transparent)]
+    Guest(anyhow::Error),
+    /// The archives could not be exported (7)
+    #[error(transparent)]
+    Export(anyhow::Error),
+    /// The target is not usable, e.g. it already exists (8)
+    #[error(transparent)]
+    Target(anyhow::Error),
+    /// The bootstrap was cancelled with its [CancellationToken](crate::CancellationToken),
+    /// the target is dealt with as with Ctrl-C (130)
+    #[error(transparent)]
+    Cancelled(anyhow::Error),
+    /// Another bootstrap is running in the same process (1)
+    #[error(transparent)]
+    Busy(anyhow::Error),
+    /// Anything else, e.g. I/O errors during stage 1 (1)
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+/// Marks the errors about the available disk space, wherever they are raised
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct NotEnoughSpace(pub String);
+
+impl From<anyhow::Error> for BootstrapError {
+    fn from(e: anyhow::Error) -> Self {
+        if e.is::<NotEnoughSpace>() {
+            BootstrapError::DiskSpace(e)
+        } else if e.is::<Cancelled>() {
+            BootstrapError::Cancelled(e)
+        } else {
+            BootstrapError::Other(e)
+        }
+    }
+}
+
+impl BootstrapError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            BootstrapError::Config(_) => 2,
+            BootstrapError::Network(_) => 3,
+            BootstrapError::Resolve(_) => 4,
+            BootstrapError::DiskSpace(_) => 5,
+            BootstrapError::Guest(_) => 6,
+            BootstrapError::Export(_) => 7,
+            BootstrapError::Target(_) => 8,
+            BootstrapError::Cancelled(_) => 130,
+            BootstrapError::Busy(_) => 1,
+            BootstrapError::Other(_) => 1,
+        }
+    }
+
+    /// The underlying error, with its context chain
+    pub fn inner(&self) -> &anyhow::Error {
+        match self {
+            BootstrapError::Config(e)
+            | BootstrapError::Network(e)
+            | BootstrapError::Resolve(e)
+            | BootstrapError::DiskSpace(e)
+            | BootstrapError::Guest(e)
+            | BootstrapError::Export(e)
+            | BootstrapError::Target(e)
+            | BootstrapError::Cancelled(e)
+            | BootstrapError::Busy(e)
+            | BootstrapError::Other(e) => e,
+        }
+    }
+}
+
+#[test]
+fn test_exit_codes() {
+    let e: BootstrapError = anyhow::Error::new(NotEnoughSpace("1 GiB more".to_string()))
+        .context("when checking the target")
+        .into();
+    assert_eq!(e.exit_code(), 5);
+    assert_eq!(e.inner().root_cause().to_string(), "1 GiB more");
+    let e: BootstrapError = anyhow::anyhow!("Failed to extract").into();
+    assert_eq!(e.exit_code(), 1);
+    assert_eq!(
+        BootstrapError::Target(anyhow::anyhow!("exists")).exit_code(),
+        8
+    );
+    let e: BootstrapError = anyhow::Error::new(Cancelled).into();
+    assert_eq!(e.exit_code(), 130);
+}
@@ -0,0 +1,324 @@
+use std::{
+    io::Write,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, RwLock,
+    },
+    time::Instant,
+};
+
+use serde::Serialize;
+
+use crate::timing::PhaseTiming;
+
+/// Version of the events written with `--json-progress`, bumped on incompatible changes
+pub const EVENTS_VERSION: u32 = 1;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// The reporter of the running bootstrap, only set within [with_reporter]
+static REPORTER: RwLock<Option<&'static dyn ProgressReporter>> = RwLock::new(None);
+static ARTIFACTS: Mutex<Vec<Artifact>> = Mutex::new(Vec::new());
+/// The phase reached, recorded in the target when the bootstrap is interrupted,
+/// and when it started if it is still running
+static PHASE: Mutex<(String, Option<Instant>)> = Mutex::new((String::new(), None));
+
+/// An event for frontends, written as a line of JSON to stdout
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Event<'a> {
+    /// A phase started: manifests, resolve, download, stage1, unpack, stage2 or export
+    Phase {
+        name: &'a str,
+    },
+    /// The running phase ended, either because the next one started or at the end of the run
+    PhaseFinished {
+        name: &'a str,
+        seconds: f64,
+    },
+    /// One more item was handled in a phase: a manifest, a package (download, extract
+    /// or install) or an exported archive
+    Progress {
+        phase: &'a str,
+        current: usize,
+        total: usize,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        package: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes: Option<u64>,
+    },
+    Warning {
+        message: &'a str,
+    },
+    Error {
+        message: &'a str,
+    },
+    /// How long each phase took, sent at the end of the run even if it failed
+    Timings {
+        phases: &'a [PhaseTiming],
+        total_seconds: f64,
+    },
+    /// The run finished successfully, having written these files
+    Result {
+        artifacts: &'a [Artifact],
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Artifact {
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Receives the progress of a run. The command line shows it on the terminal
+/// ([ConsoleReporter](crate::logging::ConsoleReporter)) or as JSON, and the programs
+/// embedding aoscbootstrap can do as they like.
+pub trait ProgressReporter: Send + Sync {
+    /// A phase started: manifests, resolve, download, stage1, unpack, stage2 or export
+    fn phase_started(&self, name: &str) {
+        let _ = name;
+    }
+    /// One more item was handled in a phase, with its name and size when known
+    fn item_progress(
+        &self,
+        phase: &str,
+        current: usize,
+        total: usize,
+        item: Option<&str>,
+        bytes: Option<u64>,
+    ) {
+        let _ = (phase, current, total, item, bytes);
+    }
+    fn phase_finished(&self, name: &str, seconds: f64) {
+        let _ = (name, seconds);
+    }
+    /// Every event, the same as written with `--json-progress`, by default passed to the
+    /// methods above
+    fn event(&self, event: &Event) {
+        match *event {
+            Event::Phase { name } => self.phase_started(name),
+            Event::PhaseFinished { name, seconds } => self.phase_finished(name, seconds),
+            Event::Progress {
+                phase,
+                current,
+                total,
+                package,
+                bytes,
+            } => self.item_progress(phase, current, total, package, bytes),
+            _ => (),
+        }
+    }
+}
+
+/// Send the events to the reporter instead of the terminal while `f` runs
+pub fn with_reporter<T>(reporter: &dyn ProgressReporter, f: impl FnOnce() -> T) -> T {
+    struct Reset;
+
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            *REPORTER.write().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+    }
+
+    // SAFETY: the reporter is only reachable through REPORTER, which is reset before this
+    // returns or unwinds, and emit holds the lock for as long as it uses the reporter
+    let reporter = unsafe {
+        std::mem::transmute::<&dyn ProgressReporter, &'static dyn ProgressReporter>(reporter)
+    };
+    *REPORTER.write().unwrap() = Some(reporter);
+    let _reset = Reset;
+
+    f()
+}
+
+/// Whether the events are consumed, on stdout or by a reporter
+pub fn active() -> bool {
+    enabled() || REPORTER.read().unwrap().is_some()
+}
+
+/// Write the events to stdout from now on
+pub fn enable() {
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::SeqCst)
+}
+
+pub fn emit(event: &Event) {
+    let reporter = REPORTER.read().unwrap_or_else(|e| e.into_inner());
+    if let Some(reporter) = *reporter {
+        reporter.event(event);
+        return;
+    }
+    drop(reporter);
+    if enabled() {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(stdout, "{}", to_json(event)).ok();
+        stdout.flush().ok();
+    } else {
+        crate::logging::CONSOLE.event(event);
+    }
+}
+
+fn to_json(event: &Event) -> String {
+    let mut value = serde_json::to_value(event).expect("events are always serializable");
+    value["version"] = EVENTS_VERSION.into();
+
+    value.to_string()
+}
+
+/// Start a phase, finishing the running one
+pub fn phase(name: &str) {
+    end_phase();
+    if let Ok(mut phase) = PHASE.lock() {
+        *phase = (name.to_string(), Some(Instant::now()));
+    }
+    emit(&Event::Phase { name });
+}
+
+/// Finish the running phase, if any
+pub fn end_phase() {
+    let finished = PHASE.lock().ok().and_then(|mut phase| {
+        let started = phase.1.take()?;
+        Some((phase.0.clone(), started.elapsed().as_secs_f64()))
+    });
+    if let Some((name, seconds)) = finished {
+        emit(&Event::PhaseFinished {
+            name: &name,
+            seconds,
+        });
+    }
+}
+
+pub fn current_phase() -> String {
+    PHASE.lock().map(|p| p.0.clone()).unwrap_or_default()
+}
+
+pub fn progress(phase: &str, current: usize, total: usize, item: &str, bytes: u64) {
+    emit(&Event::Progress {
+        phase,
+        current,
+        total,
+        package: Some(item),
+        bytes: Some(bytes),
+    });
+}
+
+/// Record a written file for the final result
+pub fn artifact(path: &Path, sha256: &str) {
+    if active() {
+        ARTIFACTS.lock().unwrap().push(Artifact {
+            path: path.display().to_string(),
+            sha256: sha256.to_string(),
+        });
+    }
+}
+
+/// Report the successful end of the run, returning the artifacts written
+pub fn finish() -> Vec<Artifact> {
+    end_phase();
+    let artifacts = std::mem::take(&mut *ARTIFACTS.lock().unwrap());
+    emit(&Event::Result {
+        artifacts: &artifacts,
+    });
+
+    artifacts
+}
+
+#[test]
+fn test_events() -> anyhow::Result<()> {
+    let parse = |event: &Event| -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::from_str(&to_json(event))?)
+    };
+
+    let phase = parse(&Event::Phase { name: "download" })?;
+    assert_eq!(
+        phase,
+        serde_json::json!({"type": "phase", "version": EVENTS_VERSION, "name": "download"})
+    );
+    let progress = parse(&Event::Progress {
+        phase: "install",
+        current: 3,
+        total: 10,
+        package: None,
+        bytes: None,
+    })?;
+    assert_eq!(
+        progress,
+        serde_json::json!({"type": "progress", "version": 1, "phase": "install", "current": 3, "total": 10})
+    );
+    let artifacts = [Artifact {
+        path: "out.tar.zst".to_string(),
+        sha256: "0".repeat(64),
+    }];
+    let result = parse(&Event::Result {
+        artifacts: &artifacts,
+    })?;
+    assert_eq!(result["type"], "result");
+    assert_eq!(result["artifacts"][0]["path"], "out.tar.zst");
+    let phases = [PhaseTiming {
+        name: "download".to_string(),
+        seconds: 310.0,
+        bytes: Some(1 << 30),
+    }];
+    let timings = parse(&Event::Timings {
+        phases: &phases,
+        total_seconds: 1771.0,
+    })?;
+    assert_eq!(timings["type"], "timings");
+    assert_eq!(timings["phases"][0]["bytes"], 1 << 30);
+    // every event is a single line
+    assert!(!to_json(&Event::Warning { message: "a\nb" }).contains('\n'));
+
+    Ok(())
+}
+
+#[test]
+fn test_reporter() {
+    #[derive(Default)]
+    struct Recorder(Mutex<Vec<String>>);
+
+    impl ProgressReporter for Recorder {
+        fn phase_started(&self, name: &str) {
+            self.0.lock().unwrap().push(format!("start {}", name));
+        }
+        fn item_progress(
+            &self,
+            phase: &str,
+            current: usize,
+            total: usize,
+            item: Option<&str>,
+            bytes: Option<u64>,
+        ) {
+            self.0.lock().unwrap().push(format!(
+                "{} {}/{} {:?} {:?}",
+                phase, current, total, item, bytes
+            ));
+        }
+        fn phase_finished(&self, name: &str, _seconds: f64) {
+            self.0.lock().unwrap().push(format!("end {}", name));
+        }
+    }
+
+    let recorder = Recorder::default();
+    let artifacts = with_reporter(&recorder, || {
+        phase("extract");
+        progress("extract", 1, 2, "bash", 1024);
+        progress("extract", 2, 2, "glibc", 4096);
+        phase("stage2");
+        finish()
+    });
+    assert!(artifacts.is_empty());
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        [
+            "start extract",
+            "extract 1/2 Some(\"bash\") Some(1024)",
+            "extract 2/2 Some(\"glibc\") Some(4096)",
+            "end extract",
+            "start stage2",
+            "end stage2",
+        ]
+    );
+}
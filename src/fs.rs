@@ -2,32 +2,177 @@ use anyhow::{anyhow, Result};
 use flate2::write::GzEncoder;
 use flate2::Compression;
 use nix::fcntl::{open, OFlag};
-use nix::sys::stat::{fchmodat, FchmodatFlags, Mode};
+use nix::sys::stat::Mode;
 use nix::unistd::close;
 use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::ffi::CString;
 use std::io::Write;
-use std::path::Path;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use std::{
-    fs::{create_dir_all, write, File},
+    fs::{create_dir_all, write, File, Permissions},
     io::Read,
 };
 use tar::Builder;
-use xz2::stream::{Filters, LzmaOptions, MtStreamBuilder, Stream};
+use xz2::stream::{Check, Filters, LzmaOptions, MtStreamBuilder, Stream};
 use xz2::write::XzEncoder;
 
+use crate::cancel::{self, StopOnCancel};
+use crate::keyring::{self, AptKey};
+
 const LZMA_PRESET_EXTREME: u32 = 1 << 31;
+const AOSC_KEYRING: &str = "/etc/apt/trusted.gpg.d/aosc-archive-keyring.gpg";
+const DEFAULT_RELEASE_PREFERENCES: &str = "etc/apt/preferences.d/00-aoscbootstrap.pref";
+/// Comes after the preferences of the pins (`aoscbootstrap-pins.pref`) and the topics
+/// (`atm-topics.pref`), whose stanzas would otherwise be shadowed
+const OTHER_BRANCHES_PREFERENCES: &str =
+    "etc/apt/preferences.d/zz-aoscbootstrap-other-branches.pref";
+const DEFAULT_RELEASE_CONF: &str = "etc/apt/apt.conf.d/00aoscbootstrap-default-release";
+/// Priority of the branch with `default-release`, the one APT gives to its default release
+pub const DEFAULT_PIN_PRIORITY: i32 = 990;
+/// Priority of the other branches, below the default of 500 so that a newer version alone
+/// does not take packages from them
+pub const OTHER_BRANCHES_PRIORITY: i32 = 400;
+
+/// Write a file of the target at once: the content goes to a new, uniquely named
+/// `.<name>.*.tmp` next to it, which replaces the file once synced to the disk, so that a
+/// crash never leaves a truncated file behind. The temporary file is created exclusively,
+/// never through a symlink planted by the packages of the target, and concurrent writers
+/// do not share it. The mode is set as given, regardless of the umask.
+pub fn atomic_write(path: &Path, data: impl AsRef<[u8]>, mode: u32) -> Result<()> {
+    let dir = path
+        .parent()
+        .ok_or_else(|| anyhow!("{} has no parent directory", path.display()))?;
+    let mut prefix = std::ffi::OsString::from(".");
+    prefix.push(
+        path.file_name()
+            .ok_or_else(|| anyhow!("{} is not a file", path.display()))?,
+    );
+    prefix.push(".");
+    let write_tmp = || -> Result<()> {
+        // removed when dropped, unless it replaced the file
+        let mut tmp = tempfile::Builder::new()
+            .prefix(&prefix)
+            .suffix(".tmp")
+            .tempfile_in(dir)?;
+        tmp.write_all(data.as_ref())?;
+        tmp.as_file()
+            .set_permissions(Permissions::from_mode(mode))?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path).map_err(|e| e.error)?;
+        // make the rename itself durable
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    };
+    write_tmp().map_err(|e| e.context(format!("when writing {}", path.display())))
+}
+
+/// Format an APT source entry, either in the one-line format or in the deb822 format,
+/// where `signed_by` are the keyrings trusted along with the AOSC OS one
+pub fn format_apt_source(
+    mirror: &str,
+    suite: &str,
+    comps: &[&str],
+    arches: &[&str],
+    deb822: bool,
+    signed_by: &[String],
+) -> String {
+    if !deb822 {
+        return format!("deb {} {} {}\n", mirror, suite, comps.join(" "));
+    }
+
+    format!(
+        "Types: deb\nURIs: {}\nSuites: {}\nComponents: {}\nArchitectures: {}\nSigned-By: {}\n",
+        mirror,
+        suite,
+        comps.join(" "),
+        arches.join(" "),
+        std::iter::once(AOSC_KEYRING)
+            .chain(signed_by.iter().map(|k| k.as_str()))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
 
-pub fn bootstrap_apt(root: &Path, mirror: &str, branch: &str) -> Result<()> {
+/// Generate apt preferences keeping the packages on the branch, even after the sources of
+/// another branch are added by hand. APT applies the first stanza matching a release, so the
+/// catch-all for the other branches goes to [OTHER_BRANCHES_PREFERENCES], read after the
+/// preferences of the pins and the topics.
+fn default_release_preferences(branch: &str, priority: i32) -> (String, String) {
+    (
+        format!(
+            "Package: *\nPin: release n={}\nPin-Priority: {}\n",
+            branch, priority
+        ),
+        format!(
+            "Package: *\nPin: release n=*\nPin-Priority: {}\n",
+            OTHER_BRANCHES_PRIORITY
+        ),
+    )
+}
+
+/// Write the APT sources and the files dpkg expects, and install the given keys,
+/// returning the paths of the keys to reference with `Signed-By`. With `default_pin`,
+/// the branch is also made the default release, pinned at that priority.
+#[allow(clippy::too_many_arguments)]
+pub fn bootstrap_apt(
+    root: &Path,
+    mirror: &str,
+    branch: &str,
+    comps: &[&str],
+    arches: &[&str],
+    deb822: bool,
+    locale: &str,
+    keys: &[AptKey],
+    default_pin: Option<i32>,
+) -> Result<Vec<String>> {
     create_dir_all(root.join("var/lib/dpkg"))?;
-    create_dir_all(root.join("etc/apt"))?;
+    create_dir_all(root.join("etc/apt/sources.list.d"))?;
     create_dir_all(root.join("var/lib/apt/lists"))?;
-    write(root.join("etc/locale.conf"), b"LANG=C.UTF-8\n")?;
-    write(root.join("etc/shadow"), b"root:x:1:0:99999:7:::\n")?;
-    write(
-        root.join("etc/apt/sources.list"),
-        format!("deb {} {} main\n", mirror, branch),
+    atomic_write(
+        &root.join("etc/locale.conf"),
+        format!("LANG={}\n", locale),
+        0o644,
+    )?;
+    // no password login for root until one is set with --root-password-hashed
+    atomic_write(&root.join("etc/shadow"), b"root:*:1:0:99999:7:::\n", 0o000)?;
+    let sources_path = if deb822 {
+        root.join("etc/apt/sources.list.d/aosc.sources")
+    } else {
+        root.join("etc/apt/sources.list")
+    };
+    let keyrings = keyring::install(root, keys, deb822)?;
+    // without deb822 sources, the keys are trusted for all the sources
+    let signed_by = if deb822 { keyrings } else { Vec::new() };
+    atomic_write(
+        &sources_path,
+        format_apt_source(mirror, branch, comps, arches, deb822, &signed_by),
+        0o644,
     )?;
+    if let Some(priority) = default_pin {
+        create_dir_all(root.join("etc/apt/preferences.d"))?;
+        create_dir_all(root.join("etc/apt/apt.conf.d"))?;
+        let (branch_preferences, other_preferences) = default_release_preferences(branch, priority);
+        atomic_write(
+            &root.join(DEFAULT_RELEASE_PREFERENCES),
+            branch_preferences,
+            0o644,
+        )?;
+        atomic_write(
+            &root.join(OTHER_BRANCHES_PREFERENCES),
+            other_preferences,
+            0o644,
+        )?;
+        atomic_write(
+            &root.join(DEFAULT_RELEASE_CONF),
+            format!("APT::Default-Release \"{}\";\n", branch),
+            0o644,
+        )?;
+    }
 
     close(open(
         &root.join("var/lib/dpkg/available"),
@@ -41,81 +186,1012 @@ pub fn bootstrap_apt(root: &Path, mirror: &str, branch: &str) -> Result<()> {
         Mode::from_bits_truncate(0o644),
     )?)
     .ok();
-    // chmod 0000 /etc/shadow
-    fchmodat(
-        None,
-        &root.join("etc/shadow"),
-        Mode::empty(),
-        FchmodatFlags::NoFollowSymlink,
-    )?;
-    // chmod 0644 /etc/apt/sources.list
-    fchmodat(
-        None,
-        &root.join("etc/apt/sources.list"),
-        Mode::from_bits_truncate(0o644),
-        FchmodatFlags::NoFollowSymlink,
-    )?;
+
+    Ok(signed_by)
+}
+
+/// Check that the name is a valid hostname (a single label, RFC 1123)
+pub fn check_hostname(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+    if !valid {
+        return Err(anyhow!(
+            "Invalid hostname '{}': expected up to 63 letters, digits and hyphens",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the value looks like a locale name, e.g. `en_US.UTF-8`
+pub fn check_locale(locale: &str) -> Result<()> {
+    let valid = locale.starts_with(|c: char| c.is_ascii_alphabetic())
+        && locale
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_.@-".contains(c));
+    if !valid {
+        return Err(anyhow!(
+            "Invalid locale '{}': expected a name like en_US.UTF-8",
+            locale
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that the value looks like a time zone name, e.g. `Asia/Shanghai`
+pub fn check_timezone(timezone: &str) -> Result<()> {
+    let valid = !timezone.is_empty()
+        && timezone.split('/').all(|part| {
+            !part.is_empty()
+                && part != "."
+                && part != ".."
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_+-.".contains(c))
+        });
+    if !valid {
+        return Err(anyhow!(
+            "Invalid time zone '{}': expected a name like Asia/Shanghai",
+            timezone
+        ));
+    }
 
     Ok(())
 }
 
-/// Make a tarball (xz compressed)
-pub fn archive_xz_tarball(root: &Path, target: &Path, threads: u32) -> Result<()> {
-    let f = File::create(target)?;
-    let xz = build_xz_encoder(threads)?;
-    let builder = build_tarball_stream(XzEncoder::new_stream(f, xz), root)?;
-    builder.into_inner()?.finish()?.sync_all()?;
+pub fn write_hostname(root: &Path, hostname: &str) -> Result<()> {
+    atomic_write(&root.join("etc/hostname"), format!("{}\n", hostname), 0o644)?;
 
     Ok(())
 }
 
-/// Make a tarball (gz compressed)
-pub fn archive_gz_tarball(root: &Path, target: &Path) -> Result<()> {
-    let f = File::create(target)?;
-    let builder = build_tarball_stream(GzEncoder::new(f, Compression::best()), root)?;
-    builder.into_inner()?.finish()?.sync_all()?;
+/// Point `/etc/localtime` to the time zone, which must be installed in the target
+pub fn set_timezone(root: &Path, timezone: &str) -> Result<()> {
+    let zoneinfo = Path::new("/usr/share/zoneinfo").join(timezone);
+    if !root.join(zoneinfo.strip_prefix("/")?).is_file() {
+        return Err(anyhow!(
+            "Time zone {} is not installed in the target (is tzdata installed?)",
+            timezone
+        ));
+    }
+    let localtime = root.join("etc/localtime");
+    if localtime.symlink_metadata().is_ok() {
+        std::fs::remove_file(&localtime)?;
+    }
+    std::os::unix::fs::symlink(zoneinfo, localtime)?;
+
+    Ok(())
+}
+
+/// How to set up `/etc/machine-id` in the target
+#[derive(Clone, Debug, PartialEq)]
+pub enum MachineId {
+    /// Remove it, so that it is generated on first boot
+    None,
+    /// Leave it empty, e.g. for WSL
+    Empty,
+    /// Generate a new one
+    Random,
+    /// Use the given ID, as 32 lowercase hexadecimal digits
+    Fixed(String),
+}
+
+pub fn parse_machine_id(value: &str) -> Result<MachineId, String> {
+    Ok(match value {
+        "none" => MachineId::None,
+        "empty" => MachineId::Empty,
+        "random" => MachineId::Random,
+        _ => {
+            let id = value.replace('-', "").to_ascii_lowercase();
+            let dashed = value.len() == 36
+                && [8, 13, 18, 23].iter().all(|i| value.as_bytes()[*i] == b'-')
+                && value.matches('-').count() == 4;
+            let valid = id.len() == 32
+                && id.chars().all(|c| c.is_ascii_hexdigit())
+                && id.chars().any(|c| c != '0')
+                && (dashed || !value.contains('-'));
+            if !valid {
+                return Err(format!(
+                    "Invalid machine ID '{}': expected none, empty, random or a UUID",
+                    value
+                ));
+            }
+            MachineId::Fixed(id)
+        }
+    })
+}
+
+pub fn set_machine_id(root: &Path, machine_id: &MachineId) -> Result<()> {
+    let path = root.join("etc/machine-id");
+    // the existing one is read-only
+    if path.symlink_metadata().is_ok() {
+        std::fs::remove_file(&path)?;
+    }
+    match machine_id {
+        MachineId::None => {
+            // usually a symlink to /etc/machine-id, otherwise a copy of it
+            let dbus = root.join("var/lib/dbus/machine-id");
+            if dbus.symlink_metadata().is_ok_and(|m| m.is_file()) {
+                std::fs::remove_file(&dbus)?;
+            }
+            return Ok(());
+        }
+        MachineId::Empty => atomic_write(&path, b"", 0o444)?,
+        MachineId::Random => {
+            atomic_write(&path, format!("{:032x}\n", rand::random::<u128>()), 0o444)?
+        }
+        MachineId::Fixed(id) => atomic_write(&path, format!("{}\n", id), 0o444)?,
+    }
+
+    Ok(())
+}
+
+/// Resolve the symlinks in a path relative to the target, as seen from inside it: absolute
+/// symlinks point from the root of the target. Fails if the path leaves the target. Missing
+/// components are kept as is.
+pub fn resolve_in_target(target: &Path, path: &Path) -> Result<PathBuf> {
+    let mut pending = path
+        .components()
+        .map(|c| c.as_os_str().to_owned())
+        .collect::<VecDeque<_>>();
+    let mut resolved = PathBuf::new();
+    let mut links = 0;
+    while let Some(name) = pending.pop_front() {
+        if name == "." {
+            continue;
+        }
+        if name == ".." {
+            if !resolved.pop() {
+                return Err(anyhow!("{} points outside the target", path.display()));
+            }
+            continue;
+        }
+        let candidate = resolved.join(&name);
+        match std::fs::symlink_metadata(target.join(&candidate)) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                links += 1;
+                if links > 40 {
+                    return Err(anyhow!("too many levels of symlinks in {}", path.display()));
+                }
+                let link = std::fs::read_link(target.join(&candidate))?;
+                if link.has_root() {
+                    resolved = PathBuf::new();
+                }
+                for component in link
+                    .components()
+                    .rev()
+                    .filter(|c| !matches!(c, Component::RootDir | Component::Prefix(_)))
+                {
+                    pending.push_front(component.as_os_str().to_owned());
+                }
+            }
+            _ => resolved = candidate,
+        }
+    }
+
+    Ok(resolved)
+}
+
+/// Copy the contents of the overlay directory onto the target, preserving the
+/// permissions, the numeric ownership, symlinks and extended attributes. Returns the
+/// files which already existed in the target, relative to it.
+pub fn apply_overlay(overlay: &Path, root: &Path) -> Result<Vec<PathBuf>> {
+    let mut replaced = Vec::new();
+    copy_overlay_dir(overlay, root, Path::new(""), Path::new(""), &mut replaced)?;
+
+    Ok(replaced)
+}
+
+/// Copy `relative` in the overlay to `dest_relative` in the target, which differ once a
+/// directory of the overlay has been copied through a symlink in the target (e.g. the
+/// usrmerge `bin -> usr/bin`)
+fn copy_overlay_dir(
+    overlay: &Path,
+    root: &Path,
+    relative: &Path,
+    dest_relative: &Path,
+    replaced: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in std::fs::read_dir(overlay.join(relative))? {
+        let entry = entry?;
+        let relative = relative.join(entry.file_name());
+        let mut dest_relative = dest_relative.join(entry.file_name());
+        let src = entry.path();
+        let mut dest = root.join(&dest_relative);
+        let meta = src.symlink_metadata()?;
+        let existing = dest.symlink_metadata().ok();
+        if meta.is_dir() {
+            match existing {
+                Some(ref m) if m.is_dir() => (),
+                // followed as from inside the target, failing if it leads out of it
+                Some(ref m)
+                    if m.is_symlink()
+                        && root.join(resolve_in_target(root, &dest_relative)?).is_dir() =>
+                {
+                    dest_relative = resolve_in_target(root, &dest_relative)?;
+                    dest = root.join(&dest_relative);
+                }
+                Some(_) => {
+                    std::fs::remove_file(&dest)?;
+                    replaced.push(dest_relative.clone());
+                    std::fs::create_dir(&dest)?;
+                }
+                None => std::fs::create_dir(&dest)?,
+            }
+            copy_overlay_dir(overlay, root, &relative, &dest_relative, replaced)?;
+        } else {
+            match existing {
+                Some(ref m) if m.is_dir() => {
+                    return Err(anyhow!(
+                        "{} is a directory in the target",
+                        dest_relative.display()
+                    ))
+                }
+                Some(_) => {
+                    std::fs::remove_file(&dest)?;
+                    replaced.push(dest_relative.clone());
+                }
+                None => (),
+            }
+            if meta.is_symlink() {
+                std::os::unix::fs::symlink(std::fs::read_link(&src)?, &dest)?;
+            } else {
+                std::fs::copy(&src, &dest)?;
+            }
+        }
+        std::os::unix::fs::lchown(&dest, Some(meta.uid()), Some(meta.gid()))?;
+        if !meta.is_symlink() {
+            std::fs::set_permissions(&dest, meta.permissions())?;
+        }
+        copy_xattrs(&src, &dest)?;
+    }
 
     Ok(())
 }
 
+/// Copy the extended attributes of a file, without following symlinks
+fn copy_xattrs(src: &Path, dest: &Path) -> Result<()> {
+    let src = CString::new(src.as_os_str().as_bytes())?;
+    let dest = CString::new(dest.as_os_str().as_bytes())?;
+    // unsafe: the buffers are sized with the lengths returned by the kernel
+    unsafe {
+        let len = libc::llistxattr(src.as_ptr(), std::ptr::null_mut(), 0);
+        if len <= 0 {
+            // not supported by the file system, or no attributes at all
+            return Ok(());
+        }
+        let mut names = vec![0u8; len as usize];
+        let len = libc::llistxattr(src.as_ptr(), names.as_mut_ptr().cast(), names.len());
+        if len < 0 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+        names.truncate(len as usize);
+        for name in names.split(|c| *c == 0).filter(|n| !n.is_empty()) {
+            let name = CString::new(name)?;
+            let len = libc::lgetxattr(src.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0);
+            if len < 0 {
+                continue;
+            }
+            let mut value = vec![0u8; len as usize];
+            let len = libc::lgetxattr(
+                src.as_ptr(),
+                name.as_ptr(),
+                value.as_mut_ptr().cast(),
+                value.len(),
+            );
+            if len < 0 {
+                continue;
+            }
+            if libc::lsetxattr(
+                dest.as_ptr(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                len as usize,
+                0,
+            ) < 0
+            {
+                return Err(anyhow!(
+                    "Failed to set extended attribute {} on {}: {}",
+                    name.to_string_lossy(),
+                    dest.to_string_lossy(),
+                    std::io::Error::last_os_error()
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Hashes the bytes written through it, so that an archive is hashed as it is written
+/// instead of being read again afterwards
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// The inner writer and the SHA256 checksum of what was written, in hex
+    pub fn finish(self) -> (W, String) {
+        (self.inner, format!("{:x}", self.hasher.finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Sync the archive written through the hashing writer, returning its checksum
+fn finish_archive(writer: HashingWriter<File>) -> Result<String> {
+    let (f, sha256) = writer.finish();
+    f.sync_all()?;
+
+    Ok(sha256)
+}
+
+/// Make an uncompressed tarball, returning its SHA256 checksum
+pub fn archive_tarball(root: &Path, target: &Path) -> Result<String> {
+    let f = HashingWriter::new(File::create(target)?);
+    let builder = build_tarball_stream(StopOnCancel::new(f), root)?;
+
+    finish_archive(builder.into_inner()?.0)
+}
+
+/// Make a tarball (xz compressed), returning its SHA256 checksum
+pub fn archive_xz_tarball(root: &Path, target: &Path, plan: &XzPlan) -> Result<String> {
+    let f = HashingWriter::new(File::create(target)?);
+    let xz = build_xz_encoder(plan)?;
+    let builder = build_tarball_stream(StopOnCancel::new(XzEncoder::new_stream(f, xz)), root)?;
+
+    finish_archive(builder.into_inner()?.0.finish()?)
+}
+
+/// Make a tarball (gz compressed), returning its SHA256 checksum
+pub fn archive_gz_tarball(root: &Path, target: &Path) -> Result<String> {
+    let f = HashingWriter::new(File::create(target)?);
+    let builder = build_tarball_stream(
+        StopOnCancel::new(GzEncoder::new(f, Compression::best())),
+        root,
+    )?;
+
+    finish_archive(builder.into_inner()?.0.finish()?)
+}
+
+/// Make a tarball (zstd compressed), returning its SHA256 checksum
+pub fn archive_zstd_tarball(root: &Path, target: &Path, threads: u32) -> Result<String> {
+    let f = HashingWriter::new(File::create(target)?);
+    let mut zstd = zstd::Encoder::new(f, 19)?;
+    zstd.multithread(threads)?;
+    let builder = build_tarball_stream(StopOnCancel::new(zstd), root)?;
+
+    finish_archive(builder.into_inner()?.0.finish()?)
+}
+
 fn build_tarball_stream<W: Write>(stream: W, root: &Path) -> Result<Builder<W>, anyhow::Error> {
     let mut builder = Builder::new(stream);
     builder.mode(tar::HeaderMode::Complete);
     builder.follow_symlinks(false);
-    builder.append_dir_all(".", root)?;
+    builder.append_dir(".", root)?;
+    let mut entries = std::fs::read_dir(root)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    for entry in entries {
+        cancel::check()?;
+        let name = Path::new(".").join(entry.file_name());
+        if entry.file_name() == TARGET_LOCK {
+            continue;
+        } else if entry.file_type()?.is_dir() {
+            builder.append_dir_all(&name, entry.path())?;
+        } else {
+            builder.append_path_with_name(entry.path(), &name)?;
+        }
+    }
     builder.finish()?;
 
     Ok(builder)
 }
 
-/// Make a squashfs (xz compressed)
-pub fn archive_squashfs(root: &Path, target: &Path, threads: u32) -> Result<()> {
-    let output = Command::new("mksquashfs")
-        .arg(root)
-        .arg(target)
-        .arg("-comp")
-        .arg("xz")
-        .arg("-processors")
-        .arg(threads.to_string())
-        .spawn()?
-        .wait_with_output()?;
+/// A tool making squashfs images
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SquashfsTool {
+    /// `mksquashfs` from squashfs-tools
+    Mksquashfs,
+    /// `gensquashfs` from squashfs-tools-ng
+    Gensquashfs,
+}
+
+impl SquashfsTool {
+    fn command(&self) -> &'static str {
+        match self {
+            SquashfsTool::Mksquashfs => "mksquashfs",
+            SquashfsTool::Gensquashfs => "gensquashfs",
+        }
+    }
+}
+
+/// The squashfs tool of the host, and what it supports
+#[derive(Clone, Debug)]
+pub struct SquashfsBackend {
+    pub tool: SquashfsTool,
+    /// The first line printed by the tool about its version
+    pub version: String,
+    pub compressors: Vec<String>,
+}
+
+impl SquashfsBackend {
+    /// Find the squashfs tool of the host, preferring mksquashfs
+    pub fn probe() -> Result<SquashfsBackend> {
+        let tool = [SquashfsTool::Mksquashfs, SquashfsTool::Gensquashfs]
+            .into_iter()
+            .find(|t| which::which(t.command()).is_ok())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Cannot find mksquashfs (squashfs-tools) or gensquashfs (squashfs-tools-ng)!"
+                )
+            })?;
+        // the tools print their help to stdout or stderr, and may exit with an error
+        let run = |arg: &str| -> Result<String> {
+            let output = Command::new(tool.command()).arg(arg).output()?;
+            let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+            text.push_str(&String::from_utf8_lossy(&output.stderr));
+            Ok(text)
+        };
+        let (version, help) = match tool {
+            SquashfsTool::Mksquashfs => (run("-version")?, run("-help")?),
+            SquashfsTool::Gensquashfs => (run("--version")?, run("--help")?),
+        };
+
+        Ok(SquashfsBackend {
+            tool,
+            version: version
+                .lines()
+                .next()
+                .unwrap_or(tool.command())
+                .trim()
+                .to_string(),
+            compressors: parse_squashfs_compressors(&help),
+        })
+    }
+
+    /// Whether the compressors could be read from the help of the tool
+    pub fn knows_compressors(&self) -> bool {
+        !self.compressors.is_empty()
+    }
+
+    /// Fail unless the tool supports the compressor. When the compressors are not known,
+    /// the tool is left to refuse it.
+    pub fn check(&self, compressor: &str) -> Result<()> {
+        if self.knows_compressors() && !self.compressors.iter().any(|c| c == compressor) {
+            return Err(anyhow!(
+                "The squashfs tool found ({}) does not support {} compression, only {}.",
+                self.version,
+                compressor,
+                self.compressors.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// The compressors listed in the help of mksquashfs or gensquashfs: a tab followed by the
+/// name (and maybe "(default)") on each line after the heading, the options of the
+/// compressors being indented further
+fn parse_squashfs_compressors(help: &str) -> Vec<String> {
+    let mut compressors = Vec::new();
+    let mut listed = false;
+    for line in help.lines() {
+        if line.starts_with("Compressors available") || line.starts_with("Available compressors") {
+            listed = true;
+            continue;
+        }
+        if !listed {
+            continue;
+        }
+        let Some(entry) = line.strip_prefix('\t') else {
+            if !line.trim().is_empty() {
+                break;
+            }
+            continue;
+        };
+        if entry.starts_with(char::is_whitespace) {
+            continue;
+        }
+        if let Some(name) = entry.split_whitespace().next() {
+            compressors.push(name.trim_matches('"').to_string());
+        }
+    }
+
+    compressors
+}
+
+/// Make a squashfs with the given compressor
+pub fn archive_squashfs(
+    root: &Path,
+    target: &Path,
+    threads: u32,
+    backend: &SquashfsBackend,
+    compressor: &str,
+) -> Result<()> {
+    cancel::check()?;
+    let mut command = Command::new(backend.tool.command());
+    let mut pack_file = tempfile::NamedTempFile::new()?;
+    match backend.tool {
+        SquashfsTool::Mksquashfs => command
+            .arg(root)
+            .arg(target)
+            .arg("-comp")
+            .arg(compressor)
+            .arg("-processors")
+            .arg(threads.to_string())
+            .arg("-e")
+            .arg(TARGET_LOCK),
+        // gensquashfs has no exclusions, the entries to pack are listed instead
+        SquashfsTool::Gensquashfs => {
+            pack_file.write_all(gensquashfs_pack_file(root)?.as_bytes())?;
+            command
+                .arg("--pack-file")
+                .arg(pack_file.path())
+                .arg("--pack-dir")
+                .arg(root)
+                .arg("--defaults")
+                .arg(gensquashfs_root_defaults(root)?)
+                .arg("--compressor")
+                .arg(compressor)
+                .arg("--num-jobs")
+                .arg(threads.to_string())
+                .arg("--keep-xattr")
+                .arg("--force")
+                .arg(target)
+        }
+    };
+    let output = command.spawn()?.wait_with_output()?;
     if !output.status.success() {
-        return Err(anyhow!("Failed to archive squashfs!"));
+        return Err(anyhow!(
+            "Failed to archive squashfs with {}!",
+            backend.version
+        ));
     }
 
     Ok(())
 }
 
-fn build_xz_encoder(threads: u32) -> Result<Stream> {
+/// The attributes of the root directory, which gensquashfs takes from its defaults
+fn gensquashfs_root_defaults(root: &Path) -> Result<String> {
+    let meta = root.metadata()?;
+    Ok(format!(
+        "uid={},gid={},mode={:o},mtime={}",
+        meta.uid(),
+        meta.gid(),
+        meta.mode() & 0o7777,
+        meta.mtime().max(0)
+    ))
+}
+
+/// Describe the target for `gensquashfs --pack-file` like [build_tarball_stream] walks it:
+/// the top-level entries but the lock file, the directories scanned recursively with
+/// their timestamps
+fn gensquashfs_pack_file(root: &Path) -> Result<String> {
+    let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut entries = std::fs::read_dir(root)?.collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+    let mut pack = String::new();
+    for entry in entries {
+        if entry.file_name() == TARGET_LOCK {
+            continue;
+        }
+        let name = entry.file_name();
+        let name = name
+            .to_str()
+            .ok_or_else(|| anyhow!("{} is not valid UTF-8", entry.path().display()))?;
+        let path = quote(&format!("/{}", name));
+        let meta = entry.metadata()?;
+        let (mode, uid, gid) = (meta.mode() & 0o7777, meta.uid(), meta.gid());
+        let file_type = meta.file_type();
+        if file_type.is_dir() {
+            pack.push_str(&format!("dir {} {:o} {} {}\n", path, mode, uid, gid));
+            pack.push_str(&format!("glob {} * * * -keeptime {}\n", path, quote(name)));
+        } else if file_type.is_symlink() {
+            let link = std::fs::read_link(entry.path())?;
+            let link = link
+                .to_str()
+                .ok_or_else(|| anyhow!("{} is not valid UTF-8", link.display()))?;
+            pack.push_str(&format!(
+                "slink {} 0777 {} {} {}\n",
+                path,
+                uid,
+                gid,
+                quote(link)
+            ));
+        } else if file_type.is_file() {
+            pack.push_str(&format!(
+                "file {} {:o} {} {} {}\n",
+                path,
+                mode,
+                uid,
+                gid,
+                quote(name)
+            ));
+        } else {
+            return Err(anyhow!(
+                "{} is a special file at the root of the target, which gensquashfs cannot pack",
+                entry.path().display()
+            ));
+        }
+    }
+
+    Ok(pack)
+}
+
+/// Dictionary size of the xz preset 9
+pub const XZ_DICT_SIZE: u32 = 64 << 20;
+/// Smallest dictionary size used to save memory, the one of preset 6
+const XZ_MIN_DICT_SIZE: u32 = 8 << 20;
+/// Share of the available memory the xz encoder may use without `--xz-memory-limit`
+const XZ_DEFAULT_MEMORY_PERCENT: u64 = 80;
+
+/// How much memory the xz encoder may use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryLimit {
+    Bytes(u64),
+    /// A percentage of the available memory
+    Percent(u64),
+}
+
+pub fn parse_memory_limit(value: &str) -> Result<MemoryLimit, String> {
+    let invalid = || {
+        format!(
+            "Invalid memory limit '{}': expected a size (e.g. 4GiB) or a percentage of the available memory (e.g. 50%)",
+            value
+        )
+    };
+    if let Some(percent) = value.strip_suffix('%') {
+        return match percent.trim().parse::<u64>() {
+            Ok(p) if (1..=100).contains(&p) => Ok(MemoryLimit::Percent(p)),
+            _ => Err(invalid()),
+        };
+    }
+    match value.parse::<bytesize::ByteSize>() {
+        Ok(size) if size.as_u64() > 0 => Ok(MemoryLimit::Bytes(size.as_u64())),
+        _ => Err(invalid()),
+    }
+}
+
+/// The memory available for new allocations without swapping, from `/proc/meminfo`
+pub fn available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kib = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    Some(kib * 1024)
+}
+
+/// The settings of the xz encoder, fitting in the memory limit if possible
+#[derive(Clone, Debug, PartialEq)]
+pub struct XzPlan {
+    pub threads: u32,
+    pub dict_size: u32,
+    /// The memory needed by the encoder, as estimated by liblzma
+    pub memusage: u64,
+    /// The limit it was fitted in, if known
+    pub limit: Option<u64>,
+}
+
+impl XzPlan {
+    /// Whether the encoder needs more than the limit even with one thread and the smallest
+    /// dictionary
+    pub fn over_limit(&self) -> bool {
+        self.limit.is_some_and(|limit| self.memusage > limit)
+    }
+}
+
+/// Reduce the number of threads, then the dictionary size, until the encoder fits in the
+/// memory limit (by default, most of the available memory)
+pub fn plan_xz_encoder(threads: u32, limit: Option<MemoryLimit>) -> Result<XzPlan> {
+    let available = available_memory();
+    let limit = match limit {
+        Some(MemoryLimit::Bytes(bytes)) => Some(bytes),
+        Some(MemoryLimit::Percent(percent)) => available.map(|a| a / 100 * percent),
+        None => available.map(|a| a / 100 * XZ_DEFAULT_MEMORY_PERCENT),
+    };
+    fit_xz_encoder(threads.max(1), limit, |threads, dict_size| {
+        Ok(xz_encoder_builder(threads, dict_size)?.memusage())
+    })
+}
+
+fn fit_xz_encoder<F: Fn(u32, u32) -> Result<u64>>(
+    threads: u32,
+    limit: Option<u64>,
+    memusage: F,
+) -> Result<XzPlan> {
+    let mut plan = XzPlan {
+        threads,
+        dict_size: XZ_DICT_SIZE,
+        memusage: memusage(threads, XZ_DICT_SIZE)?,
+        limit,
+    };
+    let Some(limit) = limit else {
+        return Ok(plan);
+    };
+    while plan.memusage > limit && plan.threads > 1 {
+        plan.threads -= 1;
+        plan.memusage = memusage(plan.threads, plan.dict_size)?;
+    }
+    while plan.memusage > limit && plan.dict_size > XZ_MIN_DICT_SIZE {
+        plan.dict_size /= 2;
+        plan.memusage = memusage(plan.threads, plan.dict_size)?;
+    }
+
+    Ok(plan)
+}
+
+fn xz_encoder_builder(threads: u32, dict_size: u32) -> Result<MtStreamBuilder> {
     let mut filter = Filters::new();
     let mut opts = LzmaOptions::new_preset(9 | LZMA_PRESET_EXTREME)?;
     opts.nice_len(273);
+    opts.dict_size(dict_size);
     filter.lzma2(&opts);
+    let mut builder = MtStreamBuilder::new();
+    builder.filters(filter).threads(threads);
+
+    Ok(builder)
+}
+
+fn build_xz_encoder(plan: &XzPlan) -> Result<Stream> {
+    Ok(xz_encoder_builder(plan.threads, plan.dict_size)?.encoder()?)
+}
+
+/// An xz encoder the kernel can decompress, which only knows of CRC32 checks
+pub fn kernel_xz_encoder(plan: &XzPlan) -> Result<Stream> {
+    let mut builder = xz_encoder_builder(plan.threads, plan.dict_size)?;
+    builder.check(Check::Crc32);
+
+    Ok(builder.encoder()?)
+}
+
+/// Remove the files directly in the directory whose names match, returning the space freed
+pub fn remove_files<F: Fn(&str) -> bool>(dir: &Path, matches: F) -> Result<u64> {
+    let entries = match std::fs::read_dir(dir) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+        entries => entries?,
+    };
+    let mut freed = 0;
+    for entry in entries {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && matches(&entry.file_name().to_string_lossy()) {
+            std::fs::remove_file(entry.path())?;
+            freed += metadata.len();
+        }
+    }
+
+    Ok(freed)
+}
+
+/// Total size of the files under the directory
+pub fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else {
+            size += metadata.len();
+        }
+    }
+
+    Ok(size)
+}
+
+/// Directories of the host refused as a target, whatever they contain
+const PROTECTED_TARGETS: &[&str] = &["/", "/usr", "/home"];
+/// Mount flags breaking stage 2: device nodes in /dev, running the binaries of the target
+/// and setuid binaries like `su`
+const RISKY_MOUNT_FLAGS: &[&str] = &["nodev", "noexec", "nosuid"];
+
+/// The real path of the target, even if it does not exist yet: the existing part is
+/// canonicalized (resolving the symlinks) and the rest appended
+pub fn resolve_target(target: &Path) -> Result<PathBuf> {
+    let mut existing = std::env::current_dir()?.join(target);
+    let mut rest = Vec::new();
+    loop {
+        match existing.canonicalize() {
+            Ok(real) => return Ok(rest.into_iter().rev().fold(real, |p, c: PathBuf| p.join(c))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let name = existing
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Cannot resolve the target {}", target.display()))?;
+                rest.push(PathBuf::from(name));
+                existing.pop();
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// The mount points and the mount options listed in `/proc/self/mountinfo`
+fn parse_mountinfo(mountinfo: &str) -> Vec<(PathBuf, Vec<&str>)> {
+    mountinfo
+        .lines()
+        .filter_map(|line| {
+            let fields = line.split(' ').collect::<Vec<_>>();
+            let (mount_point, options) = (fields.get(4)?, fields.get(5)?);
+            // spaces, tabs, newlines and backslashes are escaped in octal
+            let mut unescaped = Vec::new();
+            let mut bytes = mount_point.bytes();
+            while let Some(b) = bytes.next() {
+                if b == b'\\' {
+                    let digits = bytes.by_ref().take(3).collect::<Vec<_>>();
+                    let digits = std::str::from_utf8(&digits).ok()?;
+                    unescaped.push(u8::from_str_radix(digits, 8).ok()?);
+                } else {
+                    unescaped.push(b);
+                }
+            }
+            let mount_point = PathBuf::from(std::ffi::OsStr::from_bytes(&unescaped));
 
-    Ok(MtStreamBuilder::new()
-        .filters(filter)
-        .threads(threads)
-        .encoder()?)
+            Some((mount_point, options.split(',').collect()))
+        })
+        .collect()
+}
+
+/// The mount points listed in `/proc/self/mountinfo` at or under the target
+pub fn mounts_under(target: &Path, mountinfo: &str) -> Vec<PathBuf> {
+    let target = std::fs::canonicalize(target).unwrap_or_else(|_| target.to_owned());
+
+    parse_mountinfo(mountinfo)
+        .into_iter()
+        .map(|(m, _)| m)
+        .filter(|m| m.starts_with(&target))
+        .collect()
+}
+
+/// Refuse the targets resolving to `/`, `/usr`, `/home` or, for a `new` target, to a
+/// non-empty mount point of the host, unless `allow_dangerous` is set, and return the flags
+/// of the filesystem containing the target which break stage 2
+pub fn check_target_location(
+    target: &Path,
+    mountinfo: &str,
+    new: bool,
+    allow_dangerous: bool,
+) -> Result<Vec<String>> {
+    let path = resolve_target(target)?;
+    let mounts = parse_mountinfo(mountinfo);
+    if !allow_dangerous {
+        if PROTECTED_TARGETS.iter().any(|p| path == Path::new(p)) {
+            return Err(anyhow!(
+                "Refusing to use {} as the target, it is {} of the host system.",
+                target.display(),
+                path.display()
+            ));
+        }
+        // a fresh filesystem only has lost+found
+        let non_empty = std::fs::read_dir(&path)
+            .map(|mut entries| entries.any(|e| e.map_or(true, |e| e.file_name() != "lost+found")))
+            .unwrap_or(false);
+        if new && non_empty && mounts.iter().any(|(m, _)| *m == path) {
+            return Err(anyhow!(
+                "Refusing to use {} as the target, it is a mount point of the host system with files in it.",
+                path.display()
+            ));
+        }
+    }
+    // the last mount on the longest matching mount point is the one in effect
+    let flags = mounts
+        .iter()
+        .filter(|(m, _)| path.starts_with(m))
+        .max_by_key(|(m, _)| m.components().count())
+        .map(|(_, options)| {
+            options
+                .iter()
+                .filter(|o| RISKY_MOUNT_FLAGS.contains(o))
+                .map(|o| o.to_string())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Ok(flags)
+}
+
+/// Name of the lock file kept at the root of the target while aoscbootstrap works on it
+pub const TARGET_LOCK: &str = ".aoscbootstrap.lock";
+
+/// An exclusive lock on a target, released (and the lock file removed) when dropped
+pub struct TargetLock {
+    path: PathBuf,
+    _file: File,
+}
+
+impl TargetLock {
+    /// Lock the target, failing right away if another aoscbootstrap holds the lock
+    pub fn acquire(target: &Path) -> Result<TargetLock> {
+        use fs3::FileExt;
+
+        let path = target.join(TARGET_LOCK);
+        loop {
+            let mut f = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            if f.try_lock_exclusive().is_err() {
+                return Err(locked_error(&mut f, target));
+            }
+            // the previous holder may have removed the file while we were waiting for it
+            let locked = f.metadata()?;
+            match std::fs::metadata(&path) {
+                Ok(m) if m.dev() == locked.dev() && m.ino() == locked.ino() => (),
+                _ => continue,
+            }
+            f.set_len(0)?;
+            write!(f, "{}", std::process::id())?;
+            crate::guest::register_lock(&path);
+
+            return Ok(TargetLock { path, _file: f });
+        }
+    }
+
+    /// Fail if another aoscbootstrap holds the lock on the target, without taking it
+    pub fn check(target: &Path) -> Result<()> {
+        use fs3::FileExt;
+
+        let Ok(mut f) = File::open(target.join(TARGET_LOCK)) else {
+            return Ok(());
+        };
+        if FileExt::try_lock_shared(&f).is_err() {
+            return Err(locked_error(&mut f, target));
+        }
+
+        Ok(())
+    }
+}
+
+/// The error for a target locked by another aoscbootstrap, naming it by the pid recorded
+/// in the lock file
+fn locked_error(f: &mut File, target: &Path) -> anyhow::Error {
+    let mut pid = String::new();
+    f.read_to_string(&mut pid).ok();
+    anyhow!(
+        "Another aoscbootstrap (pid {}) is operating on this target ({}).",
+        pid.trim(),
+        target.display()
+    )
+}
+
+impl Drop for TargetLock {
+    fn drop(&mut self) {
+        crate::guest::unregister_lock(&self.path);
+        // remove the file before closing it, so that it is never unlocked in the target
+        std::fs::remove_file(&self.path).ok();
+    }
 }
 
 /// Calculate the Sha256 checksum of the given stream
@@ -125,3 +1201,536 @@ pub fn sha256sum<R: Read>(mut reader: R) -> Result<String> {
 
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+#[test]
+fn test_format_apt_source() {
+    assert_eq!(
+        format_apt_source(
+            "https://repo.aosc.io/debs",
+            "stable",
+            &["main"],
+            &["amd64", "all"],
+            false,
+            &[]
+        ),
+        "deb https://repo.aosc.io/debs stable main\n"
+    );
+    let deb822 = format_apt_source(
+        "https://repo.aosc.io/debs",
+        "stable",
+        &["main"],
+        &["amd64", "all"],
+        true,
+        &["/etc/apt/keyrings/downstream.gpg".to_string()],
+    );
+    let parsed = oma_debcontrol::parse_str(&deb822).unwrap();
+    assert_eq!(parsed.len(), 1);
+    let field = |name: &str| {
+        parsed[0]
+            .fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.value.to_string())
+            .unwrap()
+    };
+    assert_eq!(field("URIs"), "https://repo.aosc.io/debs");
+    assert_eq!(field("Suites"), "stable");
+    assert_eq!(field("Components"), "main");
+    assert_eq!(field("Architectures"), "amd64 all");
+    assert_eq!(
+        field("Signed-By"),
+        format!("{}, /etc/apt/keyrings/downstream.gpg", AOSC_KEYRING)
+    );
+}
+
+#[test]
+fn test_system_settings() -> Result<()> {
+    assert!(check_hostname("aosc-vm01").is_ok());
+    assert!(check_hostname("").is_err());
+    assert!(check_hostname("-vm").is_err());
+    assert!(check_hostname("vm.example.org").is_err());
+    assert!(check_locale("en_US.UTF-8").is_ok());
+    assert!(check_locale("C.UTF-8").is_ok());
+    assert!(check_locale("sr_RS@latin").is_ok());
+    assert!(check_locale("en US").is_err());
+    assert!(check_locale("").is_err());
+    assert!(check_timezone("Asia/Shanghai").is_ok());
+    assert!(check_timezone("Etc/GMT+8").is_ok());
+    assert!(check_timezone("UTC").is_ok());
+    assert!(check_timezone("../../etc/passwd").is_err());
+    assert!(check_timezone("/Asia/Shanghai").is_err());
+
+    let root = tempfile::tempdir()?;
+    create_dir_all(root.path().join("etc"))?;
+    assert!(set_timezone(root.path(), "Asia/Shanghai").is_err());
+    create_dir_all(root.path().join("usr/share/zoneinfo/Asia"))?;
+    write(root.path().join("usr/share/zoneinfo/Asia/Shanghai"), "TZif")?;
+    std::os::unix::fs::symlink("/usr/share/zoneinfo/UTC", root.path().join("etc/localtime"))?;
+    set_timezone(root.path(), "Asia/Shanghai")?;
+    assert_eq!(
+        std::fs::read_link(root.path().join("etc/localtime"))?,
+        Path::new("/usr/share/zoneinfo/Asia/Shanghai")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_machine_id() -> Result<()> {
+    assert_eq!(parse_machine_id("none"), Ok(MachineId::None));
+    assert_eq!(parse_machine_id("empty"), Ok(MachineId::Empty));
+    let id = "0f3a8c7e2b6d4e1f9a5c3b7d8e2f1a4c".to_string();
+    assert_eq!(
+        parse_machine_id("0F3A8C7E-2B6D-4E1F-9A5C-3B7D8E2F1A4C"),
+        Ok(MachineId::Fixed(id.clone()))
+    );
+    assert_eq!(parse_machine_id(&id), Ok(MachineId::Fixed(id.clone())));
+    assert!(parse_machine_id("0f3a8c7e2b6d4e1f9a5c3b7d8e2f1a4").is_err());
+    assert!(parse_machine_id("0f3a8c7e-2b6d4e1f9a5c3b7d8e2f1a4c").is_err());
+    assert!(parse_machine_id("00000000000000000000000000000000").is_err());
+    assert!(parse_machine_id("random-ish").is_err());
+
+    let root = tempfile::tempdir()?;
+    create_dir_all(root.path().join("etc"))?;
+    create_dir_all(root.path().join("var/lib/dbus"))?;
+    let machine_id = root.path().join("etc/machine-id");
+    set_machine_id(root.path(), &MachineId::Fixed(id.clone()))?;
+    assert_eq!(std::fs::read_to_string(&machine_id)?, format!("{}\n", id));
+    set_machine_id(root.path(), &MachineId::Random)?;
+    let random = std::fs::read_to_string(&machine_id)?;
+    assert_eq!(random.trim().len(), 32);
+    assert_ne!(random.trim(), id);
+    set_machine_id(root.path(), &MachineId::Empty)?;
+    assert_eq!(std::fs::read_to_string(&machine_id)?, "");
+    write(root.path().join("var/lib/dbus/machine-id"), &id)?;
+    set_machine_id(root.path(), &MachineId::None)?;
+    assert!(!machine_id.exists());
+    assert!(!root.path().join("var/lib/dbus/machine-id").exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_overlay() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let overlay = tempfile::tempdir()?;
+    let root = tempfile::tempdir()?;
+    create_dir_all(overlay.path().join("etc/NetworkManager/system-connections"))?;
+    let profile = overlay
+        .path()
+        .join("etc/NetworkManager/system-connections/lab.nmconnection");
+    write(&profile, "[connection]\n")?;
+    std::fs::set_permissions(&profile, std::fs::Permissions::from_mode(0o600))?;
+    write(overlay.path().join("etc/os-release"), "NAME=Custom\n")?;
+    std::os::unix::fs::symlink("os-release", overlay.path().join("etc/release"))?;
+    create_dir_all(root.path().join("etc"))?;
+    write(root.path().join("etc/os-release"), "NAME=AOSC OS\n")?;
+    // usrmerge: files for /bin go to /usr/bin, and the symlink stays
+    create_dir_all(overlay.path().join("bin"))?;
+    write(overlay.path().join("bin/hello"), "echo\n")?;
+    create_dir_all(root.path().join("usr/bin"))?;
+    std::os::unix::fs::symlink("usr/bin", root.path().join("bin"))?;
+    // absolute symlinks are followed inside the target, not on the host
+    create_dir_all(overlay.path().join("lib/modules-load.d"))?;
+    write(
+        overlay.path().join("lib/modules-load.d/vfio.conf"),
+        "vfio\n",
+    )?;
+    create_dir_all(root.path().join("usr/lib"))?;
+    std::os::unix::fs::symlink("/usr/lib", root.path().join("lib"))?;
+
+    let replaced = apply_overlay(overlay.path(), root.path())?;
+    assert_eq!(replaced, [Path::new("etc/os-release")]);
+    assert_eq!(
+        std::fs::read_link(root.path().join("bin"))?,
+        Path::new("usr/bin")
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("usr/bin/hello"))?,
+        "echo\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("usr/lib/modules-load.d/vfio.conf"))?,
+        "vfio\n"
+    );
+    let copied = root
+        .path()
+        .join("etc/NetworkManager/system-connections/lab.nmconnection");
+    assert_eq!(std::fs::read_to_string(&copied)?, "[connection]\n");
+    assert_eq!(copied.metadata()?.permissions().mode() & 0o777, 0o600);
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/os-release"))?,
+        "NAME=Custom\n"
+    );
+    assert_eq!(
+        std::fs::read_link(root.path().join("etc/release"))?,
+        Path::new("os-release")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_bootstrap_apt() -> Result<()> {
+    let read = |root: &Path, path: &str| std::fs::read_to_string(root.join(path)).unwrap();
+    let mirror = "https://mirrors.example.org/anthon/debs";
+    for (comps, deb822, path, expected) in [
+        (
+            &["main"][..],
+            false,
+            "etc/apt/sources.list",
+            format!("deb {} stable main\n", mirror),
+        ),
+        (
+            &["main", "bsp-sunxi", "bsp-rk"][..],
+            false,
+            "etc/apt/sources.list",
+            format!("deb {} stable main bsp-sunxi bsp-rk\n", mirror),
+        ),
+        (
+            &["main", "bsp-sunxi"][..],
+            true,
+            "etc/apt/sources.list.d/aosc.sources",
+            format_apt_source(
+                mirror,
+                "stable",
+                &["main", "bsp-sunxi"],
+                &["arm64", "all"],
+                true,
+                &[],
+            ),
+        ),
+    ] {
+        let root = tempfile::tempdir()?;
+        bootstrap_apt(
+            root.path(),
+            mirror,
+            "stable",
+            comps,
+            &["arm64", "all"],
+            deb822,
+            "C.UTF-8",
+            &[],
+            None,
+        )?;
+        assert_eq!(read(root.path(), path), expected);
+        if deb822 {
+            assert!(read(root.path(), path).contains("Components: main bsp-sunxi\n"));
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_default_release() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    bootstrap_apt(
+        root.path(),
+        "https://repo.aosc.io/debs",
+        "stable",
+        &["main"],
+        &["amd64", "all"],
+        false,
+        "C.UTF-8",
+        &[],
+        None,
+    )?;
+    assert!(!root.path().join(DEFAULT_RELEASE_PREFERENCES).exists());
+    assert!(!root.path().join(OTHER_BRANCHES_PREFERENCES).exists());
+    assert!(!root.path().join(DEFAULT_RELEASE_CONF).exists());
+
+    bootstrap_apt(
+        root.path(),
+        "https://repo.aosc.io/debs",
+        "stable",
+        &["main"],
+        &["amd64", "all"],
+        false,
+        "C.UTF-8",
+        &[],
+        Some(DEFAULT_PIN_PRIORITY),
+    )?;
+    let prefs = std::fs::read_to_string(root.path().join(DEFAULT_RELEASE_PREFERENCES))?;
+    let parsed = oma_debcontrol::parse_str(&prefs).unwrap();
+    assert_eq!(parsed.len(), 1);
+    let others = std::fs::read_to_string(root.path().join(OTHER_BRANCHES_PREFERENCES))?;
+    let others = oma_debcontrol::parse_str(&others).unwrap();
+    let field = |i: usize, name: &str| {
+        [&parsed[0], &others[0]][i]
+            .fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.value.clone())
+            .unwrap()
+    };
+    assert_eq!(field(0, "Package"), "*");
+    assert_eq!(field(0, "Pin"), "release n=stable");
+    assert_eq!(field(0, "Pin-Priority"), "990");
+    assert_eq!(field(1, "Pin"), "release n=*");
+    assert_eq!(field(1, "Pin-Priority"), "400");
+    assert_eq!(
+        std::fs::read_to_string(root.path().join(DEFAULT_RELEASE_CONF))?,
+        "APT::Default-Release \"stable\";\n"
+    );
+    // the suite of the sources is the one pinned
+    assert!(
+        std::fs::read_to_string(root.path().join("etc/apt/sources.list"))?.contains(" stable main")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_xz_memory_limit() -> Result<()> {
+    assert_eq!(parse_memory_limit("50%"), Ok(MemoryLimit::Percent(50)));
+    assert_eq!(
+        parse_memory_limit("4GiB"),
+        Ok(MemoryLimit::Bytes(4 * 1024 * 1024 * 1024))
+    );
+    assert_eq!(
+        parse_memory_limit("1048576"),
+        Ok(MemoryLimit::Bytes(1 << 20))
+    );
+    for invalid in ["0", "0%", "150%", "lots"] {
+        assert!(parse_memory_limit(invalid).is_err(), "{}", invalid);
+    }
+    // about 11 times the dictionary per thread, as liblzma estimates for preset 9e
+    let estimate = |threads: u32, dict_size: u32| Ok(threads as u64 * dict_size as u64 * 11);
+    let gib = 1 << 30;
+    let plan = fit_xz_encoder(16, Some(4 * gib), estimate)?;
+    assert_eq!((plan.threads, plan.dict_size), (5, XZ_DICT_SIZE));
+    assert!(plan.memusage <= 4 * gib && !plan.over_limit());
+    let plan = fit_xz_encoder(16, Some(gib / 4), estimate)?;
+    assert_eq!((plan.threads, plan.dict_size), (1, XZ_DICT_SIZE / 4));
+    let plan = fit_xz_encoder(4, Some(1 << 20), estimate)?;
+    assert_eq!((plan.threads, plan.dict_size), (1, XZ_MIN_DICT_SIZE));
+    assert!(plan.over_limit());
+    assert_eq!(fit_xz_encoder(16, None, estimate)?.threads, 16);
+    // the estimate of liblzma itself grows with the threads and the dictionary
+    let usage = |threads, dict_size| xz_encoder_builder(threads, dict_size).unwrap().memusage();
+    assert!(usage(4, XZ_DICT_SIZE) > usage(1, XZ_DICT_SIZE));
+    assert!(usage(1, XZ_DICT_SIZE) > usage(1, XZ_MIN_DICT_SIZE));
+
+    Ok(())
+}
+
+#[test]
+fn test_streamed_checksum() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    create_dir_all(root.path().join("usr/bin"))?;
+    write(root.path().join("usr/bin/hello"), "#!/bin/sh\necho hello\n")?;
+    write(root.path().join("random"), rand::random::<[u8; 32]>())?;
+    let out = tempfile::tempdir()?;
+    let plan = plan_xz_encoder(2, None)?;
+    for (name, sha256) in [
+        (
+            "rootfs.tar.gz",
+            archive_gz_tarball(root.path(), &out.path().join("rootfs.tar.gz"))?,
+        ),
+        (
+            "rootfs.tar.xz",
+            archive_xz_tarball(root.path(), &out.path().join("rootfs.tar.xz"), &plan)?,
+        ),
+        (
+            "rootfs.tar.zst",
+            archive_zstd_tarball(root.path(), &out.path().join("rootfs.tar.zst"), 2)?,
+        ),
+    ] {
+        let output = Command::new("sha256sum")
+            .arg(out.path().join(name))
+            .output()?;
+        let expected = String::from_utf8(output.stdout)?;
+        assert_eq!(expected.split_whitespace().next(), Some(sha256.as_str()));
+        assert_eq!(sha256, sha256sum(File::open(out.path().join(name))?)?);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_squashfs_compressors() -> Result<()> {
+    let mksquashfs = "SYNTAX:mksquashfs source1 source2 ...  dest [options]\n\nFilesystem build options:\n-comp <comp>\t\tselect <comp> compression\n\nCompressors available and compressor specific options:\n\tgzip (default)\n\t  -Xcompression-level <compression-level>\n\t\t<compression-level> should be 1 .. 9 (default 9)\n\tlzo\n\txz\n\t  -Xbcj filter1,filter2,...,filterN\n";
+    assert_eq!(
+        parse_squashfs_compressors(mksquashfs),
+        ["gzip", "lzo", "xz"]
+    );
+    let gensquashfs = "Usage: gensquashfs [OPTIONS...] <squashfs-file>\n\nPossible options:\n\n  --compressor, -c <name>     Select the compressor to use.\n\nAvailable compressors:\n\tgzip\n\txz (default)\n\tlzo\n\tlz4\n\tzstd\n\nExample:\n\n\tgensquashfs --pack-dir ./rootfs rootfs.sqfs\n";
+    assert_eq!(
+        parse_squashfs_compressors(gensquashfs),
+        ["gzip", "xz", "lzo", "lz4", "zstd"]
+    );
+    assert!(parse_squashfs_compressors("mksquashfs: invalid option\n").is_empty());
+
+    let backend = SquashfsBackend {
+        tool: SquashfsTool::Mksquashfs,
+        version: "mksquashfs version 4.3-git (2014/06/09)".to_string(),
+        compressors: parse_squashfs_compressors(mksquashfs),
+    };
+    assert!(backend.check("xz").is_ok());
+    let err = backend.check("zstd").unwrap_err().to_string();
+    assert!(err.contains("mksquashfs version 4.3-git"), "{}", err);
+    assert!(err.contains("only gzip, lzo, xz"), "{}", err);
+    let unknown = SquashfsBackend {
+        compressors: Vec::new(),
+        ..backend
+    };
+    assert!(!unknown.knows_compressors());
+    assert!(unknown.check("zstd").is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn test_gensquashfs_pack_file() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    create_dir_all(root.path().join("usr/bin"))?;
+    std::fs::set_permissions(root.path().join("usr"), Permissions::from_mode(0o755))?;
+    std::os::unix::fs::symlink("usr/bin", root.path().join("bin"))?;
+    write(root.path().join("a \"b\""), "")?;
+    std::fs::set_permissions(root.path().join("a \"b\""), Permissions::from_mode(0o600))?;
+    write(root.path().join(TARGET_LOCK), "1")?;
+    let (uid, gid) = (nix::unistd::getuid(), nix::unistd::getgid());
+    assert_eq!(
+        gensquashfs_pack_file(root.path())?,
+        format!(
+            "file \"/a \\\"b\\\"\" 600 {uid} {gid} \"a \\\"b\\\"\"\n\
+             slink \"/bin\" 0777 {uid} {gid} \"usr/bin\"\n\
+             dir \"/usr\" 755 {uid} {gid}\n\
+             glob \"/usr\" * * * -keeptime \"usr\"\n"
+        )
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_atomic_write() -> Result<()> {
+    let mode = |path: &Path| path.metadata().unwrap().permissions().mode() & 0o7777;
+    let root = tempfile::tempdir()?;
+    bootstrap_apt(
+        root.path(),
+        "https://mirrors.example.org/anthon/debs",
+        "stable",
+        &["main"],
+        &["amd64", "all"],
+        false,
+        "C.UTF-8",
+        &[],
+        None,
+    )?;
+    assert_eq!(mode(&root.path().join("etc/shadow")), 0o000);
+    assert_eq!(mode(&root.path().join("etc/apt/sources.list")), 0o644);
+    assert_eq!(mode(&root.path().join("etc/locale.conf")), 0o644);
+    // replacing a file keeps no residue and takes the new mode
+    let path = root.path().join("etc/machine-id");
+    atomic_write(&path, "old\n", 0o644)?;
+    atomic_write(&path, "new\n", 0o444)?;
+    assert_eq!(std::fs::read_to_string(&path)?, "new\n");
+    assert_eq!(mode(&path), 0o444);
+    // a failed write leaves the file as it was
+    std::fs::create_dir(root.path().join("etc/hostname"))?;
+    assert!(atomic_write(&root.path().join("etc/hostname"), "aosc\n", 0o644).is_err());
+    assert!(root.path().join("etc/hostname").is_dir());
+    for dir in ["etc", "etc/apt"] {
+        for entry in std::fs::read_dir(root.path().join(dir))? {
+            let name = entry?.file_name();
+            assert!(!name.to_string_lossy().ends_with(".tmp"), "{:?}", name);
+        }
+    }
+    // a symlink planted where a temporary file used to be is neither followed nor replaced
+    let outside = tempfile::tempdir()?;
+    let secret = outside.path().join("shadow");
+    std::fs::write(&secret, "root:x:::::::\n")?;
+    let planted = root.path().join("etc/hostname.tmp");
+    std::os::unix::fs::symlink(&secret, &planted)?;
+    std::os::unix::fs::symlink(&secret, root.path().join("etc/.hostname.tmp"))?;
+    std::fs::remove_dir(root.path().join("etc/hostname"))?;
+    atomic_write(&root.path().join("etc/hostname"), "aosc\n", 0o644)?;
+    assert_eq!(std::fs::read_to_string(&secret)?, "root:x:::::::\n");
+    assert_eq!(std::fs::read_link(&planted)?, secret);
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("etc/hostname"))?,
+        "aosc\n"
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_remove_files() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::write(dir.path().join("bash_5.2_amd64.deb"), "1234")?;
+    std::fs::write(dir.path().join("lock"), "")?;
+    create_dir_all(dir.path().join("partial"))?;
+    std::fs::write(dir.path().join("partial/apt_2.7_amd64.deb"), "12")?;
+    assert_eq!(dir_size(dir.path())?, 6);
+    assert_eq!(remove_files(dir.path(), |name| name.ends_with(".deb"))?, 4);
+    assert!(dir.path().join("lock").exists());
+    assert!(dir.path().join("partial/apt_2.7_amd64.deb").exists());
+    assert_eq!(remove_files(&dir.path().join("nonexistent"), |_| true)?, 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_check_target_location() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let root = dir.path().canonicalize()?;
+    std::os::unix::fs::symlink("/", root.join("host"))?;
+    std::os::unix::fs::symlink("/usr", root.join("usr"))?;
+    create_dir_all(root.join("disk/lost+found"))?;
+    create_dir_all(root.join("data/home"))?;
+    let mountinfo = format!(
+        "22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+         30 22 0:25 / {0}/disk rw,nosuid,nodev shared:2 - ext4 /dev/sdb1 rw\n\
+         31 22 0:26 / {0}/data rw shared:3 - ext4 /dev/sdc1 rw\n\
+         32 22 0:27 / {0}/my\\040disk rw,noexec shared:4 - ext4 /dev/sdd1 rw\n",
+        root.display()
+    );
+    let check = |target: &Path| check_target_location(target, &mountinfo, true, false);
+    for target in ["/", "/usr/", "/home/../usr"] {
+        assert!(check(Path::new(target)).is_err(), "{}", target);
+    }
+    assert!(check(&root.join("host")).is_err());
+    assert!(check(&root.join("usr/../usr")).is_err());
+    assert!(check(&root.join("data")).is_err());
+    assert!(check_target_location(&root.join("data"), &mountinfo, true, true).is_ok());
+    // an existing target is populated by design, e.g. a disk to run stage 2 on
+    assert!(check_target_location(&root.join("data"), &mountinfo, false, false).is_ok());
+    assert!(check_target_location(&root.join("host"), &mountinfo, false, false).is_err());
+    // a fresh filesystem, and targets which do not exist yet
+    assert_eq!(check(&root.join("disk"))?, ["nosuid", "nodev"]);
+    assert_eq!(check(&root.join("disk/rootfs/new"))?, ["nosuid", "nodev"]);
+    assert_eq!(check(&root.join("my disk/rootfs"))?, ["noexec"]);
+    assert!(check(&root.join("data/rootfs"))?.is_empty());
+    assert_eq!(
+        resolve_target(&root.join("host/usr/rootfs"))?,
+        Path::new("/usr/rootfs")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_target_lock() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    let lock = TargetLock::acquire(target.path())?;
+    let pid = std::fs::read_to_string(target.path().join(TARGET_LOCK))?;
+    assert_eq!(pid, std::process::id().to_string());
+    let e = TargetLock::acquire(target.path()).err().unwrap();
+    assert!(e
+        .to_string()
+        .contains(&format!("aoscbootstrap (pid {}) is operating", pid)));
+    let e = TargetLock::check(target.path()).err().unwrap();
+    assert!(e
+        .to_string()
+        .contains(&format!("aoscbootstrap (pid {}) is operating", pid)));
+    drop(lock);
+    TargetLock::check(target.path())?;
+    assert!(!target.path().join(TARGET_LOCK).exists());
+    let _lock = TargetLock::acquire(target.path())?;
+
+    Ok(())
+}
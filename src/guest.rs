@@ -1,16 +1,37 @@
 use std::{
+    collections::VecDeque,
     ffi::CString,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Read, Seek, SeekFrom, Write},
     mem::MaybeUninit,
-    process::{Child, Command, Stdio},
-    thread::sleep,
-    time::Duration,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Arc, Mutex},
+    thread::{sleep, JoinHandle},
+    time::{Duration, Instant},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use clap::ValueEnum;
+use libaosc::arch::get_arch_name;
 use libc::{c_char, c_int};
 use libloading::{Library, Symbol};
+use log::{error, info, log_enabled, warn, Level};
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    sys::signal::{killpg, Signal},
+    unistd::Pid,
+};
 use rand::random;
 
+use crate::{
+    cancel,
+    events::{self, Event},
+    install,
+};
+
 #[allow(non_camel_case_types)]
 enum sd_bus {}
 
@@ -41,17 +62,51 @@ fn try_open_container_bus(lib: &SystemdMachine, ns_name: &str) -> Result<()> {
     Err(anyhow!("Could not open container bus"))
 }
 
-fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<()> {
-    let systemd_lib = unsafe { Library::new("libsystemd.so")? };
-    let s1 = unsafe { systemd_lib.get(b"sd_bus_open_system_machine")? };
-    let s2 = unsafe { systemd_lib.get(b"sd_bus_flush_close_unref")? };
+/// Check whether the container is running with `machinectl`, for hosts where
+/// libsystemd cannot be loaded
+fn machinectl_running(ns_name: &str) -> bool {
+    Command::new("machinectl")
+        .args(["show", ns_name, "--property=State", "--value"])
+        .stderr(Stdio::null())
+        .output()
+        .map(|o| o.status.success() && String::from_utf8_lossy(&o.stdout).trim() == "running")
+        .unwrap_or(false)
+}
+
+fn load_libsystemd() -> Option<Library> {
+    ["libsystemd.so.0", "libsystemd.so"]
+        .into_iter()
+        .find_map(|name| unsafe { Library::new(name) }.ok())
+}
 
-    let lib = SystemdMachine {
-        sd_bus_open_system_machine: s1,
-        sd_bus_flush_close_unref: s2,
-    };
+fn machine_functions(lib: &Library) -> Option<SystemdMachine<'_>> {
+    unsafe {
+        Some(SystemdMachine {
+            sd_bus_open_system_machine: lib.get(b"sd_bus_open_system_machine").ok()?,
+            sd_bus_flush_close_unref: lib.get(b"sd_bus_flush_close_unref").ok()?,
+        })
+    }
+}
+
+/// Check that libsystemd can be loaded to wait for the container, without falling back
+/// to machinectl
+pub fn check_libsystemd() -> Result<()> {
+    let lib = load_libsystemd().ok_or_else(|| anyhow!("libsystemd.so.0 cannot be loaded"))?;
+    machine_functions(&lib)
+        .ok_or_else(|| anyhow!("libsystemd does not provide sd_bus_open_system_machine"))?;
+
+    Ok(())
+}
 
-    for i in 0..retry {
+fn wait_for_container(child: &mut Child, ns_name: &str, timeout: Duration) -> Result<()> {
+    let systemd_lib = load_libsystemd();
+    let lib = systemd_lib.as_ref().and_then(machine_functions);
+    if lib.is_none() {
+        warn!("Cannot load libsystemd, falling back to machinectl to wait for the container.");
+    }
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
         let exited = child.try_wait()?;
         if let Some(status) = exited {
             return Err(anyhow!("nspawn exited too early! (Status: {})", status));
@@ -60,20 +115,233 @@ fn wait_for_container(child: &mut Child, ns_name: &str, retry: usize) -> Result<
         // is fully initialized. To spawn a new process in the container, we need the systemd
         // in the container to be fully initialized and listening for connections.
         // One way to resolve this issue is to test the connection to the container's systemd.
-        if try_open_container_bus(&lib, ns_name).is_ok() {
+        let ready = match lib {
+            Some(ref lib) => try_open_container_bus(lib, ns_name).is_ok(),
+            None => machinectl_running(ns_name),
+        };
+        if ready {
             return Ok(());
         }
-        // wait for a while, sleep time follows a natural-logarithm distribution
-        sleep(Duration::from_secs_f32(((i + 1) as f32).ln().ceil()));
+        sleep(Duration::from_millis(500));
     }
+    child.kill().ok();
+    child.wait().ok();
 
-    Err(anyhow!("Timeout waiting for container {}", ns_name))
+    Err(anyhow!(
+        "Timeout waiting for container {} after {} seconds. Try a larger --container-wait-timeout.",
+        ns_name,
+        timeout.as_secs()
+    ))
+}
+
+/// Collect the output of the booting container in a separate thread, keeping the
+/// last `max` lines in memory, writing all of them to the log, and optionally
+/// showing them on the terminal
+fn collect_boot_log<R: Read + Send + 'static>(
+    input: R,
+    lines: Arc<Mutex<VecDeque<String>>>,
+    max: usize,
+    log: Arc<Mutex<File>>,
+    show: bool,
+) -> JoinHandle<()> {
+    std::thread::spawn(move || {
+        for line in BufReader::new(input).lines() {
+            let Ok(line) = line else {
+                break;
+            };
+            if show {
+                info!("{}", line);
+            }
+            if let Ok(mut log) = log.lock() {
+                writeln!(log, "{}", line).ok();
+            }
+            if let Ok(mut lines) = lines.lock() {
+                if lines.len() >= max {
+                    lines.pop_front();
+                }
+                lines.push_back(line);
+            }
+        }
+    })
 }
 
-fn chroot_do(target: &str, args: &[&str]) -> Result<()> {
-    let status = Command::new("chroot").arg(target).args(args).status()?;
+/// Pseudo filesystems and files set up for running commands in a chroot,
+/// cleaned up when dropped, even if the commands fail or panic
+struct ChrootMounts {
+    target: PathBuf,
+    mounted: Vec<PathBuf>,
+    /// How resolv.conf was set up in the target, to be undone on drop
+    resolv_conf: Option<ResolvConf>,
+}
+
+enum ResolvConf {
+    /// Copied from the host, the target had none
+    Copied,
+    /// The original one of the target was moved aside
+    Replaced,
+}
+
+const RESOLV_CONF_BACKUP: &str = "etc/resolv.conf.aoscbootstrap";
+/// How long the processes of stage 2 have to exit after SIGTERM when interrupted
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+
+impl ChrootMounts {
+    fn new(target: &Path) -> ChrootMounts {
+        let mut mounts = ChrootMounts {
+            target: target.to_path_buf(),
+            mounted: Vec::new(),
+            resolv_conf: None,
+        };
+        let bind = MsFlags::MS_BIND | MsFlags::MS_REC;
+        for (source, dest, fstype, flags) in [
+            ("proc", "proc", Some("proc"), MsFlags::empty()),
+            ("sysfs", "sys", Some("sysfs"), MsFlags::empty()),
+            ("/dev", "dev", None, bind),
+        ] {
+            if let Err(e) = mounts.mount(source, dest, fstype, flags) {
+                warn!(
+                    "Failed to mount {} on {}: {}. Some maintainer scripts may not work. Are you running in an unprivileged container?",
+                    source,
+                    target.join(dest).display(),
+                    e
+                );
+            }
+        }
+        if let Err(e) = mounts.setup_resolv_conf() {
+            warn!("Failed to set up resolv.conf in the target: {}", e);
+        }
+
+        mounts
+    }
+
+    fn mount(
+        &mut self,
+        source: &str,
+        dest: &str,
+        fstype: Option<&str>,
+        flags: MsFlags,
+    ) -> Result<()> {
+        let dest = self.target.join(dest);
+        std::fs::create_dir_all(&dest)?;
+        mount(Some(source), &dest, fstype, flags, None::<&str>)?;
+        if let Ok(mut cleanup) = CLEANUP.lock() {
+            cleanup.mounts.push(dest.clone());
+        }
+        self.mounted.push(dest);
+
+        Ok(())
+    }
+
+    fn setup_resolv_conf(&mut self) -> Result<()> {
+        let host = Path::new("/etc/resolv.conf");
+        if !host.exists() {
+            return Ok(());
+        }
+        let resolv_conf = self.target.join("etc/resolv.conf");
+        if resolv_conf.symlink_metadata().is_ok() {
+            std::fs::rename(&resolv_conf, self.target.join(RESOLV_CONF_BACKUP))?;
+            self.resolv_conf = Some(ResolvConf::Replaced);
+        } else {
+            self.resolv_conf = Some(ResolvConf::Copied);
+        }
+        std::fs::copy(host, &resolv_conf)?;
+
+        Ok(())
+    }
+
+    /// Leave everything mounted, for the user to inspect the target
+    fn keep(mut self) {
+        self.unregister();
+        std::mem::forget(self);
+    }
+
+    fn unregister(&mut self) {
+        if let Ok(mut cleanup) = CLEANUP.lock() {
+            cleanup.mounts.retain(|m| !self.mounted.contains(m));
+        }
+    }
+}
+
+impl Drop for ChrootMounts {
+    fn drop(&mut self) {
+        self.unregister();
+        for dest in self.mounted.iter().rev() {
+            if let Err(e) = umount2(dest, MntFlags::MNT_DETACH) {
+                warn!("Failed to unmount {}: {}", dest.display(), e);
+            }
+        }
+        let resolv_conf = self.target.join("etc/resolv.conf");
+        match self.resolv_conf {
+            Some(ResolvConf::Copied) => {
+                std::fs::remove_file(resolv_conf).ok();
+            }
+            Some(ResolvConf::Replaced) => {
+                std::fs::rename(self.target.join(RESOLV_CONF_BACKUP), resolv_conf).ok();
+            }
+            None => (),
+        }
+    }
+}
+
+/// Prepare a chroot command with a clean environment, to be completed with the
+/// command to run in the target
+fn chroot_command(target: &str, options: &GuestOptions) -> Command {
+    let mut command = Command::new("chroot");
+    command
+        .arg(target)
+        .env_clear()
+        .env(
+            "PATH",
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        )
+        .env("DEBIAN_FRONTEND", "noninteractive")
+        .env("HOME", "/root")
+        .env(
+            "TERM",
+            std::env::var("TERM").unwrap_or_else(|_| "xterm".to_string()),
+        )
+        .env("LANG", "C.UTF-8")
+        .envs(options.env.iter().map(|e| (&e.key, &e.value)));
+
+    command
+}
+
+fn chroot_do(target: &str, args: &[&str], options: &GuestOptions) -> Result<()> {
+    let mounts = ChrootMounts::new(Path::new(target));
+    let mut command = chroot_command(target, options);
+    command.args(args);
+    let status = run_logged(&mut command, options, &|| ());
+    let status = match status {
+        Ok(status) => status,
+        Err(e) => {
+            drop(mounts);
+            return Err(e);
+        }
+    };
 
     if !status.success() {
+        match options.on_failure {
+            OnFailure::Poweroff => drop(mounts),
+            OnFailure::Shell => {
+                info!("Starting a shell in the target, exit it to clean up ...");
+                chroot_command(target, options).arg("/bin/bash").status()?;
+                drop(mounts);
+            }
+            OnFailure::Keep => {
+                info!(
+                    "Keeping {} mounted. Enter it with `chroot {} /bin/bash`, and unmount {} when you are done.",
+                    target,
+                    target,
+                    mounts
+                        .mounted
+                        .iter()
+                        .map(|p| p.display().to_string())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                mounts.keep();
+            }
+        }
         return Err(anyhow!("chroot exited with status {}", status));
     }
 
@@ -82,33 +350,342 @@ fn chroot_do(target: &str, args: &[&str]) -> Result<()> {
 
 #[inline]
 /// Execute a command in the container
-fn execute_container_command(ns_name: &str, args: &[&str]) -> Result<i32> {
-    let exit_code = Command::new("systemd-run")
-        .args(["-M", ns_name, "-qt", "--"])
-        .args(args)
-        .spawn()?
-        .wait()?
+fn execute_container_command(ns_name: &str, args: &[&str], options: &GuestOptions) -> Result<i32> {
+    let mut command = Command::new("systemd-run");
+    command.args(["-M", ns_name, "-qP"]);
+    for env in &options.env {
+        // without a value, systemd-run takes it from its own environment, which
+        // keeps the value off the command line
+        command
+            .env(&env.key, &env.value)
+            .arg("--setenv")
+            .arg(&env.key);
+    }
+    command.arg("--").args(args);
+    let on_timeout = || {
+        Command::new("machinectl")
+            .args(["status", ns_name])
+            .status()
+            .ok();
+        Command::new("machinectl")
+            .args(["terminate", ns_name])
+            .status()
+            .ok();
+    };
+    let exit_code = run_logged(&mut command, options, &on_timeout)?
         .code()
         .unwrap_or(127);
 
     Ok(exit_code)
 }
 
-fn nspawn_do(target: &str, args: &[&str]) -> Result<()> {
+/// A booted container, terminated when dropped unless it is powered off or kept
+struct Container {
+    name: String,
+    running: bool,
+}
+
+impl Container {
+    fn new(name: String) -> Container {
+        if let Ok(mut cleanup) = CLEANUP.lock() {
+            cleanup.containers.push(name.clone());
+        }
+
+        Container {
+            name,
+            running: true,
+        }
+    }
+
+    /// Stop tracking the container, either because it is stopped or to keep it running
+    fn release(&mut self) {
+        self.running = false;
+        if let Ok(mut cleanup) = CLEANUP.lock() {
+            cleanup.containers.retain(|c| *c != self.name);
+        }
+    }
+}
+
+impl Drop for Container {
+    fn drop(&mut self) {
+        if self.running {
+            self.release();
+            terminate_container(&self.name);
+        }
+    }
+}
+
+fn terminate_container(name: &str) {
+    info!("Terminating the container {} ...", name);
+    Command::new("machinectl")
+        .args(["terminate", name])
+        .stderr(Stdio::null())
+        .status()
+        .ok();
+}
+
+/// What to do with the target when the bootstrap is interrupted
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnInterrupt {
+    /// Keep the target, marked as incomplete with the phase reached
+    Keep,
+    /// Remove the target entirely
+    Remove,
+}
+
+/// Containers and mounts to clean up when interrupted
+struct Cleanup {
+    containers: Vec<String>,
+    /// Process groups of the chroot or bwrap running stage 2
+    process_groups: Vec<Pid>,
+    mounts: Vec<PathBuf>,
+    loop_devices: Vec<PathBuf>,
+    /// Exports being written, incomplete until unregistered
+    exports: Vec<PathBuf>,
+    locks: Vec<PathBuf>,
+    target: Option<(PathBuf, OnInterrupt)>,
+}
+
+static CLEANUP: Mutex<Cleanup> = Mutex::new(Cleanup {
+    containers: Vec::new(),
+    process_groups: Vec::new(),
+    mounts: Vec::new(),
+    loop_devices: Vec::new(),
+    exports: Vec::new(),
+    locks: Vec::new(),
+    target: None,
+});
+
+/// Mark the target as incomplete, or remove it, when interrupted
+pub fn register_target(path: &Path, on_interrupt: OnInterrupt) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.target = Some((path.to_owned(), on_interrupt));
+    }
+}
+
+/// Leave the target alone from now on, e.g. once it is complete
+pub fn unregister_target() {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.target = None;
+    }
+}
+
+/// Remove the lock file when interrupted
+pub fn register_lock(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.locks.push(path.to_owned());
+    }
+}
+
+pub fn unregister_lock(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.locks.retain(|l| l != path);
+    }
+}
+
+/// Unmount a file system mounted outside of stage 2 when interrupted
+pub fn register_mount(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.mounts.push(path.to_owned());
+    }
+}
+
+pub fn unregister_mount(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.mounts.retain(|m| m != path);
+    }
+}
+
+/// Detach the loop device when interrupted, after unmounting its partitions
+pub fn register_loop_device(device: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.loop_devices.push(device.to_owned());
+    }
+}
+
+pub fn unregister_loop_device(device: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.loop_devices.retain(|d| d != device);
+    }
+}
+
+/// Remove an export when interrupted before it is complete
+pub fn register_export(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.exports.push(path.to_owned());
+    }
+}
+
+pub fn unregister_export(path: &Path) {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.exports.retain(|e| e != path);
+    }
+}
+
+/// Remove the incomplete target, unless something is still mounted in it, or the mounts
+/// cannot be told from `mountinfo`, in which case it is kept and marked as incomplete
+fn remove_target(target: &Path, mountinfo: Option<&str>) {
+    let mounted = match mountinfo {
+        Some(mountinfo) => crate::fs::mounts_under(target, mountinfo),
+        None => vec![target.to_owned()],
+    };
+    if !mounted.is_empty() {
+        error!(
+            "Not removing the incomplete target {}, as these are still mounted in it: {}",
+            target.display(),
+            mounted
+                .iter()
+                .map(|m| m.display().to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        keep_target(target);
+        return;
+    }
+    match std::fs::remove_dir_all(target) {
+        Ok(()) => warn!("Removed the incomplete target {}.", target.display()),
+        Err(e) => warn!("Failed to remove {}: {}", target.display(), e),
+    }
+}
+
+/// Mark the target as incomplete, with the phase it was interrupted in
+fn keep_target(target: &Path) {
+    let phase = events::current_phase();
+    if let Err(e) = install::mark_incomplete(target, &phase) {
+        warn!("Failed to mark {} as incomplete: {}", target.display(), e);
+    }
+    warn!(
+        "Kept the incomplete target {}, interrupted during the {} phase.",
+        target.display(),
+        phase
+    );
+}
+
+/// Cancel the bootstrap, clean up and exit when interrupted by SIGINT or SIGTERM
+pub fn install_cleanup_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        // stop the downloads and the exports in flight
+        cancel::token().cancel();
+        warn!("Interrupted, cleaning up ...");
+        cleanup();
+        crate::cli::report_interrupted();
+        std::process::exit(130);
+    })?;
+
+    Ok(())
+}
+
+/// Terminate the containers and unmount the file systems set up for stage 2,
+/// deal with the target as asked by `--on-interrupt` and release the target locks
+pub fn cleanup() {
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        for name in cleanup.containers.drain(..) {
+            terminate_container(&name);
+        }
+        // in their own process group, they do not get the SIGINT of the terminal
+        terminate_process_groups(&cleanup.process_groups.drain(..).collect::<Vec<_>>());
+        for dest in cleanup.mounts.drain(..).rev() {
+            umount2(&dest, MntFlags::MNT_DETACH).ok();
+        }
+        for device in cleanup.loop_devices.drain(..) {
+            Command::new("losetup")
+                .arg("--detach")
+                .arg(&device)
+                .status()
+                .ok();
+        }
+        for export in cleanup.exports.drain(..) {
+            if std::fs::remove_file(&export).is_ok() {
+                warn!("Removed the incomplete {}.", export.display());
+            }
+        }
+        nix::unistd::sync();
+        match cleanup.target.take() {
+            Some((target, OnInterrupt::Remove)) => {
+                // what failed to unmount would be removed from the host along with it
+                let mountinfo = std::fs::read_to_string("/proc/self/mountinfo");
+                remove_target(&target, mountinfo.as_deref().ok());
+            }
+            Some((target, OnInterrupt::Keep)) => keep_target(&target),
+            None => (),
+        }
+        for lock in cleanup.locks.drain(..) {
+            std::fs::remove_file(lock).ok();
+        }
+    }
+    nix::unistd::sync();
+}
+
+fn nspawn_do(target: &str, args: &[&str], options: &GuestOptions) -> Result<()> {
     let ns_name = format!("bootstrap-{:x}", random::<u32>());
     let mut child = Command::new("systemd-nspawn")
         .args(["-qbD", target, "-M", &ns_name, "--"])
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
         .spawn()?;
-    eprintln!("Waiting for the container ...");
-    wait_for_container(&mut child, &ns_name, 60)?;
-    let status = execute_container_command(&ns_name, args)?;
+    let mut container = Container::new(ns_name.clone());
+    let boot_log = Arc::new(Mutex::new(VecDeque::new()));
+    let log = open_log(&options.log)?;
+    for output in [
+        Box::new(child.stdout.take().unwrap()) as Box<dyn Read + Send>,
+        Box::new(child.stderr.take().unwrap()),
+    ] {
+        collect_boot_log(
+            output,
+            boot_log.clone(),
+            50,
+            log.clone(),
+            options.show_boot_log,
+        );
+    }
+    info!("Waiting for the container ...");
+    let booting = Instant::now();
+    if let Err(e) = wait_for_container(&mut child, &ns_name, options.container_wait) {
+        // give the collectors a moment to read what is left in the pipes
+        sleep(Duration::from_millis(200));
+        if let Ok(boot_log) = boot_log.lock() {
+            if !boot_log.is_empty() && !options.show_boot_log {
+                warn!("Last lines of the container output:");
+                for line in boot_log.iter() {
+                    warn!("  {}", line);
+                }
+            }
+        }
+        return Err(e);
+    }
+    let boot_time = booting.elapsed();
+    let status = execute_container_command(&ns_name, args, options)?;
+    if status != 0 {
+        match options.on_failure {
+            OnFailure::Poweroff => (),
+            OnFailure::Shell => {
+                info!("Starting a shell in the container, exit it to power off the container ...");
+                Command::new("machinectl")
+                    .args(["shell", &ns_name])
+                    .status()?;
+            }
+            OnFailure::Keep => {
+                info!(
+                    "Keeping the container {} running. Attach to it with `machinectl shell {}`, and stop it with `machinectl poweroff {}`.",
+                    ns_name, ns_name, ns_name
+                );
+                container.release();
+                return Err(anyhow!("nspawn exited with status {}", status));
+            }
+        }
+    }
 
-    eprintln!("Powering off the container ...");
+    info!("Powering off the container ...");
+    let powering_off = Instant::now();
     Command::new("systemctl")
         .args(["-M", &ns_name, "poweroff"])
         .status()?;
+    container.release();
+    info!(
+        "Booting the container took {:.1} s and powering it off {:.1} s, which --no-boot saves.",
+        boot_time.as_secs_f64(),
+        powering_off.elapsed().as_secs_f64()
+    );
 
     if status != 0 {
         return Err(anyhow!("nspawn exited with status {}", status));
@@ -117,12 +694,756 @@ fn nspawn_do(target: &str, args: &[&str]) -> Result<()> {
     Ok(())
 }
 
-pub fn run_in_guest(target: &str, args: &[&str]) -> Result<()> {
-    if which::which("systemd-nspawn").is_ok() {
-        return nspawn_do(target, args);
+/// Prepare a systemd-nspawn command running the command to be appended as the only process
+/// in the container, without booting systemd
+fn nspawn_command(target: &str, ns_name: &str, options: &GuestOptions) -> Command {
+    let mut command = Command::new("systemd-nspawn");
+    command
+        .args(["-qD", target, "-M", ns_name, "--as-pid2"])
+        .arg("--setenv=DEBIAN_FRONTEND=noninteractive");
+    for env in &options.env {
+        // without a value, systemd-nspawn takes it from its own environment
+        command
+            .env(&env.key, &env.value)
+            .arg(format!("--setenv={}", env.key));
+    }
+    command.arg("--");
+
+    command
+}
+
+fn nspawn_run(target: &str, args: &[&str], options: &GuestOptions) -> Result<()> {
+    let ns_name = format!("bootstrap-{:x}", random::<u32>());
+    let mut command = nspawn_command(target, &ns_name, options);
+    command.args(args);
+    let mut container = Container::new(ns_name.clone());
+    // the log is appended to, only what this run writes to it counts
+    let log_start = std::fs::metadata(&options.log).map_or(0, |m| m.len());
+    let status = run_logged(&mut command, options, &|| terminate_container(&ns_name))?;
+    // the container is gone with its only process
+    container.release();
+
+    if !status.success() {
+        if read_log_from(&options.log, log_start).is_some_and(|log| needs_boot(&log)) {
+            warn!("Some packages seem to need a running systemd or D-Bus, which is not available with --no-boot. Try again without it.");
+        }
+        match options.on_failure {
+            OnFailure::Poweroff => (),
+            OnFailure::Shell => {
+                info!("Starting a shell in the target ...");
+                nspawn_command(target, &ns_name, options)
+                    .arg("/bin/bash")
+                    .status()?;
+            }
+            OnFailure::Keep => {
+                info!("There is no container to keep running with --no-boot.");
+            }
+        }
+        return Err(anyhow!("nspawn exited with status {}", status));
+    }
+
+    Ok(())
+}
+
+/// Read the log from the given offset, lossily
+fn read_log_from(path: &Path, offset: u64) -> Option<String> {
+    let mut f = File::open(path).ok()?;
+    f.seek(SeekFrom::Start(offset)).ok()?;
+    let mut log = Vec::new();
+    f.read_to_end(&mut log).ok()?;
+
+    Some(String::from_utf8_lossy(&log).into_owned())
+}
+
+/// Whether the log shows failures of maintainer scripts talking to systemd or D-Bus: a
+/// line starting with one of their errors, followed by dpkg or apt reporting a failed
+/// package. Warnings such as systemctl's "Running in chroot, ignoring request" are not
+/// failures, and neither are the messages quoted in the middle of other lines.
+fn needs_boot(log: &str) -> bool {
+    const PATTERNS: &[&str] = &[
+        "System has not been booted with systemd",
+        "Failed to connect to bus",
+        "Failed to connect to system scope bus",
+        "Failed to get D-Bus connection",
+        "Could not connect to D-Bus",
+    ];
+    const FAILURES: &[&str] = &[
+        "dpkg: error processing package ",
+        "E: Sub-process /usr/bin/dpkg returned an error code",
+    ];
+
+    let mut seen = false;
+    for l in log.lines() {
+        if PATTERNS.iter().any(|p| l.starts_with(p))
+            // e.g. invoke-rc.d: initscript dbus, action "start" failed.
+            || (l.starts_with("invoke-rc.d: initscript") && l.trim_end().ends_with("failed."))
+        {
+            seen = true;
+        } else if seen && FAILURES.iter().any(|p| l.starts_with(p)) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Map an AOSC OS architecture to the name used by qemu-user
+fn qemu_arch(arch: &str) -> Option<&'static str> {
+    crate::arch::find(arch)?.qemu
+}
+
+/// Parse a binfmt_misc entry, returning the interpreter and whether it is
+/// preloaded by the kernel (the `F` flag), so that it needs not to exist in the target
+fn parse_binfmt(entry: &str) -> Option<(&str, bool)> {
+    let mut lines = entry.lines();
+    if lines.next()? != "enabled" {
+        return None;
+    }
+    let mut interpreter = None;
+    let mut fix_binary = false;
+    for line in lines {
+        if let Some(i) = line.strip_prefix("interpreter ") {
+            interpreter = Some(i.trim());
+        } else if let Some(flags) = line.strip_prefix("flags: ") {
+            fix_binary = flags.contains('F');
+        }
+    }
+
+    Some((interpreter?, fix_binary))
+}
+
+/// The qemu-user interpreter copied into the target, removed when dropped
+pub struct QemuInterpreter {
+    copied: Option<PathBuf>,
+}
+
+impl Drop for QemuInterpreter {
+    fn drop(&mut self) {
+        if let Some(ref path) = self.copied {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// Make sure the binaries of the given architecture can run in the target, using
+/// qemu-user through binfmt_misc if the host cannot run them natively
+pub fn prepare_foreign_arch(target: &Path, arch: &str) -> Result<Option<QemuInterpreter>> {
+    let Some((interpreter, fix_binary)) = foreign_interpreter(arch)? else {
+        return Ok(None);
+    };
+    info!("Using {} to run {} binaries.", interpreter, arch);
+    if fix_binary {
+        return Ok(Some(QemuInterpreter { copied: None }));
+    }
+    let dest = target.join(interpreter.trim_start_matches('/'));
+    if dest.exists() {
+        return Ok(Some(QemuInterpreter { copied: None }));
+    }
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(&interpreter, &dest)
+        .context(format!("when copying {} into the target", interpreter))?;
+
+    Ok(Some(QemuInterpreter { copied: Some(dest) }))
+}
+
+/// Check that binaries of the architecture can run on this host, natively or with qemu-user
+pub fn check_foreign_arch(arch: &str) -> Result<()> {
+    foreign_interpreter(arch)?;
+
+    Ok(())
+}
+
+/// Find the qemu-user interpreter registered with binfmt_misc for a foreign architecture,
+/// and whether the kernel keeps it open (the `F` flag)
+pub fn foreign_interpreter(arch: &str) -> Result<Option<(String, bool)>> {
+    let host = get_arch_name().unwrap_or_default();
+    if arch == host || arch == "all" {
+        return Ok(None);
+    }
+    let qemu = qemu_arch(arch)
+        .ok_or_else(|| anyhow!("Cannot run {} binaries on this {} host", arch, host))?;
+    let entry = std::fs::read_to_string(format!("/proc/sys/fs/binfmt_misc/qemu-{}", qemu))
+        .unwrap_or_default();
+    let Some((interpreter, fix_binary)) = parse_binfmt(&entry) else {
+        return Err(anyhow!(
+            "Cannot run {arch} binaries on this {host} host: binfmt_misc is not configured for qemu-{qemu}.\n\
+            Please install qemu-user-static (or equivalent) and register the binfmt handlers, e.g. with `systemctl restart systemd-binfmt`.\n\
+            Alternatively, use --foreign and run --second-stage on a {arch} machine."
+        ));
+    };
+
+    Ok(Some((interpreter.to_string(), fix_binary)))
+}
+
+/// How to run commands in the target during stage 2
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Backend {
+    /// Use systemd-nspawn if it can register a container, otherwise chroot or bwrap
+    Auto,
+    Nspawn,
+    Chroot,
+    Bwrap,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Backend::Auto => "auto",
+            Backend::Nspawn => "nspawn",
+            Backend::Chroot => "chroot",
+            Backend::Bwrap => "bwrap",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Check whether systemd-nspawn can boot a container and register it with
+/// systemd-machined, which is not the case inside most containers
+fn nspawn_usable() -> bool {
+    if which::which("systemd-nspawn").is_err() || which::which("machinectl").is_err() {
+        return false;
+    }
+    // systemd-detect-virt exits with 0 if we are inside a container
+    let in_container = Command::new("systemd-detect-virt")
+        .args(["-cq"])
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false);
+    if in_container || !Path::new("/run/systemd/system").is_dir() {
+        return false;
+    }
+
+    Command::new("machinectl")
+        .args(["list", "--no-legend"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .map(|s| s.success())
+        .unwrap_or(false)
+}
+
+/// Resolve the `auto` backend to a concrete one available on this host
+pub fn probe_backend(backend: Backend) -> Result<Backend> {
+    if backend != Backend::Auto {
+        return Ok(backend);
+    }
+    if nspawn_usable() {
+        return Ok(Backend::Nspawn);
     } else if which::which("chroot").is_ok() {
-        return chroot_do(target, args);
+        return Ok(Backend::Chroot);
+    } else if which::which("bwrap").is_ok() {
+        return Ok(Backend::Bwrap);
+    }
+
+    Err(anyhow!(
+        "Neither systemd-nspawn, chroot nor bwrap is available"
+    ))
+}
+
+/// Prepare a bwrap command, to be completed with the command to run in the target
+fn bwrap_command(target: &str, options: &GuestOptions) -> Command {
+    let mut command = Command::new("bwrap");
+    command
+        .args(["--bind", target, "/"])
+        .args([
+            "--proc",
+            "/proc",
+            "--dev",
+            "/dev",
+            "--ro-bind",
+            "/sys",
+            "/sys",
+        ])
+        .args(["--tmpfs", "/tmp", "--die-with-parent"]);
+    if Path::new("/etc/resolv.conf").exists() {
+        command.args(["--ro-bind", "/etc/resolv.conf", "/etc/resolv.conf"]);
+    }
+    command
+        .args([
+            "--setenv",
+            "PATH",
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+        ])
+        .args(["--setenv", "DEBIAN_FRONTEND", "noninteractive"])
+        .envs(options.env.iter().map(|e| (&e.key, &e.value)))
+        .arg("--");
+
+    command
+}
+
+fn bwrap_do(target: &str, args: &[&str], options: &GuestOptions) -> Result<()> {
+    let mut command = bwrap_command(target, options);
+    command.args(args);
+    let status = run_logged(&mut command, options, &|| ())?;
+
+    if !status.success() {
+        match options.on_failure {
+            OnFailure::Poweroff => (),
+            OnFailure::Shell => {
+                info!("Starting a shell in the target ...");
+                bwrap_command(target, options).arg("/bin/bash").status()?;
+            }
+            OnFailure::Keep => {
+                info!("There is nothing to keep running with the bwrap backend.");
+            }
+        }
+        return Err(anyhow!("bwrap exited with status {}", status));
     }
 
-    Err(anyhow!("Neither chroot nor systemd-nspawn is available"))
+    Ok(())
+}
+
+/// Run a command, copying its output to the log file as well as the terminal.
+/// If the command does not finish in time, `on_timeout` is called before its
+/// process group is killed
+fn run_logged(
+    command: &mut Command,
+    options: &GuestOptions,
+    on_timeout: &dyn Fn(),
+) -> Result<ExitStatus> {
+    // the log is appended to, e.g. by earlier runs before --resume, only what this
+    // command writes to it is summarized
+    let log_start = std::fs::metadata(&options.log).map_or(0, |m| m.len());
+    let log = open_log(&options.log)?;
+    let mut child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .process_group(0)
+        .spawn()?;
+    let pgid = Pid::from_raw(child.id() as i32);
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.process_groups.push(pgid);
+    }
+    let status = wait_logged(&mut child, log, log_start, options, on_timeout);
+    if let Ok(mut cleanup) = CLEANUP.lock() {
+        cleanup.process_groups.retain(|p| *p != pgid);
+    }
+
+    status
+}
+
+/// Copy the output of the child to the log while waiting for it
+fn wait_logged(
+    child: &mut Child,
+    log: Arc<Mutex<File>>,
+    log_start: u64,
+    options: &GuestOptions,
+    on_timeout: &dyn Fn(),
+) -> Result<ExitStatus> {
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    let tees = [
+        tee(stdout, std::io::stdout(), log.clone()),
+        tee(stderr, std::io::stderr(), log),
+    ];
+    let Some(status) = wait_timeout(child, options.timeout)? else {
+        print_failure_summary(&options.log, log_start);
+        let timeout = options.timeout.unwrap_or_default().as_secs();
+        warn!(
+            "Stage 2 did not finish in {} seconds, terminating ...",
+            timeout
+        );
+        on_timeout();
+        killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL).ok();
+        child.wait()?;
+        // leftover processes may still hold the pipes, so do not wait for the tees
+        return Err(anyhow!("Stage 2 timed out after {} seconds", timeout));
+    };
+    for t in tees {
+        t.join().ok();
+    }
+    if !status.success() {
+        print_failure_summary(&options.log, log_start);
+    }
+
+    Ok(status)
+}
+
+/// Send SIGTERM to the process groups, then SIGKILL to those still there after a while,
+/// so that nothing writes to the target once it is unmounted or removed
+fn terminate_process_groups(groups: &[Pid]) {
+    for pgid in groups {
+        killpg(*pgid, Signal::SIGTERM).ok();
+    }
+    let deadline = Instant::now() + TERMINATE_TIMEOUT;
+    // signal 0 only checks whether the group still has processes
+    while groups.iter().any(|pgid| killpg(*pgid, None).is_ok()) && Instant::now() < deadline {
+        sleep(Duration::from_millis(100));
+    }
+    for pgid in groups {
+        killpg(*pgid, Signal::SIGKILL).ok();
+    }
+}
+
+/// Open the log file for appending, to be shared between threads
+fn open_log(log: &Path) -> Result<Arc<Mutex<File>>> {
+    if let Some(parent) = log.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log)
+        .context(format!("when opening the log file {}", log.display()))?;
+
+    Ok(Arc::new(Mutex::new(file)))
+}
+
+/// Wait for the child to exit, returning `None` if it does not in time
+fn wait_timeout(child: &mut Child, timeout: Option<Duration>) -> Result<Option<ExitStatus>> {
+    let Some(timeout) = timeout else {
+        return Ok(Some(child.wait()?));
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        sleep(Duration::from_millis(200));
+    }
+}
+
+/// Copy the lines read from `input` to both `output` and `log` in a separate thread
+fn tee<R, W>(input: R, mut output: W, log: Arc<Mutex<File>>) -> JoinHandle<()>
+where
+    R: Read + Send + 'static,
+    W: Write + Send + 'static,
+{
+    // the output of stage 2 is progress, which is not shown with --quiet
+    let show = log_enabled!(Level::Info);
+    std::thread::spawn(move || {
+        let mut reader = BufReader::new(input);
+        let mut line = Vec::new();
+        while let Ok(n) = reader.read_until(b'\n', &mut line) {
+            if n == 0 {
+                break;
+            }
+            if let Some((done, total)) = parse_progress(&line) {
+                events::emit(&Event::Progress {
+                    phase: "install",
+                    current: done,
+                    total,
+                    package: None,
+                    bytes: None,
+                });
+            }
+            if show {
+                match parse_progress(&line) {
+                    Some((done, total)) => writeln!(output, "{}", progress_bar(done, total)).ok(),
+                    None => output.write_all(&line).ok(),
+                };
+                output.flush().ok();
+            }
+            if let Ok(mut log) = log.lock() {
+                log.write_all(&line).ok();
+            }
+            line.clear();
+        }
+    })
+}
+
+/// Parse a progress marker printed by the install script, e.g.
+/// `AOSCBOOTSTRAP-PROGRESS 300/1400`
+fn parse_progress(line: &[u8]) -> Option<(usize, usize)> {
+    let line = std::str::from_utf8(line).ok()?;
+    let (done, total) = line
+        .trim_end()
+        .strip_prefix("AOSCBOOTSTRAP-PROGRESS ")?
+        .split_once('/')?;
+
+    Some((done.parse().ok()?, total.parse().ok()?))
+}
+
+fn progress_bar(done: usize, total: usize) -> String {
+    const WIDTH: usize = 40;
+    let filled = (done * WIDTH)
+        .checked_div(total)
+        .unwrap_or(WIDTH)
+        .min(WIDTH);
+    format!(
+        "[{}{}] {}/{} packages unpacked",
+        "#".repeat(filled),
+        ".".repeat(WIDTH - filled),
+        done,
+        total
+    )
+}
+
+/// Pick the lines worth showing from a failed run: errors from dpkg, and the
+/// last `tail` lines of the log
+fn failure_summary(log: &str, tail: usize) -> (Vec<&str>, Vec<&str>) {
+    let lines = log.lines().collect::<Vec<_>>();
+    let errors = lines
+        .iter()
+        .filter(|l| {
+            l.starts_with("dpkg: error processing")
+                || (l.starts_with("Batch ") && l.contains(") failed: "))
+        })
+        .copied()
+        .collect();
+    let tail = lines[lines.len().saturating_sub(tail)..].to_vec();
+
+    (errors, tail)
+}
+
+/// Summarize the failure from what was written to the log after `offset`
+fn print_failure_summary(log: &Path, offset: u64) {
+    let Some(content) = read_log_from(log, offset) else {
+        return;
+    };
+    let (errors, tail) = failure_summary(&content, 50);
+    warn!("Last lines of the stage 2 log:");
+    for line in tail {
+        warn!("  {}", line);
+    }
+    if !errors.is_empty() {
+        warn!("Packages that failed to install:");
+        for line in errors {
+            warn!("  {}", line);
+        }
+    }
+}
+
+/// An environment variable passed into the guest
+pub struct GuestEnv {
+    pub key: String,
+    pub value: String,
+    /// Whether the value must not be printed
+    pub secret: bool,
+}
+
+impl Display for GuestEnv {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.secret {
+            write!(f, "{}=<hidden>", self.key)
+        } else {
+            write!(f, "{}={}", self.key, self.value)
+        }
+    }
+}
+
+/// Proxy variables forwarded from the host with `--propagate-proxy`
+pub const PROXY_VARS: &[&str] = &[
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+];
+
+/// Parse an environment variable given as `KEY=VALUE`
+pub fn parse_guest_env(spec: &str, secret: bool) -> Result<GuestEnv> {
+    let Some((key, value)) = spec.split_once('=') else {
+        return Err(anyhow!(
+            "Invalid guest environment variable '{}', expected KEY=VALUE",
+            if secret { "<hidden>" } else { spec }
+        ));
+    };
+    if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(anyhow!("Invalid guest environment variable name '{}'", key));
+    }
+
+    Ok(GuestEnv {
+        key: key.to_string(),
+        value: value.to_string(),
+        secret,
+    })
+}
+
+/// How to run commands in the target
+pub struct GuestOptions {
+    pub backend: Backend,
+    /// Environment variables passed to the commands
+    pub env: Vec<GuestEnv>,
+    /// Where the output is logged
+    pub log: PathBuf,
+    /// Kill the commands if they do not finish in time
+    pub timeout: Option<Duration>,
+    /// How long to wait for a container to boot
+    pub container_wait: Duration,
+    /// Show the output of the booting container on the terminal
+    pub show_boot_log: bool,
+    /// Boot systemd in the systemd-nspawn container, instead of running the commands directly
+    pub boot: bool,
+    /// What to do with the target when the commands fail
+    pub on_failure: OnFailure,
+}
+
+/// What to do when the commands fail in the target
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OnFailure {
+    /// Stop the container and clean up
+    Poweroff,
+    /// Start an interactive shell in the target before cleaning up
+    Shell,
+    /// Leave the container running and print how to attach to it
+    Keep,
+}
+
+pub fn run_in_guest(target: &str, args: &[&str], options: &GuestOptions) -> Result<()> {
+    cancel::check()?;
+    let result = match probe_backend(options.backend)? {
+        Backend::Nspawn if options.boot => nspawn_do(target, args, options),
+        Backend::Nspawn => nspawn_run(target, args, options),
+        Backend::Chroot => chroot_do(target, args, options),
+        Backend::Bwrap => bwrap_do(target, args, options),
+        Backend::Auto => unreachable!(),
+    };
+    if let Err(e) = result {
+        return Err(e.context(format!("see {} for the full log", options.log.display())));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_binfmt() {
+    let entry =
+        "enabled\ninterpreter /usr/bin/qemu-aarch64-static\nflags: OCF\noffset 0\nmagic 7f454c46\n";
+    assert_eq!(
+        parse_binfmt(entry),
+        Some(("/usr/bin/qemu-aarch64-static", true))
+    );
+    let entry = "enabled\ninterpreter /usr/bin/qemu-riscv64\nflags: \noffset 0\n";
+    assert_eq!(parse_binfmt(entry), Some(("/usr/bin/qemu-riscv64", false)));
+    assert_eq!(
+        parse_binfmt("disabled\ninterpreter /usr/bin/qemu-riscv64\n"),
+        None
+    );
+    assert_eq!(parse_binfmt(""), None);
+    assert_eq!(qemu_arch("arm64"), Some("aarch64"));
+    assert_eq!(qemu_arch("unknown"), None);
+}
+
+#[test]
+fn test_needs_boot() {
+    assert!(needs_boot(
+        "Setting up dbus (1.14.10) ...\nSystem has not been booted with systemd as init system (PID 1). Can't operate.\ndpkg: error processing package dbus (--configure):\n"
+    ));
+    assert!(!needs_boot(
+        "Setting up bash (5.2.21) ...\ndpkg: error processing package bash (--configure):\n"
+    ));
+    assert!(needs_boot(
+        "Setting up dbus (1.14.10) ...\ninvoke-rc.d: initscript dbus, action \"start\" failed.\nE: Sub-process /usr/bin/dpkg returned an error code (1)\n"
+    ));
+    assert!(!needs_boot(
+        "Setting up dbus (1.14.10) ...\nRunning in chroot, ignoring request: start\ndpkg: error processing package dbus (--configure):\n"
+    ));
+    // the messages only count at the start of a line, and when a package failed
+    assert!(!needs_boot(
+        "Setting up foo (1.0) ...\nfoo: ignoring \"Failed to connect to bus\" from systemctl\ndpkg: error processing package foo (--configure):\n"
+    ));
+    assert!(!needs_boot(
+        "Setting up dbus (1.14.10) ...\nFailed to connect to bus: No such file or directory\nSetting up bash (5.2.21) ...\n"
+    ));
+
+    // only the part of the log written by this run is read
+    let dir = tempfile::tempdir().unwrap();
+    let log = dir.path().join("stage2.log");
+    std::fs::write(
+        &log,
+        "Failed to connect to bus\nSetting up bash (5.2.21) ...\ndpkg: error processing package bash (--configure):\n",
+    )
+    .unwrap();
+    let log_start = "Failed to connect to bus\n".len() as u64;
+    assert!(!needs_boot(&read_log_from(&log, log_start).unwrap()));
+}
+
+#[test]
+fn test_failure_summary() {
+    let log = "Unpacking bash ...\ndpkg: error processing package foo (--configure):\n installed foo package post-installation script subprocess returned error exit status 1\ndpkg: error processing package bar (--configure):\nErrors were encountered while processing:\n foo\n bar\n";
+    let (errors, tail) = failure_summary(log, 3);
+    assert_eq!(
+        errors,
+        vec![
+            "dpkg: error processing package foo (--configure):",
+            "dpkg: error processing package bar (--configure):"
+        ]
+    );
+    assert_eq!(
+        tail,
+        vec!["Errors were encountered while processing:", " foo", " bar"]
+    );
+    let (errors, tail) = failure_summary("", 50);
+    assert!(errors.is_empty());
+    assert!(tail.is_empty());
+}
+
+#[test]
+fn test_parse_guest_env() {
+    let env = parse_guest_env("ENABLE_DEBUG_REPO=1", false).unwrap();
+    assert_eq!(
+        (env.key.as_str(), env.value.as_str()),
+        ("ENABLE_DEBUG_REPO", "1")
+    );
+    assert_eq!(env.to_string(), "ENABLE_DEBUG_REPO=1");
+    let env = parse_guest_env("TOKEN=a=b", true).unwrap();
+    assert_eq!(env.value, "a=b");
+    assert_eq!(env.to_string(), "TOKEN=<hidden>");
+    let e = parse_guest_env("TOKEN", true).unwrap_err().to_string();
+    assert!(e.contains("'<hidden>'"));
+    assert!(parse_guest_env("=1", false).is_err());
+    assert!(parse_guest_env("A B=1", false).is_err());
+}
+
+#[test]
+fn test_wait_timeout() -> Result<()> {
+    let mut child = Command::new("sleep").arg("10").spawn()?;
+    assert!(wait_timeout(&mut child, Some(Duration::from_millis(300)))?.is_none());
+    child.kill()?;
+    child.wait()?;
+    let mut child = Command::new("true").spawn()?;
+    let status = wait_timeout(&mut child, Some(Duration::from_secs(10)))?;
+    assert!(status.unwrap().success());
+
+    Ok(())
+}
+
+#[test]
+fn test_parse_progress() {
+    assert_eq!(
+        parse_progress(b"AOSCBOOTSTRAP-PROGRESS 300/1400\n"),
+        Some((300, 1400))
+    );
+    assert_eq!(parse_progress(b"AOSCBOOTSTRAP-PROGRESS 300\n"), None);
+    assert_eq!(parse_progress(b"Unpacking bash ...\n"), None);
+    assert_eq!(
+        progress_bar(10, 40),
+        format!(
+            "[{}{}] 10/40 packages unpacked",
+            "#".repeat(10),
+            ".".repeat(30)
+        )
+    );
+    assert!(progress_bar(0, 0).starts_with(&format!("[{}]", "#".repeat(40))));
+}
+
+#[test]
+fn test_remove_target() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let target = dir.path().canonicalize()?.join("rootfs");
+    std::fs::create_dir_all(target.join("dev"))?;
+    let mountinfo = format!(
+        "22 1 0:21 / / rw,relatime - ext4 /dev/sda1 rw\n98 22 0:5 / {}/dev rw,nosuid - devtmpfs devtmpfs rw\n",
+        target.display()
+    );
+    // a mount left behind keeps the target, marked as incomplete
+    remove_target(&target, Some(&mountinfo));
+    assert!(target.join("dev").is_dir());
+    assert!(install::read_incomplete_marker(&target).is_some());
+    remove_target(&target, None);
+    assert!(target.exists());
+    remove_target(
+        &target,
+        Some("22 1 0:21 / / rw,relatime - ext4 /dev/sda1 rw\n"),
+    );
+    assert!(!target.exists());
+
+    Ok(())
 }
@@ -0,0 +1,612 @@
+use std::{
+    io::Write,
+    path::{Component, Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use bytesize::ByteSize;
+use log::{info, warn};
+use nix::{
+    mount::{mount, umount2, MntFlags, MsFlags},
+    unistd::Uid,
+};
+use serde::Deserialize;
+
+use crate::{
+    cancel,
+    fs::{dir_size, TARGET_LOCK},
+    guest,
+};
+
+const MIB: u64 = 1 << 20;
+const SECTOR_SIZE: u64 = 512;
+/// Offset of the first partition without `start`, the usual alignment
+const DEFAULT_START: u64 = MIB;
+/// Room left at the end of the image, for the backup GPT
+const END_RESERVED: u64 = MIB;
+/// Free space given to a partition sized after the files it holds
+const SPARE_SPACE: u64 = 256 * MIB;
+
+/// How the partitions of a disk image are laid out, from `--image-layout`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Layout {
+    /// Partition table, `gpt` or `dos`
+    #[serde(default)]
+    pub table: Table,
+    /// Size of the whole image, by default what the partitions need
+    pub size: Option<String>,
+    /// Offset of the first partition, leaving room e.g. for U-Boot on some SBCs
+    pub start: Option<String>,
+    #[serde(rename = "partition")]
+    pub partitions: Vec<Partition>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Table {
+    #[default]
+    Gpt,
+    Dos,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PartitionType {
+    #[default]
+    Linux,
+    /// EFI system partition
+    Esp,
+    /// BIOS boot partition, where GRUB embeds itself on GPT
+    Bios,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FsType {
+    Vfat,
+    Ext4,
+    Xfs,
+    Btrfs,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub label: Option<String>,
+    #[serde(rename = "type", default)]
+    pub kind: PartitionType,
+    /// Size of the partition; only the last one may leave it out, to take the rest
+    pub size: Option<String>,
+    /// File system to make, none for a raw partition
+    pub fs: Option<FsType>,
+    /// Where the partition is mounted in the target, `/` for the root
+    pub mount: Option<String>,
+}
+
+impl FsType {
+    fn name(&self) -> &'static str {
+        match self {
+            FsType::Vfat => "vfat",
+            FsType::Ext4 => "ext4",
+            FsType::Xfs => "xfs",
+            FsType::Btrfs => "btrfs",
+        }
+    }
+
+    fn mkfs(&self) -> &'static str {
+        match self {
+            FsType::Vfat => "mkfs.vfat",
+            FsType::Ext4 => "mkfs.ext4",
+            FsType::Xfs => "mkfs.xfs",
+            FsType::Btrfs => "mkfs.btrfs",
+        }
+    }
+
+    fn max_label(&self) -> usize {
+        match self {
+            FsType::Vfat => 11,
+            FsType::Ext4 => 16,
+            FsType::Xfs => 12,
+            FsType::Btrfs => 255,
+        }
+    }
+}
+
+/// Parse a size such as `512MiB`, rounded up to MiB so that the partitions stay aligned
+fn parse_size(value: &str) -> Result<u64> {
+    match value.parse::<ByteSize>() {
+        Ok(size) if size.as_u64() > 0 => Ok(size.as_u64().div_ceil(MIB) * MIB),
+        _ => Err(anyhow!("Invalid size '{}' in the image layout", value)),
+    }
+}
+
+impl Default for Layout {
+    /// A GPT with a single ext4 root partition
+    fn default() -> Self {
+        Layout {
+            table: Table::Gpt,
+            size: None,
+            start: None,
+            partitions: vec![Partition {
+                label: Some("AOSC OS".to_string()),
+                kind: PartitionType::Linux,
+                size: None,
+                fs: Some(FsType::Ext4),
+                mount: Some("/".to_string()),
+            }],
+        }
+    }
+}
+
+impl Layout {
+    /// Read and check the layout, or the default one without a file
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let layout = match path {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .context(format!("when reading the image layout {}", path.display()))?;
+                toml::from_str(&content).map_err(|e| anyhow!("{}: {}", path.display(), e))?
+            }
+            None => Layout::default(),
+        };
+        layout.check()?;
+
+        Ok(layout)
+    }
+
+    fn check(&self) -> Result<()> {
+        if self.partitions.is_empty() {
+            return Err(anyhow!("The image layout has no partition."));
+        }
+        let mut mounts = Vec::new();
+        for (i, p) in self.partitions.iter().enumerate() {
+            let name = p.label.clone().unwrap_or_else(|| format!("#{}", i + 1));
+            if p.size.is_none() && i + 1 != self.partitions.len() {
+                return Err(anyhow!(
+                    "Partition {} has no size, only the last one may take the rest of the image.",
+                    name
+                ));
+            }
+            if let Some(ref size) = p.size {
+                parse_size(size)?;
+            }
+            match (p.kind, p.fs) {
+                (PartitionType::Esp, Some(FsType::Vfat)) | (PartitionType::Linux, _) => (),
+                (PartitionType::Esp, _) => {
+                    return Err(anyhow!("The EFI system partition {} must be vfat.", name))
+                }
+                (PartitionType::Bios, None) if self.table == Table::Gpt => (),
+                (PartitionType::Bios, _) => {
+                    return Err(anyhow!(
+                        "The BIOS boot partition {} must be on a GPT, without a file system.",
+                        name
+                    ))
+                }
+            }
+            if let (Some(label), Some(fs)) = (&p.label, p.fs) {
+                if label.len() > fs.max_label() {
+                    return Err(anyhow!(
+                        "The label {} is too long for {} ({} bytes at most).",
+                        label,
+                        fs.name(),
+                        fs.max_label()
+                    ));
+                }
+            }
+            if let Some(ref mount) = p.mount {
+                if p.fs.is_none() {
+                    return Err(anyhow!(
+                        "Partition {} is mounted on {} but has no file system.",
+                        name,
+                        mount
+                    ));
+                }
+                let path = Path::new(mount);
+                if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+                    return Err(anyhow!(
+                        "Invalid mount point {} of partition {}.",
+                        mount,
+                        name
+                    ));
+                }
+                if mounts.contains(&path) {
+                    return Err(anyhow!("{} is mounted twice in the image layout.", mount));
+                }
+                mounts.push(path);
+            }
+        }
+        if !mounts.contains(&Path::new("/")) {
+            return Err(anyhow!("The image layout has no partition mounted on /."));
+        }
+        if let Some(ref start) = self.start {
+            parse_size(start)?;
+        }
+        if let Some(ref size) = self.size {
+            parse_size(size)?;
+        }
+
+        Ok(())
+    }
+
+    /// The tools needed to make the image
+    pub fn tools(&self) -> Vec<&'static str> {
+        let mut tools = vec!["sfdisk", "losetup", "cp"];
+        for fs in self.partitions.iter().filter_map(|p| p.fs) {
+            if !tools.contains(&fs.mkfs()) {
+                tools.push(fs.mkfs());
+            }
+        }
+
+        tools
+    }
+
+    /// The sizes of the partitions and of the image, the last partition sized after
+    /// `content` with some spare space if it has no size
+    fn sizes(&self, content: u64) -> Result<(Vec<u64>, u64)> {
+        let start = self
+            .start
+            .as_deref()
+            .map_or(Ok(DEFAULT_START), parse_size)?;
+        let mut sizes = self
+            .partitions
+            .iter()
+            .map(|p| p.size.as_deref().map_or(Ok(0), parse_size))
+            .collect::<Result<Vec<_>>>()?;
+        let fixed = start + sizes.iter().sum::<u64>() + END_RESERVED;
+        let last = sizes.len() - 1;
+        let total = match self.size.as_deref().map(parse_size).transpose()? {
+            Some(total) if total <= fixed && sizes[last] == 0 => {
+                return Err(anyhow!(
+                    "The image of {} leaves no room for the last partition.",
+                    ByteSize::b(total)
+                ))
+            }
+            Some(total) if total < fixed => {
+                return Err(anyhow!(
+                    "The partitions need {}, more than the image of {}.",
+                    ByteSize::b(fixed),
+                    ByteSize::b(total)
+                ))
+            }
+            Some(total) => total,
+            None if sizes[last] == 0 => {
+                fixed + (content + content / 4 + SPARE_SPACE).div_ceil(MIB) * MIB
+            }
+            None => fixed,
+        };
+        if sizes[last] == 0 {
+            sizes[last] = total - fixed;
+        }
+
+        Ok((sizes, total))
+    }
+
+    /// The sfdisk script making the partition table
+    fn sfdisk_script(&self, sizes: &[u64]) -> Result<String> {
+        let mut offset = self
+            .start
+            .as_deref()
+            .map_or(Ok(DEFAULT_START), parse_size)?;
+        let mut script = match self.table {
+            Table::Gpt => "label: gpt\n",
+            Table::Dos => "label: dos\n",
+        }
+        .to_string();
+        for (p, size) in self.partitions.iter().zip(sizes) {
+            let kind = match (self.table, p.kind) {
+                (Table::Gpt, PartitionType::Linux) => "0FC63DAF-8483-4772-8E79-3D69D8477DE4",
+                (Table::Gpt, PartitionType::Esp) => "C12A7328-F81F-11D2-BA4B-00A0C93EC93B",
+                (Table::Gpt, PartitionType::Bios) => "21686148-6449-6E6F-744E-656564454649",
+                (Table::Dos, PartitionType::Esp) => "ef",
+                (Table::Dos, _) => "83",
+            };
+            script.push_str(&format!(
+                "start={}, size={}, type={}",
+                offset / SECTOR_SIZE,
+                size / SECTOR_SIZE,
+                kind
+            ));
+            if let (Table::Gpt, Some(label)) = (self.table, &p.label) {
+                script.push_str(&format!(", name=\"{}\"", label.replace('"', "")));
+            }
+            script.push('\n');
+            offset += size;
+        }
+
+        Ok(script)
+    }
+}
+
+/// Run a tool, returning its output and failing with its exit status
+fn run(command: &mut Command) -> Result<String> {
+    let name = command.get_program().to_string_lossy().to_string();
+    let output = command
+        .stderr(Stdio::inherit())
+        .output()
+        .context(format!("when running {}", name))?;
+    if !output.status.success() {
+        return Err(anyhow!("{} failed: {}", name, output.status));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// A loop device backed by the image, detached when dropped
+struct LoopDevice {
+    device: PathBuf,
+}
+
+impl LoopDevice {
+    fn attach(image: &Path) -> Result<LoopDevice> {
+        let device = PathBuf::from(run(Command::new("losetup")
+            .arg("--find")
+            .arg("--show")
+            .arg("--partscan")
+            .arg(image))?);
+        guest::register_loop_device(&device);
+
+        Ok(LoopDevice { device })
+    }
+
+    /// The device of the partition, once the kernel has created it
+    fn partition(&self, number: usize) -> Result<PathBuf> {
+        let mut partition = self.device.clone().into_os_string();
+        partition.push(format!("p{}", number));
+        let partition = PathBuf::from(partition);
+        for _ in 0..50 {
+            if partition.exists() {
+                return Ok(partition);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        Err(anyhow!("{} did not show up.", partition.display()))
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        guest::unregister_loop_device(&self.device);
+        if let Err(e) = run(Command::new("losetup").arg("--detach").arg(&self.device)) {
+            warn!("Failed to detach {}: {}", self.device.display(), e);
+        }
+    }
+}
+
+/// The partitions mounted to fill the image, unmounted when dropped
+struct ImageMounts {
+    mounted: Vec<PathBuf>,
+}
+
+impl ImageMounts {
+    fn mount(&mut self, device: &Path, dest: &Path, fs: FsType) -> Result<()> {
+        std::fs::create_dir_all(dest)?;
+        // vfat has no owners nor modes, let `cp -a` set them without failing
+        let data = (fs == FsType::Vfat).then_some("quiet");
+        mount(Some(device), dest, Some(fs.name()), MsFlags::empty(), data)
+            .context(format!("when mounting {}", device.display()))?;
+        guest::register_mount(dest);
+        self.mounted.push(dest.to_path_buf());
+
+        Ok(())
+    }
+}
+
+impl Drop for ImageMounts {
+    fn drop(&mut self) {
+        for dest in self.mounted.drain(..).rev() {
+            guest::unregister_mount(&dest);
+            if let Err(e) = umount2(&dest, MntFlags::empty()) {
+                warn!("Failed to unmount {}: {}, detaching it", dest.display(), e);
+                umount2(&dest, MntFlags::MNT_DETACH).ok();
+            }
+        }
+        nix::unistd::sync();
+    }
+}
+
+/// The image being made, as seen by the bootloader hook
+pub struct MountedImage<'a> {
+    pub image: &'a Path,
+    /// Where the partitions are mounted, as in the target
+    pub root: &'a Path,
+    pub device: &'a Path,
+    /// The device of each partition, with its mount point
+    pub partitions: Vec<(PathBuf, Option<&'a str>)>,
+}
+
+impl MountedImage<'_> {
+    /// The environment of the bootloader hook
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![
+            (
+                "AOSCBOOTSTRAP_IMAGE".to_string(),
+                self.image.display().to_string(),
+            ),
+            (
+                "AOSCBOOTSTRAP_IMAGE_ROOT".to_string(),
+                self.root.display().to_string(),
+            ),
+            (
+                "AOSCBOOTSTRAP_IMAGE_DEVICE".to_string(),
+                self.device.display().to_string(),
+            ),
+        ];
+        for (i, (device, mount)) in self.partitions.iter().enumerate() {
+            let var = format!("AOSCBOOTSTRAP_PARTITION_{}", i + 1);
+            if *mount == Some("/") {
+                env.push((
+                    "AOSCBOOTSTRAP_ROOT_PARTITION".to_string(),
+                    device.display().to_string(),
+                ));
+            }
+            if let Some(mount) = mount {
+                env.push((format!("{}_MOUNT", var), mount.to_string()));
+            }
+            env.push((var, device.display().to_string()));
+        }
+
+        env
+    }
+}
+
+/// Make a raw disk image of the root with the partitions of the layout, calling the
+/// bootloader hook with everything mounted. The loop device and the mounts are released
+/// whatever happens, and the image is removed if it cannot be made or when interrupted.
+pub fn build_disk_image<F>(root: &Path, target: &Path, layout: &Layout, bootloader: F) -> Result<()>
+where
+    F: FnOnce(&MountedImage) -> Result<()>,
+{
+    if !Uid::effective().is_root() {
+        return Err(anyhow!(
+            "Making a disk image needs root, for the loop device."
+        ));
+    }
+    guest::register_export(target);
+    let result = fill_disk_image(root, target, layout, bootloader);
+    if result.is_err() {
+        std::fs::remove_file(target).ok();
+    }
+    guest::unregister_export(target);
+
+    result
+}
+
+fn fill_disk_image<F>(root: &Path, target: &Path, layout: &Layout, bootloader: F) -> Result<()>
+where
+    F: FnOnce(&MountedImage) -> Result<()>,
+{
+    let (sizes, total) = layout.sizes(dir_size(root)?)?;
+    info!("Partitioning a disk image of {} ...", ByteSize::b(total));
+    // sparse, only what is written takes space
+    std::fs::File::create(target)?.set_len(total)?;
+    let mut sfdisk = Command::new("sfdisk")
+        .arg("--quiet")
+        .arg(target)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .context("when running sfdisk")?;
+    sfdisk
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(layout.sfdisk_script(&sizes)?.as_bytes())?;
+    let status = sfdisk.wait()?;
+    if !status.success() {
+        return Err(anyhow!("sfdisk failed: {}", status));
+    }
+
+    let device = LoopDevice::attach(target)?;
+    let mut partitions = Vec::new();
+    for (i, p) in layout.partitions.iter().enumerate() {
+        cancel::check()?;
+        let partition = device.partition(i + 1)?;
+        if let Some(fs) = p.fs {
+            let mut mkfs = Command::new(fs.mkfs());
+            match fs {
+                FsType::Vfat => mkfs.arg("-F").arg("32"),
+                FsType::Ext4 => mkfs.arg("-q").arg("-F"),
+                FsType::Xfs | FsType::Btrfs => mkfs.arg("-q").arg("-f"),
+            };
+            if let Some(ref label) = p.label {
+                mkfs.arg(if fs == FsType::Vfat { "-n" } else { "-L" })
+                    .arg(label);
+            }
+            run(mkfs.arg(&partition).stdout(Stdio::null()))?;
+        }
+        partitions.push((partition, p.mount.as_deref()));
+    }
+
+    let mnt = tempfile::tempdir()?;
+    let mut mounts = ImageMounts {
+        mounted: Vec::new(),
+    };
+    // parents first, as /boot/efi goes on /boot
+    let mut order = layout
+        .partitions
+        .iter()
+        .zip(&partitions)
+        .filter_map(|(p, (device, mount))| Some((mount.as_ref()?, device, p.fs?)))
+        .collect::<Vec<_>>();
+    order.sort_by_key(|(mount, _, _)| Path::new(mount).components().count());
+    for (mount, device, fs) in order {
+        let dest = mnt.path().join(mount.trim_start_matches('/'));
+        mounts.mount(device, &dest, fs)?;
+    }
+    cancel::check()?;
+    info!("Copying the target into the disk image ...");
+    let mut source = root.as_os_str().to_owned();
+    source.push("/.");
+    run(Command::new("cp").arg("-a").arg(source).arg(mnt.path()))?;
+    std::fs::remove_file(mnt.path().join(TARGET_LOCK)).ok();
+
+    cancel::check()?;
+    bootloader(&MountedImage {
+        image: target,
+        root: mnt.path(),
+        device: &device.device,
+        partitions,
+    })?;
+    drop(mounts);
+
+    Ok(())
+}
+
+#[test]
+fn test_image_layout() -> Result<()> {
+    let layout: Layout = toml::from_str(include_str!("../config/disk-image-efi.toml"))?;
+    layout.check()?;
+    assert_eq!(
+        layout.tools(),
+        ["sfdisk", "losetup", "cp", "mkfs.vfat", "mkfs.ext4"]
+    );
+    let (sizes, total) = layout.sizes(3 << 30)?;
+    assert_eq!(sizes[0], 512 * MIB);
+    assert_eq!(
+        total,
+        DEFAULT_START + sizes.iter().sum::<u64>() + END_RESERVED
+    );
+    assert!(sizes[1] > 3 << 30);
+    assert_eq!(
+        layout.sfdisk_script(&sizes)?.lines().nth(1),
+        Some("start=2048, size=1048576, type=C12A7328-F81F-11D2-BA4B-00A0C93EC93B, name=\"ESP\"")
+    );
+
+    let sized = Layout {
+        size: Some("4GiB".to_string()),
+        start: Some("16MiB".to_string()),
+        ..Layout::default()
+    };
+    sized.check()?;
+    assert_eq!(sized.sizes(0)?, (vec![(4 << 30) - 17 * MIB], 4 << 30));
+    assert!(sized
+        .sfdisk_script(&[MIB])?
+        .contains("start=32768, size=2048"));
+    let too_small = Layout {
+        size: Some("1MiB".to_string()),
+        ..Layout::default()
+    };
+    assert!(too_small.sizes(0).is_err());
+
+    for invalid in [
+        // no root
+        "[[partition]]\nfs = \"ext4\"\nmount = \"/home\"\n",
+        // a partition without a size before the last one
+        "[[partition]]\nfs = \"ext4\"\nmount = \"/\"\n[[partition]]\nsize = \"1GiB\"\n",
+        "[[partition]]\ntype = \"esp\"\nfs = \"ext4\"\nmount = \"/\"\n",
+        "table = \"dos\"\n[[partition]]\ntype = \"bios\"\nsize = \"1MiB\"\n[[partition]]\nfs = \"ext4\"\nmount = \"/\"\n",
+        "[[partition]]\nlabel = \"TOO LONG LABEL\"\nfs = \"vfat\"\nmount = \"/\"\n",
+        "[[partition]]\nfs = \"ext4\"\nmount = \"/../etc\"\n",
+        "[[partition]]\nsize = \"lots\"\nfs = \"ext4\"\nmount = \"/\"\n",
+    ] {
+        let layout: Layout = toml::from_str(invalid)?;
+        assert!(layout.check().is_err(), "{}", invalid);
+    }
+    assert!(toml::from_str::<Layout>("[[partition]]\nfs = \"ntfs\"\n").is_err());
+
+    Ok(())
+}
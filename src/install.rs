@@ -1,23 +1,40 @@
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
     fs::File,
-    io::{BufWriter, Read, Write},
-    path::Path,
+    io::{Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
 };
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use ar::Archive as ArArchive;
-use serde::Deserialize;
+use bzip2::read::BzDecoder;
+use flate2::read::GzDecoder;
+use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
 use tar::Archive as TarArchive;
-use tempfile::NamedTempFile;
+use tempfile::{NamedTempFile, TempDir};
+use url::Url;
 use xz2::read::XzDecoder;
 use zstd::Decoder;
 
-use crate::solv::PackageMeta;
+use crate::{
+    cli::{parse_list_line, ListLine},
+    fs::{atomic_write, resolve_in_target, sha256sum},
+    network::{fetch_bytes, fetch_text, make_new_client},
+    solv::{package_name, PackageMeta},
+};
 
 const BOOTSTRAP_PACK: &[u8] = include_bytes!("../assets/etc-bootstrap.tar.xz");
 const INSTALL_SCRIPT_TPL: &str = include_str!("../assets/bootstrap.sh");
 const CLEANUP_SCRIPT: &[u8] = include_bytes!("../assets/cleanup.sh");
+const VERIFY_SCRIPT: &[u8] = include_bytes!("../assets/verify.sh");
+/// Written by the verify script, relative to the target
+pub const VERIFY_LOG: &str = "var/log/aoscbootstrap-verify.log";
+
+/// Packages needed to run stage 2, unless overridden by `required-packages`
+pub const REQUIRED_PACKAGES: &[&str] = &["apt", "bash", "coreutils", "dpkg"];
 
 #[derive(Deserialize)]
 pub struct Config {
@@ -25,117 +42,2514 @@ pub struct Config {
     pub stub_packages: Vec<String>,
     #[serde(rename = "base-packages")]
     pub base_packages: Vec<String>,
+    #[serde(rename = "exclude-packages", default)]
+    pub exclude_packages: Vec<String>,
+    #[serde(rename = "prefer-providers", default)]
+    pub prefer_providers: BTreeMap<String, String>,
+    #[serde(rename = "install-recommends", default)]
+    pub install_recommends: bool,
+    #[serde(rename = "deb822-sources", default)]
+    pub deb822_sources: bool,
+    /// APT keyrings to trust in the target, relative to the config file
+    #[serde(rename = "apt-keys", default)]
+    pub apt_keys: Vec<String>,
+    /// Packages which must be in the resolved set, overriding the built-in list
+    #[serde(rename = "required-packages")]
+    pub required_packages: Option<Vec<String>>,
+    /// Whether exactly one init system must be installed
+    #[serde(rename = "requires-init", default)]
+    pub requires_init: bool,
+    /// libsolv solver flags, e.g. `allow-downgrade = true`
+    #[serde(default)]
+    pub solver: BTreeMap<String, bool>,
+    /// Default branch, used when not given on the command line
+    pub branch: Option<String>,
+    /// Default mirror, used when not given on the command line
+    pub mirror: Option<String>,
+    /// Make the branch the default release of APT in the target, pinned above the
+    /// others; it must name the branch being bootstrapped
+    #[serde(rename = "default-release")]
+    pub default_release: Option<String>,
+    /// Default architectures, for the configs whose `[arch.<name>]` overrides leave no
+    /// room for `arch = [...]`, see [Config::default_arch]
+    #[serde(rename = "default-arch", default)]
+    default_arch: Vec<String>,
+    /// The default architectures or the per-architecture overrides
+    #[serde(default)]
+    pub arch: ArchKey,
+    /// Default additional components, used when not given on the command line
+    #[serde(default)]
+    pub comps: Vec<String>,
+    /// Default topics, used when not given on the command line
+    #[serde(default)]
+    pub topics: Vec<String>,
+    /// Named variants of this recipe, selected with `--variant`
+    #[serde(default)]
+    pub variants: BTreeMap<String, Variant>,
+    /// Optional groups of packages, selected with `--groups`
+    #[serde(default)]
+    pub groups: BTreeMap<String, Vec<String>>,
+    /// Groups selected by default
+    #[serde(rename = "default-groups", default)]
+    pub default_groups: Vec<String>,
+    /// Scripts to run during stage 2, and hooks to run on the host
+    #[serde(default)]
+    pub scripts: Scripts,
+    /// Replacements of the built-in bootstrap pack and scripts
+    #[serde(default)]
+    pub assets: Assets,
+    /// Downloaded files of a remote config, removed when the config is dropped
+    #[serde(skip)]
+    pub cache: Option<TempDir>,
+    /// URL a remote config was read from, after the redirects
+    #[serde(skip)]
+    pub url: Option<Url>,
+    /// Checksum of the config file itself, recorded in the target
+    #[serde(skip)]
+    pub sha256: Option<String>,
+    /// Unknown keys, rejected with a suggestion when reading the config
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, toml::Value>,
+}
+
+/// Top-level keys of a config, including `inherits` which is handled while reading it
+const CONFIG_KEYS: &[&str] = &[
+    "inherits",
+    "stub-packages",
+    "base-packages",
+    "exclude-packages",
+    "prefer-providers",
+    "install-recommends",
+    "deb822-sources",
+    "apt-keys",
+    "required-packages",
+    "requires-init",
+    "solver",
+    "branch",
+    "mirror",
+    "default-arch",
+    "arch",
+    "comps",
+    "topics",
+    "variants",
+    "groups",
+    "default-groups",
+    "scripts",
+    "assets",
+];
+
+impl Config {
+    /// Reject unknown keys, suggesting the closest known key, and empty package lists
+    fn validate(&self) -> Result<()> {
+        if let Some(key) = self.unknown.keys().next() {
+            let closest = CONFIG_KEYS
+                .iter()
+                .map(|k| (edit_distance(key, k), k))
+                .min()
+                .filter(|(distance, _)| *distance <= 2.max(key.len() / 4));
+            return Err(match closest {
+                Some((_, known)) => anyhow!("unknown key '{}', did you mean '{}'?", key, known),
+                None => anyhow!("unknown key '{}'", key),
+            });
+        }
+        for (key, list) in [
+            ("stub-packages", &self.stub_packages),
+            ("base-packages", &self.base_packages),
+        ] {
+            if list.is_empty() {
+                return Err(anyhow!("'{}' must not be empty", key));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = (previous + usize::from(ca != *cb))
+                .min(row[j] + 1)
+                .min(current + 1);
+            previous = current;
+        }
+    }
+
+    row[b.len()]
+}
+
+/// Packages to add or exclude for an architecture
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ArchOverride {
+    /// Appended to the base packages
+    #[serde(rename = "extra-packages", default)]
+    pub extra_packages: Vec<String>,
+    /// Appended to the excluded packages
+    #[serde(rename = "exclude-packages", default)]
+    pub exclude_packages: Vec<String>,
+}
+
+/// The `arch` key of a config
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum ArchKey {
+    /// `arch = [...]`, the default architectures, used when not given on the command line
+    Default(Vec<String>),
+    /// `[arch.<name>]` tables, the per-architecture overrides, applied after variants and
+    /// groups
+    Overrides(BTreeMap<String, ArchOverride>),
+}
+
+impl Default for ArchKey {
+    fn default() -> Self {
+        ArchKey::Overrides(BTreeMap::new())
+    }
+}
+
+/// Scripts declared in the config, with paths relative to the config file
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Scripts {
+    /// Run inside the target at the end of stage 2, before the `--scripts`
+    #[serde(default)]
+    pub stage2: Vec<String>,
+    #[serde(default)]
+    pub hooks: Hooks,
+}
+
+/// Files replacing the assets built into aoscbootstrap, with paths relative to the config file
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Assets {
+    /// A tar.xz archive of the base files, extracted before the packages
+    #[serde(rename = "bootstrap-pack")]
+    pub bootstrap_pack: Option<String>,
+    /// Template of the stage 2 install script, `{}` is replaced with the packages to install
+    #[serde(rename = "install-template")]
+    pub install_template: Option<String>,
+    /// Appended to the stage 2 script when cleaning up
+    #[serde(rename = "cleanup-script")]
+    pub cleanup_script: Option<String>,
+}
+
+impl Assets {
+    /// Check that the given files are usable
+    pub fn check(&self) -> Result<()> {
+        if let Some(ref path) = self.bootstrap_pack {
+            let read_pack = || -> Result<()> {
+                let f = File::open(path)?;
+                for entry in TarArchive::new(XzDecoder::new(f)).entries()? {
+                    std::io::copy(&mut entry?, &mut std::io::sink())?;
+                }
+
+                Ok(())
+            };
+            read_pack().context(format!(
+                "Bootstrap pack {} is not a readable tar.xz archive",
+                path
+            ))?;
+        }
+        if let Some(ref path) = self.install_template {
+            let template = std::fs::read_to_string(path)
+                .context(format!("Failed to read install template {}", path))?;
+            if !template.contains("{}") {
+                return Err(anyhow!(
+                    "Install template {} does not contain the {{}} placeholder for the packages",
+                    path
+                ));
+            }
+        }
+        if let Some(ref path) = self.cleanup_script {
+            File::open(path).context(format!("Failed to open cleanup script {}", path))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Scripts to run on the host, with the target path, the phase and the
+/// architecture in the environment
+#[derive(Deserialize, Serialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Hooks {
+    /// Run after all the packages are downloaded
+    #[serde(rename = "post-download", default)]
+    pub post_download: Vec<String>,
+    /// Run after stage 1 is finished
+    #[serde(rename = "post-stage1", default)]
+    pub post_stage1: Vec<String>,
+    /// Run right before entering the target for stage 2
+    #[serde(rename = "pre-stage2", default)]
+    pub pre_stage2: Vec<String>,
+    /// Run after stage 2 is finished
+    #[serde(rename = "post-stage2", default)]
+    pub post_stage2: Vec<String>,
+    /// Run before exporting any archives
+    #[serde(rename = "pre-export", default)]
+    pub pre_export: Vec<String>,
+    /// Run after all the archives are exported
+    #[serde(rename = "post-export", default)]
+    pub post_export: Vec<String>,
+}
+
+/// Phases at which hooks can run, in the order they happen
+pub const HOOK_PHASES: &[&str] = &[
+    "post-download",
+    "post-stage1",
+    "pre-stage2",
+    "post-stage2",
+    "pre-export",
+    "post-export",
+];
+
+impl Hooks {
+    fn all(&self) -> impl Iterator<Item = &String> {
+        self.phases().into_iter().flatten()
+    }
+
+    /// The hooks of every phase, in the order of [`HOOK_PHASES`]
+    fn phases(&self) -> [&Vec<String>; 6] {
+        [
+            &self.post_download,
+            &self.post_stage1,
+            &self.pre_stage2,
+            &self.post_stage2,
+            &self.pre_export,
+            &self.post_export,
+        ]
+    }
+
+    fn phases_mut(&mut self) -> [&mut Vec<String>; 6] {
+        [
+            &mut self.post_download,
+            &mut self.post_stage1,
+            &mut self.pre_stage2,
+            &mut self.post_stage2,
+            &mut self.pre_export,
+            &mut self.post_export,
+        ]
+    }
+
+    /// Position of a phase in [`HOOK_PHASES`]
+    fn position(phase: &str) -> Result<usize> {
+        HOOK_PHASES.iter().position(|p| *p == phase).ok_or_else(|| {
+            anyhow!(
+                "Unknown hook phase '{}', expected one of: {}",
+                phase,
+                HOOK_PHASES.join(", ")
+            )
+        })
+    }
+
+    /// Get the hooks of a phase, one of [`HOOK_PHASES`]
+    pub fn phase(&self, phase: &str) -> Result<&[String]> {
+        Ok(self.phases()[Self::position(phase)?])
+    }
+
+    /// Add a hook given as `PHASE=SCRIPT`, after the ones from the config
+    pub fn add(&mut self, hook: &str) -> Result<()> {
+        let (phase, script) = hook
+            .split_once('=')
+            .filter(|(p, s)| !p.is_empty() && !s.is_empty())
+            .ok_or_else(|| anyhow!("Invalid hook '{}', expected PHASE=SCRIPT", hook))?;
+        let position = Self::position(phase)?;
+        self.phases_mut()[position].push(script.to_string());
+
+        Ok(())
+    }
+}
+
+impl Scripts {
+    /// Make sure all the scripts exist
+    pub fn check_exists(&self) -> Result<()> {
+        let missing = self
+            .stage2
+            .iter()
+            .chain(self.hooks.all())
+            .filter(|p| !Path::new(p).is_file())
+            .cloned()
+            .collect::<Vec<_>>();
+        if !missing.is_empty() {
+            return Err(anyhow!(
+                "The following scripts do not exist: {}",
+                missing.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// A variant of a recipe, replacing or extending the default package lists
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Variant {
+    /// Replaces the default stub packages
+    #[serde(rename = "stub-packages")]
+    pub stub_packages: Option<Vec<String>>,
+    /// Replaces the default base packages
+    #[serde(rename = "base-packages")]
+    pub base_packages: Option<Vec<String>>,
+    /// Appended to the base packages
+    #[serde(rename = "extra-packages", default)]
+    pub extra_packages: Vec<String>,
+    /// Appended to the excluded packages
+    #[serde(rename = "exclude-packages", default)]
+    pub exclude_packages: Vec<String>,
+}
+
+impl Config {
+    /// The default architectures, set with `arch = [...]`, or with `default-arch = [...]`
+    /// alongside `[arch.<name>]` overrides
+    pub fn default_arch(&self) -> &[String] {
+        match self.arch {
+            ArchKey::Default(ref arches) if !arches.is_empty() => arches,
+            _ => &self.default_arch,
+        }
+    }
+
+    /// Apply the overrides for the given architecture, returning the packages added
+    pub fn apply_arch_overrides(&mut self, arch: &str) -> Vec<String> {
+        let ArchKey::Overrides(ref mut overrides) = self.arch else {
+            return Vec::new();
+        };
+        let Some(overrides) = overrides.remove(arch) else {
+            return Vec::new();
+        };
+        let mut added = Vec::new();
+        for p in overrides.extra_packages {
+            if !self.base_packages.contains(&p) {
+                self.base_packages.push(p.clone());
+                added.push(p);
+            }
+        }
+        self.exclude_packages.extend(overrides.exclude_packages);
+
+        added
+    }
+
+    /// Look up the default groups and the given groups, returning the packages of each group
+    pub fn select_groups(&self, names: &[String]) -> Result<Vec<(String, Vec<String>)>> {
+        let mut selected = Vec::new();
+        for name in self.default_groups.iter().chain(names) {
+            if selected.iter().any(|(n, _)| n == name) {
+                continue;
+            }
+            let Some(packages) = self.groups.get(name) else {
+                let available = self.groups.keys().cloned().collect::<Vec<_>>();
+                if available.is_empty() {
+                    return Err(anyhow!(
+                        "Unknown group '{}': this config does not define any groups",
+                        name
+                    ));
+                }
+                return Err(anyhow!(
+                    "Unknown group '{}'. Available groups: {}",
+                    name,
+                    available.join(", ")
+                ));
+            };
+            selected.push((name.clone(), packages.clone()));
+        }
+
+        Ok(selected)
+    }
+
+    /// Replace the package lists with the ones defined by the given variant
+    pub fn apply_variant(&mut self, name: &str) -> Result<()> {
+        let Some(variant) = self.variants.remove(name) else {
+            let available = self.variants.keys().cloned().collect::<Vec<_>>();
+            if available.is_empty() {
+                return Err(anyhow!(
+                    "Unknown variant '{}': this config does not define any variants",
+                    name
+                ));
+            }
+            return Err(anyhow!(
+                "Unknown variant '{}'. Available variants: {}",
+                name,
+                available.join(", ")
+            ));
+        };
+        if let Some(stub_packages) = variant.stub_packages {
+            self.stub_packages = stub_packages;
+        }
+        if let Some(base_packages) = variant.base_packages {
+            self.base_packages = base_packages;
+        }
+        for p in variant.extra_packages {
+            if !self.base_packages.contains(&p) {
+                self.base_packages.push(p);
+            }
+        }
+        self.exclude_packages.extend(variant.exclude_packages);
+
+        Ok(())
+    }
 }
 
 #[inline]
 pub fn decompress_tar_xz<R: Read>(reader: R, target: &Path) -> Result<()> {
-    let decompress = XzDecoder::new(reader);
-    let mut tar_processor = TarArchive::new(decompress);
-    tar_processor.set_unpack_xattrs(true);
-    tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(target)?;
+    unpack_tar(XzDecoder::new(reader), target)?;
 
     Ok(())
 }
 
 #[inline]
 pub fn decompress_tar_zst<R: Read>(reader: R, target: &Path) -> Result<()> {
-    let decompress = Decoder::new(reader)?;
-    let mut tar_processor = TarArchive::new(decompress);
+    unpack_tar(Decoder::new(reader)?, target)?;
+
+    Ok(())
+}
+
+#[inline]
+pub fn decompress_tar_gz<R: Read>(reader: R, target: &Path) -> Result<()> {
+    unpack_tar(GzDecoder::new(reader), target)?;
+
+    Ok(())
+}
+
+#[inline]
+pub fn decompress_tar_bz2<R: Read>(reader: R, target: &Path) -> Result<()> {
+    unpack_tar(BzDecoder::new(reader), target)?;
+
+    Ok(())
+}
+
+/// Unpack a stage 1 archive (`.tar`, `.tar.xz`/`.txz`, `.tar.gz`/`.tgz` or
+/// `.tar.zst`/`.tzst`) into the target
+pub fn unpack_tarball(path: &Path, target: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let f = File::open(path)?;
+    let ends_with = |exts: &[&str]| exts.iter().any(|e| name.ends_with(e));
+    if ends_with(&[".tar.xz", ".txz"]) {
+        decompress_tar_xz(f, target)
+    } else if ends_with(&[".tar.gz", ".tgz"]) {
+        decompress_tar_gz(f, target)
+    } else if ends_with(&[".tar.zst", ".tzst"]) {
+        decompress_tar_zst(f, target)
+    } else if ends_with(&[".tar"]) {
+        unpack_tar(f, target)?;
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "Unknown format of {}, expected a .tar, .tar.xz, .tar.gz or .tar.zst archive",
+            path.display()
+        ))
+    }
+}
+
+/// Wrap the reader of a `*.tar{,.xz,.zst,.gz,.bz2}` deb member with a matching decoder
+fn tar_decoder<'a, R: Read + 'a>(
+    name: &str,
+    prefix: &str,
+    reader: R,
+) -> Result<Option<Box<dyn Read + 'a>>> {
+    let decoder: Box<dyn Read + 'a> = match name.strip_prefix(prefix) {
+        Some(".tar") => Box::new(reader),
+        Some(".tar.xz") => Box::new(XzDecoder::new(reader)),
+        Some(".tar.zst") => Box::new(Decoder::new(reader)?),
+        Some(".tar.gz") => Box::new(GzDecoder::new(reader)),
+        Some(".tar.bz2") => Box::new(BzDecoder::new(reader)),
+        _ => return Ok(None),
+    };
+
+    Ok(Some(decoder))
+}
+
+/// Unpack a tarball into the target, returning the (normalized) paths of the unpacked entries
+///
+/// Packages come from the mirror, so entries are checked before they touch the file system:
+/// absolute paths and paths leaving the target are rejected, and symlinks already in the target
+/// are followed as from inside it (`bin -> usr/bin` and `run -> /var/run` stay in the target),
+/// unless they lead out of it.
+fn unpack_tar<R: Read>(reader: R, target: &Path) -> Result<Vec<PathBuf>> {
+    let mut tar_processor = TarArchive::new(reader);
     tar_processor.set_unpack_xattrs(true);
     tar_processor.set_preserve_permissions(true);
-    tar_processor.unpack(target)?;
+    let mut paths = Vec::new();
+    for entry in tar_processor.entries()? {
+        let mut entry = entry?;
+        let path = normalize_entry_path(&entry.path()?)?;
+        if path.as_os_str().is_empty() {
+            // the root directory itself
+            continue;
+        }
+        let kind = entry.header().entry_type();
+        let dest = if kind.is_dir() {
+            resolve_in_target(target, &path)?
+        } else {
+            let parent = resolve_in_target(target, path.parent().unwrap_or(Path::new("")))?;
+            std::fs::create_dir_all(target.join(&parent))?;
+            // the entry replaces what is there, a symlink at its place is not followed
+            parent.join(path.file_name().unwrap())
+        };
+        let dest = target.join(dest);
+        if kind.is_hard_link() {
+            let link = entry
+                .link_name()?
+                .ok_or_else(|| anyhow!("hard link {} has no target", path.display()))?;
+            let link = normalize_entry_path(&link)?;
+            let source = resolve_in_target(target, link.parent().unwrap_or(Path::new("")))?;
+            let source = target
+                .join(source)
+                .join(link.file_name().unwrap_or_default());
+            if dest.symlink_metadata().is_ok() {
+                std::fs::remove_file(&dest)?;
+            }
+            std::fs::hard_link(&source, &dest).context(format!(
+                "when linking {} to {}",
+                path.display(),
+                link.display()
+            ))?;
+        } else {
+            entry
+                .unpack(&dest)
+                .context(format!("when unpacking {}", path.display()))?;
+        }
+        paths.push(path);
+    }
+
+    Ok(paths)
+}
+
+/// Turn the path of a tar entry into a relative path without `.` and `..`, failing if it is
+/// absolute or leaves the root
+fn normalize_entry_path(path: &Path) -> Result<PathBuf> {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => (),
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return Err(anyhow!("{} points outside the target", path.display()));
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                return Err(anyhow!("{} is an absolute path", path.display()))
+            }
+        }
+    }
+
+    Ok(normalized)
+}
+
+/// Control information of an extracted deb
+#[derive(Debug)]
+pub struct DebControl {
+    /// Contents of the `control` file
+    pub control: String,
+    /// Other files in the control archive (maintainer scripts, `md5sums`, `conffiles`, ...),
+    /// with their permission bits
+    pub files: Vec<(String, u32, Vec<u8>)>,
+    /// Paths unpacked from the data archive, relative to the target
+    pub paths: Vec<PathBuf>,
+}
+
+fn read_control_tar<R: Read>(reader: R) -> Result<(String, Vec<(String, u32, Vec<u8>)>)> {
+    let mut control = None;
+    let mut files = Vec::new();
+    for entry in TarArchive::new(reader).entries()? {
+        let mut entry = entry?;
+        if !entry.header().entry_type().is_file() {
+            continue;
+        }
+        let name = match entry.path()?.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        let mode = entry.header().mode()?;
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        if name == "control" {
+            control = Some(String::from_utf8(content).context("control file is not valid UTF-8")?);
+        } else {
+            files.push((name, mode, content));
+        }
+    }
+    let control =
+        control.ok_or_else(|| anyhow!("control file not found in the control archive"))?;
+
+    Ok((control, files))
+}
+
+/// Extract the data archive of a deb into the target, and return its control information
+pub fn extract_deb_with_control<R: Read>(reader: R, target: &Path) -> Result<DebControl> {
+    let (control, files, paths) = read_deb(reader, |name, data| {
+        unpack_tar(data, target).context(format!("when extracting {}", name))
+    })?;
+
+    Ok(DebControl {
+        control,
+        files,
+        paths,
+    })
+}
+
+/// Decompress the data archive of a deb into `staging` as a plain tarball, so that it can be
+/// unpacked later with [`unpack_staged`], which fills in the paths of the returned control
+/// information.
+pub fn stage_deb<R: Read, W: Write>(reader: R, staging: &mut W) -> Result<DebControl> {
+    let (control, files, _) = read_deb(reader, |name, data| {
+        std::io::copy(data, &mut *staging).context(format!("when decompressing {}", name))
+    })?;
+
+    Ok(DebControl {
+        control,
+        files,
+        paths: Vec::new(),
+    })
+}
+
+pub fn unpack_staged<R: Read>(staging: R, target: &Path, control: &mut DebControl) -> Result<()> {
+    control.paths = unpack_tar(staging, target)?;
 
     Ok(())
 }
 
-pub fn extract_deb<R: Read>(reader: R, target: &Path) -> Result<()> {
+/// Read the control archive of a deb, and pass the decompressed data archive to `on_data`
+fn read_deb<R: Read, T>(
+    reader: R,
+    mut on_data: impl FnMut(&str, &mut dyn Read) -> Result<T>,
+) -> Result<(String, Vec<(String, u32, Vec<u8>)>, T)> {
     let mut deb = ArArchive::new(reader);
+    let mut seen_version = false;
+    let mut control = None;
+    let mut data = None;
     while let Some(entry) = deb.next_entry() {
-        if entry.is_err() {
+        // a member skipped over could hide what follows it from the checks below
+        let entry = entry.context("malformed member in the deb archive")?;
+        let name = String::from_utf8_lossy(entry.header().identifier()).to_string();
+        // like dpkg, expect debian-binary, then control.tar.*, then data.tar.*; members
+        // starting with `_` are ignored, anything else (e.g. a second data archive) is an error
+        if !seen_version {
+            if name != "debian-binary" {
+                return Err(anyhow!(
+                    "not a deb archive: the first member is {} instead of debian-binary",
+                    name
+                ));
+            }
+            seen_version = true;
+        } else if name.starts_with('_') {
             continue;
+        } else if name.starts_with("control.") && control.is_none() && data.is_none() {
+            let decoder = tar_decoder(&name, "control", entry)?
+                .ok_or_else(|| anyhow!("control archive format unsupported: {}", name))?;
+            control = Some(read_control_tar(decoder).context(format!("when reading {}", name))?);
+        } else if name.starts_with("data.") && control.is_some() && data.is_none() {
+            let mut decoder = tar_decoder(&name, "data", entry)?
+                .ok_or_else(|| anyhow!("data archive not found or format unsupported"))?;
+            data = Some(on_data(&name, &mut decoder)?);
+        } else {
+            return Err(anyhow!("unexpected member {} in the deb archive", name));
+        }
+    }
+    let data = data.ok_or_else(|| anyhow!("data archive not found or format unsupported"))?;
+    let (control, files) =
+        control.ok_or_else(|| anyhow!("control archive not found or format unsupported"))?;
+
+    Ok((control, files, data))
+}
+
+/// Record extracted packages as unpacked in the dpkg database of the target, so that stage 2
+/// only needs to configure them. A package registered again (with `--force` or `--resume`)
+/// replaces its earlier entry.
+///
+/// The target cannot run anything while stage 1 extracts the packages, so the preinst
+/// scripts of these packages are listed in [`STUB_PREINST`] for stage 2 to run before it
+/// unpacks the rest.
+pub fn register_unpacked(target: &Path, packages: &[DebControl]) -> Result<()> {
+    let info_dir = target.join("var/lib/dpkg/info");
+    std::fs::create_dir_all(&info_dir)?;
+    let status_path = target.join("var/lib/dpkg/status");
+    let existing = match std::fs::read_to_string(&status_path) {
+        Ok(status) => status,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let mut stanzas = existing
+        .split("\n\n")
+        .map(|stanza| stanza.trim_matches('\n'))
+        .filter(|stanza| !stanza.is_empty())
+        .map(|stanza| format!("{}\n\n", stanza))
+        .collect::<Vec<_>>();
+    let preinst_path = target.join(STATE_DIR).join(STUB_PREINST);
+    let mut preinst = match std::fs::read_to_string(&preinst_path) {
+        Ok(list) => list.lines().map(str::to_string).collect::<Vec<_>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    for package in packages {
+        let stanza = status_stanza(package)?;
+        let name = dpkg_info_name(&package.control)?;
+        let key = |control: &str| {
+            (
+                control_field(control, "Package").map(str::to_string),
+                control_field(control, "Architecture").map(str::to_string),
+            )
+        };
+        let new_key = key(&package.control);
+        match stanzas.iter_mut().find(|old| key(old) == new_key) {
+            Some(old) => *old = stanza,
+            None => stanzas.push(stanza),
+        }
+        let mut list = String::from("/.\n");
+        for path in &package.paths {
+            list.push_str(&format!("/{}\n", path.display()));
+        }
+        // thousands of these for a base system, synced all at once below
+        std::fs::write(info_dir.join(format!("{}.list", name)), list)?;
+        for (file, mode, content) in &package.files {
+            let path = info_dir.join(format!("{}.{}", name, file));
+            std::fs::write(&path, content)?;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        if package.files.iter().any(|(file, _, _)| file == "preinst") && !preinst.contains(&name) {
+            preinst.push(name);
+        }
+    }
+    // the status must not list packages whose files are not on disk yet
+    nix::unistd::sync();
+    atomic_write(&status_path, stanzas.concat(), 0o644)?;
+    if !preinst.is_empty() {
+        std::fs::create_dir_all(preinst_path.parent().unwrap())?;
+        let mut list = preinst.join("\n");
+        list.push('\n');
+        atomic_write(&preinst_path, list, 0o644)?;
+    }
+
+    Ok(())
+}
+
+fn control_field<'a>(control: &'a str, name: &str) -> Option<&'a str> {
+    control.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        if key.eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Name of the package in `info/`, qualified with the architecture for `Multi-Arch: same`
+fn dpkg_info_name(control: &str) -> Result<String> {
+    let package =
+        control_field(control, "Package").ok_or_else(|| anyhow!("control file has no Package"))?;
+    match (
+        control_field(control, "Multi-Arch"),
+        control_field(control, "Architecture"),
+    ) {
+        (Some("same"), Some(arch)) => Ok(format!("{}:{}", package, arch)),
+        _ => Ok(package.to_string()),
+    }
+}
+
+fn status_stanza(package: &DebControl) -> Result<String> {
+    let mut stanza = String::new();
+    let mut has_package = false;
+    for line in package.control.trim_end().lines() {
+        stanza.push_str(line);
+        stanza.push('\n');
+        if !has_package && line.starts_with("Package:") {
+            stanza.push_str("Status: install ok unpacked\n");
+            has_package = true;
+        }
+    }
+    if !has_package {
+        return Err(anyhow!("control file has no Package"));
+    }
+    // like dpkg, mark conffiles that have never been configured with a placeholder hash
+    let conffiles = package
+        .files
+        .iter()
+        .find(|(name, _, _)| name == "conffiles")
+        .map(|(_, _, content)| String::from_utf8_lossy(content).to_string())
+        .unwrap_or_default();
+    let conffiles = conffiles
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('/'))
+        .collect::<Vec<_>>();
+    if !conffiles.is_empty() {
+        stanza.push_str("Conffiles:\n");
+        for conffile in conffiles {
+            stanza.push_str(&format!(" {} newconffile\n", conffile));
+        }
+    }
+    stanza.push('\n');
+
+    Ok(stanza)
+}
+
+/// Read a config file. `${NAME}` in string values is replaced with the given built-in
+/// variables, or the environment variable of the same name; `$$` is a literal `$`.
+pub fn read_config<P: AsRef<Path>>(path: P, vars: &BTreeMap<String, String>) -> Result<Config> {
+    let mut location = ConfigLocation::File(path.as_ref().to_path_buf());
+    let content = location.read()?;
+    let config = parse_config_table(&location, &content, vars, None, 0)?;
+    let mut config: Config = toml::Value::Table(config)
+        .try_into()
+        .map_err(|e| anyhow!("{}: {}", location, e))?;
+    config.validate()?;
+    config.sha256 = Some(sha256sum(content.as_bytes())?);
+
+    Ok(config)
+}
+
+/// Download a config and the configs and scripts it references, verifying the
+/// config itself against the given SHA256 checksum if any.
+pub fn read_remote_config(
+    url: &Url,
+    vars: &BTreeMap<String, String>,
+    sha256: Option<&str>,
+) -> Result<Config> {
+    let client = make_new_client()?;
+    let cache = tempfile::tempdir()?;
+    let mut location = ConfigLocation::Url {
+        url: url.clone(),
+        client: &client,
+        cache: cache.path(),
+    };
+    let content = location.read()?;
+    let config = parse_config_table(&location, &content, vars, sha256, 0)?;
+    let mut config: Config = toml::Value::Table(config)
+        .try_into()
+        .map_err(|e| anyhow!("{}: {}", location, e))?;
+    config.validate()?;
+    if let ConfigLocation::Url { url, .. } = location {
+        config.url = Some(url);
+    }
+    config.cache = Some(cache);
+    config.sha256 = Some(sha256sum(content.as_bytes())?);
+
+    Ok(config)
+}
+
+impl Config {
+    /// The local paths of the package lists given with `-f`. With a remote config, the
+    /// relative ones not found here are downloaded from next to the config, along with the
+    /// lists they `%include`.
+    pub fn package_lists(&self, lists: &[String]) -> Result<Vec<String>> {
+        let (Some(url), Some(cache)) = (&self.url, &self.cache) else {
+            return Ok(lists.to_vec());
+        };
+        let client = make_new_client()?;
+        lists
+            .iter()
+            .map(|list| {
+                if Path::new(list).is_absolute() || Path::new(list).exists() {
+                    return Ok(list.clone());
+                }
+                let path = fetch_package_list(&client, &url.join(list)?, cache.path(), 0)
+                    .context(format!("when downloading package list '{}'", list))?;
+                Ok(path.to_string_lossy().to_string())
+            })
+            .collect()
+    }
+}
+
+/// Download a package list and the lists it includes, laid out in `cache` as on the server
+/// so that the `%include` of the local copies resolve the same, and return its local path
+fn fetch_package_list(client: &Client, url: &Url, cache: &Path, depth: usize) -> Result<PathBuf> {
+    if depth > 32 {
+        return Err(anyhow!("Recursion limit exceeded. Is there a loop?"));
+    }
+    let (content, url) = fetch_text(client, url)?;
+    let path = cache.join("lists").join(url.path().trim_start_matches('/'));
+    if path.is_file() {
+        return Ok(path);
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, &content)?;
+    for line in content.lines() {
+        if let Ok(ListLine::Include(include)) = parse_list_line(line) {
+            fetch_package_list(client, &url.join(include)?, cache, depth + 1)?;
         }
-        let entry = entry.unwrap();
-        match entry.header().identifier() {
-            b"data.tar.xz" => {
-                decompress_tar_xz(entry, target)?;
-                return Ok(());
+    }
+
+    Ok(path)
+}
+
+/// Where a config file is read from
+enum ConfigLocation<'a> {
+    File(PathBuf),
+    Url {
+        url: Url,
+        client: &'a Client,
+        /// Directory to save the referenced scripts in
+        cache: &'a Path,
+    },
+}
+
+impl ConfigLocation<'_> {
+    /// Read the file, following the redirects of a URL, which the relative paths are then
+    /// resolved against
+    fn read(&mut self) -> Result<String> {
+        match self {
+            ConfigLocation::File(path) => {
+                let mut f =
+                    File::open(path).context(format!("Failed to open file: {}", path.display()))?;
+                let mut content = String::new();
+                content.reserve(4096);
+                f.read_to_string(&mut content)?;
+                Ok(content)
             }
-            b"data.tar.zst" => {
-                decompress_tar_zst(entry, target)?;
-                return Ok(());
+            ConfigLocation::Url { url, client, .. } => {
+                let (content, final_url) = fetch_text(client, url)?;
+                *url = final_url;
+                Ok(content)
             }
-            _ => continue,
         }
     }
 
-    Err(anyhow!("data archive not found or format unsupported"))
+    /// Resolve a path relative to this config
+    fn join(&self, path: &str) -> Result<Self> {
+        match self {
+            ConfigLocation::File(file) => {
+                let real_path = file.canonicalize()?;
+                let real_path = real_path.parent().ok_or_else(|| anyhow!("Invalid path"))?;
+                Ok(ConfigLocation::File(real_path.join(path)))
+            }
+            ConfigLocation::Url { url, client, cache } => Ok(ConfigLocation::Url {
+                url: url.join(path)?,
+                client: *client,
+                cache: *cache,
+            }),
+        }
+    }
+
+    /// Return a local path of a file referenced by this config, downloading it if needed
+    fn script(&self, path: &str) -> Result<String> {
+        match self.join(path)? {
+            ConfigLocation::File(path) => Ok(path.to_string_lossy().to_string()),
+            ConfigLocation::Url { url, client, cache } => {
+                let name = url
+                    .path_segments()
+                    .and_then(|mut s| s.next_back())
+                    .unwrap_or("script");
+                let path = cache.join(format!("{:016x}-{}", rand::random::<u64>(), name));
+                atomic_write(&path, fetch_bytes(client, &url)?, 0o644)?;
+                Ok(path.to_string_lossy().to_string())
+            }
+        }
+    }
 }
 
-pub fn read_config<P: AsRef<Path>>(path: P) -> Result<Config> {
-    let mut f = File::open(path)?;
-    let mut content = String::new();
-    content.reserve(4096);
-    f.read_to_string(&mut content)?;
-    let config = toml::from_str(&content)?;
+impl Display for ConfigLocation<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigLocation::File(path) => write!(f, "{}", path.display()),
+            ConfigLocation::Url { url, .. } => write!(f, "{}", url),
+        }
+    }
+}
 
-    Ok(config)
+/// Read a config file, merging in the configs it inherits from
+fn read_config_table(
+    mut location: ConfigLocation,
+    vars: &BTreeMap<String, String>,
+    sha256: Option<&str>,
+    depth: usize,
+) -> Result<toml::Table> {
+    if depth > 32 {
+        return Err(anyhow!("Recursion limit exceeded. Is there a loop?"));
+    }
+
+    let content = location.read()?;
+    parse_config_table(&location, &content, vars, sha256, depth)
+}
+
+/// Parse the content of a config file, see [read_config_table]
+fn parse_config_table(
+    location: &ConfigLocation,
+    content: &str,
+    vars: &BTreeMap<String, String>,
+    sha256: Option<&str>,
+    depth: usize,
+) -> Result<toml::Table> {
+    if let Some(expected) = sha256 {
+        let actual = sha256sum(content.as_bytes())?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            return Err(anyhow!(
+                "Checksum mismatch for {}: expected {}, got {}",
+                location,
+                expected,
+                actual
+            ));
+        }
+    }
+    // the error includes the line, the column and the offending snippet
+    let mut config: toml::Table = toml::from_str(content)
+        .map_err(|e| anyhow!("{}: {}", location, e.to_string().trim_end()))?;
+    // a config from a URL must not read the host environment, e.g. the tokens of a CI,
+    // into the URLs it then has fetched
+    let env = matches!(location, ConfigLocation::File(_));
+    for (key, value) in config.iter_mut() {
+        interpolate_value(value, vars, env).context(format!("in '{}'", key))?;
+    }
+    // `arch = [...]` would replace the `[arch.<name>]` overrides of the inherited configs
+    // instead of being merged with them, so it is kept as `default-arch`
+    if let Some(toml::Value::Array(_)) = config.get("arch") {
+        if config.contains_key("default-arch") {
+            return Err(anyhow!(
+                "{}: both 'arch' and 'default-arch' set the default architectures, keep one of them",
+                location
+            ));
+        }
+        let arches = config.remove("arch").unwrap();
+        config.insert("default-arch".to_string(), arches);
+    }
+    for table in ["scripts", "assets"] {
+        if let Some(toml::Value::Table(paths)) = config.get_mut(table) {
+            resolve_script_paths(paths, location)?;
+        }
+    }
+    if let Some(toml::Value::Array(keys)) = config.get_mut("apt-keys") {
+        for key in keys.iter_mut() {
+            if let toml::Value::String(path) = key {
+                *path = location.script(path)?;
+            }
+        }
+    }
+    let Some(parents) = config.remove("inherits") else {
+        return Ok(config);
+    };
+    let parents: Vec<String> = parents
+        .try_into()
+        .context("'inherits' must be an array of paths")?;
+    let mut merged = toml::Table::new();
+    for parent in parents {
+        let parent = read_config_table(location.join(&parent)?, vars, None, depth + 1)
+            .context(format!("when reading inherited config '{}'", parent))?;
+        merge_config(&mut merged, parent);
+    }
+    merge_config(&mut merged, config);
+
+    Ok(merged)
+}
+
+/// Interpolate the variables in all the strings in a config value
+fn interpolate_value(
+    value: &mut toml::Value,
+    vars: &BTreeMap<String, String>,
+    env: bool,
+) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = interpolate(s, vars, env)?,
+        toml::Value::Array(values) => {
+            for v in values.iter_mut() {
+                interpolate_value(v, vars, env)?;
+            }
+        }
+        toml::Value::Table(table) => {
+            for v in table.values_mut() {
+                interpolate_value(v, vars, env)?;
+            }
+        }
+        _ => (),
+    }
+
+    Ok(())
+}
+
+/// Replace `${NAME}` in a string with the value of the variable, falling back to the
+/// environment only with `env`
+fn interpolate(s: &str, vars: &BTreeMap<String, String>, env: bool) -> Result<String> {
+    let mut result = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(pos) = rest.find('$') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        if let Some(r) = rest.strip_prefix('$') {
+            result.push('$');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('{') {
+            let end = r
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated variable in '{}'", s))?;
+            let name = &r[..end];
+            let value = match vars.get(name) {
+                Some(value) => value.clone(),
+                None if !env => {
+                    return Err(anyhow!(
+                        "Undefined variable '{}' in '{}', a config from a URL cannot read the environment",
+                        name,
+                        s
+                    ))
+                }
+                None => std::env::var(name)
+                    .map_err(|_| anyhow!("Undefined variable '{}' in '{}'", name, s))?,
+            };
+            result.push_str(&value);
+            rest = &r[end + 1..];
+        } else {
+            result.push('$');
+        }
+    }
+    result.push_str(rest);
+
+    Ok(result)
+}
+
+/// Make the paths in the `[scripts]` and `[assets]` tables relative to the config declaring them
+fn resolve_script_paths(scripts: &mut toml::Table, location: &ConfigLocation) -> Result<()> {
+    for value in scripts.values_mut() {
+        match value {
+            toml::Value::String(path) => *path = location.script(path)?,
+            toml::Value::Array(paths) => {
+                for path in paths.iter_mut() {
+                    if let toml::Value::String(path) = path {
+                        *path = location.script(path)?;
+                    }
+                }
+            }
+            toml::Value::Table(hooks) => resolve_script_paths(hooks, location)?,
+            _ => (),
+        }
+    }
+
+    Ok(())
+}
+
+/// Merge a child config into its parent: arrays are concatenated without duplicates,
+/// tables are merged recursively and other values in the child win.
+fn merge_config(parent: &mut toml::Table, child: toml::Table) {
+    for (key, value) in child {
+        let value = match (parent.remove(&key), value) {
+            (Some(toml::Value::Array(mut a)), toml::Value::Array(b)) => {
+                for v in b {
+                    if !a.contains(&v) {
+                        a.push(v);
+                    }
+                }
+                toml::Value::Array(a)
+            }
+            (Some(toml::Value::Table(mut a)), toml::Value::Table(b)) => {
+                merge_config(&mut a, b);
+                toml::Value::Table(a)
+            }
+            (_, value) => value,
+        };
+        parent.insert(key, value);
+    }
 }
 
-pub fn extract_bootstrap_pack(target: &Path) -> Result<()> {
+/// Record how this system was bootstrapped in `/etc/aoscbootstrap-release`,
+/// in the same format as `os-release`
+pub fn write_build_info(target: &Path, fields: &[(&str, &str)]) -> Result<()> {
+    let mut f = String::new();
+    for (key, value) in fields {
+        let mut escaped = String::with_capacity(value.len());
+        for c in value.chars() {
+            // the characters to escape in os-release, as in shell double quotes
+            if matches!(c, '"' | '\\' | '$' | '`') {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        f.push_str(&format!("{}=\"{}\"\n", key, escaped));
+    }
+    atomic_write(&target.join("etc/aoscbootstrap-release"), f, 0o644)?;
+
+    Ok(())
+}
+
+pub fn extract_bootstrap_pack(target: &Path, assets: &Assets) -> Result<()> {
+    if let Some(ref path) = assets.bootstrap_pack {
+        return decompress_tar_xz(File::open(path)?, target);
+    }
     let reader = std::io::Cursor::new(BOOTSTRAP_PACK);
     decompress_tar_xz(reader, target)?;
 
     Ok(())
 }
 
-fn generate_dpkg_install_script(packages: &[String]) -> String {
+fn generate_dpkg_install_script(template: &str, packages: &[String]) -> String {
     let mut package_list = String::new();
     for package in packages {
         package_list.push_str(&format!("'{}' ", package));
     }
 
-    INSTALL_SCRIPT_TPL.replacen("{}", &package_list, 1)
+    template.replacen("{}", &package_list, 1)
 }
 
+/// Mark the packages which are not requested explicitly as automatically installed
+///
+/// Packages are matched by name and architecture: a request without an architecture
+/// qualifier means the main architecture, and `all` packages are recorded under the main
+/// architecture as apt does.
 pub fn generate_apt_extended_state(
     target: &Path,
     manual_pkgs: &[String],
     all_packages: &[PackageMeta],
     main_arch: &str,
 ) -> Result<()> {
-    let extended_state = File::create(target.join("var/lib/apt/extended_states"))?;
-    let mut extended_state = BufWriter::new(extended_state);
+    let mut extended_state = String::new();
     let mut manual_installed = HashSet::new();
 
     for p in manual_pkgs {
-        manual_installed.insert(p);
+        let (name, arch) = package_name(p)
+            .split_once(':')
+            .unwrap_or((package_name(p), main_arch));
+        manual_installed.insert((name, if arch == "all" { main_arch } else { arch }));
     }
 
     for pkg in all_packages {
-        if manual_installed.contains(&pkg.name) {
+        let arch = if pkg.arch == "all" {
+            main_arch
+        } else {
+            pkg.arch.as_str()
+        };
+        if manual_installed.contains(&(pkg.name.as_str(), arch)) {
             continue;
         }
-        writeln!(
-            &mut extended_state,
-            "Package: {}\nArchitecture: {}\nAuto-Installed: 1\n",
-            pkg.name, main_arch
-        )?;
+        extended_state.push_str(&format!(
+            "Package: {}\nArchitecture: {}\nAuto-Installed: 1\n\n",
+            pkg.name, arch
+        ));
+    }
+    atomic_write(
+        &target.join("var/lib/apt/extended_states"),
+        extended_state,
+        0o644,
+    )?;
+
+    Ok(())
+}
+
+/// Quote a string for bash
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+/// Check that the name is valid for a user or a group
+fn check_account_name(name: &str) -> Result<()> {
+    let valid = name.len() <= 32
+        && name.starts_with(|c: char| c.is_ascii_lowercase() || c == '_')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_' || c == '-');
+    if !valid {
+        return Err(anyhow!("Invalid user or group name '{}'", name));
     }
 
     Ok(())
 }
 
+/// Check that the value is a crypt(3) hash rather than a plain text password
+pub fn check_password_hash(hash: &str) -> Result<()> {
+    let fields = hash.split('$').collect::<Vec<_>>();
+    let valid = fields.len() >= 4
+        && fields[0].is_empty()
+        && fields[1..].iter().all(|f| !f.is_empty())
+        && !hash.contains(|c: char| c == ':' || c.is_whitespace());
+    if !valid {
+        return Err(anyhow!(
+            "The root password must be a crypt(3) hash like $6$salt$hash, e.g. from `openssl passwd -6`"
+        ));
+    }
+
+    Ok(())
+}
+
+/// A user to create in stage 2, given as `name[:uid][:groups]`
+#[derive(Debug, PartialEq)]
+pub struct UserSpec {
+    pub name: String,
+    pub uid: Option<u32>,
+    pub groups: Vec<String>,
+}
+
+impl UserSpec {
+    pub fn parse(spec: &str) -> Result<UserSpec> {
+        let mut parts = spec.split(':');
+        let name = parts.next().unwrap_or_default();
+        check_account_name(name)?;
+        let uid =
+            match parts.next() {
+                None | Some("") => None,
+                Some(uid) => Some(uid.parse().map_err(|_| {
+                    anyhow!("Invalid user '{}', expected name[:uid][:groups]", spec)
+                })?),
+            };
+        let groups = parts
+            .next()
+            .map(|g| {
+                g.split(',')
+                    .filter(|g| !g.is_empty())
+                    .map(|g| g.to_string())
+                    .collect()
+            })
+            .unwrap_or_default();
+        if parts.next().is_some() {
+            return Err(anyhow!(
+                "Invalid user '{}', expected name[:uid][:groups]",
+                spec
+            ));
+        }
+        for group in &groups {
+            check_account_name(group)?;
+        }
+
+        Ok(UserSpec {
+            name: name.to_string(),
+            uid,
+            groups,
+        })
+    }
+}
+
+/// Generate the commands setting up the root account and creating the users
+pub fn generate_account_script(
+    root_password_hash: Option<&str>,
+    root_locked: bool,
+    users: &[UserSpec],
+) -> String {
+    let mut script = String::new();
+    if root_password_hash.is_none() && !root_locked && users.is_empty() {
+        return script;
+    }
+    script.push_str("\necho -e '\\e[1m\\e[94mSetting up user accounts ...\\e[0m'\n");
+    if let Some(hash) = root_password_hash {
+        script.push_str(&format!(
+            "printf '%s\\n' {} | chpasswd -e\n",
+            shell_quote(&format!("root:{}", hash))
+        ));
+    }
+    if root_locked {
+        script.push_str("usermod -L root\n");
+    }
+    for user in users {
+        script.push_str("useradd -m -s /bin/bash");
+        if let Some(uid) = user.uid {
+            script.push_str(&format!(" -u {}", uid));
+        }
+        if !user.groups.is_empty() {
+            script.push_str(&format!(" -G {}", shell_quote(&user.groups.join(","))));
+        }
+        script.push_str(&format!(" {}\n", shell_quote(&user.name)));
+    }
+
+    script
+}
+
+/// Steps of the clean up, in the order they run
+pub const CLEAN_STEPS: &[&str] = &[
+    "unowned-files",
+    "apt-cache",
+    "apt-lists",
+    "logs",
+    "tmp",
+    "machine-id",
+    "ssh-host-keys",
+    "doc-trim",
+];
+/// Steps run by `--clean` alone, which removes what earlier versions did
+const DEFAULT_CLEAN_STEPS: &[&str] = &[
+    "unowned-files",
+    "apt-cache",
+    "apt-lists",
+    "logs",
+    "tmp",
+    "machine-id",
+    "ssh-host-keys",
+];
+
+/// Choose the clean up steps: `only` replaces the default steps, and `except` is removed from
+/// them. Steps are returned in the order they run.
+pub fn select_clean_steps(only: &[String], except: &[String]) -> Result<Vec<&'static str>> {
+    if let Some(unknown) = only
+        .iter()
+        .chain(except)
+        .find(|s| !CLEAN_STEPS.contains(&s.as_str()))
+    {
+        return Err(anyhow!(
+            "Unknown clean up step '{}', expected one of: {}",
+            unknown,
+            CLEAN_STEPS.join(", ")
+        ));
+    }
+    let steps = CLEAN_STEPS
+        .iter()
+        .filter(|step| {
+            if only.is_empty() {
+                DEFAULT_CLEAN_STEPS.contains(step)
+            } else {
+                only.iter().any(|s| s == *step)
+            }
+        })
+        .filter(|step| !except.iter().any(|s| s == *step))
+        .copied()
+        .collect();
+
+    Ok(steps)
+}
+
 pub fn write_install_script(
     packages: &[String],
-    cleanup: bool,
+    accounts: &str,
+    clean_steps: &[&str],
+    assets: &Assets,
     target: &Path,
 ) -> Result<NamedTempFile> {
     let mut f = NamedTempFile::new_in(target)?;
-    f.write_all(generate_dpkg_install_script(packages).as_bytes())?;
-    if cleanup {
-        f.write_all(CLEANUP_SCRIPT)?;
+    let template = match assets.install_template {
+        Some(ref path) => std::fs::read_to_string(path)?,
+        None => INSTALL_SCRIPT_TPL.to_string(),
+    };
+    f.write_all(generate_dpkg_install_script(&template, packages).as_bytes())?;
+    f.write_all(accounts.as_bytes())?;
+    if !clean_steps.is_empty() {
+        writeln!(f, "\nCLEAN_STEPS=({})", clean_steps.join(" "))?;
+        match assets.cleanup_script {
+            Some(ref path) => f.write_all(&std::fs::read(path)?)?,
+            None => f.write_all(CLEANUP_SCRIPT)?,
+        }
     }
 
     Ok(f)
 }
+
+/// Path of the stage 2 script kept in the target, relative to the target
+pub const STAGE2_SCRIPT: &str = "aoscbootstrap-stage2.sh";
+/// Directory recording the state of an unfinished bootstrap, relative to the target
+/// (the install script also records its progress there)
+const STATE_DIR: &str = "var/lib/aoscbootstrap";
+/// List of the packages extracted in stage 1 whose preinst stage 2 still has to run,
+/// relative to [`STATE_DIR`]
+const STUB_PREINST: &str = "stub-preinst";
+
+/// Keep the install script in the target for `--second-stage` and `--resume`,
+/// and record that stage 2 is pending
+pub fn save_pending_stage2(target: &Path, script: NamedTempFile, arch: &str) -> Result<()> {
+    atomic_write(
+        &target.join(STAGE2_SCRIPT),
+        std::fs::read(script.path())?,
+        0o600,
+    )?;
+    let state_dir = target.join(STATE_DIR);
+    std::fs::create_dir_all(&state_dir)?;
+    atomic_write(
+        &state_dir.join("pending-stage2"),
+        format!("script=/{}\narch={}\n", STAGE2_SCRIPT, arch),
+        0o644,
+    )?;
+
+    Ok(())
+}
+
+/// Marker left at the root of a target by an interrupted bootstrap
+pub const INCOMPLETE_MARKER: &str = ".aoscbootstrap-incomplete";
+
+/// Record that the bootstrap was interrupted, and in which phase
+pub fn mark_incomplete(target: &Path, phase: &str) -> Result<()> {
+    atomic_write(
+        &target.join(INCOMPLETE_MARKER),
+        format!("phase={}\n", phase),
+        0o644,
+    )?;
+
+    Ok(())
+}
+
+/// The phase in which the bootstrap of the target was interrupted, if it was
+pub fn read_incomplete_marker(target: &Path) -> Option<String> {
+    let marker = std::fs::read_to_string(target.join(INCOMPLETE_MARKER)).ok()?;
+    let phase = marker
+        .lines()
+        .find_map(|l| l.strip_prefix("phase="))
+        .unwrap_or("unknown");
+
+    Some(phase.to_string())
+}
+
+pub fn clear_incomplete_marker(target: &Path) -> Result<()> {
+    match std::fs::remove_file(target.join(INCOMPLETE_MARKER)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Name of the checksum list of the package archives, checked by the install script
+/// with `sha256sum -c` before installing
+const ARCHIVE_CHECKSUMS: &str = "archives.sha256sums";
+
+/// Record the checksums of the packages to install in stage 2, so that the install script
+/// can verify the archives before installing them
+pub fn write_archive_checksums(target: &Path, packages: &[PackageMeta]) -> Result<()> {
+    let state_dir = target.join(STATE_DIR);
+    std::fs::create_dir_all(&state_dir)?;
+    let mut checksums = String::new();
+    for package in packages {
+        checksums.push_str(&format!("{}  {}\n", package.sha256, package.file_name()));
+    }
+    atomic_write(&state_dir.join(ARCHIVE_CHECKSUMS), checksums, 0o644)?;
+
+    Ok(())
+}
+
+/// Read the state recorded by an unfinished bootstrap, returning the path of the
+/// stage 2 script inside the target and the target architecture
+pub fn read_pending_stage2(target: &Path) -> Result<(String, String)> {
+    let state =
+        std::fs::read_to_string(target.join(STATE_DIR).join("pending-stage2")).context(format!(
+            "{} has no pending stage 2. Was it bootstrapped with --foreign or --stage1-only, or did stage 2 fail?",
+            target.display()
+        ))?;
+    let get = |key: &str| {
+        state
+            .lines()
+            .find_map(|l| l.strip_prefix(key)?.strip_prefix('='))
+            .map(|v| v.to_string())
+            .ok_or_else(|| anyhow!("Missing '{}' in the pending stage 2 state", key))
+    };
+    let script = get("script")?;
+    if !target.join(script.trim_start_matches('/')).is_file() {
+        return Err(anyhow!(
+            "Stage 2 script {} is missing in the target",
+            script
+        ));
+    }
+
+    Ok((script, get("arch")?))
+}
+
+/// Remove the stage 2 script and the pending state after stage 2 finishes
+pub fn clear_pending_stage2(target: &Path) -> Result<()> {
+    std::fs::remove_file(target.join(STAGE2_SCRIPT))?;
+    // also holds the progress written by the install script, see assets/bootstrap.sh
+    std::fs::remove_dir_all(target.join(STATE_DIR))?;
+
+    Ok(())
+}
+
+/// Write the script checking the installation after stage 2
+pub fn write_verify_script(target: &Path) -> Result<NamedTempFile> {
+    let mut f = NamedTempFile::new_in(target)?;
+    f.write_all(VERIFY_SCRIPT)?;
+
+    Ok(f)
+}
+
+/// Collect the problems reported by the verify script
+pub fn read_verify_problems(target: &Path) -> Result<Vec<String>> {
+    let log = std::fs::read_to_string(target.join(VERIFY_LOG))
+        .context("when reading the verification log")?;
+
+    Ok(log
+        .lines()
+        .filter_map(|l| l.strip_prefix("FAIL: "))
+        .map(|l| l.to_string())
+        .collect())
+}
+
+#[test]
+fn test_config_inheritance() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let write = |name: &str, content: &str| -> Result<()> {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content)?;
+        Ok(())
+    };
+    write(
+        "common.toml",
+        "stub-packages = [\"bash\", \"coreutils\"]\nbase-packages = [\"apt\"]\ninstall-recommends = true\n[solver]\nallow-downgrade = true\n",
+    )?;
+    write(
+        "desktop.toml",
+        "inherits = [\"common.toml\"]\nbase-packages = [\"apt\", \"plasma\"]\n",
+    )?;
+    write(
+        "server.toml",
+        "inherits = [\"common.toml\"]\nbase-packages = [\"openssh\"]\ninstall-recommends = false\n",
+    )?;
+    // diamond inheritance, with paths relative to the including file
+    write(
+        "recipes/workstation.toml",
+        "inherits = [\"../desktop.toml\", \"../server.toml\"]\nstub-packages = [\"dpkg\"]\n[solver]\nallow-uninstall = true\n",
+    )?;
+    let config = read_config(
+        dir.path().join("recipes/workstation.toml"),
+        &BTreeMap::new(),
+    )?;
+    assert_eq!(config.stub_packages, vec!["bash", "coreutils", "dpkg"]);
+    assert_eq!(config.base_packages, vec!["apt", "plasma", "openssh"]);
+    assert!(!config.install_recommends);
+    assert_eq!(config.solver.len(), 2);
+
+    write("loop-a.toml", "inherits = [\"loop-b.toml\"]\n")?;
+    write("loop-b.toml", "inherits = [\"loop-a.toml\"]\n")?;
+    assert!(read_config(dir.path().join("loop-a.toml"), &BTreeMap::new()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_variant() -> Result<()> {
+    let content = r#"
+stub-packages = ["bash", "dpkg"]
+base-packages = ["apt", "systemd"]
+
+[variants.container]
+base-packages = ["apt"]
+extra-packages = ["iproute2"]
+exclude-packages = ["systemd"]
+
+[variants.minbase]
+stub-packages = ["bash"]
+"#;
+    let mut config: Config = toml::from_str(content)?;
+    config.apply_variant("container")?;
+    assert_eq!(config.stub_packages, vec!["bash", "dpkg"]);
+    assert_eq!(config.base_packages, vec!["apt", "iproute2"]);
+    assert_eq!(config.exclude_packages, vec!["systemd"]);
+
+    let mut config: Config = toml::from_str(content)?;
+    let err = config.apply_variant("buildd").unwrap_err().to_string();
+    assert!(err.contains("container, minbase"));
+
+    Ok(())
+}
+
+#[test]
+fn test_config_scripts() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::create_dir_all(dir.path().join("recipes/scripts"))?;
+    std::fs::write(dir.path().join("common.sh"), "true\n")?;
+    std::fs::write(dir.path().join("recipes/scripts/desktop.sh"), "true\n")?;
+    std::fs::write(
+        dir.path().join("common.toml"),
+        "stub-packages = [\"bash\"]\nbase-packages = [\"apt\"]\n[scripts]\nstage2 = [\"common.sh\"]\n",
+    )?;
+    std::fs::write(
+        dir.path().join("recipes/desktop.toml"),
+        "inherits = [\"../common.toml\"]\n[scripts]\nstage2 = [\"scripts/desktop.sh\"]\nhooks = { post-stage1 = [\"scripts/missing.sh\"] }\n",
+    )?;
+    let config = read_config(dir.path().join("recipes/desktop.toml"), &BTreeMap::new())?;
+    let root = dir.path().canonicalize()?;
+    assert_eq!(
+        config.scripts.stage2,
+        vec![
+            root.join("common.sh").to_string_lossy().to_string(),
+            root.join("recipes/scripts/desktop.sh")
+                .to_string_lossy()
+                .to_string()
+        ]
+    );
+    let err = config.scripts.check_exists().unwrap_err().to_string();
+    assert!(err.contains("scripts/missing.sh"));
+    assert!(!err.contains("desktop.sh"));
+
+    Ok(())
+}
+
+#[test]
+fn test_select_groups() -> Result<()> {
+    let config: Config = toml::from_str(
+        r#"
+stub-packages = []
+base-packages = []
+default-groups = ["fonts"]
+
+[groups]
+fonts = ["noto-fonts", "noto-cjk-fonts"]
+firmware = ["firmware-free", "firmware-nonfree"]
+"#,
+    )?;
+    let selected = config.select_groups(&["firmware".to_string(), "fonts".to_string()])?;
+    let names = selected.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+    assert_eq!(names, vec!["fonts", "firmware"]);
+    assert_eq!(selected[1].1, vec!["firmware-free", "firmware-nonfree"]);
+    let err = config
+        .select_groups(&["input-methods".to_string()])
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("firmware, fonts"));
+
+    Ok(())
+}
+
+#[test]
+fn test_config_interpolation() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    std::fs::create_dir_all(dir.path().join("stable"))?;
+    std::fs::write(
+        dir.path().join("stable/common.toml"),
+        "stub-packages = [\"bash\"]\nbase-packages = []\n",
+    )?;
+    std::fs::write(
+        dir.path().join("recipe.toml"),
+        r#"
+inherits = ["${BRANCH}/common.toml"]
+base-packages = ["linux-kernel-${ARCH}"]
+mirror = "https://${AOSCBOOTSTRAP_TEST_HOST}/debs"
+[variants.container]
+extra-packages = ["price-$$5", "${ARCH}-${BRANCH}", "$HOME"]
+"#,
+    )?;
+    let mut vars = BTreeMap::new();
+    vars.insert("ARCH".to_string(), "amd64".to_string());
+    vars.insert("BRANCH".to_string(), "stable".to_string());
+    vars.insert(
+        "AOSCBOOTSTRAP_TEST_HOST".to_string(),
+        "repo.example.org".to_string(),
+    );
+    let mut config = read_config(dir.path().join("recipe.toml"), &vars)?;
+    assert_eq!(config.stub_packages, vec!["bash"]);
+    assert_eq!(config.base_packages, vec!["linux-kernel-amd64"]);
+    assert_eq!(
+        config.mirror.as_deref(),
+        Some("https://repo.example.org/debs")
+    );
+    config.apply_variant("container")?;
+    assert_eq!(
+        config.base_packages,
+        vec!["linux-kernel-amd64", "price-$5", "amd64-stable", "$HOME"]
+    );
+
+    vars.remove("BRANCH");
+    let err = read_config(dir.path().join("recipe.toml"), &vars).unwrap_err();
+    assert!(format!("{:?}", err).contains("Undefined variable 'BRANCH'"));
+    assert!(interpolate("${ARCH", &vars, true).is_err());
+    // the environment is read for the rest, without setting anything in it
+    assert_eq!(interpolate("${PATH}", &vars, true)?, std::env::var("PATH")?);
+
+    Ok(())
+}
+
+#[test]
+fn test_apply_arch_overrides() -> Result<()> {
+    let content = r#"
+stub-packages = ["bash"]
+base-packages = ["apt", "grub"]
+default-arch = ["amd64"]
+
+[arch.amd64]
+extra-packages = ["grub", "intel-ucode"]
+
+[arch.arm64]
+extra-packages = ["u-boot-tools", "raspi-firmware"]
+exclude-packages = ["grub"]
+"#;
+    let mut config: Config = toml::from_str(content)?;
+    assert_eq!(config.default_arch(), ["amd64"]);
+    assert_eq!(config.apply_arch_overrides("amd64"), vec!["intel-ucode"]);
+    assert_eq!(config.base_packages, vec!["apt", "grub", "intel-ucode"]);
+
+    let mut config: Config = toml::from_str(content)?;
+    config.apply_arch_overrides("arm64");
+    assert_eq!(
+        config.base_packages,
+        vec!["apt", "grub", "u-boot-tools", "raspi-firmware"]
+    );
+    assert_eq!(config.exclude_packages, vec!["grub"]);
+
+    let mut config: Config = toml::from_str(content)?;
+    assert!(config.apply_arch_overrides("riscv64").is_empty());
+
+    // without overrides, the default architectures are set with `arch`
+    let mut config: Config = toml::from_str(
+        "stub-packages = [\"bash\"]\nbase-packages = [\"apt\"]\narch = [\"arm64\"]\n",
+    )?;
+    assert_eq!(config.default_arch(), ["arm64"]);
+    assert!(config.apply_arch_overrides("arm64").is_empty());
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("recipe.toml");
+    std::fs::write(
+        &path,
+        "stub-packages = [\"bash\"]\nbase-packages = [\"apt\"]\narch = [\"arm64\"]\n",
+    )?;
+    let config = read_config(&path, &BTreeMap::new())?;
+    assert_eq!(config.default_arch(), ["arm64"]);
+    // and kept along with the overrides of the inherited configs
+    std::fs::write(
+        dir.path().join("common.toml"),
+        content.replace("default-arch = [\"amd64\"]\n", ""),
+    )?;
+    std::fs::write(
+        &path,
+        "inherits = [\"common.toml\"]\narch = [\"arm64\"]\n",
+    )?;
+    let mut config = read_config(&path, &BTreeMap::new())?;
+    assert_eq!(config.default_arch(), ["arm64"]);
+    assert_eq!(
+        config.apply_arch_overrides("arm64"),
+        vec!["u-boot-tools", "raspi-firmware"]
+    );
+    std::fs::write(
+        &path,
+        "stub-packages = [\"bash\"]\nbase-packages = [\"apt\"]\narch = [\"arm64\"]\ndefault-arch = [\"amd64\"]\n",
+    )?;
+    assert!(read_config(&path, &BTreeMap::new()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_pending_stage2() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    assert!(read_pending_stage2(target.path()).is_err());
+    let script = write_install_script(
+        &["bash_5.2_arm64.deb".to_string()],
+        "",
+        &[],
+        &Assets::default(),
+        target.path(),
+    )?;
+    save_pending_stage2(target.path(), script, "arm64")?;
+    let (script, arch) = read_pending_stage2(target.path())?;
+    assert_eq!(script, format!("/{}", STAGE2_SCRIPT));
+    assert_eq!(arch, "arm64");
+    let content = std::fs::read_to_string(target.path().join(STAGE2_SCRIPT))?;
+    assert!(content.contains("bash_5.2_arm64.deb"));
+    assert!(content.contains(&format!("/{}/stage2-progress", STATE_DIR)));
+    std::fs::write(target.path().join(STATE_DIR).join("stage2-progress"), "1\n")?;
+    clear_pending_stage2(target.path())?;
+    assert!(!target.path().join(STATE_DIR).exists());
+    assert!(read_pending_stage2(target.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_unpack_tarball() -> Result<()> {
+    let source = tempfile::tempdir()?;
+    let script = write_install_script(&[], "", &[], &Assets::default(), source.path())?;
+    save_pending_stage2(source.path(), script, "riscv64")?;
+    let archives = tempfile::tempdir()?;
+    let tarball = archives.path().join("stage1.tar.gz");
+    crate::fs::archive_gz_tarball(source.path(), &tarball)?;
+
+    let target = tempfile::tempdir()?;
+    unpack_tarball(&tarball, target.path())?;
+    let (script, arch) = read_pending_stage2(target.path())?;
+    assert_eq!(script, format!("/{}", STAGE2_SCRIPT));
+    assert_eq!(arch, "riscv64");
+    assert!(unpack_tarball(&archives.path().join("stage1.zip"), target.path()).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_incomplete_marker() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    assert_eq!(read_incomplete_marker(target.path()), None);
+    clear_incomplete_marker(target.path())?;
+    mark_incomplete(target.path(), "download")?;
+    assert_eq!(
+        read_incomplete_marker(target.path()).as_deref(),
+        Some("download")
+    );
+    clear_incomplete_marker(target.path())?;
+    assert_eq!(read_incomplete_marker(target.path()), None);
+
+    Ok(())
+}
+
+#[test]
+fn test_hooks_add() {
+    let mut hooks = Hooks {
+        post_stage1: vec!["/config/certs.sh".to_string()],
+        ..Default::default()
+    };
+    hooks.add("post-stage1=certs2.sh").unwrap();
+    hooks.add("pre-export=zerofree.sh").unwrap();
+    assert_eq!(
+        hooks.phase("post-stage1").unwrap(),
+        ["/config/certs.sh", "certs2.sh"]
+    );
+    assert_eq!(hooks.phase("pre-export").unwrap(), ["zerofree.sh"]);
+    assert!(hooks.phase("pre-stage1").is_err());
+    assert_eq!(hooks.all().count(), 3);
+    assert!(hooks
+        .add("pre-stage1=x.sh")
+        .unwrap_err()
+        .to_string()
+        .starts_with("Unknown hook phase 'pre-stage1'"));
+    assert!(hooks.add("post-stage2").is_err());
+    assert!(hooks.add("post-stage2=").is_err());
+}
+
+#[test]
+fn test_read_verify_problems() -> Result<()> {
+    let target = tempfile::tempdir()?;
+    assert!(read_verify_problems(target.path()).is_err());
+    std::fs::create_dir_all(target.path().join("var/log"))?;
+    std::fs::write(
+        target.path().join(VERIFY_LOG),
+        "=== dpkg --audit\n\nFAIL: only 3 of 5 packages are in /var/lib/dpkg/status\n=== apt-get check\nFAIL: apt-get check reported unmet dependencies\n",
+    )?;
+    assert_eq!(
+        read_verify_problems(target.path())?,
+        [
+            "only 3 of 5 packages are in /var/lib/dpkg/status",
+            "apt-get check reported unmet dependencies"
+        ]
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_accounts() -> Result<()> {
+    assert_eq!(
+        UserSpec::parse("aosc")?,
+        UserSpec {
+            name: "aosc".to_string(),
+            uid: None,
+            groups: vec![]
+        }
+    );
+    assert_eq!(
+        UserSpec::parse("aosc:1000:wheel,audio")?,
+        UserSpec {
+            name: "aosc".to_string(),
+            uid: Some(1000),
+            groups: vec!["wheel".to_string(), "audio".to_string()]
+        }
+    );
+    assert_eq!(UserSpec::parse("aosc::wheel")?.groups, ["wheel"]);
+    assert!(UserSpec::parse("Aosc").is_err());
+    assert!(UserSpec::parse("aosc:abc").is_err());
+    assert!(UserSpec::parse("aosc:1000:wheel:x").is_err());
+    assert!(UserSpec::parse("aosc:1000:wh;eel").is_err());
+
+    assert!(check_password_hash("$6$salt$abcdef").is_ok());
+    assert!(check_password_hash("hunter2").is_err());
+    assert!(check_password_hash("$6$salt$").is_err());
+    assert!(check_password_hash("$6$sa:lt$abc").is_err());
+
+    assert_eq!(generate_account_script(None, false, &[]), "");
+    let script = generate_account_script(
+        Some("$6$it's$abc"),
+        false,
+        &[UserSpec::parse("aosc:1000:wheel")?],
+    );
+    assert!(script.contains("printf '%s\\n' 'root:$6$it'\\''s$abc' | chpasswd -e\n"));
+    assert!(script.contains("useradd -m -s /bin/bash -u 1000 -G 'wheel' 'aosc'\n"));
+    assert!(generate_account_script(None, true, &[]).contains("usermod -L root\n"));
+
+    Ok(())
+}
+
+#[test]
+fn test_extract_deb() -> Result<()> {
+    use flate2::write::GzEncoder;
+
+    fn make_tar(files: &[(&str, u32, &[u8])]) -> Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, mode, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(*mode);
+            header.set_cksum();
+            tar.append_data(&mut header, path, *content)?;
+        }
+
+        Ok(tar.into_inner()?)
+    }
+
+    fn make_deb(data_name: &str, compress: fn(Vec<u8>) -> Vec<u8>) -> Result<Vec<u8>> {
+        let control = make_tar(&[
+            (
+                "control",
+                0o644,
+                b"Package: hello\nVersion: 1.0\nArchitecture: amd64\nMulti-Arch: same\n",
+            ),
+            ("conffiles", 0o644, b"/etc/hello.conf\n"),
+            ("preinst", 0o755, b"#!/bin/sh\n"),
+            ("postinst", 0o755, b"#!/bin/sh\n"),
+        ])?;
+        let data = compress(make_tar(&[
+            ("usr/bin/hello", 0o755, b"echo"),
+            ("etc/hello.conf", 0o644, b""),
+        ])?);
+        let mut deb = ar::Builder::new(Vec::new());
+        deb.append(
+            &ar::Header::new(b"debian-binary".to_vec(), 4),
+            &b"2.0\n"[..],
+        )?;
+        deb.append(
+            &ar::Header::new(b"control.tar".to_vec(), control.len() as u64),
+            &control[..],
+        )?;
+        deb.append(
+            &ar::Header::new(data_name.as_bytes().to_vec(), data.len() as u64),
+            &data[..],
+        )?;
+
+        Ok(deb.into_inner()?)
+    }
+
+    let formats: [(&str, fn(Vec<u8>) -> Vec<u8>); 5] = [
+        ("data.tar", |d| d),
+        ("data.tar.gz", |d| {
+            let mut e = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            e.write_all(&d).unwrap();
+            e.finish().unwrap()
+        }),
+        ("data.tar.bz2", |d| {
+            let mut e = bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+            e.write_all(&d).unwrap();
+            e.finish().unwrap()
+        }),
+        ("data.tar.xz", |d| {
+            let mut e = xz2::write::XzEncoder::new(Vec::new(), 6);
+            e.write_all(&d).unwrap();
+            e.finish().unwrap()
+        }),
+        ("data.tar.zst", |d| zstd::encode_all(&d[..], 0).unwrap()),
+    ];
+    for (name, compress) in formats {
+        let target = tempfile::tempdir()?;
+        extract_deb_with_control(&make_deb(name, compress)?[..], target.path())?;
+        let path = target.path().join("usr/bin/hello");
+        assert_eq!(std::fs::read_to_string(&path)?, "echo", "{}", name);
+        assert_eq!(
+            path.metadata()?.permissions().mode() & 0o777,
+            0o755,
+            "{}",
+            name
+        );
+    }
+    let target = tempfile::tempdir()?;
+    let control = extract_deb_with_control(&make_deb("data.tar", |d| d)?[..], target.path())?;
+    register_unpacked(target.path(), &[control])?;
+    assert_eq!(
+        std::fs::read_to_string(target.path().join("var/lib/dpkg/status"))?,
+        "Package: hello\nStatus: install ok unpacked\nVersion: 1.0\nArchitecture: amd64\n\
+         Multi-Arch: same\nConffiles:\n /etc/hello.conf newconffile\n\n"
+    );
+    // registering again replaces the entry
+    let control = extract_deb_with_control(&make_deb("data.tar", |d| d)?[..], target.path())?;
+    register_unpacked(target.path(), &[control])?;
+    assert_eq!(
+        std::fs::read_to_string(target.path().join("var/lib/dpkg/status"))?
+            .matches("Package: hello\n")
+            .count(),
+        1
+    );
+    // and stage 2 runs its preinst once
+    assert_eq!(
+        std::fs::read_to_string(target.path().join(STATE_DIR).join(STUB_PREINST))?,
+        "hello:amd64\n"
+    );
+    let info = target.path().join("var/lib/dpkg/info");
+    assert_eq!(
+        std::fs::read_to_string(info.join("hello:amd64.list"))?,
+        "/.\n/usr/bin/hello\n/etc/hello.conf\n"
+    );
+    assert_eq!(
+        info.join("hello:amd64.postinst")
+            .metadata()?
+            .permissions()
+            .mode()
+            & 0o777,
+        0o755
+    );
+    // staging gives the same result
+    let staged = tempfile::tempdir()?;
+    let mut staging = Vec::new();
+    let mut staged_control = stage_deb(&make_deb("data.tar.xz", formats[3].1)?[..], &mut staging)?;
+    assert!(staged_control.paths.is_empty());
+    unpack_staged(&staging[..], staged.path(), &mut staged_control)?;
+    assert_eq!(
+        staged_control.paths,
+        [
+            PathBuf::from("usr/bin/hello"),
+            PathBuf::from("etc/hello.conf")
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(staged.path().join("usr/bin/hello"))?,
+        "echo"
+    );
+    let err =
+        extract_deb_with_control(&make_deb("data.tar.lz4", |d| d)?[..], target.path()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "data archive not found or format unsupported"
+    );
+    // a second data archive appended to the deb is refused
+    let mut smuggled = make_deb("data.tar", |d| d)?;
+    let payload = make_deb("data.tar", |d| d)?;
+    let mut extra = ar::Builder::new(Vec::new());
+    extra.append(
+        &ar::Header::new(b"data.tar".to_vec(), payload.len() as u64),
+        &payload[..],
+    )?;
+    // skip the global header of the second archive
+    smuggled.extend_from_slice(&extra.into_inner()?[8..]);
+    let err = extract_deb_with_control(&smuggled[..], target.path()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "unexpected member data.tar in the deb archive"
+    );
+    // so is a garbage or truncated member header
+    let mut garbage = make_deb("data.tar", |d| d)?;
+    garbage.extend_from_slice(&[b'x'; 60]);
+    let err = extract_deb_with_control(&garbage[..], target.path()).unwrap_err();
+    assert_eq!(err.to_string(), "malformed member in the deb archive");
+    let mut truncated = make_deb("data.tar", |d| d)?;
+    truncated.extend_from_slice(b"_extra          0           0     0     100644  ");
+    let err = extract_deb_with_control(&truncated[..], target.path()).unwrap_err();
+    assert_eq!(err.to_string(), "malformed member in the deb archive");
+
+    Ok(())
+}
+
+#[test]
+fn test_unpack_tar_hostile() -> Result<()> {
+    use tar::EntryType::{Directory, Link, Regular, Symlink};
+
+    // tar::Builder refuses to write such paths, so the names are copied into the header
+    fn make_tar(entries: &[(&str, tar::EntryType, &str)]) -> Result<Vec<u8>> {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, kind, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            let gnu = header.as_gnu_mut().unwrap();
+            gnu.name[..path.len()].copy_from_slice(path.as_bytes());
+            gnu.linkname[..link.len()].copy_from_slice(link.as_bytes());
+            header.set_entry_type(*kind);
+            header.set_mode(0o755);
+            let content: &[u8] = if kind.is_file() { b"bad" } else { b"" };
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            tar.append(&header, content)?;
+        }
+
+        Ok(tar.into_inner()?)
+    }
+
+    let outside = tempfile::tempdir()?;
+    let outside_path = outside.path().to_string_lossy().to_string();
+    let absolute = format!("{}/escaped", outside_path);
+    let hostile = [
+        vec![("../escaped", Regular, "")],
+        vec![("usr/../../escaped", Regular, "")],
+        vec![(absolute.as_str(), Regular, "")],
+        vec![("up", Symlink, "../"), ("up/escaped", Regular, "")],
+        vec![("up", Symlink, "/.."), ("up/escaped", Regular, "")],
+        vec![("up", Symlink, "usr/../.."), ("up/escaped", Regular, "")],
+        vec![("escaped", Link, "../escaped")],
+    ];
+    for entries in hostile {
+        let sandbox = tempfile::tempdir()?;
+        let target = sandbox.path().join("target");
+        std::fs::create_dir(&target)?;
+        assert!(
+            unpack_tar(&make_tar(&entries)?[..], &target).is_err(),
+            "{:?}",
+            entries
+        );
+        assert_eq!(
+            std::fs::read_dir(sandbox.path())?.count(),
+            1,
+            "{:?}",
+            entries
+        );
+        assert_eq!(
+            std::fs::read_dir(outside.path())?.count(),
+            0,
+            "{:?}",
+            entries
+        );
+    }
+
+    // relative symlinks inside the target are followed, as with a merged /usr
+    let target = tempfile::tempdir()?;
+    let paths = unpack_tar(
+        &make_tar(&[
+            ("./usr/bin/", Directory, ""),
+            ("./bin", Symlink, "usr/bin"),
+            ("./bin/hello", Regular, ""),
+            ("./usr/bin/hi", Link, "./bin/hello"),
+        ])?[..],
+        target.path(),
+    )?;
+    assert_eq!(
+        paths,
+        ["usr/bin", "bin", "bin/hello", "usr/bin/hi"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        std::fs::read_to_string(target.path().join("usr/bin/hello"))?,
+        "bad"
+    );
+    assert!(target.path().join("usr/bin/hi").is_file());
+    // so are absolute ones, which point from the root of the target, not of the host
+    let target = tempfile::tempdir()?;
+    unpack_tar(
+        &make_tar(&[
+            ("./run", Symlink, "/var/run"),
+            ("./run/pid", Regular, ""),
+            ("./evil", Symlink, outside_path.as_str()),
+            ("./evil/escaped", Regular, ""),
+        ])?[..],
+        target.path(),
+    )?;
+    assert!(target.path().join("var/run/pid").is_file());
+    assert!(target
+        .path()
+        .join(outside_path.trim_start_matches('/'))
+        .join("escaped")
+        .is_file());
+    assert_eq!(std::fs::read_dir(outside.path())?.count(), 0);
+
+    Ok(())
+}
+
+#[test]
+fn test_assets() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let path = |name: &str| Some(dir.path().join(name).to_string_lossy().to_string());
+    std::fs::write(dir.path().join("template.sh"), "PACKAGES=({})\n")?;
+    std::fs::write(dir.path().join("no-placeholder.sh"), "PACKAGES=()\n")?;
+    std::fs::write(dir.path().join("cleanup.sh"), "echo cleaning\n")?;
+    std::fs::write(dir.path().join("pack.tar.xz"), BOOTSTRAP_PACK)?;
+    std::fs::write(dir.path().join("bad.tar.xz"), "not an archive")?;
+    let assets = Assets {
+        bootstrap_pack: path("pack.tar.xz"),
+        install_template: path("template.sh"),
+        cleanup_script: path("cleanup.sh"),
+    };
+    assets.check()?;
+    let target = tempfile::tempdir()?;
+    let script = write_install_script(
+        &["bash_5.2_amd64.deb".to_string()],
+        "",
+        &["logs"],
+        &assets,
+        target.path(),
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(script.path())?,
+        "PACKAGES=('bash_5.2_amd64.deb' )\n\nCLEAN_STEPS=(logs)\necho cleaning\n"
+    );
+    for broken in [
+        Assets {
+            install_template: path("no-placeholder.sh"),
+            ..Default::default()
+        },
+        Assets {
+            bootstrap_pack: path("bad.tar.xz"),
+            ..Default::default()
+        },
+        Assets {
+            cleanup_script: path("missing.sh"),
+            ..Default::default()
+        },
+    ] {
+        assert!(broken.check().is_err());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_select_clean_steps() -> Result<()> {
+    let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+    assert_eq!(select_clean_steps(&[], &[])?, DEFAULT_CLEAN_STEPS);
+    assert_eq!(
+        select_clean_steps(&names(&["tmp", "apt-cache"]), &[])?,
+        ["apt-cache", "tmp"]
+    );
+    let steps = select_clean_steps(&[], &names(&["logs", "unowned-files"]))?;
+    assert!(!steps.contains(&"logs") && !steps.contains(&"unowned-files"));
+    assert!(steps.contains(&"apt-cache") && !steps.contains(&"doc-trim"));
+    assert!(select_clean_steps(&names(&["docs"]), &[]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_remote_config() -> Result<()> {
+    let root = tempfile::tempdir()?;
+    let recipes = root.path().join("recipes");
+    std::fs::create_dir_all(recipes.join("lists"))?;
+    std::fs::write(
+        recipes.join("base.toml"),
+        "stub-packages = [\"bash\"]\nbase-packages = [\"vim\"]\n",
+    )?;
+    std::fs::write(
+        recipes.join("lists/desktop.lst"),
+        "plasma\n%include common.lst\n",
+    )?;
+    std::fs::write(recipes.join("lists/common.lst"), "firefox\n")?;
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let port = server.server_addr().to_ip().unwrap().port();
+    let dir = root.path().to_path_buf();
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let url = request.url().to_string();
+            // /moved/ is redirected on the same origin, /away/ to another one
+            let location = if let Some(path) = url.strip_prefix("/moved/") {
+                Some(format!("/recipes/{}", path))
+            } else {
+                url.strip_prefix("/away/")
+                    .map(|path| format!("http://localhost:{}/recipes/{}", port, path))
+            };
+            let response = match (location, std::fs::read(dir.join(&url[1..]))) {
+                (Some(location), _) => tiny_http::Response::from_data(Vec::new())
+                    .with_status_code(302)
+                    .with_header(
+                        tiny_http::Header::from_bytes("Location", location.as_bytes()).unwrap(),
+                    ),
+                (None, Ok(data)) => tiny_http::Response::from_data(data),
+                (None, Err(_)) => tiny_http::Response::from_data(Vec::new()).with_status_code(404),
+            };
+            request.respond(response).ok();
+        }
+    });
+    let url = |path: &str| Url::parse(&format!("http://127.0.0.1:{}{}", port, path));
+
+    // the package lists are resolved against the URL the config was redirected to
+    let config = read_remote_config(&url("/moved/base.toml")?, &BTreeMap::new(), None)?;
+    assert_eq!(config.url, Some(url("/recipes/base.toml")?));
+    let lists = config.package_lists(&["lists/desktop.lst".to_string()])?;
+    assert_eq!(
+        std::fs::read_to_string(&lists[0])?,
+        "plasma\n%include common.lst\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(Path::new(&lists[0]).with_file_name("common.lst"))?,
+        "firefox\n"
+    );
+    let err = read_remote_config(&url("/away/base.toml")?, &BTreeMap::new(), None)
+        .unwrap_err()
+        .to_string();
+    assert!(err.contains("redirected to another origin"), "{}", err);
+    // only the built-in variables are interpolated, never the environment
+    std::fs::write(
+        recipes.join("env.toml"),
+        "stub-packages = [\"bash\"]\nbase-packages = [\"vim\"]\nmirror = \"https://${PATH}@mirror.example.org/${BRANCH}\"\n",
+    )?;
+    let mut vars = BTreeMap::new();
+    vars.insert("BRANCH".to_string(), "stable".to_string());
+    let err = read_remote_config(&url("/recipes/env.toml")?, &vars, None).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("Undefined variable 'PATH'"),
+        "{:#}",
+        err
+    );
+    std::fs::write(
+        recipes.join("env.toml"),
+        "stub-packages = [\"bash\"]\nbase-packages = [\"vim\"]\nmirror = \"https://mirror.example.org/${BRANCH}\"\n",
+    )?;
+    let config = read_remote_config(&url("/recipes/env.toml")?, &vars, None)?;
+    assert_eq!(
+        config.mirror.as_deref(),
+        Some("https://mirror.example.org/stable")
+    );
+
+    Ok(())
+}
+
+#[test]
+fn test_config_errors() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let read = |content: &str| -> String {
+        let path = dir.path().join("recipe.toml");
+        std::fs::write(&path, content).unwrap();
+        match read_config(&path, &BTreeMap::new()) {
+            Ok(_) => String::new(),
+            Err(e) => format!("{:#}", e),
+        }
+    };
+    let err = read("stub-packages = [\"bash\"]\nbase-packages = [\"vim\"\n");
+    assert!(
+        err.contains("recipe.toml: TOML parse error at line 2"),
+        "{}",
+        err
+    );
+    assert!(err.contains("base-packages = [\"vim\""), "{}", err);
+    let err = read("stub-packages = [\"bash\"]\nbase-package = [\"vim\"]\n");
+    assert_eq!(
+        err,
+        "unknown key 'base-package', did you mean 'base-packages'?"
+    );
+    let err = read("stub-packages = [\"bash\"]\nbase-packages = [\"vim\"]\nfoo = 1\n");
+    assert_eq!(err, "unknown key 'foo'");
+    let err = read("stub-packages = [\"bash\"]\nbase-packages = []\n");
+    assert_eq!(err, "'base-packages' must not be empty");
+    let err = read("stub-packages = [\"bash\"]\nbase-packages = 1\n");
+    assert!(err.contains("recipe.toml: invalid type"), "{}", err);
+    assert_eq!(
+        read("stub-packages = [\"bash\"]\nbase-packages = [\"vim\"]\n"),
+        ""
+    );
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_apt_extended_state() -> Result<()> {
+    use crate::solv::pkg;
+
+    let transaction = [
+        pkg("bash", "amd64"),
+        pkg("aosc-aaa", "all"),
+        pkg("glibc", "amd64"),
+        pkg("glibc", "i386"),
+        pkg("tzdata", "all"),
+        pkg("wine", "i386"),
+    ];
+    let manual = [
+        "bash",
+        "aosc-aaa>=1.0",
+        "glibc",
+        "wine:i386",
+        "not-resolved",
+    ]
+    .iter()
+    .map(|s| s.to_string())
+    .collect::<Vec<_>>();
+    let target = tempfile::tempdir()?;
+    std::fs::create_dir_all(target.path().join("var/lib/apt"))?;
+    generate_apt_extended_state(target.path(), &manual, &transaction, "amd64")?;
+    let content = std::fs::read_to_string(target.path().join("var/lib/apt/extended_states"))?;
+    let parsed = oma_debcontrol::parse_str(&content).map_err(|e| anyhow!("{e}"))?;
+    let auto = parsed
+        .iter()
+        .map(|p| {
+            let field = |name: &str| {
+                p.fields
+                    .iter()
+                    .find(|f| f.name == name)
+                    .map(|f| f.value.to_string())
+                    .unwrap()
+            };
+            assert_eq!(field("Auto-Installed"), "1");
+            (field("Package"), field("Architecture"))
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        auto,
+        [
+            ("glibc".to_string(), "i386".to_string()),
+            ("tzdata".to_string(), "amd64".to_string())
+        ]
+    );
+    // every package in the transaction is either requested or recorded as automatic
+    assert_eq!(auto.len() + 4, transaction.len());
+
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use std::{
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context, Result};
+use log::info;
+use sequoia_openpgp::{cert::CertParser, parse::Parse, serialize::Serialize, Packet, PacketPile};
+use sha2::{Digest, Sha256};
+use tempfile::TempDir;
+
+use crate::fs::atomic_write;
+
+/// Keys trusted by APT for all the sources
+const TRUSTED_DIR: &str = "etc/apt/trusted.gpg.d";
+/// Keys only trusted for the sources referencing them with `Signed-By`
+const KEYRINGS_DIR: &str = "etc/apt/keyrings";
+
+/// A keyring given with `--apt-key`, dearmored
+#[derive(Debug)]
+pub struct AptKey {
+    /// File name in the target, without the directory
+    pub file_name: String,
+    /// The certificates, in the binary format read by APT
+    pub data: Vec<u8>,
+}
+
+/// Read the given keyrings, checking that they only contain OpenPGP certificates
+pub fn load(paths: &[PathBuf]) -> Result<Vec<AptKey>> {
+    let mut keys: Vec<AptKey> = Vec::new();
+    for path in paths {
+        let key = load_one(path).context(format!("Invalid APT key {}", path.display()))?;
+        if keys.iter().any(|k| k.file_name == key.file_name) {
+            return Err(anyhow!(
+                "More than one APT key would be installed as {}",
+                key.file_name
+            ));
+        }
+        keys.push(key);
+    }
+
+    Ok(keys)
+}
+
+fn load_one(path: &Path) -> Result<AptKey> {
+    let content = std::fs::read(path)?;
+    let mut data = Vec::new();
+    // armored and binary keyrings are both accepted
+    for cert in CertParser::from_bytes(&content)? {
+        let cert = cert?;
+        if cert.is_tsk() {
+            return Err(anyhow!(
+                "{} contains secret key material",
+                cert.fingerprint()
+            ));
+        }
+        info!(
+            "Trusting APT key {} from {}",
+            cert.fingerprint(),
+            path.display()
+        );
+        cert.serialize(&mut data)?;
+    }
+    if data.is_empty() {
+        return Err(anyhow!("No OpenPGP certificate found"));
+    }
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .filter(|s| !s.is_empty() && !s.starts_with('.'))
+        .ok_or_else(|| anyhow!("Cannot name the key after its file"))?;
+
+    Ok(AptKey {
+        file_name: format!("{}.gpg", stem),
+        data,
+    })
+}
+
+/// Install the keys into the target, returning their paths in the target. With deb822 sources,
+/// the keys go to `/etc/apt/keyrings` to be referenced with `Signed-By` instead of being
+/// trusted for every source.
+pub fn install(root: &Path, keys: &[AptKey], deb822: bool) -> Result<Vec<String>> {
+    let dir = if deb822 { KEYRINGS_DIR } else { TRUSTED_DIR };
+    if !keys.is_empty() {
+        std::fs::create_dir_all(root.join(dir))?;
+    }
+    let mut paths = Vec::new();
+    for key in keys {
+        atomic_write(&root.join(dir).join(&key.file_name), &key.data, 0o644)?;
+        paths.push(format!("/{}/{}", dir, key.file_name));
+    }
+
+    Ok(paths)
+}
+
+/// Make a root containing the given keys, and the keys trusted by the host with `host`,
+/// to verify the InRelease files with
+pub fn verification_root(keys: &[AptKey], host: bool) -> Result<TempDir> {
+    let root = tempfile::tempdir().context("when creating a temporary directory")?;
+    let trusted = root.path().join(TRUSTED_DIR);
+    std::fs::create_dir_all(&trusted)?;
+    if host {
+        if let Ok(entries) = std::fs::read_dir(Path::new("/").join(TRUSTED_DIR)) {
+            for entry in entries {
+                let entry = entry?;
+                if entry.file_type()?.is_file() {
+                    std::fs::copy(entry.path(), trusted.join(entry.file_name()))?;
+                }
+            }
+        }
+        if Path::new("/etc/apt/trusted.gpg").is_file() {
+            std::fs::copy(
+                "/etc/apt/trusted.gpg",
+                root.path().join("etc/apt/trusted.gpg"),
+            )?;
+        }
+    }
+    for key in keys {
+        std::fs::write(trusted.join(&key.file_name), &key.data)?;
+    }
+
+    Ok(root)
+}
+
+/// Checksum of the keys trusted by APT under a root, which changes whenever one of them is
+/// added, removed or replaced
+pub fn trusted_keys_digest(root: &Path) -> Result<String> {
+    let mut files = Vec::new();
+    if let Ok(entries) = std::fs::read_dir(root.join(TRUSTED_DIR)) {
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                files.push(entry.path());
+            }
+        }
+    }
+    files.sort();
+    files.push(root.join("etc/apt/trusted.gpg"));
+    let mut hasher = Sha256::new();
+    for path in files.iter().filter(|p| p.is_file()) {
+        let data = std::fs::read(path)?;
+        hasher.update(path.strip_prefix(root)?.as_os_str().as_bytes());
+        hasher.update((data.len() as u64).to_le_bytes());
+        hasher.update(&data);
+    }
+
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// IDs of the keys which made the signatures of a clearsigned file
+pub fn signature_issuers(signed: &str) -> Vec<String> {
+    let Some(start) = signed.find("-----BEGIN PGP SIGNATURE-----") else {
+        return Vec::new();
+    };
+    let Ok(pile) = PacketPile::from_bytes(signed[start..].as_bytes()) else {
+        return Vec::new();
+    };
+    let mut issuers = Vec::new();
+    for packet in pile.descendants() {
+        if let Packet::Signature(sig) = packet {
+            for issuer in sig.get_issuers() {
+                let id = issuer.to_hex();
+                if !issuers.contains(&id) {
+                    issuers.push(id);
+                }
+            }
+        }
+    }
+
+    issuers
+}
+
+#[test]
+fn test_load_valid_key() -> Result<()> {
+    use sequoia_openpgp::{cert::CertBuilder, serialize::SerializeInto, Cert};
+
+    let (tsk, _) =
+        CertBuilder::general_purpose(None, Some("Downstream <repo@example.org>")).generate()?;
+    let cert = tsk.clone().strip_secret_key_material();
+    let dir = tempfile::tempdir()?;
+    // an armored key is dearmored, a binary one is kept as is
+    let armored = dir.path().join("downstream.asc");
+    std::fs::write(&armored, cert.armored().to_vec()?)?;
+    let binary = dir.path().join("other.gpg");
+    std::fs::write(&binary, cert.to_vec()?)?;
+    let keys = load(&[armored, binary])?;
+    assert_eq!(
+        keys.iter()
+            .map(|k| k.file_name.as_str())
+            .collect::<Vec<_>>(),
+        ["downstream.gpg", "other.gpg"]
+    );
+    for key in &keys {
+        assert_eq!(
+            Cert::from_bytes(&key.data)?.fingerprint(),
+            cert.fingerprint()
+        );
+    }
+    let root = tempfile::tempdir()?;
+    install(root.path(), &keys, false)?;
+    let installed = std::fs::read(root.path().join(TRUSTED_DIR).join("downstream.gpg"))?;
+    assert_eq!(Cert::from_bytes(&installed)?, cert);
+
+    // a key with its secret part is refused
+    let secret = dir.path().join("secret.asc");
+    std::fs::write(&secret, tsk.as_tsk().armored().to_vec()?)?;
+    let e = load(&[secret]).unwrap_err();
+    assert!(format!("{:#}", e).contains("secret key material"));
+
+    Ok(())
+}
+
+#[test]
+fn test_load() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let write = |name: &str, content: &str| -> Result<PathBuf> {
+        let path = dir.path().join(name);
+        std::fs::write(&path, content)?;
+        Ok(path)
+    };
+    let garbage = write("garbage.asc", "not a key\n")?;
+    let e = load(&[garbage]).unwrap_err();
+    assert!(format!("{:#}", e).contains("Invalid APT key"));
+    let empty = write("empty.gpg", "")?;
+    assert!(load(&[empty]).is_err());
+
+    let root = tempfile::tempdir()?;
+    let key = AptKey {
+        file_name: "downstream.gpg".to_string(),
+        data: vec![0x99, 0x00],
+    };
+    assert_eq!(
+        install(root.path(), std::slice::from_ref(&key), false)?,
+        ["/etc/apt/trusted.gpg.d/downstream.gpg"]
+    );
+    assert_eq!(
+        install(root.path(), &[key], true)?,
+        ["/etc/apt/keyrings/downstream.gpg"]
+    );
+    assert_eq!(
+        std::fs::read(root.path().join("etc/apt/keyrings/downstream.gpg"))?,
+        [0x99, 0x00]
+    );
+
+    Ok(())
+}
@@ -0,0 +1,73 @@
+//! Bootstrap AOSC OS from scratch, as the `aoscbootstrap` command does.
+//!
+//! [BootstrapBuilder] takes the same options as the command line, and reports the progress
+//! of the run to a [ProgressReporter] instead of the terminal:
+//!
+//! ```no_run
+//! use aoscbootstrap::{BootstrapBuilder, Event, ProgressReporter};
+//!
+//! struct Printer;
+//!
+//! impl ProgressReporter for Printer {
+//!     fn event(&self, event: &Event) {
+//!         if let Event::Progress { phase, current, total, .. } = event {
+//!             println!("{}: {}/{}", phase, current, total);
+//!         }
+//!     }
+//! }
+//!
+//! let report = BootstrapBuilder::new()
+//!     .config("/usr/share/aoscbootstrap/recipes/base.toml")
+//!     .branch("stable")
+//!     .target("/var/lib/images/base")
+//!     .arches(["amd64"])
+//!     .include(["vim"])
+//!     .export_tar_zst("/var/lib/images/base.tar.zst")
+//!     .run(&Printer)?;
+//! for artifact in &report.artifacts {
+//!     println!("{} {}", artifact.sha256, artifact.path);
+//! }
+//! # Ok::<(), aoscbootstrap::Error>(())
+//! ```
+//!
+//! The messages are logged with the [log] crate, to the logger set up by the program if any.
+//!
+//! The state of a run is kept per process, so only one bootstrap can run at a time: `run`
+//! fails with [Error::Busy] while another one is running.
+
+mod arch;
+mod builder;
+mod cancel;
+#[doc(hidden)]
+pub mod cli;
+mod cpio;
+mod docker;
+mod doctor;
+mod error;
+mod events;
+mod fs;
+mod guest;
+mod image;
+mod install;
+mod keyring;
+mod lint;
+mod lockfile;
+mod logging;
+mod manifest;
+mod network;
+mod pin;
+mod snapshot;
+mod solv;
+mod timing;
+mod topics;
+
+pub use builder::{BootstrapBuilder, BuildReport};
+pub use cancel::CancellationToken;
+pub use error::BootstrapError as Error;
+pub use events::{Artifact, Event, ProgressReporter};
+pub use install::Config;
+pub use solv::PackageMeta;
+pub use timing::PhaseTiming;
+pub use topics::Topic;
+
+const DEFAULT_MIRROR: &str = "https://repo.aosc.io/debs";
@@ -0,0 +1,261 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::{
+    cli::{parse_arch_qualifier, parse_list_line, ListLine},
+    install::Config,
+    solv::{package_name, Pool},
+};
+
+/// A problem found in a config or a package list
+pub struct Finding {
+    /// File (and line, if known) where the problem is
+    pub location: String,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.location, self.message)
+    }
+}
+
+/// A package entry, along with where it is listed
+pub struct Entry {
+    pub spec: String,
+    pub location: String,
+}
+
+/// Collect the package entries from the config and the package lists, checking them
+/// for problems along the way
+pub fn check_config(
+    config_path: &str,
+    config: &Config,
+    lists: &[String],
+) -> (Vec<Entry>, Vec<Finding>) {
+    let mut entries = Vec::new();
+    let mut findings = Vec::new();
+    let finding = |message: String| Finding {
+        location: config_path.to_string(),
+        message,
+    };
+
+    // unknown keys and empty package lists are already rejected when reading the config
+    for (key, list) in [
+        ("stub-packages", &config.stub_packages),
+        ("base-packages", &config.base_packages),
+    ] {
+        for spec in list {
+            entries.push(Entry {
+                spec: spec.clone(),
+                location: format!("{} ({})", config_path, key),
+            });
+        }
+    }
+    for exclude in &config.exclude_packages {
+        if config.stub_packages.contains(exclude) {
+            findings.push(finding(format!("stub package {} is excluded", exclude)));
+        }
+    }
+    for list in lists {
+        check_list(Path::new(list), &mut entries, &mut findings, 0);
+    }
+    check_entries(&entries, &mut findings);
+
+    (entries, findings)
+}
+
+/// Collect the entries from a package list, following `%include` directives. The entries
+/// of `%ifarch` blocks get the architectures of the block as their qualifier.
+fn check_list(path: &Path, entries: &mut Vec<Entry>, findings: &mut Vec<Finding>, depth: usize) {
+    if depth > 32 {
+        findings.push(Finding {
+            location: path.display().to_string(),
+            message: "recursion limit exceeded, is there a loop?".to_string(),
+        });
+        return;
+    }
+    let lines = match File::open(path).and_then(|f| {
+        BufReader::new(f)
+            .lines()
+            .collect::<std::io::Result<Vec<_>>>()
+    }) {
+        Ok(lines) => lines,
+        Err(e) => {
+            findings.push(Finding {
+                location: path.display().to_string(),
+                message: format!("failed to read: {}", e),
+            });
+            return;
+        }
+    };
+    let mut blocks: Vec<Vec<&str>> = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        let location = format!("{}:{}", path.display(), i + 1);
+        let line = match parse_list_line(line) {
+            Ok(line) => line,
+            Err(e) => {
+                findings.push(Finding {
+                    location,
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        match line {
+            ListLine::Blank => (),
+            ListLine::Include(inc) => match include_path(path, inc) {
+                Ok(inc) if inc.is_file() => check_list(&inc, entries, findings, depth + 1),
+                _ => findings.push(Finding {
+                    location,
+                    message: format!("included file {} does not exist", inc),
+                }),
+            },
+            ListLine::IfArch(arches) => blocks.push(arches),
+            ListLine::EndIf => {
+                if blocks.pop().is_none() {
+                    findings.push(Finding {
+                        location,
+                        message: "%endif without %ifarch".to_string(),
+                    });
+                }
+            }
+            ListLine::Entry(spec) => {
+                // nested blocks only apply to the architectures they all name
+                let arches = blocks.split_first().map(|(outer, inner)| {
+                    outer
+                        .iter()
+                        .filter(|arch| inner.iter().all(|block| block.contains(arch)))
+                        .copied()
+                        .collect::<Vec<_>>()
+                });
+                let spec = match arches {
+                    Some(arches) if arches.is_empty() => {
+                        findings.push(Finding {
+                            location,
+                            message: format!(
+                                "{} is never listed, the enclosing %ifarch blocks have no architecture in common",
+                                spec
+                            ),
+                        });
+                        continue;
+                    }
+                    Some(arches) if !spec.contains('[') => {
+                        format!("{} [{}]", spec, arches.join(" "))
+                    }
+                    _ => spec.to_string(),
+                };
+                entries.push(Entry { spec, location });
+            }
+        }
+    }
+    if !blocks.is_empty() {
+        findings.push(Finding {
+            location: path.display().to_string(),
+            message: "%ifarch without %endif".to_string(),
+        });
+    }
+}
+
+/// Resolve an included path relative to the including file
+fn include_path(path: &Path, inc: &str) -> Result<PathBuf> {
+    let real_path = path.canonicalize()?;
+    let real_path = real_path.parent().ok_or_else(|| anyhow!("Invalid path"))?;
+
+    Ok(real_path.join(inc))
+}
+
+/// Check the entries for invalid qualifiers and duplicates
+fn check_entries(entries: &[Entry], findings: &mut Vec<Finding>) {
+    let mut seen: HashMap<(&str, &str), &str> = HashMap::new();
+    for entry in entries {
+        // the architecture does not matter here, only the syntax
+        let name = match parse_arch_qualifier(&entry.spec, "") {
+            Ok((name, _)) => name,
+            Err(e) => {
+                findings.push(Finding {
+                    location: entry.location.clone(),
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        let qualifier = entry.spec.find('[').map_or("", |i| &entry.spec[i..]);
+        let name = package_name(name);
+        if let Some(first) = seen.insert((name, qualifier), &entry.location) {
+            findings.push(Finding {
+                location: entry.location.clone(),
+                message: format!("{} is already listed at {}", name, first),
+            });
+        }
+    }
+}
+
+/// Check that the entries applicable to the given architecture exist in the pool
+pub fn check_online(pool: &Pool, entries: &[Entry], arch: &str) -> Result<Vec<Finding>> {
+    let mut findings = Vec::new();
+    for entry in entries {
+        let Ok((spec, true)) = parse_arch_qualifier(&entry.spec, arch) else {
+            continue;
+        };
+        if !pool.has_package(spec)? {
+            findings.push(Finding {
+                location: entry.location.clone(),
+                message: format!("{} is not available for {}", spec, arch),
+            });
+        }
+    }
+
+    Ok(findings)
+}
+
+#[test]
+fn test_check_config() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let config_path = dir.path().join("recipe.toml");
+    std::fs::write(
+        &config_path,
+        "stub-packages = [\"bash\", \"dpkg\"]\nbase-packages = [\"apt\"]\nexclude-packages = [\"dpkg\"]\n",
+    )?;
+    let list = dir.path().join("base.lst");
+    std::fs::write(
+        &list,
+        "# comment\nbash\ngrub [amd64]\ngrub [arm64]\n%include missing.lst\nfoo [amd64\nvim\nvim>=9.0\n",
+    )?;
+    let config_path = config_path.to_string_lossy().to_string();
+    let config = crate::install::read_config(&config_path, &Default::default())?;
+    let (entries, findings) =
+        check_config(&config_path, &config, &[list.to_string_lossy().to_string()]);
+    assert_eq!(entries.len(), 9);
+    let messages = findings.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages.len(), 5, "{:?}", messages);
+    assert!(messages[0].ends_with("stub package dpkg is excluded"));
+    assert!(messages[1].ends_with("base.lst:5: included file missing.lst does not exist"));
+    assert!(messages[2].contains("base.lst:2: bash is already listed at"));
+    assert!(messages[3].contains("base.lst:6: Unterminated architecture qualifier"));
+    assert!(messages[4].contains("base.lst:8: vim is already listed at"));
+    assert!(messages[4].ends_with("base.lst:7"));
+
+    // the entries of %ifarch blocks only apply to their architectures
+    let fixture = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/lists/base.lst");
+    let (entries, findings) = check_config(&config_path, &config, &[fixture.to_string()]);
+    let specs = entries.iter().map(|e| e.spec.as_str()).collect::<Vec<_>>();
+    assert!(specs.contains(&"grub [amd64 i486]"));
+    assert!(specs.contains(&"rockchip-firmware [arm64]"));
+    assert!(!specs.iter().any(|s| s.starts_with("never-listed")));
+    let messages = findings.iter().map(|f| f.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages.len(), 5, "{:?}", messages);
+    assert!(messages[1].contains("base.lst:12: never-listed is never listed"));
+    assert!(messages[2].contains("base.lst:2: bash is already listed at"));
+    assert!(messages[3].contains("desktop.lst:3: coreutils is already listed at"));
+    assert!(messages[4].contains("base.lst:15: bash is already listed at"));
+
+    Ok(())
+}
@@ -0,0 +1,187 @@
+use std::{fs::File, io::Write, path::Path};
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{solv::PackageMeta, DEFAULT_MIRROR};
+
+const LOCKFILE_VERSION: u32 = 1;
+
+/// A frozen set of packages, which can be used to replay a previous resolution.
+#[derive(Deserialize, Serialize)]
+pub struct Lockfile {
+    version: u32,
+    branch: String,
+    /// Package names requested by the user, used to generate APT extended states.
+    pub requested: Vec<String>,
+    /// Packages in installation order.
+    #[serde(rename = "package")]
+    packages: Vec<LockedPackage>,
+}
+
+#[derive(Deserialize, Serialize)]
+struct LockedPackage {
+    name: String,
+    version: String,
+    arch: String,
+    sha256: String,
+    path: String,
+    url: String,
+    #[serde(default)]
+    in_topic: bool,
+    #[serde(default)]
+    repo: String,
+    #[serde(default)]
+    section: String,
+    #[serde(default)]
+    installed_size: u64,
+    #[serde(default)]
+    download_size: u64,
+    /// Whether this package is extracted during stage 1.
+    #[serde(default)]
+    stub: bool,
+}
+
+impl Lockfile {
+    pub fn new(
+        branch: &str,
+        mirror: &str,
+        requested: &[String],
+        packages: &[PackageMeta],
+        stub_packages: &[PackageMeta],
+    ) -> Self {
+        let packages = packages
+            .iter()
+            .map(|p| {
+                let mirror = if p.in_topic { DEFAULT_MIRROR } else { mirror };
+                LockedPackage {
+                    name: p.name.clone(),
+                    version: p.version.clone(),
+                    arch: p.arch.clone(),
+                    sha256: p.sha256.clone(),
+                    path: p.path.clone(),
+                    url: format!("{}/{}", mirror, p.path),
+                    in_topic: p.in_topic,
+                    repo: p.repo.clone(),
+                    section: p.section.clone(),
+                    installed_size: p.installed_size,
+                    download_size: p.download_size,
+                    stub: stub_packages.iter().any(|s| s.name == p.name),
+                }
+            })
+            .collect();
+
+        Lockfile {
+            version: LOCKFILE_VERSION,
+            branch: branch.to_string(),
+            requested: requested.to_vec(),
+            packages,
+        }
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let lockfile: Lockfile = toml::from_str(&content)?;
+        if lockfile.version != LOCKFILE_VERSION {
+            return Err(anyhow!(
+                "Unsupported lockfile version {} (expected {})",
+                lockfile.version,
+                LOCKFILE_VERSION
+            ));
+        }
+
+        Ok(lockfile)
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let mut f = File::create(path)?;
+        f.write_all(b"# This file is generated by aoscbootstrap. Do not edit.\n")?;
+        f.write_all(toml::to_string(self)?.as_bytes())?;
+        f.sync_all()?;
+
+        Ok(())
+    }
+
+    pub fn branch(&self) -> &str {
+        &self.branch
+    }
+
+    /// The mirror the lockfile was generated against, from the URLs of the packages not
+    /// taken from a topic
+    pub fn mirror(&self) -> Option<&str> {
+        self.packages
+            .iter()
+            .filter(|p| !p.in_topic)
+            .find_map(|p| p.url.strip_suffix(p.path.as_str())?.strip_suffix('/'))
+    }
+
+    /// All the locked packages, in installation order
+    pub fn packages(&self) -> Vec<PackageMeta> {
+        self.packages.iter().map(PackageMeta::from).collect()
+    }
+
+    /// The locked packages to be extracted during stage 1
+    pub fn stub_packages(&self) -> Vec<PackageMeta> {
+        self.packages
+            .iter()
+            .filter(|p| p.stub)
+            .map(PackageMeta::from)
+            .collect()
+    }
+}
+
+impl From<&LockedPackage> for PackageMeta {
+    fn from(p: &LockedPackage) -> Self {
+        PackageMeta {
+            name: p.name.clone(),
+            version: p.version.clone(),
+            sha256: p.sha256.clone(),
+            path: p.path.clone(),
+            arch: p.arch.clone(),
+            in_topic: p.in_topic,
+            repo: p.repo.clone(),
+            section: p.section.clone(),
+            installed_size: p.installed_size,
+            download_size: p.download_size,
+        }
+    }
+}
+
+#[test]
+fn test_lockfile_roundtrip() -> Result<()> {
+    let make_package = |name: &str| PackageMeta {
+        version: "1:1.0-1".to_string(),
+        sha256: "0".repeat(64),
+        path: format!("pool/stable/main/{name}.deb"),
+        section: "utils".to_string(),
+        installed_size: 1024,
+        download_size: 512,
+        ..crate::solv::pkg(name, "amd64")
+    };
+    let packages = vec![make_package("bash"), make_package("tzdata")];
+    let lockfile = Lockfile::new(
+        "stable",
+        "https://repo.aosc.io/debs",
+        &["bash".to_string()],
+        &packages,
+        &packages[..1],
+    );
+    let tmp = tempfile::NamedTempFile::new()?;
+    lockfile.write(tmp.path())?;
+    let lockfile = Lockfile::read(tmp.path())?;
+    assert_eq!(lockfile.branch(), "stable");
+    assert_eq!(lockfile.mirror(), Some("https://repo.aosc.io/debs"));
+    assert_eq!(lockfile.requested, vec!["bash"]);
+    let names = lockfile
+        .packages()
+        .into_iter()
+        .map(|p| p.name)
+        .collect::<Vec<_>>();
+    assert_eq!(names, vec!["bash", "tzdata"]);
+    let stub = lockfile.stub_packages();
+    assert_eq!(stub.len(), 1);
+    assert_eq!(stub[0].version, "1:1.0-1");
+    assert_eq!(stub[0].file_name(), "bash_1%3a1.0-1_amd64.deb");
+
+    Ok(())
+}
@@ -0,0 +1,235 @@
+use std::{
+    io::{IsTerminal, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex, OnceLock,
+    },
+};
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, Level, LevelFilter, Log, Metadata, Record};
+use owo_colors::colored::*;
+
+use crate::events::{self, Event, ProgressReporter};
+
+/// Target of the messages naming an artifact, which are shown even with `--quiet`
+pub const ARTIFACT: &str = "artifact";
+
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+pub enum ColorChoice {
+    /// Use colors if stderr is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+struct Logger {
+    level: LevelFilter,
+    color: bool,
+}
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+/// The progress bar being drawn, which is hidden while a message is written
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        if events::enabled() {
+            // the JSON events only carry warnings and errors, and list the artifacts
+            // in the final result
+            return metadata.level() <= Level::Warn && target != ARTIFACT;
+        }
+        if target == ARTIFACT {
+            return true;
+        }
+        // other crates (reqwest and friends) only get to report problems
+        let level = if target.starts_with(env!("CARGO_CRATE_NAME")) {
+            self.level
+        } else {
+            self.level.min(LevelFilter::Warn)
+        };
+
+        metadata.level() <= level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = record.args().to_string();
+        if events::enabled() {
+            let message = strip_colors(&message);
+            events::emit(&match record.level() {
+                Level::Error => Event::Error { message: &message },
+                _ => Event::Warning { message: &message },
+            });
+            return;
+        }
+        let message = match record.level() {
+            Level::Error => message.red().bold().to_string(),
+            Level::Warn => message.yellow().to_string(),
+            _ => message,
+        };
+        let message = if self.color {
+            message
+        } else {
+            strip_colors(&message)
+        };
+        let write = || {
+            writeln!(std::io::stderr().lock(), "{}", message).ok();
+        };
+        match PROGRESS_BAR.lock().unwrap().as_ref() {
+            Some(bar) => bar.suspend(write),
+            None => write(),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Set up the logger: `quiet` only shows warnings, errors and artifacts, the default
+/// shows the progress of each phase, one `verbose` adds the details of every package
+/// and two also the HTTP requests and the decisions of the solver
+pub fn init(quiet: bool, verbose: u8, color: ColorChoice) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let color = match color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            !std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty())
+                && std::io::stderr().is_terminal()
+        }
+    };
+    let logger = LOGGER.get_or_init(|| Logger { level, color });
+    if log::set_logger(logger).is_ok() {
+        // artifacts are logged at the info level
+        log::set_max_level(level.max(LevelFilter::Info));
+    }
+}
+
+/// Shows the progress on the terminal, when `--json-progress` is not used. The log messages
+/// are written by the logger.
+pub struct ConsoleReporter {
+    /// Whether to draw a progress bar while extracting the packages
+    show_bar: AtomicBool,
+    /// Size of the packages to extract, the length of the progress bar
+    extract_size: AtomicU64,
+    /// Size of the package being extracted, added to the bar when the next one starts
+    extracting: AtomicU64,
+}
+
+pub static CONSOLE: ConsoleReporter = ConsoleReporter {
+    show_bar: AtomicBool::new(false),
+    extract_size: AtomicU64::new(0),
+    extracting: AtomicU64::new(0),
+};
+
+/// Draw a progress bar while extracting the packages, instead of a line for each one
+pub fn show_progress_bar(show: bool) {
+    CONSOLE.show_bar.store(show, Ordering::SeqCst);
+}
+
+/// Set the size of the packages about to be extracted, so that the progress bar counts bytes:
+/// a few large packages would otherwise look stalled
+pub fn set_extract_size(size: u64) {
+    CONSOLE.extract_size.store(size, Ordering::SeqCst);
+}
+
+impl ConsoleReporter {
+    fn clear_bar(&self) {
+        if let Some(bar) = PROGRESS_BAR.lock().unwrap().take() {
+            bar.finish_and_clear();
+        }
+    }
+}
+
+impl ProgressReporter for ConsoleReporter {
+    fn phase_started(&self, _name: &str) {
+        self.clear_bar();
+    }
+
+    fn item_progress(
+        &self,
+        phase: &str,
+        current: usize,
+        total: usize,
+        item: Option<&str>,
+        bytes: Option<u64>,
+    ) {
+        let item = item.unwrap_or_default();
+        match phase {
+            "extract" if self.show_bar.load(Ordering::SeqCst) => {
+                let mut bar = PROGRESS_BAR.lock().unwrap();
+                let bar = bar.get_or_insert_with(|| {
+                    self.extracting.store(0, Ordering::SeqCst);
+                    ProgressBar::new(self.extract_size.load(Ordering::SeqCst)).with_style(
+                        ProgressStyle::with_template("{bar:40} {bytes}/{total_bytes} {wide_msg}")
+                            .expect("the template is valid"),
+                    )
+                });
+                bar.inc(self.extracting.swap(bytes.unwrap_or_default(), Ordering::SeqCst));
+                bar.set_message(item.to_string());
+            }
+            "extract" => info!("[{}/{}] Extracting {} ...", current, total, item.cyan()),
+            "manifests" => debug!("[{}/{}] Fetched {}", current, total, item),
+            // the output of stage 2 shows the progress of the installation
+            _ => (),
+        }
+    }
+
+    fn phase_finished(&self, _name: &str, _seconds: f64) {
+        self.clear_bar();
+    }
+}
+
+/// Remove the escape sequences setting colors and styles
+fn strip_colors(s: &str) -> String {
+    let mut stripped = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        let sequence = &rest[start + 2..];
+        let end = sequence
+            .find(|c: char| ('@'..='~').contains(&c))
+            .map_or(sequence.len(), |i| i + 1);
+        rest = &sequence[end..];
+    }
+    stripped.push_str(rest);
+
+    stripped
+}
+
+#[test]
+fn test_strip_colors() {
+    let colored = format!("{} {}", "Tarball".green().bold(), "out.tar.zst".cyan());
+    assert_ne!(colored, "Tarball out.tar.zst");
+    assert_eq!(strip_colors(&colored), "Tarball out.tar.zst");
+    assert_eq!(strip_colors("plain [1/2]"), "plain [1/2]");
+    assert_eq!(strip_colors("cut \x1b[1"), "cut ");
+}
+
+#[test]
+fn test_enabled() {
+    let logger = Logger {
+        level: LevelFilter::Warn,
+        color: false,
+    };
+    let metadata = |level, target| Metadata::builder().level(level).target(target).build();
+    assert!(logger.enabled(&metadata(Level::Warn, "aoscbootstrap::network")));
+    assert!(!logger.enabled(&metadata(Level::Info, "aoscbootstrap::network")));
+    assert!(logger.enabled(&metadata(Level::Info, ARTIFACT)));
+
+    let logger = Logger {
+        level: LevelFilter::Trace,
+        color: false,
+    };
+    assert!(logger.enabled(&metadata(Level::Trace, "aoscbootstrap::solv")));
+    assert!(!logger.enabled(&metadata(Level::Debug, "reqwest::connect")));
+    assert!(logger.enabled(&metadata(Level::Warn, "reqwest::connect")));
+}
@@ -0,0 +1,315 @@
+use std::{cmp::Ordering, collections::BTreeMap, io::Write, path::Path};
+
+use anyhow::{anyhow, Context, Result};
+use log::warn;
+use owo_colors::colored::*;
+
+use crate::solv::PackageMeta;
+
+/// A package in the manifest, written as `name<TAB>version<TAB>arch`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Entry {
+    pub name: String,
+    pub version: String,
+    pub arch: String,
+}
+
+pub fn from_packages(packages: &[PackageMeta]) -> Vec<Entry> {
+    let mut entries = packages
+        .iter()
+        .map(|p| Entry {
+            name: p.name.clone(),
+            version: p.version.clone(),
+            arch: p.arch.clone(),
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    entries
+}
+
+/// Read the installed packages from `/var/lib/dpkg/status` of the target, if it has one
+pub fn from_dpkg_status(target: &Path) -> Result<Option<Vec<Entry>>> {
+    let status = match std::fs::read_to_string(target.join("var/lib/dpkg/status")) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        status => status?,
+    };
+    let mut entries = Vec::new();
+    for stanza in status.split("\n\n") {
+        let field = |name: &str| {
+            stanza.lines().find_map(|l| {
+                l.strip_prefix(name)
+                    .and_then(|v| v.strip_prefix(':'))
+                    .map(|v| v.trim())
+            })
+        };
+        if !field("Status").is_some_and(|s| s.ends_with(" installed")) {
+            continue;
+        }
+        if let (Some(name), Some(version), Some(arch)) =
+            (field("Package"), field("Version"), field("Architecture"))
+        {
+            entries.push(Entry {
+                name: name.to_string(),
+                version: version.to_string(),
+                arch: arch.to_string(),
+            });
+        }
+    }
+    entries.sort();
+
+    Ok(Some(entries))
+}
+
+/// Warn about the differences between the resolved packages and the installed ones
+pub fn cross_check(resolved: &[Entry], installed: &[Entry]) -> usize {
+    let diff = diff(resolved, installed);
+    for e in &diff.added {
+        warn!(
+            "{} {} is installed, but was not in the resolved package set",
+            e.name, e.version
+        );
+    }
+    for e in &diff.removed {
+        warn!(
+            "{} {} was resolved, but is not installed",
+            e.name, e.version
+        );
+    }
+    for (old, new) in &diff.changed {
+        warn!(
+            "{} was resolved at version {}, but {} is installed",
+            old.name, old.version, new.version
+        );
+    }
+
+    diff.added.len() + diff.removed.len() + diff.changed.len()
+}
+
+pub fn write(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    for e in entries {
+        writeln!(f, "{}\t{}\t{}", e.name, e.version, e.arch)?;
+    }
+    f.flush()?;
+
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Vec<Entry>> {
+    let content =
+        std::fs::read_to_string(path).context(format!("Failed to read {}", path.display()))?;
+    let mut entries = Vec::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split('\t');
+        let (Some(name), Some(version), Some(arch), None) =
+            (fields.next(), fields.next(), fields.next(), fields.next())
+        else {
+            return Err(anyhow!(
+                "{}:{}: expected name<TAB>version<TAB>arch",
+                path.display(),
+                i + 1
+            ));
+        };
+        entries.push(Entry {
+            name: name.to_string(),
+            version: version.to_string(),
+            arch: arch.to_string(),
+        });
+    }
+
+    Ok(entries)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Diff<'a> {
+    pub added: Vec<&'a Entry>,
+    pub removed: Vec<&'a Entry>,
+    /// The packages whose version changed, old and new
+    pub changed: Vec<(&'a Entry, &'a Entry)>,
+}
+
+/// Compare two manifests, by package name and architecture
+pub fn diff<'a>(old: &'a [Entry], new: &'a [Entry]) -> Diff<'a> {
+    let index = |entries: &'a [Entry]| {
+        entries
+            .iter()
+            .map(|e| ((e.name.as_str(), e.arch.as_str()), e))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let (old, new) = (index(old), index(new));
+    let mut diff = Diff::default();
+    for (key, e) in &new {
+        match old.get(key) {
+            None => diff.added.push(*e),
+            Some(o) if o.version != e.version => diff.changed.push((*o, *e)),
+            _ => (),
+        }
+    }
+    diff.removed = old
+        .iter()
+        .filter(|(key, _)| !new.contains_key(*key))
+        .map(|(_, e)| *e)
+        .collect();
+
+    diff
+}
+
+/// Print the packages added, removed, upgraded and downgraded between two manifests
+pub fn print_diff(old: &Path, new: &Path) -> Result<()> {
+    let (old, new) = (read(old)?, read(new)?);
+    let diff = diff(&old, &new);
+    let (upgraded, downgraded): (Vec<_>, Vec<_>) = diff
+        .changed
+        .iter()
+        .partition(|(o, n)| compare_versions(&o.version, &n.version) != Ordering::Greater);
+    for (title, entries) in [("Added", &diff.added), ("Removed", &diff.removed)] {
+        if !entries.is_empty() {
+            println!("{} ({}):", title.bold(), entries.len());
+            for e in entries {
+                println!("  {} {} ({})", e.name, e.version, e.arch);
+            }
+        }
+    }
+    for (title, entries) in [("Upgraded", &upgraded), ("Downgraded", &downgraded)] {
+        if !entries.is_empty() {
+            println!("{} ({}):", title.bold(), entries.len());
+            for (o, n) in entries {
+                println!("  {} {} -> {} ({})", n.name, o.version, n.version, n.arch);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Compare two Debian versions, `[epoch:]upstream[-revision]`
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |v: &'_ str| -> (u64, String, String) {
+        let (epoch, rest) = match v.split_once(':') {
+            Some((e, rest)) if e.chars().all(|c| c.is_ascii_digit()) => {
+                (e.parse().unwrap_or(0), rest)
+            }
+            _ => (0, v),
+        };
+        let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+
+        (epoch, upstream.to_string(), revision.to_string())
+    };
+    let (a, b) = (split(a), split(b));
+
+    a.0.cmp(&b.0)
+        .then_with(|| compare_fragment(&a.1, &b.1))
+        .then_with(|| compare_fragment(&a.2, &b.2))
+}
+
+/// The dpkg ordering of the non-digit characters: `~` before anything, even the end,
+/// then letters before the other characters
+fn char_order(c: Option<char>) -> i32 {
+    match c {
+        Some('~') => -1,
+        None => 0,
+        Some(c) if c.is_ascii_alphabetic() => c as i32,
+        Some(c) => c as i32 + 256,
+    }
+}
+
+fn compare_fragment(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a, b);
+    while !a.is_empty() || !b.is_empty() {
+        // the non-digit prefixes, compared character by character
+        let a_len = a.find(|c: char| c.is_ascii_digit()).unwrap_or(a.len());
+        let b_len = b.find(|c: char| c.is_ascii_digit()).unwrap_or(b.len());
+        let (mut ac, mut bc) = (a[..a_len].chars(), b[..b_len].chars());
+        loop {
+            let (x, y) = (ac.next(), bc.next());
+            if x.is_none() && y.is_none() {
+                break;
+            }
+            match char_order(x).cmp(&char_order(y)) {
+                Ordering::Equal => (),
+                other => return other,
+            }
+        }
+        (a, b) = (&a[a_len..], &b[b_len..]);
+        // then the numbers
+        let a_len = a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len());
+        let b_len = b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len());
+        let number = |s: &str| s.trim_start_matches('0').to_string();
+        let (x, y) = (number(&a[..a_len]), number(&b[..b_len]));
+        match x.len().cmp(&y.len()).then_with(|| x.cmp(&y)) {
+            Ordering::Equal => (),
+            other => return other,
+        }
+        (a, b) = (&a[a_len..], &b[b_len..]);
+    }
+
+    Ordering::Equal
+}
+
+#[test]
+fn test_compare_versions() {
+    use Ordering::*;
+    assert_eq!(compare_versions("1.0", "1.0"), Equal);
+    assert_eq!(compare_versions("1.10", "1.9"), Greater);
+    assert_eq!(compare_versions("1.0~rc1", "1.0"), Less);
+    assert_eq!(compare_versions("1:0.1", "2.0"), Greater);
+    assert_eq!(compare_versions("2.7-1", "2.7-0.1"), Greater);
+    assert_eq!(compare_versions("1.0a", "1.0+"), Less);
+    assert_eq!(compare_versions("1.0", "1.0.0"), Less);
+}
+
+#[test]
+fn test_manifest() -> Result<()> {
+    let entry = |name: &str, version: &str| Entry {
+        name: name.to_string(),
+        version: version.to_string(),
+        arch: "amd64".to_string(),
+    };
+    let old = vec![
+        entry("bash", "5.2.15"),
+        entry("grub", "2.12"),
+        entry("vim", "9.0"),
+    ];
+    let new = vec![
+        entry("bash", "5.2.21"),
+        entry("grub", "2.06"),
+        entry("zsh", "5.9"),
+    ];
+    let dir = tempfile::tempdir()?;
+    let path = dir.path().join("old.packages");
+    write(&path, &old)?;
+    assert_eq!(
+        std::fs::read_to_string(&path)?.lines().next(),
+        Some("bash\t5.2.15\tamd64")
+    );
+    assert_eq!(read(&path)?, old);
+    std::fs::write(&path, "bash 5.2.15 amd64\n")?;
+    assert!(read(&path).is_err());
+
+    let d = diff(&old, &new);
+    assert_eq!(d.added, [&new[2]]);
+    assert_eq!(d.removed, [&old[2]]);
+    assert_eq!(d.changed, [(&old[0], &new[0]), (&old[1], &new[1])]);
+
+    std::fs::create_dir_all(dir.path().join("var/lib/dpkg"))?;
+    assert_eq!(from_dpkg_status(&dir.path().join("nonexistent"))?, None);
+    std::fs::write(
+        dir.path().join("var/lib/dpkg/status"),
+        "Package: zsh\nStatus: install ok installed\nArchitecture: amd64\nVersion: 5.9\n\n\
+         Package: vim\nStatus: deinstall ok config-files\nArchitecture: amd64\nVersion: 9.0\n",
+    )?;
+    assert_eq!(
+        from_dpkg_status(dir.path())?,
+        Some(vec![entry("zsh", "5.9")])
+    );
+    assert_eq!(
+        cross_check(&[entry("zsh", "5.9")], &[entry("zsh", "5.9")]),
+        0
+    );
+
+    Ok(())
+}
@@ -1,33 +1,57 @@
 use anyhow::{anyhow, Context, Result};
+use log::{debug, info, trace, warn};
+use owo_colors::OwoColorize;
 use rayon::prelude::*;
-use reqwest::blocking::Client;
-use std::{fs::File, io::Write};
+use reqwest::blocking::{Client, Response};
+use reqwest::StatusCode;
 use std::{
-    path::Path,
+    fs::File,
+    io::{BufReader, Read, Write},
+};
+use std::{
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
 };
 use std::{
-    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
     thread::sleep,
     time::Duration,
 };
 use url::Url;
 
-use crate::DEFAULT_MIRROR;
-use crate::{fs::sha256sum, solv::PackageMeta};
+use crate::{
+    cancel::{self, StopOnCancel},
+    events, keyring, timing, DEFAULT_MIRROR,
+};
+use crate::{
+    fs::{atomic_write, sha256sum},
+    solv::{PackageMeta, TOPIC_REPO_PREFIX},
+};
+
+/// Buffer size for hashing files, large enough to keep the reads out of the way
+const HASH_BUFFER_SIZE: usize = 4 << 20;
+
+pub(crate) fn sha256sum_file(path: &Path) -> Result<String> {
+    let f = File::open(path)?;
+
+    sha256sum(BufReader::with_capacity(HASH_BUFFER_SIZE, f))
+}
 
-fn sha256sum_file(path: &Path) -> Result<String> {
-    let mut f = File::open(path)?;
+/// Hash a file and write its checksum next to it, returning the checksum
+pub(crate) fn sha256sum_file_tag(path: &Path) -> Result<String> {
+    let sha256 = sha256sum_file(path)?;
+    write_sha256sum_tag(path, &sha256)?;
 
-    sha256sum(&mut f)
+    Ok(sha256)
 }
 
-pub(crate) fn sha256sum_file_tag(path: &Path) -> Result<()> {
+/// Write the checksum of a file next to it, as `sha256sum` prints it
+pub(crate) fn write_sha256sum_tag(path: &Path, sha256: &str) -> Result<()> {
     let mut f = File::create(format!("{}.sha256sum", path.to_string_lossy()))?;
     f.write_all(
         format!(
             "{} *{}\n",
-            sha256sum_file(path)?,
+            sha256,
             path.file_name()
                 .context("Failed to get file name")?
                 .to_string_lossy()
@@ -45,14 +69,54 @@ pub fn make_new_client() -> Result<Client> {
 }
 
 pub fn fetch_url(client: &Client, url: &str, path: &Path) -> Result<()> {
-    let mut f = File::create(path)?;
+    trace!("GET {}", url);
+    let f = File::create(path)?;
     let mut resp = client.get(url).send()?;
     resp.error_for_status_ref()?;
-    resp.copy_to(&mut f)?;
+    resp.copy_to(&mut StopOnCancel::new(f))?;
 
     Ok(())
 }
 
+/// Fetch a text file, failing on any non-successful response, and return it with the URL
+/// it was read from after the redirects, see [fetch]
+pub fn fetch_text(client: &Client, url: &Url) -> Result<(String, Url)> {
+    let resp = fetch(client, url)?;
+    let url = resp.url().clone();
+
+    Ok((resp.text()?, url))
+}
+
+/// Fetch a file, failing on any non-successful response, see [fetch]
+pub fn fetch_bytes(client: &Client, url: &Url) -> Result<Vec<u8>> {
+    Ok(fetch(client, url)?.bytes()?.to_vec())
+}
+
+/// Fetch a file of a remote config. A redirect to another origin is refused, as the config
+/// and the files next to it are trusted for coming from the given one.
+fn fetch(client: &Client, url: &Url) -> Result<Response> {
+    trace!("GET {}", url);
+    let resp = client
+        .get(url.as_str())
+        .send()
+        .context(format!("when fetching {}", url))?;
+    if resp.url() != url {
+        if resp.url().origin() != url.origin() {
+            return Err(anyhow!(
+                "{} was redirected to another origin ({}), use that URL directly if it is trusted",
+                url,
+                resp.url()
+            ));
+        }
+        info!("{} was redirected to {}", url, resp.url());
+    }
+    let resp = resp
+        .error_for_status()
+        .context(format!("when fetching {}", url))?;
+
+    Ok(resp)
+}
+
 #[inline]
 fn combination<'a, 'b>(a: &'a [&str], b: &'b [&str]) -> Vec<(&'a str, &'b str)> {
     let mut ret = Vec::new();
@@ -65,6 +129,232 @@ fn combination<'a, 'b>(a: &'a [&str], b: &'b [&str]) -> Vec<(&'a str, &'b str)>
     ret
 }
 
+/// A downloaded manifest
+#[derive(Clone)]
+pub struct Manifest {
+    /// File name under `var/lib/apt/lists`
+    pub file_name: String,
+    /// Name of the repository this manifest belongs to
+    pub repo: String,
+}
+
+/// File name of the InRelease of a topic under `var/lib/apt/lists`
+pub(crate) fn topic_inrelease_name(topic: &str) -> String {
+    let url = format!("{}/dists/{}/InRelease", DEFAULT_MIRROR, topic);
+    let url = Url::parse(&url).expect("the default mirror is a valid URL");
+
+    (url.host_str().unwrap_or_default().to_string() + url.path()).replace('/', "_")
+}
+
+/// How the InRelease files of the topics are verified
+#[derive(Default)]
+pub struct TopicVerifier<'a> {
+    /// Root whose trusted keys verify the InRelease files, the host by default
+    pub keys_root: Option<&'a Path>,
+    /// Keyring named when the verification fails, the trusted keys of `keys_root` by default
+    pub keyring: Option<&'a Path>,
+    /// Use the InRelease files failing the verification anyway, for development repositories
+    pub allow_unverified: bool,
+    /// Directory keeping the verified InRelease files across runs
+    pub cache: Option<PathBuf>,
+}
+
+/// Directory keeping the verified InRelease files of the topics
+const TOPIC_CACHE_DIR: &str = "/var/cache/aoscbootstrap/topics";
+
+/// Directory keeping the verified InRelease files of the topics, only when running as
+/// root: what is cached there is trusted without verifying it again, so it must not be
+/// writable by anyone else
+pub fn topic_cache_dir() -> Option<PathBuf> {
+    nix::unistd::Uid::effective()
+        .is_root()
+        .then(|| PathBuf::from(TOPIC_CACHE_DIR))
+}
+
+/// Whether the file is owned by the current user and writable by no one else
+fn owned_and_private(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    std::fs::symlink_metadata(path)
+        .is_ok_and(|m| m.uid() == nix::unistd::Uid::effective().as_raw() && m.mode() & 0o022 == 0)
+}
+
+/// Parse a date of a Release file, e.g. `Sat, 18 Oct 2025 12:00:00 UTC`, as a timestamp
+fn parse_release_date(date: &str) -> Option<u64> {
+    let mut fields = date.split_whitespace();
+    let mut day = fields.next()?;
+    if day.ends_with(',') {
+        // the day of the week
+        day = fields.next()?;
+    }
+    let day = day.parse::<i64>().ok()?;
+    let month = fields.next()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|m| *m == month)? as i64
+        + 1;
+    let year = fields.next()?.parse::<i64>().ok()?;
+    let time = fields
+        .next()?
+        .split(':')
+        .map(|t| t.parse::<i64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let [hour, minute, second] = time[..] else {
+        return None;
+    };
+    if !matches!(fields.next(), None | Some("UTC" | "GMT" | "+0000" | "Z")) {
+        return None;
+    }
+    // Adapted from Howard Hinnant's `days_from_civil` algorithm
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (month + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second).ok()
+}
+
+/// Whether the Valid-Until date of the Release message, if any, is past
+fn release_expired(message: &str) -> bool {
+    let Some(valid_until) = message.lines().find_map(|l| l.strip_prefix("Valid-Until:")) else {
+        return false;
+    };
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    // a date which cannot be parsed is taken as expired, as APT does
+    !matches!(parse_release_date(valid_until.trim()), Some(date) if date >= now)
+}
+
+/// Extract the message of a clearsigned InRelease file without verifying it
+fn strip_clearsign(inrelease: &str) -> String {
+    if !inrelease.starts_with("-----BEGIN PGP SIGNED MESSAGE-----") {
+        return inrelease.to_string();
+    }
+    let message = inrelease
+        .split_once("\n\n")
+        .map_or("", |(_, rest)| rest)
+        .split("\n-----BEGIN PGP SIGNATURE-----")
+        .next()
+        .unwrap_or_default();
+
+    message
+        .lines()
+        .map(|l| l.strip_prefix("- ").unwrap_or(l))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl TopicVerifier<'_> {
+    /// Path of the verified message in the cache, keyed by the topic and the checksum of the
+    /// InRelease and of the trusted keys, so that a message verified with other keys is
+    /// verified again
+    fn cached_path(&self, topic: &str, inrelease: &str) -> Result<Option<PathBuf>> {
+        let Some(ref dir) = self.cache else {
+            return Ok(None);
+        };
+        let keys = keyring::trusted_keys_digest(self.keys_root.unwrap_or(Path::new("/")))?;
+        let key = sha256sum(format!("{}\n{}", keys, inrelease).as_bytes())?;
+
+        Ok(Some(dir.join(format!("{}-{}", topic, key))))
+    }
+
+    /// Verify the InRelease of a topic, returning its message. The messages verified
+    /// once are taken from the cache afterwards (see [cached_path](Self::cached_path)), as
+    /// long as the cache is private to the current user and they have not expired.
+    fn verify(&self, topic: &str, inrelease: &str) -> Result<String> {
+        let cached = self.cached_path(topic, inrelease)?;
+        if let Some(message) = cached
+            .as_ref()
+            .filter(|p| p.parent().is_some_and(owned_and_private) && owned_and_private(p))
+            .and_then(|p| std::fs::read_to_string(p).ok())
+            .filter(|m| !release_expired(m))
+        {
+            debug!(
+                "Using the verified InRelease of topic {} from the cache.",
+                topic
+            );
+            return Ok(message);
+        }
+        let keys_root = self.keys_root.unwrap_or(Path::new("/"));
+        let rootfs = keys_root.to_string_lossy().to_string();
+        let verified = oma_repo_verify::verify_inrelease(inrelease, None, rootfs.as_str(), false);
+        let verified = verified.map_err(anyhow::Error::from).and_then(|message| {
+            if release_expired(&message) {
+                return Err(anyhow!("the InRelease has expired (Valid-Until is past)"));
+            }
+
+            Ok(message)
+        });
+        let message = match verified {
+            Ok(message) => message,
+            Err(e) => {
+                let issuers = keyring::signature_issuers(inrelease);
+                let e = anyhow!(
+                    "Cannot verify the InRelease of topic {}: {}\nSigned by: {}\nKeyring searched: {}",
+                    topic,
+                    e,
+                    if issuers.is_empty() {
+                        "no key found in the signature".to_string()
+                    } else {
+                        issuers.join(", ")
+                    },
+                    match self.keyring {
+                        Some(keyring) => keyring.display().to_string(),
+                        None => keys_root.join("etc/apt/trusted.gpg.d").display().to_string(),
+                    }
+                );
+                if !self.allow_unverified {
+                    return Err(e);
+                }
+                warn!("{:#}", e);
+                warn!(
+                    "{}",
+                    format!(
+                        "Using the UNVERIFIED InRelease of topic {} as --allow-unverified-topics is given: its packages may have been tampered with!",
+                        topic
+                    )
+                    .red()
+                    .bold()
+                );
+                // never cached, so that it is verified again next time
+                return Ok(strip_clearsign(inrelease));
+            }
+        };
+        if let Some(path) = cached {
+            if let Err(e) = save_verified(&path, &message) {
+                debug!("Not caching the InRelease of topic {}: {:#}", topic, e);
+            }
+        }
+
+        Ok(message)
+    }
+}
+
+/// Save a verified message to the cache. The cache is shared by the runs of the host,
+/// which may save the same message at once: each writes through its own temporary file,
+/// and the last one to finish replaces the others.
+fn save_verified(path: &Path, message: &str) -> Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    std::fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(path.parent().unwrap())?;
+
+    atomic_write(path, message, 0o644)
+}
+
+/// Download the manifests of the branch and the topics into `root`, verifying the InRelease
+/// files of the topics with `verifier`. Topic manifests already in `root` are kept when
+/// they match their InRelease.
+#[allow(clippy::too_many_arguments)]
 pub fn fetch_manifests(
     client: &Client,
     mirror: &str,
@@ -73,38 +363,108 @@ pub fn fetch_manifests(
     arches: &[&str],
     comps: &[&str],
     root: &Path,
-) -> Result<Vec<String>> {
+    verifier: &TopicVerifier,
+) -> Result<Vec<Manifest>> {
     let manifests = Arc::new(Mutex::new(Vec::new()));
     let manifests_clone = manifests.clone();
     let manifests_clone_2 = manifests.clone();
     let combined = combination(arches, comps);
-    combined
-        .par_iter()
-        .try_for_each(move |(arch, comp)| -> Result<()> {
-            let url = format!(
-                "{}/dists/{}/{}/binary-{}/Packages",
-                mirror, branch, comp, arch
-            );
-            let parsed = Url::parse(&url)?;
-            let manifest_name = parsed.host_str().unwrap_or_default().to_string() + parsed.path();
-            let manifest_name = manifest_name.replace('/', "_");
+    let main_arch = arches.iter().find(|a| **a != "all").unwrap_or(&"all");
+    let fetched = AtomicUsize::new(0);
+    let fetch_one = |arch: &str, comp: &str| -> Result<()> {
+        let url = format!(
+            "{}/dists/{}/{}/binary-{}/Packages",
+            mirror, branch, comp, arch
+        );
+        let parsed = Url::parse(&url)?;
+        let manifest_name = parsed.host_str().unwrap_or_default().to_string() + parsed.path();
+        let manifest_name = manifest_name.replace('/', "_");
 
-            fetch_url(
-                client,
-                &url,
-                &root.join("var/lib/apt/lists").join(manifest_name.clone()),
-            )?;
-            manifests_clone.lock().unwrap().push(manifest_name);
+        let path = root.join("var/lib/apt/lists").join(&manifest_name);
+        let result = fetch_url(client, &url, &path)
+            .and_then(|_| check_manifest(&path, &url, comp == "main" && arch == *main_arch));
+        if let Err(e) = result {
+            // do not leave a partial or bogus manifest behind for the pool
+            std::fs::remove_file(&path).ok();
+            return Err(e);
+        }
+        events::progress(
+            "manifests",
+            fetched.fetch_add(1, Ordering::SeqCst) + 1,
+            combined.len(),
+            &manifest_name,
+            path.metadata()?.len(),
+        );
+        manifests_clone.lock().unwrap().push(Manifest {
+            file_name: manifest_name,
+            repo: branch.to_string(),
+        });
 
-            Ok(())
-        })?;
+        Ok(())
+    };
+    let results = combined
+        .par_iter()
+        .map(|(arch, comp)| fetch_one(arch, comp))
+        .collect::<Vec<_>>();
+    // only the components added by the user may be missing, e.g. bsp on some architectures
+    let mut skipped = Vec::new();
+    let mut main_arch_fetched = false;
+    for ((arch, comp), result) in combined.iter().zip(results) {
+        let Err(e) = result else {
+            main_arch_fetched |= arch == main_arch;
+            continue;
+        };
+        if *comp == "main" {
+            return Err(e.context(format!(
+                "when fetching the {} manifest of {} for {}",
+                comp, branch, arch
+            )));
+        }
+        warn!(
+            "Failed to fetch the {} manifest of {} for {}: {:#}",
+            comp, branch, arch, e
+        );
+        skipped.push(format!("{}/binary-{}", comp, arch));
+    }
+    if !skipped.is_empty() {
+        warn!(
+            "Skipped {} of {} manifests: {}",
+            skipped.len(),
+            combined.len(),
+            skipped.join(", ")
+        );
+    }
+    if !main_arch_fetched {
+        return Err(anyhow!(
+            "No manifest of {} could be fetched for {}.",
+            branch,
+            main_arch
+        ));
+    }
 
     topics.par_iter().try_for_each(move |topic| -> Result<()> {
         // Always use AOSC OS Repo for topics
         let url = format!("{}/dists/{}/InRelease", DEFAULT_MIRROR, topic);
 
-        let inrelease = client.get(&url).send()?.error_for_status()?.text()?;
-        let inrelease = oma_repo_verify::verify_inrelease(&inrelease, None, "/", false)?;
+        trace!("GET {}", url);
+        let response = client.get(&url).send()?;
+        // a topic may be closed while the base branch is still fine
+        if response.status() == StatusCode::NOT_FOUND {
+            warn!(
+                "Topic {} has no InRelease ({} is not found), skipping it.",
+                topic, url
+            );
+            return Ok(());
+        }
+        let inrelease = response.error_for_status()?.text()?;
+        let verified = verifier.verify(topic, &inrelease)?;
+        // keep the signed InRelease next to the manifests, as APT would
+        std::fs::write(
+            root.join("var/lib/apt/lists")
+                .join(topic_inrelease_name(topic)),
+            &inrelease,
+        )?;
+        let inrelease = verified;
         let inrelease = oma_debcontrol::parse_str(&inrelease).map_err(|e| anyhow!("{e}"))?;
         let inrelease = inrelease.first().context("InRelease is empty")?;
 
@@ -115,27 +475,62 @@ pub fn fetch_manifests(
             .context("Illage InRelease")?
             .value;
 
+        let mut wanted = Vec::new();
         for i in sha256.trim().lines() {
             let name = i
                 .split_ascii_whitespace()
                 .next_back()
                 .context("Illage InRelease")?;
+            let hash = i
+                .split_ascii_whitespace()
+                .next()
+                .context("Illage InRelease")?;
 
-            if arches
+            if let Some(arch) = arches
                 .iter()
-                .any(|arch| name.ends_with(&format!("binary-{}/Packages", arch)))
+                .find(|arch| name.ends_with(&format!("binary-{}/Packages", arch)))
             {
+                wanted.push((*arch, name, hash));
+            }
+        }
+        wanted
+            .par_iter()
+            .try_for_each(|(_, name, hash)| -> Result<()> {
                 let url = format!("{}/dists/{}/{}", DEFAULT_MIRROR, topic, name);
                 let url = Url::parse(&url)?;
                 let manifest_name = url.host_str().unwrap_or_default().to_string() + url.path();
                 let manifest_name = manifest_name.replace('/', "_");
 
-                fetch_url(
-                    client,
-                    url.as_str(),
-                    &root.join("var/lib/apt/lists").join(manifest_name.clone()),
-                )?;
-                manifests_clone_2.lock().unwrap().push(manifest_name);
+                let path = root.join("var/lib/apt/lists").join(&manifest_name);
+                // left by a previous run
+                let current = path.is_file()
+                    && sha256sum_file(&path).is_ok_and(|s| s.eq_ignore_ascii_case(hash));
+                if current {
+                    debug!("{} is up to date.", manifest_name);
+                } else {
+                    fetch_url(client, url.as_str(), &path)
+                        .and_then(|_| check_manifest(&path, url.as_str(), false))
+                        .context(format!("when fetching {} for topic {}", name, topic))?;
+                    if !sha256sum_file(&path)?.eq_ignore_ascii_case(hash) {
+                        std::fs::remove_file(&path).ok();
+                        return Err(anyhow!(
+                            "{} does not match the checksum in the InRelease of topic {}",
+                            url,
+                            topic
+                        ));
+                    }
+                }
+                manifests_clone_2.lock().unwrap().push(Manifest {
+                    file_name: manifest_name,
+                    repo: format!("{}{}", TOPIC_REPO_PREFIX, topic),
+                });
+
+                Ok(())
+            })?;
+        let found = wanted.iter().map(|(arch, _, _)| *arch).collect::<Vec<_>>();
+        for arch in arches.iter().filter(|a| **a != "all") {
+            if !found.contains(arch) {
+                warn!("Topic {} has no binary-{} index, skipping.", topic, arch);
             }
         }
 
@@ -145,29 +540,125 @@ pub fn fetch_manifests(
     Ok(Arc::try_unwrap(manifests).unwrap().into_inner().unwrap())
 }
 
+/// Check that a downloaded `Packages` manifest looks like one, and not like the error page
+/// of a captive portal or a misconfigured mirror. An empty manifest is only an error if
+/// `required`, e.g. for the main component of the main architecture.
+fn check_manifest(path: &Path, url: &str, required: bool) -> Result<()> {
+    let mut head = Vec::new();
+    File::open(path)?.take(64 * 1024).read_to_end(&mut head)?;
+    let head = String::from_utf8_lossy(&head);
+    if head.trim().is_empty() {
+        if required {
+            return Err(anyhow!(
+                "{} is empty, are the branch and the mirror right?",
+                url
+            ));
+        }
+        return Ok(());
+    }
+    let stanza = head.trim_start().split("\n\n").next().unwrap_or_default();
+    let valid = oma_debcontrol::parse_str(stanza)
+        .ok()
+        .and_then(|paragraphs| paragraphs.into_iter().next())
+        .is_some_and(|p| {
+            ["Package", "SHA256"]
+                .iter()
+                .all(|field| p.fields.iter().any(|f| f.name == *field))
+        });
+    if !valid {
+        let start = head.chars().take(80).collect::<String>();
+        return Err(anyhow!(
+            "{} is not a Packages manifest, it starts with {:?}",
+            url,
+            start
+        ));
+    }
+
+    Ok(())
+}
+
 pub fn batch_download(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Result<()> {
+    let mut failed = Vec::new();
     for i in 1..=3 {
-        if batch_download_inner(pkgs, mirror, root).is_ok() {
+        failed = batch_download_inner(pkgs, mirror, root)?;
+        cancel::check()?;
+        if failed.is_empty() {
             return Ok(());
         }
-        eprintln!("[{}/3] Retrying ...", i);
+        warn!("[{}/3] Retrying ...", i);
         sleep(Duration::from_secs(2));
     }
 
-    Err(anyhow!("Failed to download packages"))
+    Err(anyhow!(
+        "Failed to download packages: {}",
+        failed.join(", ")
+    ))
+}
+
+/// Link or copy the packages found in the given directories, e.g. `/var/cache/apt/archives`
+/// or a previous target, into `root`, returning how many were found and their size.
+/// Only the files matching the checksum from the repository are used.
+pub fn reuse_packages(pkgs: &[PackageMeta], dirs: &[PathBuf], root: &Path) -> (usize, u64) {
+    let reused = AtomicUsize::new(0);
+    let size = AtomicU64::new(0);
+    pkgs.par_iter().for_each(|pkg| {
+        let filename = pkg.file_name();
+        let path = root.join(&filename);
+        if path.is_file() {
+            return;
+        }
+        for dir in dirs {
+            let candidate = dir.join(&filename);
+            if !candidate.is_file() {
+                continue;
+            }
+            if !sha256sum_file(&candidate)
+                .map(|x| x == pkg.sha256)
+                .unwrap_or(false)
+            {
+                warn!(
+                    "Not reusing {}, its checksum does not match the repository.",
+                    candidate.display()
+                );
+                continue;
+            }
+            // a hard link only works on the same filesystem
+            let linked = std::fs::hard_link(&candidate, &path)
+                .or_else(|_| std::fs::copy(&candidate, &path).map(|_| ()));
+            match linked {
+                Ok(()) => {
+                    trace!("Reusing {}", candidate.display());
+                    reused.fetch_add(1, Ordering::SeqCst);
+                    size.fetch_add(pkg.download_size, Ordering::SeqCst);
+                    return;
+                }
+                Err(e) => {
+                    std::fs::remove_file(&path).ok();
+                    warn!("Failed to reuse {}: {}", candidate.display(), e);
+                }
+            }
+        }
+    });
+
+    (reused.into_inner(), size.into_inner())
 }
 
-fn batch_download_inner(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Result<()> {
+/// Download and verify the packages, returning the names of the failed ones
+fn batch_download_inner(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Result<Vec<String>> {
     let client = make_new_client()?;
     let total = pkgs.len() * 2;
     let count = AtomicUsize::new(0);
-    let error = AtomicBool::new(false);
+    let done = AtomicUsize::new(0);
+    let failed = Mutex::new(Vec::new());
     pkgs.par_iter().for_each_init(
         move || client.clone(),
         |client, pkg| {
+            if cancel::cancelled() {
+                return;
+            }
             let filename = pkg.file_name();
             count.fetch_add(1, Ordering::SeqCst);
-            println!(
+            debug!(
                 "[{}/{}] Downloading {}...",
                 count.load(Ordering::SeqCst),
                 total,
@@ -179,12 +670,12 @@ fn batch_download_inner(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Resu
             if !path.is_file()
                 && fetch_url(client, &format!("{}/{}", mirror, pkg.path), &path).is_err()
             {
-                error.store(true, Ordering::SeqCst);
-                eprintln!("Download failed: {}", pkg.name);
+                failed.lock().unwrap().push(pkg.name.clone());
+                warn!("Download failed: {}", pkg.name);
                 return;
             }
             count.fetch_add(1, Ordering::SeqCst);
-            println!(
+            debug!(
                 "[{}/{}] Verifying {}...",
                 count.load(Ordering::SeqCst),
                 total,
@@ -195,15 +686,179 @@ fn batch_download_inner(pkgs: &[PackageMeta], mirror: &str, root: &Path) -> Resu
                 .unwrap_or(false)
             {
                 std::fs::remove_file(path).ok();
-                error.store(true, Ordering::SeqCst);
-                eprintln!("Verification failed: {}", pkg.name);
+                failed.lock().unwrap().push(pkg.name.clone());
+                warn!("Verification failed: {}", pkg.name);
+                return;
             }
+            timing::add_bytes(pkg.download_size);
+            events::progress(
+                "download",
+                done.fetch_add(1, Ordering::SeqCst) + 1,
+                pkgs.len(),
+                &pkg.name,
+                pkg.download_size,
+            );
         },
     );
 
-    if error.load(Ordering::SeqCst) {
-        return Err(anyhow!("Unable to download files"));
-    }
+    Ok(failed.into_inner().unwrap())
+}
+
+#[test]
+fn test_check_manifest() -> Result<()> {
+    let dir = tempfile::tempdir()?;
+    let check = |content: &str, required: bool| -> Result<()> {
+        let path = dir.path().join("Packages");
+        std::fs::write(&path, content)?;
+        check_manifest(
+            &path,
+            "https://mirror/dists/stable/main/binary-amd64/Packages",
+            required,
+        )
+    };
+    let stanza = format!(
+        "Package: bash\nVersion: 5.2\nArchitecture: amd64\nFilename: pool/stable/main/b/bash_5.2_amd64.deb\nSize: 1024\nSHA256: {}\n\nPackage: zsh\n",
+        "0".repeat(64)
+    );
+    assert!(check(&stanza, true).is_ok());
+    let e = check(
+        "<!DOCTYPE html>\n<html><body>Please log in</body></html>\n",
+        false,
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(e.contains("binary-amd64/Packages is not a Packages manifest"));
+    assert!(e.contains("<!DOCTYPE html>"));
+    assert!(check("Package: bash\nVersion: 5.2\n\n", false).is_err());
+    assert!(check("", false).is_ok());
+    assert!(check("\n", true)
+        .unwrap_err()
+        .to_string()
+        .contains("are the branch and the mirror right?"));
+
+    Ok(())
+}
+
+#[test]
+fn test_reuse_packages() -> Result<()> {
+    let package = |name: &str, content: &str| PackageMeta {
+        version: "1:1.0".to_string(),
+        sha256: sha256sum(&mut content.as_bytes()).unwrap(),
+        path: format!("pool/main/{}.deb", name),
+        section: "utils".to_string(),
+        download_size: content.len() as u64,
+        ..crate::solv::pkg(name, "amd64")
+    };
+    let (cache, previous, root) = (
+        tempfile::tempdir()?,
+        tempfile::tempdir()?,
+        tempfile::tempdir()?,
+    );
+    let pkgs = [
+        package("bash", "bash"),
+        package("vim", "vim"),
+        package("zsh", "zsh"),
+    ];
+    std::fs::write(cache.path().join(pkgs[0].file_name()), "bash")?;
+    // a stale file with the same name is skipped for the next directory
+    std::fs::write(cache.path().join(pkgs[1].file_name()), "tampered")?;
+    std::fs::write(previous.path().join(pkgs[1].file_name()), "vim")?;
+    std::fs::write(previous.path().join("zsh_1.0_amd64.deb"), "zsh")?;
+    let dirs = [cache.path().to_path_buf(), previous.path().to_path_buf()];
+    assert_eq!(reuse_packages(&pkgs, &dirs, root.path()), (2, 7));
+    assert_eq!(
+        std::fs::read_to_string(root.path().join("vim_1%3a1.0_amd64.deb"))?,
+        "vim"
+    );
+    assert!(!root.path().join(pkgs[2].file_name()).exists());
+
+    Ok(())
+}
+
+#[test]
+fn test_topic_verifier() -> Result<()> {
+    let inrelease = "-----BEGIN PGP SIGNED MESSAGE-----\nHash: SHA512\n\nOrigin: AOSC\nSuite: foo\n- --dashed\n-----BEGIN PGP SIGNATURE-----\n\nbogus\n-----END PGP SIGNATURE-----\n";
+    assert_eq!(
+        strip_clearsign(inrelease),
+        "Origin: AOSC\nSuite: foo\n--dashed"
+    );
+    assert_eq!(strip_clearsign("Suite: foo\n"), "Suite: foo\n");
+    assert!(keyring::signature_issuers(inrelease).is_empty());
+
+    let keys_root = tempfile::tempdir()?;
+    let cache = tempfile::tempdir()?;
+    let mut verifier = TopicVerifier {
+        keys_root: Some(keys_root.path()),
+        keyring: Some(Path::new("/tmp/topics.gpg")),
+        cache: Some(cache.path().to_path_buf()),
+        ..Default::default()
+    };
+    let e = verifier.verify("foo", inrelease).unwrap_err().to_string();
+    assert!(e.contains("topic foo"), "{}", e);
+    assert!(e.contains("Keyring searched: /tmp/topics.gpg"), "{}", e);
+
+    verifier.allow_unverified = true;
+    assert_eq!(
+        verifier.verify("foo", inrelease)?,
+        "Origin: AOSC\nSuite: foo\n--dashed"
+    );
+    // what failed verification is not cached
+    assert_eq!(std::fs::read_dir(cache.path())?.count(), 0);
+
+    // the messages verified before are taken from the cache, if no one else can write
+    // there and they are still valid
+    use std::os::unix::fs::PermissionsExt;
+    verifier.allow_unverified = false;
+    let cached = verifier.cached_path("foo", inrelease)?.unwrap();
+    std::fs::write(&cached, "Suite: foo\n")?;
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o644))?;
+    assert_eq!(verifier.verify("foo", inrelease)?, "Suite: foo\n");
+    assert!(verifier.verify("bar", inrelease).is_err());
+    // nor with other trusted keys
+    let trusted = keys_root.path().join("etc/apt/trusted.gpg.d");
+    std::fs::create_dir_all(&trusted)?;
+    std::fs::write(trusted.join("extra.gpg"), "key")?;
+    assert!(verifier.verify("foo", inrelease).is_err());
+    std::fs::remove_file(trusted.join("extra.gpg"))?;
+    assert_eq!(verifier.verify("foo", inrelease)?, "Suite: foo\n");
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o666))?;
+    assert!(verifier.verify("foo", inrelease).is_err());
+    std::fs::set_permissions(&cached, std::fs::Permissions::from_mode(0o644))?;
+    std::fs::write(
+        &cached,
+        "Suite: foo\nValid-Until: Thu, 01 Jan 2015 00:00:00 UTC\n",
+    )?;
+    assert!(verifier.verify("foo", inrelease).is_err());
+    // concurrent runs saving the same message do not clobber each other
+    let shared = cache.path().join("shared/foo-0");
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let shared = &shared;
+            scope.spawn(move || {
+                for _ in 0..16 {
+                    save_verified(shared, &format!("Suite: foo\n{}\n", "x".repeat(i * 4096)))
+                        .unwrap();
+                }
+            });
+        }
+    });
+    let saved = std::fs::read_to_string(&shared)?;
+    assert!((0..8).any(|i| saved == format!("Suite: foo\n{}\n", "x".repeat(i * 4096))));
+    assert_eq!(std::fs::read_dir(cache.path().join("shared"))?.count(), 1);
+
+    assert_eq!(
+        parse_release_date("Sat, 18 Oct 2025 12:34:56 UTC"),
+        Some(1760790896)
+    );
+    assert_eq!(parse_release_date("1 Jan 1970 00:00:00 GMT"), Some(0));
+    assert_eq!(parse_release_date("Sat, 18 Oct 2025 12:34:56 +0800"), None);
+    assert!(!release_expired("Suite: foo\n"));
+    assert!(release_expired(
+        "Valid-Until: Sat, 18 Oct 2025 12:34:56 UTC\n"
+    ));
+    assert!(!release_expired(
+        "Valid-Until: Fri, 01 Jan 2100 00:00:00 UTC\n"
+    ));
 
     Ok(())
 }
@@ -0,0 +1,203 @@
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::fs::{atomic_write, format_apt_source};
+
+const PINS_LIST: &str = "etc/apt/sources.list.d/aoscbootstrap-pins.list";
+const PINS_SOURCES: &str = "etc/apt/sources.list.d/aoscbootstrap-pins.sources";
+const PINS_PREFERENCES: &str = "etc/apt/preferences.d/aoscbootstrap-pins.pref";
+
+/// Packages taken from another branch, by branch
+#[derive(Debug, Default, PartialEq)]
+pub struct Pins(BTreeMap<String, Vec<String>>);
+
+impl Pins {
+    /// Parse the `<package>=<branch>` arguments of `--pin`
+    pub fn parse(specs: &[String], branch: &str) -> Result<Self> {
+        let mut pins = BTreeMap::<String, Vec<String>>::new();
+        let mut seen = BTreeMap::new();
+        for spec in specs {
+            let Some((package, pin_branch)) = spec
+                .split_once('=')
+                .filter(|(p, b)| !p.is_empty() && !b.is_empty())
+            else {
+                return Err(anyhow!(
+                    "Invalid pin '{}', expected <package>=<branch>.",
+                    spec
+                ));
+            };
+            if pin_branch == branch {
+                return Err(anyhow!(
+                    "Cannot pin {} to {}, the branch being bootstrapped.",
+                    package,
+                    pin_branch
+                ));
+            }
+            if let Some(other) = seen.insert(package, pin_branch) {
+                if other != pin_branch {
+                    return Err(anyhow!(
+                        "{} is pinned to both {} and {}.",
+                        package,
+                        other,
+                        pin_branch
+                    ));
+                }
+                continue;
+            }
+            pins.entry(pin_branch.to_string())
+                .or_default()
+                .push(package.to_string());
+        }
+
+        Ok(Pins(pins))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn branches(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.0.iter().map(|(b, p)| (b.as_str(), p.as_slice()))
+    }
+
+    /// Write the sources of the pinned branches, and the apt preferences keeping the pinned
+    /// packages on them while the other packages stay on the main branch
+    pub fn save(
+        &self,
+        root: &Path,
+        mirror: &str,
+        comps: &[&str],
+        arches: &[&str],
+        deb822: bool,
+        signed_by: &[String],
+    ) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        info!("Saving the sources of the pinned branches ...");
+        let sources = self
+            .0
+            .keys()
+            .map(|b| format_apt_source(mirror, b, comps, arches, deb822, signed_by))
+            .collect::<Vec<_>>()
+            .join(if deb822 { "\n" } else { "" });
+        let path = root.join(if deb822 { PINS_SOURCES } else { PINS_LIST });
+        std::fs::create_dir_all(root.join("etc/apt/sources.list.d"))?;
+        atomic_write(&path, sources, 0o644)?;
+        std::fs::create_dir_all(root.join("etc/apt/preferences.d"))?;
+        atomic_write(&root.join(PINS_PREFERENCES), self.preferences(), 0o644)?;
+
+        Ok(())
+    }
+
+    fn preferences(&self) -> String {
+        let mut stanzas = Vec::new();
+        for (branch, packages) in &self.0 {
+            stanzas.push(format!(
+                "Package: {}\nPin: release n={}\nPin-Priority: 1001\n",
+                packages.join(" "),
+                branch
+            ));
+            // only the pinned packages come from the branch, not the upgrades of the others
+            stanzas.push(format!(
+                "Package: *\nPin: release n={}\nPin-Priority: 100\n",
+                branch
+            ));
+        }
+
+        stanzas.join("\n")
+    }
+}
+
+/// Copy the stanzas of the given packages from a `Packages` manifest into `dest`,
+/// returning the names of the packages found
+pub fn filter_manifest(src: &Path, dest: &Path, packages: &[String]) -> Result<Vec<String>> {
+    let content = std::fs::read_to_string(src)?;
+    let mut kept = String::new();
+    let mut found = Vec::new();
+    for stanza in content.split("\n\n") {
+        let name = stanza
+            .lines()
+            .find_map(|l| l.strip_prefix("Package:"))
+            .map(|n| n.trim());
+        if let Some(name) = name.filter(|n| packages.iter().any(|p| p == n)) {
+            kept.push_str(stanza.trim_end_matches('\n'));
+            kept.push_str("\n\n");
+            found.push(name.to_string());
+        }
+    }
+    std::fs::write(dest, kept)?;
+
+    Ok(found)
+}
+
+/// Path of the filtered copy of a manifest in `dir`
+pub fn filtered_path(dir: &Path, manifest: &str) -> PathBuf {
+    dir.join(format!("{}.pinned", manifest))
+}
+
+#[test]
+fn test_pins() -> Result<()> {
+    let specs = |s: &[&str]| s.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+    let pins = Pins::parse(
+        &specs(&[
+            "linux-kernel=testing",
+            "mesa=testing",
+            "mesa=testing",
+            "gcc=next",
+        ]),
+        "stable",
+    )?;
+    assert_eq!(
+        pins.branches().collect::<Vec<_>>(),
+        [
+            ("next", &specs(&["gcc"])[..]),
+            ("testing", &specs(&["linux-kernel", "mesa"])[..])
+        ]
+    );
+    assert!(Pins::parse(&specs(&["mesa"]), "stable").is_err());
+    assert!(Pins::parse(&specs(&["mesa="]), "stable").is_err());
+    assert!(Pins::parse(&specs(&["mesa=stable"]), "stable").is_err());
+    assert!(Pins::parse(&specs(&["mesa=testing", "mesa=next"]), "stable").is_err());
+
+    let root = tempfile::tempdir()?;
+    pins.save(
+        root.path(),
+        "https://repo.aosc.io/debs",
+        &["main"],
+        &["amd64", "all"],
+        false,
+        &[],
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(root.path().join(PINS_LIST))?,
+        "deb https://repo.aosc.io/debs next main\ndeb https://repo.aosc.io/debs testing main\n"
+    );
+    let prefs = std::fs::read_to_string(root.path().join(PINS_PREFERENCES))?;
+    assert!(
+        prefs.contains("Package: linux-kernel mesa\nPin: release n=testing\nPin-Priority: 1001\n")
+    );
+    assert!(prefs.contains("Package: *\nPin: release n=next\nPin-Priority: 100\n"));
+
+    let manifest = root.path().join("Packages");
+    std::fs::write(
+        &manifest,
+        "Package: bash\nVersion: 5.2\n\nPackage: linux-kernel\nVersion: 6.12\n\nPackage: mesa\nVersion: 24.3\n",
+    )?;
+    let dest = filtered_path(root.path(), "Packages");
+    assert_eq!(
+        filter_manifest(&manifest, &dest, &specs(&["mesa", "linux-kernel", "gcc"]))?,
+        ["linux-kernel", "mesa"]
+    );
+    assert_eq!(
+        std::fs::read_to_string(&dest)?,
+        "Package: linux-kernel\nVersion: 6.12\n\nPackage: mesa\nVersion: 24.3\n\n"
+    );
+
+    Ok(())
+}
@@ -0,0 +1,295 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    fs::atomic_write,
+    network::{sha256sum_file, topic_inrelease_name, Manifest},
+    solv::TOPIC_REPO_PREFIX,
+};
+
+const SNAPSHOT_VERSION: u32 = 1;
+const INDEX_FILE: &str = "index.toml";
+const TOPICS_FILE: &str = "topics.json";
+
+/// A copy of the manifests a build was resolved against, which can be used to replay it
+/// after the mirror has moved on.
+#[derive(Deserialize, Serialize)]
+pub struct Snapshot {
+    version: u32,
+    mirror: String,
+    branch: String,
+    arches: Vec<String>,
+    comps: Vec<String>,
+    #[serde(rename = "file")]
+    files: Vec<SnapshotFile>,
+    #[serde(skip)]
+    dir: PathBuf,
+}
+
+#[derive(Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum FileKind {
+    Packages,
+    InRelease,
+    Topics,
+}
+
+#[derive(Deserialize, Serialize)]
+struct SnapshotFile {
+    name: String,
+    kind: FileKind,
+    /// Repository the file belongs to, empty for the topics manifest.
+    #[serde(default)]
+    repo: String,
+    sha256: String,
+}
+
+impl Snapshot {
+    /// Copy the manifests from `lists` into `dir`, along with the InRelease files of the
+    /// topics and the topics manifest, and index them with their checksums.
+    #[allow(clippy::too_many_arguments)]
+    pub fn save(
+        dir: &Path,
+        lists: &Path,
+        mirror: &str,
+        branch: &str,
+        arches: &[&str],
+        comps: &[&str],
+        manifests: &[Manifest],
+        topics_manifest: Option<&str>,
+    ) -> Result<()> {
+        std::fs::create_dir_all(dir)?;
+        let mut files = Vec::new();
+        let mut copy = |name: String, kind: FileKind, repo: &str| -> Result<()> {
+            let path = dir.join(&name);
+            std::fs::copy(lists.join(&name), &path).context(format!("when copying {}", name))?;
+            files.push(SnapshotFile {
+                sha256: sha256sum_file(&path)?,
+                name,
+                kind,
+                repo: repo.to_string(),
+            });
+
+            Ok(())
+        };
+        for m in manifests {
+            copy(m.file_name.clone(), FileKind::Packages, &m.repo)?;
+        }
+        let mut topics = manifests
+            .iter()
+            .filter_map(|m| m.repo.strip_prefix(TOPIC_REPO_PREFIX))
+            .collect::<Vec<_>>();
+        topics.sort_unstable();
+        topics.dedup();
+        for topic in topics {
+            copy(
+                topic_inrelease_name(topic),
+                FileKind::InRelease,
+                &format!("{}{}", TOPIC_REPO_PREFIX, topic),
+            )?;
+        }
+        if let Some(topics_manifest) = topics_manifest {
+            let path = dir.join(TOPICS_FILE);
+            atomic_write(&path, topics_manifest, 0o644)?;
+            files.push(SnapshotFile {
+                name: TOPICS_FILE.to_string(),
+                kind: FileKind::Topics,
+                repo: String::new(),
+                sha256: sha256sum_file(&path)?,
+            });
+        }
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        let snapshot = Snapshot {
+            version: SNAPSHOT_VERSION,
+            mirror: mirror.to_string(),
+            branch: branch.to_string(),
+            arches: arches.iter().map(|a| a.to_string()).collect(),
+            comps: comps.iter().map(|c| c.to_string()).collect(),
+            files,
+            dir: dir.to_path_buf(),
+        };
+        let mut index = String::from("# This file is generated by aoscbootstrap. Do not edit.\n");
+        index.push_str(&toml::to_string(&snapshot)?);
+
+        atomic_write(&dir.join(INDEX_FILE), index, 0o644)
+    }
+
+    /// Read the snapshot in `dir`, checking every file against the index.
+    pub fn read(dir: &Path) -> Result<Self> {
+        let index = std::fs::read_to_string(dir.join(INDEX_FILE))
+            .context(format!("when reading {}", INDEX_FILE))?;
+        let mut snapshot: Snapshot = toml::from_str(&index)?;
+        if snapshot.version != SNAPSHOT_VERSION {
+            return Err(anyhow!(
+                "Unsupported snapshot version {} (expected {})",
+                snapshot.version,
+                SNAPSHOT_VERSION
+            ));
+        }
+        for f in &snapshot.files {
+            // the names end up in paths, do not let them point outside the snapshot
+            if f.name.contains('/') || f.name.starts_with('.') {
+                return Err(anyhow!("Invalid file name '{}' in the snapshot", f.name));
+            }
+            let sha256 =
+                sha256sum_file(&dir.join(&f.name)).context(format!("when reading {}", f.name))?;
+            if !sha256.eq_ignore_ascii_case(&f.sha256) {
+                return Err(anyhow!(
+                    "{} does not match the checksum in the snapshot index",
+                    f.name
+                ));
+            }
+        }
+        snapshot.dir = dir.to_path_buf();
+
+        Ok(snapshot)
+    }
+
+    pub fn mirror(&self) -> &str {
+        &self.mirror
+    }
+
+    /// Check that the snapshot covers exactly what is requested, so that the pool is the
+    /// same as the one the snapshot was taken from.
+    pub fn check(&self, branch: &str, arches: &[&str], comps: &[&str]) -> Result<()> {
+        if self.branch != branch {
+            return Err(anyhow!(
+                "The snapshot was saved for branch {}, but {} is requested.",
+                self.branch,
+                branch
+            ));
+        }
+        let same = |a: &[String], b: &[&str]| {
+            a.len() == b.len() && b.iter().all(|x| a.iter().any(|y| y == x))
+        };
+        if !same(&self.arches, arches) {
+            return Err(anyhow!(
+                "The snapshot was saved for {}, but {} is requested.",
+                self.arches.join(", "),
+                arches.join(", ")
+            ));
+        }
+        if !same(&self.comps, comps) {
+            return Err(anyhow!(
+                "The snapshot was saved with components {}, but {} is requested.",
+                self.comps.join(", "),
+                comps.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The topics manifest saved along with the manifests.
+    pub fn topics_manifest(&self) -> Result<String> {
+        if !self.files.iter().any(|f| f.kind == FileKind::Topics) {
+            return Err(anyhow!(
+                "The snapshot has no topics manifest, it was saved without topics."
+            ));
+        }
+
+        Ok(std::fs::read_to_string(self.dir.join(TOPICS_FILE))?)
+    }
+
+    /// Copy the files of the given repositories into `lists`, returning their manifests.
+    pub fn restore(&self, lists: &Path, repos: &[String]) -> Result<Vec<Manifest>> {
+        let mut manifests = Vec::new();
+        for repo in repos {
+            let files = self.files.iter().filter(|f| &f.repo == repo);
+            let mut found = false;
+            for f in files {
+                std::fs::copy(self.dir.join(&f.name), lists.join(&f.name))
+                    .context(format!("when copying {}", f.name))?;
+                if f.kind == FileKind::Packages {
+                    manifests.push(Manifest {
+                        file_name: f.name.clone(),
+                        repo: repo.clone(),
+                    });
+                    found = true;
+                }
+            }
+            if !found {
+                return Err(anyhow!("The snapshot has no manifest of {}.", repo));
+            }
+        }
+
+        Ok(manifests)
+    }
+}
+
+#[test]
+fn test_snapshot_roundtrip() -> Result<()> {
+    let lists = tempfile::tempdir()?;
+    let manifests = [
+        (
+            "repo.aosc.io_debs_dists_stable_main_binary-amd64_Packages",
+            "stable",
+        ),
+        (
+            "repo.aosc.io_debs_dists_stable_main_binary-all_Packages",
+            "stable",
+        ),
+        (
+            "repo.aosc.io_debs_dists_foo_main_binary-amd64_Packages",
+            "topic:foo",
+        ),
+    ]
+    .iter()
+    .map(|(name, repo)| {
+        std::fs::write(lists.path().join(name), format!("Package: {}\n", name)).unwrap();
+        Manifest {
+            file_name: name.to_string(),
+            repo: repo.to_string(),
+        }
+    })
+    .collect::<Vec<_>>();
+    std::fs::write(lists.path().join(topic_inrelease_name("foo")), "signed")?;
+    let dir = tempfile::tempdir()?;
+    Snapshot::save(
+        dir.path(),
+        lists.path(),
+        "https://repo.aosc.io/debs",
+        "stable",
+        &["amd64", "all"],
+        &["main"],
+        &manifests,
+        Some("[]"),
+    )?;
+
+    let snapshot = Snapshot::read(dir.path())?;
+    assert_eq!(snapshot.mirror(), "https://repo.aosc.io/debs");
+    snapshot.check("stable", &["all", "amd64"], &["main"])?;
+    assert!(snapshot
+        .check("testing", &["amd64", "all"], &["main"])
+        .is_err());
+    assert!(snapshot
+        .check("stable", &["arm64", "all"], &["main"])
+        .is_err());
+    assert!(snapshot
+        .check("stable", &["amd64", "all"], &["main", "bsp"])
+        .is_err());
+    assert_eq!(snapshot.topics_manifest()?, "[]");
+
+    let target = tempfile::tempdir()?;
+    let restored = snapshot.restore(
+        target.path(),
+        &["stable".to_string(), "topic:foo".to_string()],
+    )?;
+    assert_eq!(restored.len(), 3);
+    assert!(target.path().join(topic_inrelease_name("foo")).exists());
+    assert_eq!(
+        std::fs::read_to_string(target.path().join(&manifests[2].file_name))?,
+        std::fs::read_to_string(lists.path().join(&manifests[2].file_name))?
+    );
+    assert!(snapshot
+        .restore(target.path(), &["topic:bar".to_string()])
+        .is_err());
+
+    // a tampered manifest is refused
+    std::fs::write(dir.path().join(&manifests[0].file_name), "Package: evil\n")?;
+    assert!(Snapshot::read(dir.path()).is_err());
+
+    Ok(())
+}
@@ -1,31 +1,252 @@
-use super::PackageMeta;
+use super::{package_name, parse_version_constraint, PackageMeta, TOPIC_REPO_PREFIX};
 use anyhow::{anyhow, Result};
 use faster_hex::hex_string;
 use libc::{c_char, c_int};
 use libsolv_sys::ffi;
-use std::{convert::TryInto, ffi::CStr, os::unix::ffi::OsStrExt, slice};
-use std::{ffi::CString, path::Path, ptr::null_mut};
+use std::{collections::HashSet, ffi::CStr, os::unix::ffi::OsStrExt, slice};
+use std::{ffi::CString, marker::PhantomData, path::Path, ptr::null_mut};
 
 pub const SELECTION_NAME: c_int = 1 << 0;
 pub const SELECTION_FLAT: c_int = 1 << 10;
 pub const SELECTION_ADD: c_int = 1 << 28;
 
+pub const REL_GT: c_int = 1;
+pub const REL_EQ: c_int = 2;
+pub const REL_LT: c_int = 4;
+
+pub const SOLVER_SOLVABLE_NAME: c_int = 0x02;
 pub const SOLVER_INSTALL: c_int = 0x100;
+pub const SOLVER_LOCK: c_int = 0x600;
+pub const SOLVER_FAVOR: c_int = 0xc00;
 
+pub const SOLVER_FLAG_IGNORE_RECOMMENDED: c_int = 7;
 pub const SOLVER_FLAG_BEST_OBEY_POLICY: c_int = 12;
 
+/// Symbolic names of the libsolv solver flags, without the `SOLVER_FLAG_` prefix
+pub const SOLVER_FLAGS: &[(&str, c_int)] = &[
+    ("ALLOW_DOWNGRADE", 1),
+    ("ALLOW_ARCHCHANGE", 2),
+    ("ALLOW_VENDORCHANGE", 3),
+    ("ALLOW_UNINSTALL", 4),
+    ("NO_UPDATEPROVIDE", 5),
+    ("SPLITPROVIDES", 6),
+    ("IGNORE_RECOMMENDED", SOLVER_FLAG_IGNORE_RECOMMENDED),
+    ("ADD_ALREADY_RECOMMENDED", 8),
+    ("NO_INFARCHCHECK", 9),
+    ("ALLOW_NAMECHANGE", 10),
+    ("KEEP_EXPLICIT_OBSOLETES", 11),
+    ("BEST_OBEY_POLICY", SOLVER_FLAG_BEST_OBEY_POLICY),
+    ("NO_AUTOTARGET", 13),
+    ("DUP_ALLOW_DOWNGRADE", 14),
+    ("DUP_ALLOW_ARCHCHANGE", 15),
+    ("DUP_ALLOW_VENDORCHANGE", 16),
+    ("DUP_ALLOW_NAMECHANGE", 17),
+    ("KEEP_ORPHANS", 18),
+    ("BREAK_ORPHANS", 19),
+    ("FOCUS_INSTALLED", 20),
+    ("YUM_OBSOLETES", 21),
+    ("NEED_UPDATEPROVIDE", 22),
+    ("URPM_REORDER", 23),
+    ("FOCUS_BEST", 24),
+    ("STRONG_RECOMMENDS", 25),
+    ("INSTALL_ALSO_UPDATES", 26),
+    ("ONLY_NAMESPACE_RECOMMENDED", 27),
+    ("STRICT_REPO_PRIORITY", 28),
+];
+
+pub const SOLVER_REASON_UNIT_RULE: c_int = 1;
+pub const SOLVER_REASON_RESOLVE_JOB: c_int = 3;
+pub const SOLVER_REASON_RESOLVE: c_int = 6;
+
+pub const SOLVER_RULE_JOB: c_int = 0x400;
+
+pub const SOLVER_TRANSACTION_SHOW_ACTIVE: c_int = 1 << 0;
+pub const SOLVER_TRANSACTION_SHOW_MULTIINSTALL: c_int = 1 << 3;
+pub const SOLVER_TRANSACTION_INSTALL: c_int = 0x20;
+
+/// Errors when converting transaction steps into package metadata
+#[derive(Debug)]
+pub enum MetadataError {
+    /// The transaction step does not install a new package
+    UnsupportedStep { name: String, step: &'static str },
+    /// The package is missing a field required for bootstrapping
+    MissingMetadata { name: String, field: &'static str },
+    /// The package uses a checksum type other than SHA256
+    UnsupportedChecksum { name: String, sum_type: ffi::Id },
+    /// The same version of the package is in two repositories with different contents
+    ChecksumMismatch {
+        name: String,
+        version: String,
+        arch: String,
+        repos: (String, String),
+    },
+}
+
+impl std::fmt::Display for MetadataError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataError::UnsupportedStep { name, step } => write!(
+                f,
+                "Unsupported transaction step for {}: {} (only installation is supported)",
+                name, step
+            ),
+            MetadataError::MissingMetadata { name, field } => {
+                write!(f, "Package {} has no {} field in the manifest", name, field)
+            }
+            MetadataError::UnsupportedChecksum { name, sum_type } => write!(
+                f,
+                "Package {} has an unsupported checksum type: {}",
+                name, sum_type
+            ),
+            MetadataError::ChecksumMismatch {
+                name,
+                version,
+                arch,
+                repos,
+            } => write!(
+                f,
+                "Package {} {} ({}) is in both {} and {} with different checksums, the repositories are inconsistent",
+                name, version, arch, repos.0, repos.1
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MetadataError {}
+
+/// Describe a libsolv transaction type
+fn step_name(step: c_int) -> &'static str {
+    match step {
+        0x00 => "ignore",
+        0x10 => "erase",
+        0x11 => "reinstalled",
+        0x12 => "downgraded",
+        0x13 => "changed",
+        0x14 => "upgraded",
+        0x15 => "obsoleted",
+        0x21 => "reinstall",
+        0x22 => "downgrade",
+        0x23 => "change",
+        0x24 => "upgrade",
+        0x25 => "obsoletes",
+        0x30 => "multi-install",
+        0x31 => "multi-reinstall",
+        _ => "unknown",
+    }
+}
+
+/// The packages known to libsolv. The repositories, solvers and transactions borrow it,
+/// so that it outlives them.
 pub struct Pool {
     pool: *mut ffi::Pool,
 }
 
+// the pool is only reached through this struct and what borrows it, so it may move to
+// another thread with them, but not be used from two threads at once (it is not Sync)
+unsafe impl Send for Pool {}
+
 macro_rules! cstr {
     ($s:expr) => {
         CString::new($s)?.as_ptr() as *const c_char
     };
 }
 
+/// The elements of a libsolv queue
+unsafe fn queue_slice(queue: &ffi::Queue) -> &[ffi::Id] {
+    if queue.count <= 0 || queue.elements.is_null() {
+        return &[];
+    }
+    slice::from_raw_parts(queue.elements, queue.count as usize)
+}
+
+/// The solvable `p` of the pool, panicking if there is no such solvable
+unsafe fn solvable(pool: *mut ffi::Pool, p: ffi::Id) -> *mut ffi::Solvable {
+    assert!(
+        p > 0 && p < (*pool).nsolvables,
+        "solvable {} is not in the pool",
+        p
+    );
+    (*pool).solvables.offset(p as isize)
+}
+
+/// Name of the solvable `p` of the pool
+unsafe fn solvable_name(pool: *mut ffi::Pool, p: ffi::Id) -> String {
+    CStr::from_ptr(ffi::pool_id2str(pool, (*solvable(pool, p)).name))
+        .to_string_lossy()
+        .to_string()
+}
+
+/// Collect the solvables providing the given dependency.
+/// Equivalent to the `pool_whatprovides` inline function in libsolv.
+unsafe fn whatprovides(pool: *mut ffi::Pool, dep: ffi::Id) -> Vec<ffi::Id> {
+    // ISRELDEP
+    let offset = if dep as u32 & 0x80000000 != 0 {
+        ffi::pool_addrelproviders(pool, dep) as isize
+    } else if (*pool).whatprovides.is_null() || dep <= 0 || dep >= (*pool).ss.nstrings {
+        return Vec::new();
+    } else {
+        *(*pool).whatprovides.offset(dep as isize) as isize
+    };
+    let mut providers = Vec::new();
+    let mut p = (*pool).whatprovidesdata.offset(offset);
+    while *p != 0 {
+        providers.push(*p);
+        p = p.offset(1);
+    }
+
+    providers
+}
+
+/// The solvables of the pool with the same name, version and architecture as `p`, from
+/// every repository, including `p` itself
+unsafe fn equivalents(pool: *mut ffi::Pool, p: ffi::Id) -> Vec<ffi::Id> {
+    let s = solvable(pool, p);
+    let mut same = whatprovides(pool, (*s).name)
+        .into_iter()
+        .filter(|q| {
+            let other = solvable(pool, *q);
+            !(*other).repo.is_null()
+                && (*other).name == (*s).name
+                && (*other).evr == (*s).evr
+                && (*other).arch == (*s).arch
+        })
+        .collect::<Vec<_>>();
+    if !same.contains(&p) {
+        same.push(p);
+    }
+
+    same
+}
+
+/// Priority of the repository of the solvable
+unsafe fn repo_priority(s: *mut ffi::Solvable) -> c_int {
+    (*(*s).repo).priority
+}
+
+#[inline]
+fn lookup_num(s: *mut ffi::Solvable, key: u32) -> u64 {
+    unsafe { ffi::solvable_lookup_num(s, key as i32, 0) }
+}
+
+#[inline]
+fn lookup_str(s: *mut ffi::Solvable, key: u32) -> Option<String> {
+    unsafe {
+        let value = ffi::solvable_lookup_str(s, key as i32);
+        if value.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(value).to_string_lossy().to_string())
+        }
+    }
+}
+
 #[inline]
 fn solvable_to_meta(s: *mut ffi::Solvable) -> Result<PackageMeta> {
+    let name = lookup_str(s, ffi::solv_knownid_SOLVABLE_NAME).unwrap_or_default();
+    let missing = |field| MetadataError::MissingMetadata {
+        name: name.clone(),
+        field,
+    };
     let mut sum_type: ffi::Id = 0;
     let checksum = unsafe {
         ffi::solvable_lookup_bin_checksum(
@@ -34,49 +255,44 @@ fn solvable_to_meta(s: *mut ffi::Solvable) -> Result<PackageMeta> {
             &mut sum_type,
         )
     };
+    if checksum.is_null() || sum_type == 0 {
+        return Err(missing("SHA256").into());
+    }
     if sum_type != (ffi::solv_knownid_REPOKEY_TYPE_SHA256 as i32) {
-        return Err(anyhow!("Unsupported checksum type: {}", sum_type));
+        return Err(MetadataError::UnsupportedChecksum {
+            name: name.clone(),
+            sum_type,
+        }
+        .into());
     }
     let checksum = unsafe { slice::from_raw_parts(checksum, 32) };
-    let name = unsafe {
-        CStr::from_ptr(ffi::solvable_lookup_str(
-            s,
-            ffi::solv_knownid_SOLVABLE_NAME as i32,
-        ))
-    };
-    let version = unsafe {
-        CStr::from_ptr(ffi::solvable_lookup_str(
-            s,
-            ffi::solv_knownid_SOLVABLE_EVR as i32,
-        ))
-    };
-    let path = unsafe {
-        CStr::from_ptr(ffi::solvable_lookup_str(
-            s,
-            ffi::solv_knownid_SOLVABLE_MEDIADIR as i32,
-        ))
-    };
-    let filename = unsafe {
-        CStr::from_ptr(ffi::solvable_lookup_str(
-            s,
-            ffi::solv_knownid_SOLVABLE_MEDIAFILE as i32,
-        ))
-    };
-    let arch = unsafe {
-        CStr::from_ptr(ffi::solvable_lookup_str(
-            s,
-            ffi::solv_knownid_SOLVABLE_ARCH as i32,
-        ))
-    };
-    let in_topic = !String::from(path.to_string_lossy()).contains("stable");
+    let version =
+        lookup_str(s, ffi::solv_knownid_SOLVABLE_EVR).ok_or_else(|| missing("Version"))?;
+    let path =
+        lookup_str(s, ffi::solv_knownid_SOLVABLE_MEDIADIR).ok_or_else(|| missing("Filename"))?;
+    let filename =
+        lookup_str(s, ffi::solv_knownid_SOLVABLE_MEDIAFILE).ok_or_else(|| missing("Filename"))?;
+    let arch =
+        lookup_str(s, ffi::solv_knownid_SOLVABLE_ARCH).ok_or_else(|| missing("Architecture"))?;
+    let repo = unsafe { CStr::from_ptr((*(*s).repo).name) }
+        .to_string_lossy()
+        .to_string();
+    let in_topic = repo.starts_with(TOPIC_REPO_PREFIX);
+    let installed_size = lookup_num(s, ffi::solv_knownid_SOLVABLE_INSTALLSIZE);
+    let download_size = lookup_num(s, ffi::solv_knownid_SOLVABLE_DOWNLOADSIZE);
+    let section = lookup_str(s, ffi::solv_knownid_SOLVABLE_GROUP).unwrap_or_default();
 
     Ok(PackageMeta {
-        name: name.to_string_lossy().to_string(),
-        version: version.to_string_lossy().to_string(),
+        name,
+        version,
         sha256: hex_string(checksum),
-        path: path.to_string_lossy().to_string() + "/" + &filename.to_string_lossy(),
-        arch: arch.to_string_lossy().to_string(),
+        path: path + "/" + &filename,
+        arch,
         in_topic,
+        repo,
+        section,
+        installed_size,
+        download_size,
     })
 }
 
@@ -94,6 +310,25 @@ impl Pool {
                 "internal error: `createwhatprovides` needs to be called first."
             ));
         }
+        if let Some((name, flags, evr)) = parse_version_constraint(name)? {
+            unsafe {
+                let name_id = ffi::pool_str2id(self.pool, cstr!(name), 0);
+                if name_id == 0 {
+                    // unknown package name, leave the selection empty
+                    return Ok(queue);
+                }
+                let evr_id = ffi::pool_str2id(self.pool, cstr!(evr), 1);
+                let rel_id = ffi::pool_rel2id(self.pool, name_id, evr_id, flags, 1);
+                ffi::queue_insert2(
+                    &mut queue.queue,
+                    queue.queue.count,
+                    SOLVER_SOLVABLE_NAME,
+                    rel_id,
+                );
+            }
+
+            return Ok(queue);
+        }
         unsafe {
             ffi::selection_make(
                 self.pool,
@@ -106,6 +341,48 @@ impl Pool {
         Ok(queue)
     }
 
+    /// Check whether any package in the pool matches the given name
+    pub fn has_package(&self, name: &str) -> Result<bool> {
+        let q = self.match_package(name, Queue::new())?;
+
+        Ok(!q.is_empty())
+    }
+
+    /// Names of all the packages in the pool
+    pub fn package_names(&self) -> Vec<String> {
+        let mut names = HashSet::new();
+        unsafe {
+            // the first two solvables are reserved by libsolv
+            for p in 2..(*self.pool).nsolvables {
+                if (*solvable(self.pool, p)).repo.is_null() {
+                    continue;
+                }
+                names.insert(solvable_name(self.pool, p));
+            }
+        }
+
+        names.into_iter().collect()
+    }
+
+    /// Names of the packages in the pool providing the given capability
+    pub fn providers(&self, dep: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        unsafe {
+            let id = ffi::pool_str2id(self.pool, cstr!(dep), 0);
+            if id == 0 {
+                return Ok(names);
+            }
+            for p in whatprovides(self.pool, id) {
+                let name = solvable_name(self.pool, p);
+                if !(*solvable(self.pool, p)).repo.is_null() && !names.contains(&name) {
+                    names.push(name);
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
     pub fn createwhatprovides(&mut self) {
         unsafe { ffi::pool_createwhatprovides(self.pool) }
     }
@@ -117,18 +394,25 @@ impl Drop for Pool {
     }
 }
 
-pub struct Repo {
+/// A repository of the pool, freed along with it
+pub struct Repo<'a> {
     repo: *mut ffi::Repo,
+    pool: PhantomData<&'a Pool>,
 }
 
-impl Repo {
-    pub fn new(pool: &Pool, name: &str) -> Result<Repo> {
+impl<'a> Repo<'a> {
+    pub fn new(pool: &'a Pool, name: &str) -> Result<Repo<'a>> {
         let name = CString::new(name)?;
         Ok(Repo {
             repo: unsafe { ffi::repo_create(pool.pool, name.as_ptr()) },
+            pool: PhantomData,
         })
     }
 
+    pub fn set_priority(&mut self, priority: c_int) {
+        unsafe { (*self.repo).priority = priority }
+    }
+
     pub fn add_debpackages(&mut self, path: &Path) -> Result<()> {
         let mut path_buf = path.as_os_str().as_bytes().to_owned();
         path_buf.push(0);
@@ -150,6 +434,9 @@ pub struct Queue {
     queue: ffi::Queue,
 }
 
+// the elements are owned by the queue, not shared with the pool
+unsafe impl Send for Queue {}
+
 impl Queue {
     pub fn new() -> Queue {
         Queue {
@@ -162,12 +449,57 @@ impl Queue {
         }
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.queue.count == 0
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.count.max(0) as usize
+    }
+
     pub fn mark_all_for_install(&mut self) {
-        for item in (0..self.queue.count).step_by(2) {
-            unsafe {
-                let addr = self.queue.elements.offset(item.try_into().unwrap());
-                (*addr) |= SOLVER_INSTALL;
-            }
+        self.mark_all(SOLVER_INSTALL);
+    }
+
+    pub fn mark_all_for_lock(&mut self) {
+        self.mark_all(SOLVER_LOCK);
+    }
+
+    pub fn mark_all_for_favor(&mut self) {
+        self.mark_all(SOLVER_FAVOR);
+    }
+
+    /// The elements of the queue, pairs of (how, what) for jobs
+    pub fn as_slice(&self) -> &[ffi::Id] {
+        unsafe { queue_slice(&self.queue) }
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [ffi::Id] {
+        if self.queue.count <= 0 || self.queue.elements.is_null() {
+            return &mut [];
+        }
+        unsafe { slice::from_raw_parts_mut(self.queue.elements, self.queue.count as usize) }
+    }
+
+    fn mark_all(&mut self, job: c_int) {
+        // the jobs are pairs of (how, what)
+        for pair in self.as_mut_slice().chunks_exact_mut(2) {
+            pair[0] |= job;
+        }
+    }
+
+    /// Append all the jobs from another queue
+    pub fn extend(&mut self, other: &Queue) {
+        if other.is_empty() {
+            return;
+        }
+        unsafe {
+            ffi::queue_insertn(
+                &mut self.queue,
+                self.queue.count,
+                other.queue.count,
+                other.queue.elements,
+            )
         }
     }
 }
@@ -178,48 +510,271 @@ impl Drop for Queue {
     }
 }
 
-pub struct Transaction {
+/// The result of a solver run, borrowing the pool its steps refer to
+pub struct Transaction<'a> {
     t: *mut ffi::Transaction,
+    pool: &'a Pool,
 }
 
-impl Transaction {
+impl Transaction<'_> {
     pub fn get_size_change(&self) -> i64 {
         unsafe { ffi::transaction_calc_installsizechange(self.t) }
     }
 
-    pub fn order(&self, flags: c_int) {
-        unsafe { ffi::transaction_order(self.t, flags) }
+    /// The solvables installed by this transaction, in transaction order
+    fn steps(&self) -> &[ffi::Id] {
+        unsafe { queue_slice(&(*self.t).steps) }
     }
 
-    pub fn create_metadata(&self) -> Result<Vec<PackageMeta>> {
+    /// Find the solvable with the given name in this transaction
+    pub fn find_solvable(&self, name: &str) -> Option<ffi::Id> {
+        let pool = self.pool.pool;
+        self.steps()
+            .iter()
+            .copied()
+            .find(|p| unsafe { solvable_name(pool, *p) } == name)
+    }
+
+    /// Names of all the packages in this transaction
+    pub fn names(&self) -> Vec<String> {
+        let pool = self.pool.pool;
+        self.steps()
+            .iter()
+            .map(|p| unsafe { solvable_name(pool, *p) })
+            .collect()
+    }
+
+    /// Names of the packages in this transaction providing the given capability
+    pub fn providers(&self, dep: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        unsafe {
+            let pool = self.pool.pool;
+            let id = ffi::pool_str2id(pool, cstr!(dep), 0);
+            if id == 0 {
+                return Ok(names);
+            }
+            let steps = self.steps();
+            for p in whatprovides(pool, id) {
+                if steps.contains(&p) {
+                    names.push(solvable_name(pool, p));
+                }
+            }
+        }
+
+        Ok(names)
+    }
+
+    /// Find the dependencies of the packages in this transaction which could be satisfied
+    /// by differently named packages. Returns the dependency, all the candidates,
+    /// and the candidates chosen in this transaction.
+    pub fn ambiguous_providers(&self) -> Vec<(String, Vec<String>, Vec<String>)> {
+        let mut results = Vec::new();
+        let mut seen = HashSet::new();
+        unsafe {
+            let pool = self.pool.pool;
+            let steps = self.steps();
+            let installed = steps.iter().copied().collect::<HashSet<_>>();
+            for p in steps {
+                let mut requires = Queue::new();
+                ffi::solvable_lookup_deparray(
+                    solvable(pool, *p),
+                    ffi::solv_knownid_SOLVABLE_REQUIRES as i32,
+                    &mut requires.queue,
+                    -1,
+                );
+                for dep in requires.as_slice() {
+                    if !seen.insert(*dep) {
+                        continue;
+                    }
+                    let mut candidates = Vec::new();
+                    let mut chosen = Vec::new();
+                    for provider in whatprovides(pool, *dep) {
+                        let name = solvable_name(pool, provider);
+                        if installed.contains(&provider) && !chosen.contains(&name) {
+                            chosen.push(name.clone());
+                        }
+                        if !candidates.contains(&name) {
+                            candidates.push(name);
+                        }
+                    }
+                    if candidates.len() > 1 {
+                        let dep = CStr::from_ptr(ffi::pool_dep2str(pool, *dep))
+                            .to_string_lossy()
+                            .to_string();
+                        results.push((dep, candidates, chosen));
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    /// The packages in this transaction with a dependency satisfied by the solvable `p`,
+    /// along with the dependency, in transaction order
+    pub fn requirers(&self, p: ffi::Id) -> Vec<(ffi::Id, String)> {
         let mut results = Vec::new();
         unsafe {
-            let steps = (*self.t).steps.elements;
-            for i in 0..((*self.t).steps.count) {
-                let p = *steps.offset(i as isize);
-                let pool = (*self.t).pool;
-                results.push(solvable_to_meta((*pool).solvables.offset(p as isize))?);
+            let pool = self.pool.pool;
+            for q in self.steps().iter().copied().filter(|q| *q != p) {
+                let mut requires = Queue::new();
+                ffi::solvable_lookup_deparray(
+                    solvable(pool, q),
+                    ffi::solv_knownid_SOLVABLE_REQUIRES as i32,
+                    &mut requires.queue,
+                    0,
+                );
+                for dep in requires.as_slice() {
+                    if *dep == ffi::solv_knownid_SOLVABLE_PREREQMARKER as ffi::Id {
+                        continue;
+                    }
+                    if whatprovides(pool, *dep).contains(&p) {
+                        let dep = CStr::from_ptr(ffi::pool_dep2str(pool, *dep))
+                            .to_string_lossy()
+                            .to_string();
+                        results.push((q, dep));
+                        break;
+                    }
+                }
+            }
+        }
+
+        results
+    }
+
+    /// Collect the packages in this transaction needed by the given packages, including
+    /// themselves, in transaction order. Dependencies are only satisfied by the packages
+    /// chosen in this transaction, so the result is always a subset of it.
+    pub fn closure(&self, names: &[String]) -> Result<Vec<PackageMeta>> {
+        let mut results = Vec::new();
+        unsafe {
+            let pool = self.pool.pool;
+            let steps = self.steps();
+            let installed = steps.iter().copied().collect::<HashSet<_>>();
+            let mut stack = Vec::new();
+            for name in names {
+                let name = package_name(name);
+                let p = self
+                    .find_solvable(name)
+                    .ok_or_else(|| anyhow!("{} is not in the resolved package set", name))?;
+                stack.push(p);
+            }
+            let mut needed = HashSet::new();
+            while let Some(p) = stack.pop() {
+                if !needed.insert(p) {
+                    continue;
+                }
+                let mut requires = Queue::new();
+                ffi::solvable_lookup_deparray(
+                    solvable(pool, p),
+                    ffi::solv_knownid_SOLVABLE_REQUIRES as i32,
+                    &mut requires.queue,
+                    0,
+                );
+                for dep in requires.as_slice() {
+                    if *dep == ffi::solv_knownid_SOLVABLE_PREREQMARKER as ffi::Id {
+                        continue;
+                    }
+                    stack.extend(
+                        whatprovides(pool, *dep)
+                            .into_iter()
+                            .filter(|provider| installed.contains(provider)),
+                    );
+                }
+            }
+            for p in steps.iter().filter(|p| needed.contains(p)) {
+                results.push(self.step_to_meta(*p)?);
             }
         }
 
         Ok(results)
     }
+
+    /// Convert an installation step of this transaction into package metadata. When the
+    /// same package is in several repositories, which must agree on its checksum, the one
+    /// of the repository with the highest priority is used, a topic on ties, so that the
+    /// origin does not depend on the order the repositories were loaded in.
+    fn step_to_meta(&self, p: ffi::Id) -> Result<PackageMeta> {
+        unsafe {
+            let pool = self.pool.pool;
+            let s = solvable(pool, p);
+            let step = ffi::transaction_type(
+                self.t,
+                p,
+                SOLVER_TRANSACTION_SHOW_ACTIVE | SOLVER_TRANSACTION_SHOW_MULTIINSTALL,
+            );
+            if step != SOLVER_TRANSACTION_INSTALL {
+                return Err(MetadataError::UnsupportedStep {
+                    name: solvable_name(pool, p),
+                    step: step_name(step),
+                }
+                .into());
+            }
+            let mut chosen = (repo_priority(s), solvable_to_meta(s)?);
+            for q in equivalents(pool, p).into_iter().filter(|q| *q != p) {
+                let other = solvable(pool, q);
+                let meta = solvable_to_meta(other)?;
+                if meta.sha256 != chosen.1.sha256 {
+                    return Err(MetadataError::ChecksumMismatch {
+                        name: meta.name,
+                        version: meta.version,
+                        arch: meta.arch,
+                        repos: (chosen.1.repo, meta.repo),
+                    }
+                    .into());
+                }
+                let key = |priority: c_int, meta: &PackageMeta| {
+                    (
+                        priority,
+                        meta.in_topic,
+                        std::cmp::Reverse(meta.repo.clone()),
+                    )
+                };
+                if key(repo_priority(other), &meta) > key(chosen.0, &chosen.1) {
+                    chosen = (repo_priority(other), meta);
+                }
+            }
+
+            Ok(chosen.1)
+        }
+    }
+
+    pub fn order(&self, flags: c_int) {
+        unsafe { ffi::transaction_order(self.t, flags) }
+    }
+
+    /// The packages to download and install, each (name, version, architecture) once
+    pub fn create_metadata(&self) -> Result<Vec<PackageMeta>> {
+        let mut seen = HashSet::new();
+        let mut packages = Vec::new();
+        for p in self.steps() {
+            let meta = self.step_to_meta(*p)?;
+            if seen.insert((meta.name.clone(), meta.version.clone(), meta.arch.clone())) {
+                packages.push(meta);
+            }
+        }
+
+        Ok(packages)
+    }
 }
 
-impl Drop for Transaction {
+impl Drop for Transaction<'_> {
     fn drop(&mut self) {
         unsafe { ffi::transaction_free(self.t) }
     }
 }
 
-pub struct Solver {
+/// A solver for the jobs on the packages of the pool it borrows
+pub struct Solver<'a> {
     solver: *mut ffi::Solver,
+    pool: &'a Pool,
 }
 
-impl Solver {
-    pub fn new(pool: &Pool) -> Solver {
+impl<'a> Solver<'a> {
+    pub fn new(pool: &'a Pool) -> Solver<'a> {
         Solver {
             solver: unsafe { ffi::solver_create(pool.pool) },
+            pool,
         }
     }
 
@@ -232,13 +787,14 @@ impl Solver {
         Ok(())
     }
 
-    pub fn create_transaction(&mut self) -> Result<Transaction> {
+    /// The transaction of the last solve, which may outlive the solver but not the pool
+    pub fn create_transaction(&mut self) -> Result<Transaction<'a>> {
         let t = unsafe { ffi::solver_create_transaction(self.solver) };
         if t.is_null() {
             return Err(anyhow!("Failed to create transaction"));
         }
 
-        Ok(Transaction { t })
+        Ok(Transaction { t, pool: self.pool })
     }
 
     pub fn solve(&self, queue: &mut Queue) -> Result<()> {
@@ -250,6 +806,29 @@ impl Solver {
         Ok(())
     }
 
+    /// Whether the solvable `p` was chosen because the user requested it
+    pub fn is_requested(&self, p: ffi::Id) -> bool {
+        let mut rule: ffi::Id = 0;
+        let reason = unsafe { ffi::solver_describe_decision(self.solver, p, &mut rule) };
+        if reason == SOLVER_REASON_RESOLVE_JOB {
+            return true;
+        }
+        if reason != SOLVER_REASON_RESOLVE && reason != SOLVER_REASON_UNIT_RULE {
+            return false;
+        }
+        let (mut from, mut to, mut dep): (ffi::Id, ffi::Id, ffi::Id) = (0, 0, 0);
+        let rule_type =
+            unsafe { ffi::solver_ruleinfo(self.solver, rule, &mut from, &mut to, &mut dep) }
+                as c_int;
+
+        rule_type & 0xff00 == SOLVER_RULE_JOB
+    }
+
+    /// Name of the solvable `p`
+    pub fn solvable_name(&self, p: ffi::Id) -> String {
+        unsafe { solvable_name(self.pool.pool, p) }
+    }
+
     pub fn get_problems(&self) -> Result<Vec<String>> {
         let mut problems = Vec::new();
         let count = unsafe { ffi::solver_problem_count(self.solver) };
@@ -265,7 +844,7 @@ impl Solver {
     }
 }
 
-impl Drop for Solver {
+impl Drop for Solver<'_> {
     fn drop(&mut self) {
         unsafe { ffi::solver_free(self.solver) }
     }
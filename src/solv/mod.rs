@@ -1,10 +1,35 @@
 mod ffi;
-use std::path::PathBuf;
+use std::{cmp::Ordering, collections::BTreeMap, path::PathBuf};
 
 use anyhow::{bail, Result};
-pub use ffi::{Pool, Queue, Repo, Solver, Transaction, SOLVER_FLAG_BEST_OBEY_POLICY};
+pub use ffi::{
+    MetadataError, Pool, Queue, Repo, Solver, Transaction, SOLVER_FLAGS,
+    SOLVER_FLAG_BEST_OBEY_POLICY, SOLVER_FLAG_IGNORE_RECOMMENDED,
+};
+use ffi::{REL_EQ, REL_GT, REL_LT};
+use libc::c_int;
+use libsolv_sys::ffi::Id;
+use log::{debug, warn};
+use serde::Serialize;
 
-#[derive(Clone, Debug)]
+/// Prefix of the names of the repositories created for topics
+pub const TOPIC_REPO_PREFIX: &str = "topic:";
+/// Default priority of topic repositories, higher than the branch itself
+pub const TOPIC_REPO_PRIORITY: i32 = 100;
+/// Prefix of the names of the repositories created for the branches given with `--pin`
+pub const PIN_REPO_PREFIX: &str = "pin:";
+/// Priority of the pinned packages, higher than the topics
+pub const PIN_REPO_PRIORITY: i32 = 200;
+
+/// A repository to be loaded into the pool
+pub struct RepoSource {
+    pub name: String,
+    pub priority: i32,
+    /// Paths to the `Packages` files of this repository
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Clone, Debug, Serialize)]
 pub struct PackageMeta {
     pub name: String,
     pub version: String,
@@ -12,6 +37,13 @@ pub struct PackageMeta {
     pub path: String,
     pub arch: String,
     pub in_topic: bool,
+    /// Name of the repository this package comes from
+    pub repo: String,
+    pub section: String,
+    /// Installed size in bytes
+    pub installed_size: u64,
+    /// Size of the package archive in bytes
+    pub download_size: u64,
 }
 
 impl PackageMeta {
@@ -24,34 +56,864 @@ impl PackageMeta {
     }
 }
 
+/// A package of the `stable` repository for the tests, at version 1.0
+#[cfg(test)]
+pub(crate) fn pkg(name: &str, arch: &str) -> PackageMeta {
+    PackageMeta {
+        name: name.to_string(),
+        version: "1.0".to_string(),
+        sha256: String::new(),
+        path: String::new(),
+        arch: arch.to_string(),
+        in_topic: false,
+        repo: "stable".to_string(),
+        section: String::new(),
+        installed_size: 0,
+        download_size: 0,
+    }
+}
+
+/// Split a package specification like `name>=1.0` into the package name,
+/// the libsolv relation flags and the version. Returns `None` for plain names.
+pub fn parse_version_constraint(spec: &str) -> Result<Option<(&str, c_int, &str)>> {
+    let Some(pos) = spec.find(['<', '>', '=']) else {
+        return Ok(None);
+    };
+    let (name, rest) = spec.split_at(pos);
+    let op_len = rest
+        .find(|c| !matches!(c, '<' | '>' | '='))
+        .unwrap_or(rest.len());
+    let (op, evr) = rest.split_at(op_len);
+    let flags = match op {
+        "=" => REL_EQ,
+        ">=" => REL_GT | REL_EQ,
+        "<=" => REL_LT | REL_EQ,
+        ">>" | ">" => REL_GT,
+        "<<" | "<" => REL_LT,
+        _ => bail!("Invalid version constraint '{}' in '{}'", op, spec),
+    };
+    let (name, evr) = (name.trim(), evr.trim());
+    if name.is_empty() || evr.is_empty() {
+        bail!("Invalid package specification '{}'", spec);
+    }
+
+    Ok(Some((name, flags, evr)))
+}
+
+/// Return the package name of a package specification, without any version constraint
+pub fn package_name(spec: &str) -> &str {
+    match spec.find(['<', '>', '=']) {
+        Some(pos) => spec[..pos].trim(),
+        None => spec,
+    }
+}
+
+/// Return the name of the package a specification is about, without any version
+/// constraint or architecture qualifier, e.g. `dpkg` for `dpkg:amd64>=1.21`
+pub fn bare_package_name(spec: &str) -> &str {
+    let name = package_name(spec);
+    name.split_once(':').map_or(name, |(name, _)| name)
+}
+
+/// Whether a package of the given name and version is matched by the specification
+pub fn spec_matches(spec: &str, name: &str, version: &str) -> bool {
+    if bare_package_name(spec) != name {
+        return false;
+    }
+    // an invalid constraint is refused by the solver anyway, match conservatively
+    let Ok(Some((_, flags, evr))) = parse_version_constraint(spec) else {
+        return true;
+    };
+    let relation = match crate::manifest::compare_versions(version, evr) {
+        Ordering::Less => REL_LT,
+        Ordering::Equal => REL_EQ,
+        Ordering::Greater => REL_GT,
+    };
+
+    flags & relation != 0
+}
+
+/// Options affecting the dependency resolution
+#[derive(Default)]
+pub struct ResolveOptions {
+    /// Packages which must not be installed
+    pub excludes: Vec<String>,
+    /// Providers to favor for virtual packages, as (virtual package, provider)
+    pub prefer: Vec<(String, String)>,
+    /// Install recommended packages as well, which are left out by default
+    pub install_recommends: bool,
+    /// Only warn about requested packages which do not exist
+    pub ignore_missing: bool,
+    /// Extra solver flags, applied after the defaults
+    pub flags: Vec<(c_int, c_int)>,
+}
+
+/// Look up a solver flag by its symbolic name, e.g. `ALLOW_DOWNGRADE` or `allow-downgrade`
+pub fn solver_flag(name: &str) -> Result<c_int> {
+    let normalized = name.trim().to_ascii_uppercase().replace('-', "_");
+    let normalized = normalized
+        .strip_prefix("SOLVER_FLAG_")
+        .unwrap_or(&normalized);
+    if let Some((_, flag)) = SOLVER_FLAGS.iter().find(|(n, _)| *n == normalized) {
+        return Ok(*flag);
+    }
+    let suggestions = suggest(normalized, SOLVER_FLAGS.iter().map(|(n, _)| *n));
+    if suggestions.is_empty() {
+        bail!(
+            "Unknown solver flag '{}' (see --list-solver-flags for valid names)",
+            name
+        );
+    }
+    bail!(
+        "Unknown solver flag '{}'. Did you mean: {}?",
+        name,
+        suggestions.join(", ")
+    );
+}
+
+/// Convert symbolic solver flags into libsolv flags and values
+pub fn parse_solver_flags(flags: &BTreeMap<String, bool>) -> Result<Vec<(c_int, c_int)>> {
+    flags
+        .iter()
+        .map(|(name, value)| Ok((solver_flag(name)?, *value as c_int)))
+        .collect()
+}
+
 /// Simulate the apt dependency resolution
-pub fn calculate_deps(pool: &mut Pool, names: &[String]) -> Result<Transaction> {
+pub fn calculate_deps<'a>(
+    pool: &'a Pool,
+    names: &[String],
+    opts: &ResolveOptions,
+) -> Result<Transaction<'a>> {
+    Ok(resolve(pool, names, opts)?.1)
+}
+
+/// Simulate the apt dependency resolution, keeping the solver for introspection
+pub fn resolve<'a>(
+    pool: &'a Pool,
+    names: &[String],
+    opts: &ResolveOptions,
+) -> Result<(Solver<'a>, Transaction<'a>)> {
+    let excludes = &opts.excludes;
     let mut q = Queue::new();
+    let mut missing = Vec::new();
     for name in names {
+        let count = q.len();
         q = pool.match_package(name, q)?;
+        if q.len() == count {
+            missing.push(name.as_str());
+        }
+    }
+    if !missing.is_empty() {
+        let known = pool.package_names();
+        let mut message = String::from("The following requested packages do not exist:");
+        for name in &missing {
+            let suggestions = suggest(package_name(name), known.iter().map(|n| n.as_str()));
+            message.push_str(&format!("\n  {}", name));
+            if !suggestions.is_empty() {
+                message.push_str(&format!(" (did you mean: {}?)", suggestions.join(", ")));
+            }
+        }
+        if !opts.ignore_missing {
+            bail!("{}", message);
+        }
+        warn!("{}", message);
     }
     q.mark_all_for_install();
+    let mut locks = Queue::new();
+    for name in excludes {
+        locks = pool.match_package(name, locks)?;
+    }
+    locks.mark_all_for_lock();
+    q.extend(&locks);
+    let mut favors = Queue::new();
+    let providers = opts
+        .prefer
+        .iter()
+        .map(|(virtual_name, _)| pool.providers(virtual_name))
+        .collect::<Result<Vec<_>>>()?;
+    for name in order_preferences(&opts.prefer, &providers)? {
+        favors = pool.match_package(name, favors)?;
+    }
+    favors.mark_all_for_favor();
+    q.extend(&favors);
     let mut solver = Solver::new(pool);
     solver.set_flag(SOLVER_FLAG_BEST_OBEY_POLICY, 1)?;
+    // libsolv honours Recommends by default, keep the package set minimal unless asked
+    solver.set_flag(
+        SOLVER_FLAG_IGNORE_RECOMMENDED,
+        if opts.install_recommends { 0 } else { 1 },
+    )?;
+    for (flag, value) in &opts.flags {
+        solver.set_flag(*flag, *value)?;
+    }
 
     if let Err(e) = solver.solve(&mut q) {
-        eprintln!("{e}");
-        bail!("{}", solver.get_problems()?.join("\n"));
+        debug!("{e}");
+        let mut problems = solver.get_problems()?.join("\n");
+        if !excludes.is_empty() {
+            problems.push_str(&format!("\nExcluded packages: {}", excludes.join(", ")));
+        }
+        bail!("{}", problems);
     }
 
     let trans = solver.create_transaction()?;
     trans.order(0);
 
-    Ok(trans)
+    Ok((solver, trans))
+}
+
+/// Order the preferred providers for favoring, given the `providers` of each virtual
+/// package. libsolv lets whatever is favored last win, so each provider is favored after
+/// the other preferred providers of its virtual package, which keeps a provider preferred
+/// for one virtual package from winning another one it also provides.
+fn order_preferences<'a>(
+    prefer: &'a [(String, String)],
+    providers: &[Vec<String>],
+) -> Result<Vec<&'a str>> {
+    for ((virtual_name, provider), names) in prefer.iter().zip(providers) {
+        if !names.contains(provider) {
+            bail!(
+                "{} does not provide {}, it cannot be preferred for it (providers: {})",
+                provider,
+                virtual_name,
+                if names.is_empty() {
+                    "none".to_string()
+                } else {
+                    names.join(", ")
+                }
+            );
+        }
+    }
+    // whether preference i must be favored after preference j
+    let after =
+        |i: usize, j: usize| prefer[j].1 != prefer[i].1 && providers[i].contains(&prefer[j].1);
+    let mut pending = (0..prefer.len()).collect::<Vec<_>>();
+    let mut ordered = Vec::new();
+    while !pending.is_empty() {
+        let Some(pos) = pending
+            .iter()
+            .position(|&i| !pending.iter().any(|&j| after(i, j)))
+        else {
+            let conflicting = pending
+                .iter()
+                .map(|&i| format!("{}={}", prefer[i].0, prefer[i].1))
+                .collect::<Vec<_>>();
+            bail!(
+                "Conflicting provider preferences: {}",
+                conflicting.join(", ")
+            );
+        };
+        ordered.push(prefer[pending.remove(pos)].1.as_str());
+    }
+
+    Ok(ordered)
+}
+
+/// At most this many dependency chains are shown for a package
+const MAX_CHAINS: usize = 16;
+
+/// Why a package was pulled into the transaction
+pub struct Explanation {
+    /// The dependency chains from a requested package down to the package
+    pub chains: Vec<Vec<String>>,
+    /// Whether there are more chains than [MAX_CHAINS]
+    pub truncated: bool,
+}
+
+/// Explain why a package was pulled into the transaction.
+/// Returns every dependency chain from a requested package down to the given package,
+/// following all the packages in the transaction that depend on it, not only the one the
+/// solver happened to record.
+pub fn explain(solver: &Solver, trans: &Transaction, name: &str) -> Result<Explanation> {
+    let Some(p) = trans.find_solvable(name) else {
+        let names = trans.names();
+        let suggestions = suggest(name, names.iter().map(|n| n.as_str()));
+        if suggestions.is_empty() {
+            bail!("{} is not in the resolved package set", name);
+        }
+        bail!(
+            "{} is not in the resolved package set. Did you mean: {}?",
+            name,
+            suggestions.join(", ")
+        );
+    };
+    let mut explanation = Explanation {
+        chains: Vec::new(),
+        truncated: false,
+    };
+    let mut path = vec![(p, name.to_string())];
+    explain_from(solver, trans, &mut path, &mut explanation);
+    if explanation.chains.is_empty() {
+        explanation
+            .chains
+            .push(vec!["(unknown reason)".to_string(), name.to_string()]);
+    }
+
+    Ok(explanation)
+}
+
+/// Extend the chain in `path`, whose last element is the package being explained, up to
+/// the requested packages, depth first
+fn explain_from(
+    solver: &Solver,
+    trans: &Transaction,
+    path: &mut Vec<(Id, String)>,
+    explanation: &mut Explanation,
+) {
+    let emit = |path: &[(Id, String)], reason: &str, explanation: &mut Explanation| {
+        if explanation.chains.len() >= MAX_CHAINS {
+            explanation.truncated = true;
+            return;
+        }
+        let mut chain = vec![reason.to_string()];
+        chain.extend(path.iter().rev().map(|(_, step)| step.clone()));
+        explanation.chains.push(chain);
+    };
+    let (p, _) = path[path.len() - 1];
+    if solver.is_requested(p) {
+        emit(path, "(requested)", explanation);
+        return;
+    }
+    let requirers = trans.requirers(p);
+    if requirers.is_empty() {
+        emit(path, "(unknown reason)", explanation);
+        return;
+    }
+    for (by, dep) in requirers {
+        if explanation.truncated {
+            return;
+        }
+        // guard against loops in the dependency graph
+        if path.iter().any(|(q, _)| *q == by) {
+            continue;
+        }
+        path.push((
+            by,
+            format!("{} (requires {})", solver.solvable_name(by), dep),
+        ));
+        explain_from(solver, trans, path, explanation);
+        path.pop();
+    }
+}
+
+/// Calculate the Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(current[j] + 1);
+        }
+        prev = current;
+    }
+
+    prev[b.len()]
+}
+
+/// Find up to three names closest to the given name
+pub fn suggest<'a, I: Iterator<Item = &'a str>>(name: &str, candidates: I) -> Vec<&'a str> {
+    let threshold = (name.len() / 3).max(2);
+    let mut scored = candidates
+        .map(|c| (edit_distance(name, c), c))
+        .filter(|(d, _)| *d <= threshold)
+        .collect::<Vec<_>>();
+    scored.sort();
+    scored.dedup();
+
+    scored.into_iter().take(3).map(|(_, c)| c).collect()
 }
 
 /// Populate the packages pool with metadata
-pub fn populate_pool(pool: &mut Pool, paths: &[PathBuf]) -> Result<()> {
-    let mut repo = Repo::new(pool, "stable")?;
-    for path in paths {
-        repo.add_debpackages(path)?;
+pub fn populate_pool(pool: &mut Pool, sources: &[RepoSource]) -> Result<()> {
+    for source in sources {
+        let mut repo = Repo::new(pool, &source.name)?;
+        repo.set_priority(source.priority);
+        for path in &source.paths {
+            repo.add_debpackages(path)?;
+        }
     }
     pool.createwhatprovides();
 
     Ok(())
 }
+
+#[test]
+fn test_parse_version_constraint() -> Result<()> {
+    assert_eq!(parse_version_constraint("bash")?, None);
+    assert_eq!(
+        parse_version_constraint("util-linux=2.39.3")?,
+        Some(("util-linux", REL_EQ, "2.39.3"))
+    );
+    assert_eq!(
+        parse_version_constraint("kernel>=6.6")?,
+        Some(("kernel", REL_GT | REL_EQ, "6.6"))
+    );
+    assert_eq!(
+        parse_version_constraint("gcc <= 1:14.2.0")?,
+        Some(("gcc", REL_LT | REL_EQ, "1:14.2.0"))
+    );
+    assert!(parse_version_constraint("gcc=>14").is_err());
+    assert!(parse_version_constraint("gcc=").is_err());
+    assert_eq!(package_name("kernel>=6.6"), "kernel");
+    assert_eq!(package_name("bash"), "bash");
+    assert_eq!(bare_package_name("dpkg:amd64>=1.21"), "dpkg");
+    assert_eq!(bare_package_name("dpkg"), "dpkg");
+    assert!(spec_matches("dpkg:amd64", "dpkg", "1.22.0"));
+    assert!(spec_matches("dpkg<1.23", "dpkg", "1.22.0"));
+    assert!(!spec_matches("dpkg<1.22", "dpkg", "1.22.0"));
+    assert!(!spec_matches("dpkg-dev", "dpkg", "1.22.0"));
+
+    Ok(())
+}
+
+#[test]
+fn test_suggest() {
+    let names = ["network-manager", "networkmanager-openvpn", "bash", "nano"];
+    assert_eq!(edit_distance("bash", "bash"), 0);
+    assert_eq!(edit_distance("kitten", "sitting"), 3);
+    assert_eq!(
+        suggest("networkmanger", names.iter().copied()),
+        vec!["network-manager"]
+    );
+    assert_eq!(suggest("bsh", names.iter().copied()), vec!["bash"]);
+    assert!(suggest("zzzzzzzz", names.iter().copied()).is_empty());
+}
+
+#[test]
+fn test_repo_priority() -> Result<()> {
+    use std::io::Write;
+
+    let stanza = |version: &str, dir: &str| {
+        format!(
+            "Package: foo\nVersion: {version}\nArchitecture: amd64\nInstalled-Size: 4\nFilename: pool/{dir}/main/f/foo_{version}_amd64.deb\nSize: 1024\nSHA256: {}\nDescription: test\n\n",
+            "0".repeat(64)
+        )
+    };
+    let mut stable = tempfile::NamedTempFile::new()?;
+    stable.write_all(stanza("1.0", "stable").as_bytes())?;
+    let mut topic = tempfile::NamedTempFile::new()?;
+    topic.write_all(stanza("0.9", "test-topic").as_bytes())?;
+    let sources = |topic_priority: i32| {
+        vec![
+            RepoSource {
+                name: "stable".to_string(),
+                priority: 0,
+                paths: vec![stable.path().to_path_buf()],
+            },
+            RepoSource {
+                name: format!("{}test-topic", TOPIC_REPO_PREFIX),
+                priority: topic_priority,
+                paths: vec![topic.path().to_path_buf()],
+            },
+        ]
+    };
+
+    // the topic wins with a higher priority, even with a lower version
+    let mut pool = Pool::new();
+    populate_pool(&mut pool, &sources(TOPIC_REPO_PRIORITY))?;
+    let t = calculate_deps(&pool, &["foo".to_string()], &ResolveOptions::default())?;
+    let packages = t.create_metadata()?;
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].version, "0.9");
+    assert!(packages[0].in_topic);
+
+    // with equal priorities, the higher version wins
+    let mut pool = Pool::new();
+    populate_pool(&mut pool, &sources(0))?;
+    let t = calculate_deps(&pool, &["foo".to_string()], &ResolveOptions::default())?;
+    let packages = t.create_metadata()?;
+    assert_eq!(packages[0].version, "1.0");
+    assert_eq!(packages[0].repo, "stable");
+    assert!(!packages[0].in_topic);
+
+    Ok(())
+}
+
+#[test]
+fn test_duplicate_packages() -> Result<()> {
+    use std::io::Write;
+
+    let stanza = |dir: &str, sha256: char| {
+        format!(
+            "Package: foo\nVersion: 1.0\nArchitecture: amd64\nInstalled-Size: 4\nFilename: pool/{dir}/main/f/foo_1.0_amd64.deb\nSize: 1024\nSHA256: {}\nDescription: test\n\n",
+            sha256.to_string().repeat(64)
+        )
+    };
+    let manifest = |content: String| -> Result<tempfile::NamedTempFile> {
+        let mut f = tempfile::NamedTempFile::new()?;
+        f.write_all(content.as_bytes())?;
+        Ok(f)
+    };
+    let stable = manifest(stanza("stable", '0'))?;
+    // overlapping components of the branch
+    let bsp = manifest(stanza("stable", '0'))?;
+    let topic = manifest(stanza("test-topic", '0'))?;
+    let tampered = manifest(stanza("test-topic", '1'))?;
+    let resolve = |topic: &tempfile::NamedTempFile, priorities: (i32, i32)| {
+        let mut pool = Pool::new();
+        let source = |name: String, priority, paths: Vec<PathBuf>| RepoSource {
+            name,
+            priority,
+            paths,
+        };
+        populate_pool(
+            &mut pool,
+            &[
+                source(
+                    format!("{}test-topic", TOPIC_REPO_PREFIX),
+                    priorities.1,
+                    vec![topic.path().to_path_buf()],
+                ),
+                source(
+                    "stable".to_string(),
+                    priorities.0,
+                    vec![stable.path().to_path_buf(), bsp.path().to_path_buf()],
+                ),
+            ],
+        )?;
+        let t = calculate_deps(&pool, &["foo".to_string()], &ResolveOptions::default())?;
+        t.create_metadata()
+    };
+
+    // the topic wins ties, whichever repository the solver picked
+    let packages = resolve(&topic, (0, 0))?;
+    assert_eq!(packages.len(), 1);
+    assert!(packages[0].in_topic);
+    assert_eq!(packages[0].path, "pool/test-topic/main/f/foo_1.0_amd64.deb");
+    let packages = resolve(&topic, (0, TOPIC_REPO_PRIORITY))?;
+    assert!(packages[0].in_topic);
+    // unless the branch has a higher priority
+    let packages = resolve(&topic, (TOPIC_REPO_PRIORITY, 0))?;
+    assert_eq!(packages.len(), 1);
+    assert_eq!(packages[0].repo, "stable");
+    let err = resolve(&tampered, (0, 0)).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<MetadataError>(),
+        Some(MetadataError::ChecksumMismatch { name, .. }) if name == "foo"
+    ));
+    assert!(err.to_string().contains("topic:test-topic"), "{}", err);
+
+    Ok(())
+}
+
+#[test]
+fn test_transaction_closure() -> Result<()> {
+    use std::io::Write;
+
+    let stanza = |name: &str, version: &str, depends: &str| {
+        let depends = if depends.is_empty() {
+            String::new()
+        } else {
+            format!("Depends: {depends}\n")
+        };
+        format!(
+            "Package: {name}\nVersion: {version}\nArchitecture: amd64\n{depends}Filename: pool/stable/main/{name}_{version}_amd64.deb\nSize: 1024\nSHA256: {}\nDescription: test\n\n",
+            "0".repeat(64)
+        )
+    };
+    let mut packages = tempfile::NamedTempFile::new()?;
+    for (name, version, depends) in [
+        ("stub", "1.0", "libfoo"),
+        ("libfoo", "1.0", "base"),
+        ("libfoo", "2.0", "base"),
+        ("base", "1.0", ""),
+        ("extra", "1.0", "libfoo (>= 2.0)"),
+    ] {
+        packages.write_all(stanza(name, version, depends).as_bytes())?;
+    }
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[RepoSource {
+            name: "stable".to_string(),
+            priority: 0,
+            paths: vec![packages.path().to_path_buf()],
+        }],
+    )?;
+    let t = calculate_deps(
+        &pool,
+        &["stub".to_string(), "extra".to_string()],
+        &ResolveOptions::default(),
+    )?;
+    let all = t.create_metadata()?;
+    let stub = t.closure(&["stub".to_string()])?;
+    let mut names = stub.iter().map(|p| p.name.as_str()).collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, vec!["base", "libfoo", "stub"]);
+    for p in &stub {
+        assert!(all
+            .iter()
+            .any(|a| a.name == p.name && a.version == p.version));
+    }
+    assert!(t.closure(&["missing".to_string()]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_prefer_providers() -> Result<()> {
+    use std::io::Write;
+
+    // a and b provide x, b and c provide y
+    let mut packages = tempfile::NamedTempFile::new()?;
+    for (name, fields) in [
+        ("a", "Provides: x\n"),
+        ("b", "Provides: x, y\n"),
+        ("c", "Provides: y\n"),
+        ("app", "Depends: x, y\n"),
+    ] {
+        packages.write_all(
+            format!(
+                "Package: {name}\nVersion: 1.0\nArchitecture: amd64\n{fields}Filename: pool/stable/main/{name}_1.0_amd64.deb\nSize: 1024\nSHA256: {}\nDescription: test\n\n",
+                "0".repeat(64)
+            )
+            .as_bytes(),
+        )?;
+    }
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[RepoSource {
+            name: "stable".to_string(),
+            priority: 0,
+            paths: vec![packages.path().to_path_buf()],
+        }],
+    )?;
+    let prefer = |pairs: &[(&str, &str)]| ResolveOptions {
+        prefer: pairs
+            .iter()
+            .map(|(v, p)| (v.to_string(), p.to_string()))
+            .collect(),
+        ..Default::default()
+    };
+    let t = calculate_deps(
+        &pool,
+        &["app".to_string()],
+        &prefer(&[("x", "a"), ("y", "c")]),
+    )?;
+    let mut names = t.names();
+    names.sort();
+    assert_eq!(names, ["a", "app", "c"]);
+    let err = calculate_deps(&pool, &["app".to_string()], &prefer(&[("y", "a")]))
+        .err()
+        .unwrap()
+        .to_string();
+    assert!(err.contains("a does not provide y"), "{}", err);
+
+    // b is preferred for y only, so it is favored before a, preferred for x
+    let pairs = [
+        ("x".to_string(), "a".to_string()),
+        ("y".to_string(), "b".to_string()),
+    ];
+    let providers = [pool.providers("x")?, pool.providers("y")?];
+    assert_eq!(order_preferences(&pairs, &providers)?, ["b", "a"]);
+    let pairs = [
+        ("y".to_string(), "b".to_string()),
+        ("x".to_string(), "a".to_string()),
+    ];
+    let providers = [pool.providers("y")?, pool.providers("x")?];
+    assert_eq!(order_preferences(&pairs, &providers)?, ["b", "a"]);
+    // with the same providers, a for x and b for z cannot both be honoured by favoring
+    let pairs = [
+        ("x".to_string(), "a".to_string()),
+        ("z".to_string(), "b".to_string()),
+    ];
+    let providers = [pool.providers("x")?, pool.providers("x")?];
+    assert!(order_preferences(&pairs, &providers)
+        .unwrap_err()
+        .to_string()
+        .contains("Conflicting provider preferences"));
+
+    Ok(())
+}
+
+#[test]
+fn test_recommends() -> Result<()> {
+    use std::io::Write;
+
+    let mut packages = tempfile::NamedTempFile::new()?;
+    for (name, recommends) in [("foo", "Recommends: bar\n"), ("bar", "")] {
+        packages.write_all(
+            format!(
+                "Package: {name}\nVersion: 1.0\nArchitecture: amd64\n{recommends}Filename: pool/stable/main/{name}_1.0_amd64.deb\nSize: 1024\nSHA256: {}\nDescription: test\n\n",
+                "0".repeat(64)
+            )
+            .as_bytes(),
+        )?;
+    }
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[RepoSource {
+            name: "stable".to_string(),
+            priority: 0,
+            paths: vec![packages.path().to_path_buf()],
+        }],
+    )?;
+    let resolve = |opts: &ResolveOptions| -> Result<Vec<String>> {
+        let t = calculate_deps(&pool, &["foo".to_string()], opts)?;
+        let mut names = t
+            .create_metadata()?
+            .into_iter()
+            .map(|p| p.name)
+            .collect::<Vec<_>>();
+        names.sort();
+
+        Ok(names)
+    };
+    // the recommended packages are left out unless asked for
+    assert_eq!(resolve(&ResolveOptions::default())?, ["foo"]);
+    let opts = ResolveOptions {
+        install_recommends: true,
+        ..Default::default()
+    };
+    assert_eq!(resolve(&opts)?, ["bar", "foo"]);
+    // an explicit solver flag has the last word
+    let opts = ResolveOptions {
+        flags: vec![(SOLVER_FLAG_IGNORE_RECOMMENDED, 0)],
+        ..Default::default()
+    };
+    assert_eq!(resolve(&opts)?, ["bar", "foo"]);
+
+    Ok(())
+}
+
+#[test]
+fn test_missing_checksum() -> Result<()> {
+    use std::io::Write;
+
+    let mut packages = tempfile::NamedTempFile::new()?;
+    packages.write_all(
+        b"Package: foo\nVersion: 1.0\nArchitecture: amd64\nFilename: pool/stable/main/f/foo_1.0_amd64.deb\nSize: 1024\nDescription: test\n\n",
+    )?;
+    let mut pool = Pool::new();
+    populate_pool(
+        &mut pool,
+        &[RepoSource {
+            name: "stable".to_string(),
+            priority: 0,
+            paths: vec![packages.path().to_path_buf()],
+        }],
+    )?;
+    let t = calculate_deps(&pool, &["foo".to_string()], &ResolveOptions::default())?;
+    let err = t.create_metadata().unwrap_err();
+    match err.downcast_ref::<MetadataError>() {
+        Some(MetadataError::MissingMetadata { name, field }) => {
+            assert_eq!(name, "foo");
+            assert_eq!(*field, "SHA256");
+        }
+        _ => panic!("unexpected error: {}", err),
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_solver_flag() -> Result<()> {
+    assert_eq!(solver_flag("ALLOW_DOWNGRADE")?, 1);
+    assert_eq!(solver_flag("allow-downgrade")?, 1);
+    assert_eq!(
+        solver_flag("SOLVER_FLAG_BEST_OBEY_POLICY")?,
+        SOLVER_FLAG_BEST_OBEY_POLICY
+    );
+    let err = solver_flag("ALLOW_DOWNGRAD").unwrap_err().to_string();
+    assert!(err.contains("ALLOW_DOWNGRADE"));
+    assert!(solver_flag("FOO").is_err());
+    let mut flags = BTreeMap::new();
+    flags.insert("allow-uninstall".to_string(), true);
+    flags.insert("best-obey-policy".to_string(), false);
+    assert_eq!(
+        parse_solver_flags(&flags)?,
+        vec![(4, 1), (SOLVER_FLAG_BEST_OBEY_POLICY, 0)]
+    );
+    flags.insert("nonsense".to_string(), true);
+    assert!(parse_solver_flags(&flags).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_ffi_safety() -> Result<()> {
+    fn assert_send<T: Send>() {}
+    assert_send::<Pool>();
+    assert_send::<Queue>();
+
+    let fixture_pool = || -> Result<Pool> {
+        let mut pool = Pool::new();
+        populate_pool(
+            &mut pool,
+            &[RepoSource {
+                name: "stable".to_string(),
+                priority: 0,
+                paths: vec![PathBuf::from(concat!(
+                    env!("CARGO_MANIFEST_DIR"),
+                    "/tests/fixtures/Packages"
+                ))],
+            }],
+        )?;
+
+        Ok(pool)
+    };
+    let pool = fixture_pool()?;
+    let (solver, t) = resolve(&pool, &["app".to_string()], &ResolveOptions::default())?;
+    let why = explain(&solver, &t, "base")?;
+    assert!(!why.truncated);
+    assert_eq!(
+        why.chains,
+        [[
+            "(requested)",
+            "app (requires libfoo >= 2.0)",
+            "libfoo (requires base)",
+            "base"
+        ]]
+    );
+    // the transaction only needs the pool, not the solver
+    drop(solver);
+    let mut names = t
+        .create_metadata()?
+        .into_iter()
+        .map(|p| format!("{} {}", p.name, p.version))
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["app 1.0", "base 1.0", "libfoo 2.0"]);
+    assert_eq!(t.providers("libfoo")?, ["libfoo"]);
+    drop(t);
+    // several transactions from the same pool, dropped in any order
+    let first = calculate_deps(&pool, &["base".to_string()], &ResolveOptions::default())?;
+    let second = calculate_deps(&pool, &["app-doc".to_string()], &ResolveOptions::default())?;
+    drop(first);
+    assert_eq!(second.create_metadata()?.len(), 4);
+    drop(second);
+
+    // an unknown solvable is refused instead of read out of bounds
+    let solver = Solver::new(&pool);
+    let name = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        solver.solvable_name(1 << 20)
+    }));
+    assert!(name.is_err());
+    drop(solver);
+
+    // queues hold (how, what) pairs, only the how is marked
+    let mut q = pool.match_package("app", Queue::new())?;
+    q.extend(&Queue::new());
+    assert_eq!(q.len(), 2);
+    let what = q.as_slice()[1];
+    q.mark_all_for_install();
+    assert_eq!(q.as_slice()[1], what);
+
+    // the pool moves to a worker thread, where it is resolved
+    let packages = std::thread::spawn(move || -> Result<Vec<PackageMeta>> {
+        let t = calculate_deps(&pool, &["libfoo".to_string()], &ResolveOptions::default())?;
+        let packages = t.create_metadata()?;
+
+        Ok(packages)
+    })
+    .join()
+    .unwrap()?;
+    assert_eq!(packages.len(), 2);
+
+    Ok(())
+}
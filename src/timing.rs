@@ -0,0 +1,146 @@
+use std::{sync::Mutex, time::Instant};
+
+use bytesize::ByteSize;
+use log::info;
+use serde::Serialize;
+
+use crate::events::{self, Event};
+
+/// How long a phase took, and how much data it went through
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PhaseTiming {
+    pub name: String,
+    pub seconds: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+struct Timings {
+    started: Option<Instant>,
+    running: Option<(String, Instant, Option<u64>)>,
+    finished: Vec<PhaseTiming>,
+}
+
+static TIMINGS: Mutex<Timings> = Mutex::new(Timings {
+    started: None,
+    running: None,
+    finished: Vec::new(),
+});
+
+impl Timings {
+    fn end_running(&mut self) {
+        if let Some((name, start, bytes)) = self.running.take() {
+            self.finished.push(PhaseTiming {
+                name,
+                seconds: start.elapsed().as_secs_f64(),
+                bytes,
+            });
+        }
+    }
+}
+
+/// Start timing a phase, ending the previous one
+pub fn start(name: &str) {
+    let mut timings = TIMINGS.lock().unwrap();
+    timings.end_running();
+    let now = Instant::now();
+    timings.started.get_or_insert(now);
+    timings.running = Some((name.to_string(), now, None));
+}
+
+/// Count the data handled by the running phase, e.g. the downloaded packages
+pub fn add_bytes(bytes: u64) {
+    if let Some((_, _, ref mut total)) = TIMINGS.lock().unwrap().running {
+        *total = Some(total.unwrap_or(0) + bytes);
+    }
+}
+
+/// The timings of the phases so far, including the running one
+pub fn so_far() -> Vec<PhaseTiming> {
+    let timings = TIMINGS.lock().unwrap();
+    let mut phases = timings.finished.clone();
+    if let Some((ref name, start, bytes)) = timings.running {
+        phases.push(PhaseTiming {
+            name: name.clone(),
+            seconds: start.elapsed().as_secs_f64(),
+            bytes,
+        });
+    }
+
+    phases
+}
+
+/// End the running phase, returning the timings of all of them and the total time,
+/// and start over for the next run
+pub fn finish() -> (Vec<PhaseTiming>, f64) {
+    let mut timings = TIMINGS.lock().unwrap();
+    timings.end_running();
+    let total = timings
+        .started
+        .take()
+        .map_or(0.0, |started| started.elapsed().as_secs_f64());
+
+    (std::mem::take(&mut timings.finished), total)
+}
+
+/// Print how long each phase took, also when the run failed, to see where the time goes,
+/// returning the timings
+pub fn report() -> (Vec<PhaseTiming>, f64) {
+    let (phases, total) = finish();
+    if phases.is_empty() {
+        return (phases, total);
+    }
+    events::emit(&Event::Timings {
+        phases: &phases,
+        total_seconds: total,
+    });
+    let width = phases.iter().map(|p| p.name.len()).max().unwrap_or(0);
+    info!("Time spent:");
+    for phase in &phases {
+        info!(
+            "  {:<width$}  {:>10}{}",
+            phase.name,
+            format_duration(phase.seconds),
+            phase.bytes.map_or(String::new(), |b| format!(
+                "  ({}, {}/s)",
+                ByteSize::b(b),
+                ByteSize::b((b as f64 / phase.seconds.max(0.001)) as u64)
+            ))
+        );
+    }
+    info!("  {:<width$}  {:>10}", "total", format_duration(total));
+
+    (phases, total)
+}
+
+fn format_duration(seconds: f64) -> String {
+    let secs = seconds as u64;
+    match secs {
+        0..=59 => format!("{} s", secs),
+        60..=3599 => format!("{} m {} s", secs / 60, secs % 60),
+        _ => format!("{} h {} m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(4.2), "4 s");
+    assert_eq!(format_duration(1771.0), "29 m 31 s");
+    assert_eq!(format_duration(3.0 * 3600.0 + 125.0), "3 h 2 m");
+}
+
+#[test]
+fn test_timings() {
+    start("manifests");
+    start("download");
+    add_bytes(1024);
+    add_bytes(1024);
+    let (phases, total) = finish();
+    assert_eq!(
+        phases.iter().map(|p| p.name.as_str()).collect::<Vec<_>>(),
+        ["manifests", "download"]
+    );
+    assert_eq!(phases[0].bytes, None);
+    assert_eq!(phases[1].bytes, Some(2048));
+    assert!(total >= phases.iter().map(|p| p.seconds).sum::<f64>());
+}
@@ -1,19 +1,19 @@
 use std::{
-    fs::{create_dir_all, File},
-    io::Write,
+    fs::create_dir_all,
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Result};
+use log::{info, warn};
 use owo_colors::OwoColorize;
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 
-use crate::DEFAULT_MIRROR;
+use crate::fs::{atomic_write, format_apt_source};
 
 /// Represents a topic. Serializes to /var/lib/atm/state.
 #[derive(Deserialize, Serialize, Clone)]
-// arch and draft are not used
+// draft is not used
 #[allow(dead_code)]
 pub struct Topic {
     /// Topic name.
@@ -34,31 +34,88 @@ pub struct Topic {
     draft: bool,
 }
 
+impl Topic {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn packages(&self) -> &[String] {
+        &self.packages
+    }
+}
+
 const ATM_STATE: &str = "var/lib/atm/state";
 const ATM_LIST: &str = "etc/apt/sources.list.d/atm.list";
+const ATM_SOURCES: &str = "etc/apt/sources.list.d/atm.sources";
+const ATM_PREFERENCES: &str = "etc/apt/preferences.d/atm-topics.pref";
 const TOPIC_MANIFEST_URL: &str = "https://repo.aosc.io/debs/manifest/topics.json";
 
 pub fn fetch_topics() -> Result<Vec<Topic>> {
-    eprintln!("Fetching topics manifest ...");
+    parse_topics(&fetch_topics_manifest()?)
+}
+
+/// Download the topics manifest as is, e.g. to keep it in a snapshot.
+pub fn fetch_topics_manifest() -> Result<String> {
+    info!("Fetching topics manifest ...");
     let client = Client::builder()
         .user_agent("Wget/1.20.3 (linux-gnu)")
         .build()?;
     let response = client.get(TOPIC_MANIFEST_URL).send()?;
     response.error_for_status_ref()?;
-    let topics: Vec<Topic> = serde_json::from_str(&response.text()?)?;
-    Ok(topics)
+
+    Ok(response.text()?)
+}
+
+pub fn parse_topics(manifest: &str) -> Result<Vec<Topic>> {
+    Ok(serde_json::from_str(manifest)?)
 }
 
-pub fn filter_topics(specified: Vec<String>, all: Vec<Topic>) -> Result<Vec<Topic>> {
-    eprintln!("Checking availability of specified topics ...");
+/// Find the topics matching a user-specified name. Exact names are preferred,
+/// otherwise the name is used as a glob pattern or a prefix.
+fn match_topics<'a>(spec: &str, all: &'a [Topic]) -> Result<Vec<&'a Topic>> {
+    if let Some(topic) = all.iter().find(|t| t.name == spec) {
+        return Ok(vec![topic]);
+    }
+    if spec.contains(['*', '?', '[']) {
+        let pattern = glob::Pattern::new(spec)?;
+        return Ok(all.iter().filter(|t| pattern.matches(&t.name)).collect());
+    }
+
+    Ok(all.iter().filter(|t| t.name.starts_with(spec)).collect())
+}
+
+pub fn filter_topics(specified: Vec<String>, all: Vec<Topic>, strict: bool) -> Result<Vec<Topic>> {
+    info!("Checking availability of specified topics ...");
     let mut filtered = Vec::<Topic>::new();
     let mut specified = specified.clone();
     specified.sort();
-    all.iter().for_each(|t| {
-        if specified.contains(&t.name) {
-            filtered.push(t.clone());
+    for spec in specified.iter() {
+        let matched = match_topics(spec, &all)?;
+        if matched.is_empty() {
+            warn!("Topic {} does not exist, skipping.", spec.cyan());
+            continue;
         }
-    });
+        if matched.len() > 1 {
+            let names = matched.iter().map(|t| t.name.as_str()).collect::<Vec<_>>();
+            if strict {
+                return Err(anyhow!(
+                    "Topic pattern {} is ambiguous, matched: {}",
+                    spec,
+                    names.join(", ")
+                ));
+            }
+            info!(
+                "Topic pattern {} matched: {}",
+                spec.cyan(),
+                names.join(", ")
+            );
+        }
+        for topic in matched {
+            if !filtered.iter().any(|t| t.name == topic.name) {
+                filtered.push(topic.clone());
+            }
+        }
+    }
     if filtered.is_empty() {
         let all_names = &all.into_iter().map(|x| x.name).collect::<Vec<_>>();
         return Err(anyhow!(
@@ -71,13 +128,131 @@ pub fn filter_topics(specified: Vec<String>, all: Vec<Topic>) -> Result<Vec<Topi
     Ok(filtered)
 }
 
-pub fn save_topics(sysroot: &Path, topics: Vec<Topic>) -> Result<()> {
-    eprintln!("{}", "Saving topic sources and ATM state ...".bold());
+/// Check whether the specified topics provide packages for the given architecture.
+/// Missing architectures are reported as warnings, or as an error when `strict` is set.
+pub fn check_topics_arch(topics: &[Topic], arch: &str, strict: bool) -> Result<()> {
+    let missing = topics
+        .iter()
+        .filter(|t| !t.arch.iter().any(|a| a == arch))
+        .map(|t| t.name.as_str())
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    if strict {
+        return Err(anyhow!(
+            "The following topics do not provide packages for {}: {}",
+            arch,
+            missing.join(", ")
+        ));
+    }
+    for name in missing {
+        warn!(
+            "Topic {} does not provide packages for {}, nothing will be installed from it.",
+            name.cyan(),
+            arch
+        );
+    }
+
+    Ok(())
+}
+
+/// Generate apt preferences pinning the packages of each topic to the topic itself.
+fn generate_topic_preferences(topics: &[Topic]) -> String {
+    let mut stanzas = Vec::new();
+    for topic in topics {
+        if topic.packages.is_empty() {
+            continue;
+        }
+        stanzas.push(format!(
+            "Package: {}\nPin: release n={}\nPin-Priority: 1001\n",
+            topic.packages.join(" "),
+            topic.name
+        ));
+    }
+
+    stanzas.join("\n")
+}
+
+/// Convert a UNIX timestamp to a `YYYY-MM-DD` date string (UTC).
+pub fn format_date(timestamp: u64) -> String {
+    // Adapted from Howard Hinnant's `civil_from_days` algorithm
+    let z = (timestamp / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Print the available topics, optionally limited to the given architectures.
+pub fn print_topics(topics: &[Topic], arches: &[String], json: bool) -> Result<()> {
+    let topics = topics
+        .iter()
+        .filter(|t| arches.is_empty() || t.arch.iter().any(|a| arches.contains(a)))
+        .collect::<Vec<_>>();
+    if json {
+        let output = topics
+            .iter()
+            .map(|t| {
+                serde_json::json!({
+                    "name": t.name,
+                    "description": t.description,
+                    "date": t.date,
+                    "update_date": t.update_date,
+                    "arch": t.arch,
+                    "packages": t.packages,
+                })
+            })
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    let name_width = topics
+        .iter()
+        .map(|t| t.name.len())
+        .max()
+        .unwrap_or_default()
+        .max(4);
+    println!(
+        "{:<name_width$}  {:<10}  {:<40}  DESCRIPTION",
+        "NAME", "UPDATED", "ARCH"
+    );
+    for topic in topics {
+        println!(
+            "{:<name_width$}  {:<10}  {:<40}  {}",
+            topic.name,
+            format_date(topic.update_date),
+            topic.arch.join(","),
+            topic.description.as_deref().unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+pub fn save_topics(
+    sysroot: &Path,
+    topics: Vec<Topic>,
+    mirror: &str,
+    arches: &[&str],
+    deb822: bool,
+    signed_by: &[String],
+) -> Result<()> {
+    info!("{}", "Saving topic sources and ATM state ...".bold());
     // Prepare paths
     let mut atm_list_path = PathBuf::from(sysroot);
-    atm_list_path.push(ATM_LIST);
+    atm_list_path.push(if deb822 { ATM_SOURCES } else { ATM_LIST });
     let mut atm_state_path = PathBuf::from(sysroot);
     atm_state_path.push(ATM_STATE);
+    let mut atm_pref_path = PathBuf::from(sysroot);
+    atm_pref_path.push(ATM_PREFERENCES);
     let atm_list_parent = atm_list_path.parent().ok_or(anyhow!(
         "Failed to get parent path of {:#?}",
         &atm_list_path
@@ -86,31 +261,33 @@ pub fn save_topics(sysroot: &Path, topics: Vec<Topic>) -> Result<()> {
         "Failed to get parent path of {:#?}",
         &atm_state_path
     ))?;
+    let atm_pref_parent = atm_pref_path.parent().ok_or(anyhow!(
+        "Failed to get parent path of {:#?}",
+        &atm_pref_path
+    ))?;
     create_dir_all(atm_list_parent)?;
     create_dir_all(atm_state_parent)?;
+    create_dir_all(atm_pref_parent)?;
 
     // Prepare APT sources
     let topic_sources: Vec<String> = topics
         .iter()
-        .map(|x| format!("deb {} {} main", DEFAULT_MIRROR, x.name.clone()))
+        .map(|x| format_apt_source(mirror, &x.name, &["main"], arches, deb822, signed_by))
         .collect();
 
     // Save atm.list
-    eprintln!("{}", "Saving topic sources ...".bold().cyan());
-    let content = topic_sources
-        .into_iter()
-        .map(|x| (x + "\n"))
-        .collect::<String>();
-    let buf = content.as_bytes();
-    let mut writer = File::create(atm_list_path)?;
-    writer.write_all(buf)?;
-    writer.sync_all()?;
+    info!("{}", "Saving topic sources ...".bold().cyan());
+    let content = topic_sources.join(if deb822 { "\n" } else { "" });
+    atomic_write(&atm_list_path, content, 0o644)?;
+
+    // Save atm-topics.pref
+    info!("{}", "Saving topic preferences ...".bold().cyan());
+    atomic_write(&atm_pref_path, generate_topic_preferences(&topics), 0o644)?;
 
     // Save /var/lib/atm/state
-    eprintln!("{}", "Saving ATM state file ...".bold().cyan());
-    let writer = File::create(atm_state_path)?;
-    serde_json::to_writer(writer, &topics)?;
-    eprintln!(
+    info!("{}", "Saving ATM state file ...".bold().cyan());
+    atomic_write(&atm_state_path, serde_json::to_vec(&topics)?, 0o644)?;
+    info!(
         "{} {} {}",
         "Saved".bold(),
         topics.len().bold().cyan(),
@@ -133,14 +310,165 @@ fn test_fetch_topics() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn test_check_topics_arch() {
+    let topic = Topic {
+        name: "test-topic".to_string(),
+        description: None,
+        date: 0,
+        update_date: 0,
+        arch: vec!["amd64".to_string()],
+        packages: vec![],
+        draft: false,
+    };
+    let topics = vec![topic];
+    assert!(check_topics_arch(&topics, "amd64", true).is_ok());
+    assert!(check_topics_arch(&topics, "loongarch64", false).is_ok());
+    assert!(check_topics_arch(&topics, "loongarch64", true).is_err());
+}
+
+#[test]
+fn test_generate_topic_preferences() {
+    let topics = vec![
+        Topic {
+            name: "systemd-256".to_string(),
+            description: None,
+            date: 0,
+            update_date: 0,
+            arch: vec!["amd64".to_string()],
+            packages: vec!["systemd".to_string(), "systemd-boot".to_string()],
+            draft: false,
+        },
+        Topic {
+            name: "empty".to_string(),
+            description: None,
+            date: 0,
+            update_date: 0,
+            arch: vec!["amd64".to_string()],
+            packages: vec![],
+            draft: false,
+        },
+        Topic {
+            name: "kernel-6.12.9".to_string(),
+            description: None,
+            date: 0,
+            update_date: 0,
+            arch: vec!["amd64".to_string()],
+            packages: vec!["linux-kernel-6.12.9".to_string()],
+            draft: false,
+        },
+    ];
+    let prefs = generate_topic_preferences(&topics);
+    let parsed = oma_debcontrol::parse_str(&prefs).unwrap();
+    assert_eq!(parsed.len(), 2);
+    let field = |i: usize, name: &str| {
+        parsed[i]
+            .fields
+            .iter()
+            .find(|f| f.name == name)
+            .map(|f| f.value.clone())
+            .unwrap()
+    };
+    assert_eq!(field(0, "Package"), "systemd systemd-boot");
+    assert_eq!(field(0, "Pin"), "release n=systemd-256");
+    assert_eq!(field(0, "Pin-Priority"), "1001");
+    assert_eq!(field(1, "Pin"), "release n=kernel-6.12.9");
+}
+
+#[test]
+fn test_filter_topics() -> Result<()> {
+    let make_topic = |name: &str| Topic {
+        name: name.to_string(),
+        description: None,
+        date: 0,
+        update_date: 0,
+        arch: vec!["amd64".to_string()],
+        packages: vec![],
+        draft: false,
+    };
+    let all = vec![
+        make_topic("kernel-6.12.9"),
+        make_topic("kernel-6.12.10"),
+        make_topic("glibc-2.40"),
+        make_topic("glibc-2.40-round2"),
+    ];
+    let names = |topics: Vec<Topic>| topics.into_iter().map(|t| t.name).collect::<Vec<_>>();
+
+    // exact names keep exact semantics
+    let filtered = filter_topics(vec!["glibc-2.40".to_string()], all.clone(), true)?;
+    assert_eq!(names(filtered), vec!["glibc-2.40"]);
+    // glob patterns
+    let filtered = filter_topics(vec!["kernel-*".to_string()], all.clone(), false)?;
+    assert_eq!(names(filtered), vec!["kernel-6.12.9", "kernel-6.12.10"]);
+    // prefix matches
+    let filtered = filter_topics(vec!["glibc-2.40-r".to_string()], all.clone(), true)?;
+    assert_eq!(names(filtered), vec!["glibc-2.40-round2"]);
+    // ambiguous patterns are errors in strict mode
+    assert!(filter_topics(vec!["kernel".to_string()], all.clone(), true).is_err());
+    // no matches at all
+    assert!(filter_topics(vec!["nonexistent".to_string()], all, false).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn test_format_date() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(951782400), "2000-02-29");
+    assert_eq!(format_date(1735689599), "2024-12-31");
+}
+
 #[test]
 fn test_save_topics() -> Result<()> {
     let topics = fetch_topics()?;
-    save_topics(&PathBuf::from("/tmp/aoscbootstrap"), topics)
+    save_topics(
+        &PathBuf::from("/tmp/aoscbootstrap"),
+        topics,
+        crate::DEFAULT_MIRROR,
+        &["amd64", "all"],
+        false,
+        &[],
+    )
 }
 
 #[test]
 fn test_save_empty_topics() -> Result<()> {
     let topics = Vec::<Topic>::new();
-    save_topics(&PathBuf::from("/tmp/aoscbootstrap"), topics)
+    save_topics(
+        &PathBuf::from("/tmp/aoscbootstrap"),
+        topics,
+        crate::DEFAULT_MIRROR,
+        &["amd64", "all"],
+        false,
+        &[],
+    )
+}
+
+#[test]
+fn test_save_topics_mirror() -> Result<()> {
+    let topic = Topic {
+        name: "kernel-6.12".to_string(),
+        description: None,
+        date: 0,
+        update_date: 0,
+        arch: vec!["amd64".to_string()],
+        packages: vec!["linux-kernel-6.12".to_string()],
+        draft: false,
+    };
+    let root = tempfile::tempdir()?;
+    let mirror = "https://mirrors.example.org/anthon/debs";
+    save_topics(
+        root.path(),
+        vec![topic],
+        mirror,
+        &["amd64", "all"],
+        false,
+        &[],
+    )?;
+    assert_eq!(
+        std::fs::read_to_string(root.path().join(ATM_LIST))?,
+        format!("deb {} kernel-6.12 main\n", mirror)
+    );
+
+    Ok(())
 }
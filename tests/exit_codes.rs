@@ -0,0 +1,125 @@
+use std::process::{Command, Output};
+
+fn aoscbootstrap(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_aoscbootstrap"))
+        .args(args)
+        .arg("--color=never")
+        .output()
+        .expect("failed to run aoscbootstrap")
+}
+
+fn write_config(dir: &std::path::Path) -> String {
+    let path = dir.join("test.toml");
+    std::fs::write(
+        &path,
+        "stub-packages = [\"bash\"]\nbase-packages = [\"apt\"]\nbranch = \"stable\"\n",
+    )
+    .unwrap();
+
+    path.display().to_string()
+}
+
+#[test]
+fn test_config_errors() {
+    // no config at all
+    let output = Command::new(env!("CARGO_BIN_EXE_aoscbootstrap"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+    let output = aoscbootstrap(&["check-config", "-c", "/nonexistent/aoscbootstrap.toml"]);
+    assert_eq!(output.status.code(), Some(2));
+    // the uname name is mapped, but typos are not
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let target = dir.path().join("target").display().to_string();
+    let output = aoscbootstrap(&["-c", &config, "-a", "arm65", &target]);
+    assert_eq!(output.status.code(), Some(2));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Unknown architecture arm65"), "{}", stderr);
+    assert!(stderr.contains("arm64"), "{}", stderr);
+}
+
+#[test]
+fn test_existing_target() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let target = dir.path().display().to_string();
+    let output = aoscbootstrap(&["-c", &config, &target]);
+    assert_eq!(output.status.code(), Some(8));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("already exists"), "{}", stderr);
+    assert!(stderr.contains("--force"), "{}", stderr);
+    assert!(!stderr.contains("panicked"), "{}", stderr);
+}
+
+#[test]
+fn test_excluded_stub() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let target = dir.path().join("target").display().to_string();
+    // refused before reaching the (unreachable) mirror, qualified or not
+    for exclude in ["bash", "bash:amd64", "bash<6"] {
+        let output = aoscbootstrap(&[
+            "-c",
+            &config,
+            &target,
+            "--dry-run",
+            "--arch",
+            "amd64",
+            "--mirror",
+            "http://127.0.0.1:9/debs",
+            "-e",
+            exclude,
+        ]);
+        assert_eq!(output.status.code(), Some(2), "{}", exclude);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("is a stub package"), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_unreachable_mirror() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    // nothing listens on the discard port of the loopback
+    let output = aoscbootstrap(&[
+        "check-config",
+        "-c",
+        &config,
+        "--online",
+        "--arch",
+        "amd64",
+        "--mirror",
+        "http://127.0.0.1:9/debs",
+    ]);
+    assert_eq!(output.status.code(), Some(3));
+}
+
+#[test]
+fn test_interrupted_target() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = write_config(dir.path());
+    let target = dir.path().join("target");
+    std::fs::create_dir(&target).unwrap();
+    std::fs::write(target.join(".aoscbootstrap-incomplete"), "phase=download\n").unwrap();
+    let target = target.display().to_string();
+    let output = aoscbootstrap(&["-c", &config, &target]);
+    assert_eq!(output.status.code(), Some(8));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("during the download phase"), "{}", stderr);
+    assert!(stderr.contains("start afresh"), "{}", stderr);
+
+    // with stage 1 done, it can be resumed
+    let state = dir.path().join("target/var/lib/aoscbootstrap");
+    std::fs::create_dir_all(&state).unwrap();
+    std::fs::write(
+        state.join("pending-stage2"),
+        "script=/aoscbootstrap-stage2.sh\narch=amd64\n",
+    )
+    .unwrap();
+    std::fs::write(dir.path().join("target/aoscbootstrap-stage2.sh"), "").unwrap();
+    let output = aoscbootstrap(&["-c", &config, &target]);
+    assert_eq!(output.status.code(), Some(8));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("aoscbootstrap resume"), "{}", stderr);
+}
@@ -0,0 +1,269 @@
+//! End-to-end tests against a local mirror, run with `cargo test --features integration`
+//!
+//! The mirror serves a few tiny packages built here, so the tests do not need the network
+//! nor root: they stop after stage 1 (`--stage1-only --unprivileged`) and export the target.
+#![cfg(feature = "integration")]
+
+use std::{
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use sha2::{Digest, Sha256};
+
+struct Package {
+    name: &'static str,
+    version: &'static str,
+    depends: &'static str,
+    /// Files shipped by the package, with their contents
+    files: &'static [(&'static str, &'static str)],
+}
+
+const PACKAGES: &[Package] = &[
+    Package {
+        name: "hello",
+        version: "1.0",
+        depends: "libgreet (>= 2.0)",
+        files: &[("usr/bin/hello", "#!/bin/sh\necho hello\n")],
+    },
+    Package {
+        name: "libgreet",
+        version: "1.0",
+        depends: "",
+        files: &[("usr/share/greet/greeting", "hi\n")],
+    },
+    Package {
+        name: "libgreet",
+        version: "2.0",
+        depends: "",
+        files: &[("usr/share/greet/greeting", "hello\n")],
+    },
+    Package {
+        name: "extra",
+        version: "0.1",
+        depends: "hello",
+        files: &[("usr/share/doc/extra/README", "extra\n")],
+    },
+];
+
+fn sha256(data: &[u8]) -> String {
+    faster_hex::hex_string(&Sha256::digest(data))
+}
+
+fn tar_gz(entries: &[(&str, &[u8], u32)]) -> Vec<u8> {
+    let mut tar = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::fast()));
+    for (path, data, mode) in entries {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_mode(*mode);
+        header.set_mtime(0);
+        header.set_cksum();
+        tar.append_data(&mut header, path, *data).unwrap();
+    }
+
+    tar.into_inner().unwrap().finish().unwrap()
+}
+
+fn build_deb(package: &Package) -> Vec<u8> {
+    let mut control = format!(
+        "Package: {}\nVersion: {}\nArchitecture: amd64\nMaintainer: Tester <tester@example.com>\nInstalled-Size: 1\n",
+        package.name, package.version
+    );
+    if !package.depends.is_empty() {
+        control.push_str(&format!("Depends: {}\n", package.depends));
+    }
+    control.push_str("Description: test package\n");
+    let control = tar_gz(&[("./control", control.as_bytes(), 0o644)]);
+    let files = package
+        .files
+        .iter()
+        .map(|(path, content)| {
+            let mode = if path.contains("/bin/") { 0o755 } else { 0o644 };
+            (*path, content.as_bytes(), mode)
+        })
+        .collect::<Vec<_>>();
+    let data = tar_gz(&files);
+
+    let mut deb = ar::Builder::new(Vec::new());
+    for (name, content) in [
+        ("debian-binary", &b"2.0\n"[..]),
+        ("control.tar.gz", &control),
+        ("data.tar.gz", &data),
+    ] {
+        let header = ar::Header::new(name.as_bytes().to_vec(), content.len() as u64);
+        deb.append(&header, content).unwrap();
+    }
+
+    deb.into_inner().unwrap()
+}
+
+/// Write the pool and the manifests of the `stable` branch into `root`
+fn build_repo(root: &Path) {
+    let mut manifest = String::new();
+    for package in PACKAGES {
+        let deb = build_deb(package);
+        let path = format!(
+            "pool/stable/main/{}_{}_amd64.deb",
+            package.name, package.version
+        );
+        std::fs::create_dir_all(root.join("pool/stable/main")).unwrap();
+        std::fs::write(root.join(&path), &deb).unwrap();
+        manifest.push_str(&format!(
+            "Package: {}\nVersion: {}\nArchitecture: amd64\nInstalled-Size: 1\nFilename: {}\nSize: {}\nSHA256: {}\nDescription: test package\n",
+            package.name,
+            package.version,
+            path,
+            deb.len(),
+            sha256(&deb)
+        ));
+        if !package.depends.is_empty() {
+            manifest.push_str(&format!("Depends: {}\n", package.depends));
+        }
+        manifest.push('\n');
+    }
+    for (arch, content) in [("amd64", manifest.as_str()), ("all", "")] {
+        let dir = root.join(format!("dists/stable/main/binary-{}", arch));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("Packages"), content).unwrap();
+    }
+}
+
+/// Serve the files under `root` over HTTP, returning the URL of the mirror
+fn serve(root: PathBuf) -> String {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", server.server_addr());
+    std::thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let path = root.join(request.url().trim_start_matches('/'));
+            let response = match std::fs::read(&path) {
+                Ok(data) if !request.url().contains("..") => {
+                    request.respond(tiny_http::Response::from_data(data))
+                }
+                _ => request.respond(tiny_http::Response::empty(404)),
+            };
+            response.ok();
+        }
+    });
+
+    url
+}
+
+fn aoscbootstrap(args: &[&str]) -> Output {
+    let output = Command::new(env!("CARGO_BIN_EXE_aoscbootstrap"))
+        .args(args)
+        .args(["--color=never", "--unprivileged", "--no-progressbar"])
+        .output()
+        .expect("failed to run aoscbootstrap");
+    assert!(
+        output.status.success(),
+        "aoscbootstrap {:?} failed:\n{}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    output
+}
+
+#[test]
+fn test_stage1_and_export() {
+    let repo = tempfile::tempdir().unwrap();
+    build_repo(repo.path());
+    let mirror = serve(repo.path().to_path_buf());
+    let work = tempfile::tempdir().unwrap();
+    let config = work.path().join("test.toml");
+    std::fs::write(
+        &config,
+        "stub-packages = [\"hello\"]\nbase-packages = [\"extra\"]\nrequired-packages = [\"hello\"]\nbranch = \"stable\"\n",
+    )
+    .unwrap();
+    let target = work.path().join("target");
+    let target = target.to_str().unwrap();
+
+    // resolution, download and stage 1, with a component missing from the mirror
+    let output = aoscbootstrap(&[
+        "create",
+        "--config",
+        config.to_str().unwrap(),
+        "--target",
+        target,
+        "--mirror",
+        &mirror,
+        "--arch",
+        "amd64",
+        "--comps",
+        "bsp",
+        "--stage1-only",
+    ]);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Skipped 2 of 4 manifests: bsp/binary-amd64, bsp/binary-all"),
+        "{}",
+        stderr
+    );
+    let target = Path::new(target);
+    // the stub packages are extracted, with the newest libgreet as hello requires
+    assert_eq!(
+        std::fs::read_to_string(target.join("usr/share/greet/greeting")).unwrap(),
+        "hello\n"
+    );
+    assert!(target.join("usr/bin/hello").is_file());
+    // the base packages are only downloaded, for stage 2
+    assert!(!target.join("usr/share/doc/extra").exists());
+    let archives = target.join("var/cache/apt/archives");
+    for name in [
+        "hello_1.0_amd64.deb",
+        "libgreet_2.0_amd64.deb",
+        "extra_0.1_amd64.deb",
+    ] {
+        let deb = std::fs::read(archives.join(name)).unwrap();
+        let path = format!("pool/stable/main/{}", name);
+        assert_eq!(
+            sha256(&deb),
+            sha256(&std::fs::read(repo.path().join(path)).unwrap())
+        );
+    }
+    assert!(!archives.join("libgreet_1.0_amd64.deb").exists());
+    // only the dependencies are marked as automatically installed
+    let extended_states =
+        std::fs::read_to_string(target.join("var/lib/apt/extended_states")).unwrap();
+    assert_eq!(
+        extended_states,
+        "Package: libgreet\nArchitecture: amd64\nAuto-Installed: 1\n\n"
+    );
+    let sources = std::fs::read_to_string(target.join("etc/apt/sources.list")).unwrap();
+    assert!(sources.contains(&format!("deb {} stable main", mirror)));
+    let status = std::fs::read_to_string(target.join("var/lib/dpkg/status")).unwrap();
+    assert!(status.contains("Package: libgreet\n"));
+
+    // export, with the checksum written next to the archive
+    let archive = work.path().join("out.tar.gz");
+    aoscbootstrap(&[
+        "export",
+        "--target",
+        target.to_str().unwrap(),
+        "--export-tar-gz",
+        archive.to_str().unwrap(),
+        "--arch",
+        "amd64",
+    ]);
+    let data = std::fs::read(&archive).unwrap();
+    assert_eq!(
+        std::fs::read_to_string(work.path().join("out.tar.gz.sha256sum")).unwrap(),
+        format!("{} *out.tar.gz\n", sha256(&data))
+    );
+    let mut tar = tar::Archive::new(GzDecoder::new(&data[..]));
+    let mut greeting = None;
+    for entry in tar.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().to_path_buf();
+        assert_ne!(path, Path::new("./.aoscbootstrap.lock"));
+        if path == Path::new("./usr/share/greet/greeting") {
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            greeting = Some(content);
+        }
+    }
+    assert_eq!(greeting.as_deref(), Some("hello\n"));
+}